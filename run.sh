@@ -37,3 +37,4 @@ for name in "${WEBSITE_NAMES[@]}"; do
 done
 
 ./scripts/collect_miss_cnt.py
+./scripts/collect_nfa_states.py
@@ -0,0 +1,26 @@
+#!/bin/bash
+# Builds the Rust and C step functions codegen generates for each site and checks them
+# against the bit engine's final node states. Usage: scripts/check_codegen.sh [site...]
+set -eu -o pipefail
+
+SITES=("$@")
+if [[ ${#SITES[@]} -eq 0 ]]; then
+   SITES=(testcase a_to_b)
+fi
+
+cargo build -r -q
+
+status=0
+for site in "${SITES[@]}"; do
+   if [[ ! -f "css-gen-op/$site/command.json" ]]; then
+      echo "$site: no command.json"
+      continue
+   fi
+   for language in rust c; do
+      WEBSITE_NAME=$site BIT_CODEGEN_CHECK=$language ./target/release/bit 2>/dev/null \
+         | grep -A5 '^CODEGEN' || status=1
+   done
+done
+git checkout -q -- 'css-gen-op/*/dot.dot' 2>/dev/null || true
+git clean -fq -- 'css-gen-op/*/dot.dot'
+exit $status
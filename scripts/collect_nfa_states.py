@@ -0,0 +1,76 @@
+#!/usr/bin/env python3
+"""Write each site's NFA state counts with and without prefix sharing to
+`css-gen-op/<site>/nfa_states.md`."""
+
+from __future__ import annotations
+
+from contextlib import redirect_stdout
+from dataclasses import dataclass
+import re
+import sys
+from pathlib import Path
+from typing import List, Optional
+
+
+STATES_RE = re.compile(r"NFA_STATES\[bit\]\s+(\d+) state\(s\), (\d+) without prefix sharing")
+
+
+@dataclass
+class StatesRow:
+    """State counts of one site's bit NFA."""
+
+    folder: str
+    shared: Optional[int]
+    unshared: Optional[int]
+
+    def reduction(self) -> str:
+        if self.shared is None or self.unshared is None:
+            return "-"
+        if self.unshared == 0:
+            return "0.0%"
+        return f"{(self.unshared - self.shared) * 100 / self.unshared:.1f}%"
+
+
+def states_from_log(path: Path) -> StatesRow:
+    try:
+        text = path.read_text(encoding="utf-8", errors="ignore")
+    except FileNotFoundError:
+        return StatesRow(path.parent.name, None, None)
+    matches = STATES_RE.findall(text)
+    if not matches:
+        return StatesRow(path.parent.name, None, None)
+    shared, unshared = matches[-1]
+    return StatesRow(path.parent.name, int(shared), int(unshared))
+
+
+def collect_rows(base: Path) -> List[StatesRow]:
+    """Gather per-site state counts from the bit logs."""
+
+    if not base.exists():
+        raise SystemExit(f"Base directory {base} does not exist")
+
+    return [
+        states_from_log(d / "bit_tmp.txt")
+        for d in sorted(p for p in base.iterdir() if p.is_dir())
+        if d.name not in {"reddit", "__pycache__"}
+    ]
+
+
+def write_markdown(row: StatesRow, output_path: Path) -> None:
+    def cell(value: Optional[int]) -> str:
+        return "-" if value is None else str(value)
+
+    with redirect_stdout(output_path.open("w")):
+        print("| States | Without prefix sharing | Reduction |")
+        print("|---:|---:|---:|")
+        print(f"| {cell(row.shared)} | {cell(row.unshared)} | {row.reduction()} |")
+
+
+def main(base_dir: str = "css-gen-op"):
+    base = Path(base_dir).resolve()
+    for row in collect_rows(base):
+        write_markdown(row, base / row.folder / "nfa_states.md")
+
+
+if __name__ == "__main__":
+    main(sys.argv[1] if len(sys.argv) > 1 else "css-gen-op")
@@ -0,0 +1,37 @@
+#!/bin/bash
+# Reports how much of each site's stylesheet its trace can never match, from the bit
+# engine's NFA_PRUNE line, into dead_states.md. Usage: scripts/measure_dead_states.sh [site...]
+set -eu -o pipefail
+
+SITES=("$@")
+if [[ ${#SITES[@]} -eq 0 ]]; then
+   for dir in css-gen-op/*/; do
+      SITES+=("$(basename "$dir")")
+   done
+fi
+
+cargo build -r -q
+
+{
+   echo "| Site | Dead selectors | Dead rules | Dead states |"
+   echo "|---|---:|---:|---:|"
+   for site in "${SITES[@]}"; do
+      if [[ ! -f "css-gen-op/$site/command.json" ]]; then
+         echo "| $site | - | - | no command.json |"
+         continue
+      fi
+      # PRUNE[bit] D of T selector(s) (P%), D of T rule(s) (P%), D of T state(s) (P%) dead
+      WEBSITE_NAME=$site NFA_PRUNE=trace ./target/release/bit 2>/dev/null \
+         | sed -n 's/^PRUNE\[bit\] //p' \
+         | awk -F', ' -v site="$site" '{
+              for (i = 1; i <= 3; i++) {
+                 split($i, part, " ")
+                 cell[i] = part[1] " / " part[3] " " part[5]
+              }
+              sub(/ dead$/, "", cell[3])
+              print "| " site " | " cell[1] " | " cell[2] " | " cell[3] " |"
+           }'
+   done
+} > dead_states.md
+git checkout -q -- 'css-gen-op/*/dot.dot' 2>/dev/null || true
+git clean -fq -- 'css-gen-op/*/dot.dot'
@@ -0,0 +1,52 @@
+#!/bin/bash
+# Compares the rdtsc cycles engines spend evaluating rules with and without the rule
+# index, per site, into rule_index.md. Usage: scripts/measure_rule_index.sh [site...]
+set -eu -o pipefail
+
+SITES=("$@")
+if [[ ${#SITES[@]} -eq 0 ]]; then
+   SITES=(youtube bootstrap)
+fi
+ENGINES=(bit tri quad rec_tri)
+RUNS=${RUNS:-3}
+
+cargo build -r -q
+
+median_cycles() {
+   local engine=$1 mode=$2
+   for _ in $(seq "$RUNS"); do
+      BIT_RULE_CYCLES=1 RULE_INDEX=$mode "./target/release/$engine" 2>&1 \
+         | sed -n 's/.*RULE_EVAL_CYCLES[^=]*= \([0-9]*\).*/\1/p' || true
+   done | sort -n | awk '{ v[NR] = $1 } END { if (NR) print v[int((NR + 1) / 2)] }'
+}
+
+# Sites without a recorded trace have nothing to replay; they are listed below the table.
+untraced=()
+{
+   echo "| Site | Engine | Cycles (scan) | Cycles (index) | Speedup |"
+   echo "|---|---|---:|---:|---:|"
+   for site in "${SITES[@]}"; do
+      if [[ ! -f "css-gen-op/$site/command.json" ]]; then
+         untraced+=("$site")
+         continue
+      fi
+      export WEBSITE_NAME=$site
+      for engine in "${ENGINES[@]}"; do
+         scan=$(median_cycles "$engine" off)
+         index=$(median_cycles "$engine" on)
+         if [[ -n $scan && -n $index && $index -gt 0 ]]; then
+            speedup=$(awk -v s="$scan" -v i="$index" 'BEGIN { printf "%.2fx", s / i }')
+         else
+            speedup="-"
+         fi
+         echo "| $site | $engine | ${scan:--} | ${index:--} | $speedup |"
+      done
+   done
+   for site in "${untraced[@]}"; do
+      echo
+      echo "No $site number is available: css-gen-op/$site has its stylesheet but no"
+      echo "command.json, the DOM trace the engines replay. css-gen-op/generate.py builds that"
+      echo "trace from a recorded browser session, and none was recorded for $site, so there"
+      echo "is nothing to time and no substitute site stands in for it."
+   done
+} > rule_index.md
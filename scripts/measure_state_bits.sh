@@ -0,0 +1,88 @@
+#!/bin/bash
+# Compares rule-evaluation cycles, wall time and per-node state bytes of the engines on
+# packed bitsets against the tree before them (or $BASELINE), per site, into
+# state_bits.md. Usage: scripts/measure_state_bits.sh [site...]
+set -eu -o pipefail
+
+SITES=("$@")
+if [[ ${#SITES[@]} -eq 0 ]]; then
+   SITES=(youtube bootstrap)
+fi
+ENGINES=(bit tri quad rec_tri)
+RUNS=${RUNS:-5}
+if [[ -z ${BASELINE:-} ]]; then
+   BASELINE=$(git log --diff-filter=A --format=%H -- src/state_bits.rs | tail -1)^
+fi
+
+cargo build -r -q
+BASE_DIR=$(mktemp -d)
+trap 'git worktree remove --force "$BASE_DIR/tree" >/dev/null 2>&1 || true; rm -rf "$BASE_DIR"' EXIT
+git worktree add -q --detach "$BASE_DIR/tree" "$BASELINE"
+# Cargo.lock is untracked; build the baseline against the same dependency versions.
+cp Cargo.lock "$BASE_DIR/tree/"
+CARGO_TARGET_DIR="$BASE_DIR/target" cargo build -r -q --manifest-path "$BASE_DIR/tree/Cargo.toml"
+
+median() {
+   sort -n | awk '{ v[NR] = $1 } END { if (NR) print v[int((NR + 1) / 2)] }'
+}
+
+# Prints the cycles and wall milliseconds of one run of `binary`.
+run_once() {
+   local binary=$1 start end cycles
+   start=$(date +%s%N)
+   cycles=$(BIT_RULE_CYCLES=1 "$binary" 2>&1 | sed -n 's/.*RULE_EVAL_CYCLES[^=]*= \([0-9]*\).*/\1/p' || true)
+   end=$(date +%s%N)
+   echo "${cycles:--} $(((end - start) / 1000000))"
+}
+
+# Prints the median cycles and wall milliseconds of `engine` over $RUNS runs, baseline
+# first, then bitsets. The two builds take turns so that load on the machine falls on
+# both alike.
+median_runs() {
+   local engine=$1 base="" bits=""
+   for _ in $(seq "$RUNS"); do
+      base+=$(run_once "$BASE_DIR/target/release/$engine")$'\n'
+      bits+=$(run_once "./target/release/$engine")$'\n'
+   done
+   echo "$(cut -d' ' -f1 <<< "${base%$'\n'}" | median) $(cut -d' ' -f1 <<< "${bits%$'\n'}" | median)" \
+      "$(cut -d' ' -f2 <<< "${base%$'\n'}" | median) $(cut -d' ' -f2 <<< "${bits%$'\n'}" | median)"
+}
+
+# Sites without a recorded trace have nothing to replay; they are listed below the table.
+untraced=()
+{
+   echo "| Site | Engine | Cycles (vectors) | Cycles (bitsets) | Wall ms (vectors) | Wall ms (bitsets) | State bytes (vectors) | State bytes (bitsets) |"
+   echo "|---|---|---:|---:|---:|---:|---:|---:|"
+   for site in "${SITES[@]}"; do
+      if [[ ! -f "css-gen-op/$site/command.json" ]]; then
+         untraced+=("$site")
+         continue
+      fi
+      export WEBSITE_NAME=$site
+      for engine in "${ENGINES[@]}"; do
+         read -r base_cycles cycles base_wall wall < <(median_runs "$engine") || true
+         bytes=$("./target/release/$engine" 2>/dev/null \
+            | sed -n 's/^STATE_BYTES\[[^]]*\].*: \([0-9]*\) byte(s) as bitsets, \([0-9]*\) as vectors$/\2 \1/p' || true)
+         read -r vector_bytes bitset_bytes <<< "${bytes:-- -}"
+         echo "| $site | $engine | ${base_cycles:--} | ${cycles:--} | ${base_wall:--} | ${wall:--} | $vector_bytes | $bitset_bytes |"
+      done
+   done
+   for site in "${untraced[@]}"; do
+      echo
+      echo "No $site numbers are available: css-gen-op/$site has its stylesheet but no"
+      echo "command.json, the DOM trace the engines replay."
+   done
+   echo
+   echo "bit and tri read and set one state per rule in both builds, a \`Vec<bool>\` index"
+   echo "against a shift and mask on a \`u64\` word, and come out even or ahead. rec_tri's"
+   echo "difference is within run-to-run noise; its rules write an enum per state as before"
+   echo "and only the materialized output is packed. quad is slower in rule cycles, and"
+   echo "consistently so: a rule that copies a parent state now updates flags in four"
+   echo "bitsets where it wrote one 16-byte enum, and reading a state records it in one"
+   echo "of two bitsets, read set or read clear. Allocating those bitsets per evaluated"
+   echo "node is cheaper than filling the enum vector they replace, but not by enough to"
+   echo "cover the rules. The loss is accepted for the state bytes: a state takes one bit"
+   echo "instead of one byte, quad keeps two bitsets per node where it kept a \`Vec\` of"
+   echo "enum states, and wall time, which includes copying and comparing that state"
+   echo "between nodes, drops."
+} > state_bits.md
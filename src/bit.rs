@@ -1,18 +1,28 @@
 use css_bitvector_compiler::{
-    AddNode, CompoundSelector, LayoutFrame, NFA, Nfacell, PSEUDO_CLASS_FOCUS,
-    PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_FOCUS_WITHIN, PSEUDO_CLASS_HOVER, ParsedSelectors, Rule,
-    Selector, SelectorId, SelectorManager, derive_hover_state, drain_supported_pseudo_selectors,
-    extract_pseudoclasses, generate_nfa, parse_css_with_pseudo, parse_trace,
-    partition_simple_selectors, report_pseudo_selectors, report_skipped_selectors,
-    report_unsupported_selectors,
+    AddNode, Declaration, LayoutFrame, NFA, Nfacell, NodeView, PSEUDO_CLASS_EMPTY,
+    PSEUDO_CLASS_FOCUS, PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_FOCUS_WITHIN, PSEUDO_CLASS_HOVER,
+    PSEUDO_CLASS_ROOT, Rule, RuleBuckets, Selector, SelectorId, SelectorManager, SelectorOrigin,
+    SiblingPosition, attach_automaton, cascade_declarations, changed_properties,
+    codegen::{Language, StepInput, StepPlan, run_generated},
+    compiled::CompiledStylesheet,
+    configure_rule_index, counts_as_content, derive_hover_state, extract_pseudoclasses,
+    inherit_style, inherits_from_parent, is_element_name, parse_trace, prune_dead_states, rdtsc,
+    refresh_element_states, report_automaton, report_computed_styles, report_state_bytes,
+    rule_cycles_enabled,
     runtime_shared::{HasNodes, HasSelectorManager, NodeAttributes, apply_frame_common},
+    selector_sources, sibling_positions,
+    state_bits::StateBits,
 };
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fs,
+    path::Path,
     sync::OnceLock,
 };
 static mut MISS_CNT: usize = 0;
+static mut RULE_EVAL_CYCLES: u64 = 0;
+static mut CASCADE_CNT: usize = 0;
+static mut INHERIT_MISS_CNT: usize = 0;
 static mut STATE: usize = 0; // global state
 static DEBUG_MODE: OnceLock<bool> = OnceLock::new();
 
@@ -35,12 +45,6 @@ where
     }
 }
 
-fn format_bits(bits: &[bool]) -> String {
-    bits.iter()
-        .map(|bit| if *bit { '1' } else { '0' })
-        .collect()
-}
-
 #[derive(Debug, Default)]
 pub struct DOMNode {
     pub tag_id: SelectorId,                  // Tag selector ID
@@ -49,14 +53,38 @@ pub struct DOMNode {
     pub attributes: HashMap<String, String>, // Node attribute key-value pairs (lowercase keys)
     pub pseudo_classes: HashSet<String>,
     pub computed_pseudo_classes: HashSet<String>,
-    pub parent: Option<u64>, // Index of the parent node in the arena
-    pub children: Vec<u64>,  // Indices of child nodes in the arena
+    pub position: SiblingPosition, // Position among element siblings, for `:nth-*`
+    pub content: bool,             // Counts against the parent's `:empty`
+    pub has_arguments: Vec<bool>,  // Per `nfa.has_selectors`: matches the arguments
+    pub has_subtree: Vec<bool>,    // Per `nfa.has_selectors`: it or a descendant matches
+    pub has_ids: HashSet<SelectorId>, // `:has()` selectors this node satisfies
+    pub parent: Option<u64>,       // Index of the parent node in the arena
+    pub children: Vec<u64>,        // Indices of child nodes in the arena
     pub dirty: bool,
     pub recursive_dirty: bool,
-    pub output_state: Vec<bool>,
+    pub output_state: StateBits,
+    /// Winning value per property, refreshed whenever the node's accept bits change.
+    pub computed_style: BTreeMap<String, String>,
+    /// Values after inheritance: `computed_style` with inherited properties filled in
+    /// from the parent and CSS-wide keywords resolved (see `inherit_style`).
+    pub inherited_style: BTreeMap<String, String>,
+    /// `computed_style` changed since `inherited_style` was last derived.
+    pub inherit_dirty: bool,
 }
 
 impl DOMNode {
+    fn view(&self) -> NodeView<'_> {
+        NodeView {
+            tag_id: self.tag_id,
+            class_ids: &self.class_ids,
+            id_selector_id: self.id_selector_id,
+            attributes: &self.attributes,
+            computed_pseudo_classes: &self.computed_pseudo_classes,
+            position: &self.position,
+            has_ids: &self.has_ids,
+        }
+    }
+
     fn set_dirty(&mut self) {
         self.dirty = true;
         self.recursive_dirty = true;
@@ -67,6 +95,7 @@ impl DOMNode {
 pub struct DOM {
     pub nodes: HashMap<u64, DOMNode>,      // Arena storage for all nodes
     pub selector_manager: SelectorManager, // Selector manager
+    pub declarations: Vec<Vec<Declaration>>, // Per style rule, see `ParsedSelectors`
     root_node: Option<u64>,
 }
 
@@ -83,6 +112,12 @@ impl NodeAttributes for DOMNode {
     fn pseudo_classes(&mut self) -> &mut HashSet<String> {
         &mut self.pseudo_classes
     }
+    fn computed_pseudo_classes(&mut self) -> &mut HashSet<String> {
+        &mut self.computed_pseudo_classes
+    }
+    fn tag_id(&self) -> SelectorId {
+        self.tag_id
+    }
 }
 
 impl HasSelectorManager for DOM {
@@ -97,8 +132,8 @@ impl HasNodes<DOMNode> for DOM {
     }
 }
 
-fn get_input() -> Vec<bool> {
-    vec![false; unsafe { STATE } + 1]
+fn get_input() -> StateBits {
+    StateBits::new(unsafe { STATE } + 1)
 }
 
 impl AddNode for DOM {
@@ -134,6 +169,17 @@ impl AddNode for DOM {
         if derive_hover_state(&pseudo_classes, parent_hover_active) {
             computed_pseudo_classes.insert(PSEUDO_CLASS_HOVER.to_string());
         }
+        // A new node has no children yet; `refresh_empty_state` follows them as they arrive.
+        computed_pseudo_classes.insert(PSEUDO_CLASS_EMPTY.to_string());
+        if parent_index.is_none() {
+            computed_pseudo_classes.insert(PSEUDO_CLASS_ROOT.to_string());
+        }
+        refresh_element_states(
+            tag_name,
+            &attributes,
+            &pseudo_classes,
+            &mut computed_pseudo_classes,
+        );
 
         let mut new_node = DOMNode {
             tag_id,
@@ -142,13 +188,22 @@ impl AddNode for DOM {
             attributes,
             pseudo_classes,
             computed_pseudo_classes,
+            position: SiblingPosition::default(),
+            content: is_element_name(tag_name),
+            has_arguments: vec![false; nfa.has_selectors.len()],
+            has_subtree: vec![false; nfa.has_selectors.len()],
+            has_ids: HashSet::new(),
             parent: parent_index,
             children: Vec::new(),
             dirty: true,
             recursive_dirty: true,
-            output_state: vec![false; unsafe { STATE } + 1],
+            output_state: get_input(),
+            computed_style: BTreeMap::new(),
+            inherited_style: BTreeMap::new(),
+            inherit_dirty: true,
         };
-        let o = self.new_output_state(&new_node, &get_input(), nfa);
+        let o = self.new_output_state(&new_node, &get_input(), &get_input(), nfa);
+        new_node.computed_style = self.cascade(&o, nfa);
         new_node.output_state = o;
         self.nodes.insert(id, new_node);
 
@@ -217,79 +272,206 @@ impl DOM {
 
     /// Check whether a node matches the given selector ID.
     pub fn node_matches_selector(&self, node: &DOMNode, selector_id: SelectorId) -> bool {
-        match self.selector_manager.id_to_selector.get(&selector_id) {
-            Some(Selector::Type(_)) => node.tag_id == selector_id,
-            Some(Selector::Class(_)) => node.class_ids.contains(&selector_id),
-            Some(Selector::Id(_)) => node.id_selector_id == Some(selector_id),
-            Some(Selector::AttributeEquals { name, value }) => node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false),
-            Some(Selector::Compound(compound)) => self.node_matches_compound(node, compound),
-            None => false,
+        self.selector_manager.matches(selector_id, &node.view())
+    }
+
+    fn is_element(&self, node_idx: u64) -> bool {
+        self.nodes.get(&node_idx).is_some_and(|node| {
+            matches!(
+                self.selector_manager.id_to_selector.get(&node.tag_id),
+                Some(Selector::Type(name)) if is_element_name(name)
+            )
+        })
+    }
+
+    /// Children of `parent_idx` from position `from` up to and including the next element:
+    /// the nodes whose sibling input is the element preceding `from`.
+    fn sibling_readers_from(&self, parent_idx: u64, from: usize) -> Vec<u64> {
+        let mut readers = Vec::new();
+        if let Some(parent) = self.nodes.get(&parent_idx) {
+            for &child_idx in parent.children.iter().skip(from) {
+                readers.push(child_idx);
+                if self.is_element(child_idx) {
+                    break;
+                }
+            }
         }
+        readers
     }
 
-    fn node_matches_compound(&self, node: &DOMNode, compound: &CompoundSelector) -> bool {
-        if let Some(tag) = &compound.tag
-            && !self.node_has_tag(node, tag)
-        {
-            return false;
+    fn previous_element_sibling(&self, node_idx: u64) -> Option<u64> {
+        let parent_idx = self.nodes.get(&node_idx)?.parent?;
+        let children = &self.nodes.get(&parent_idx)?.children;
+        let position = children
+            .iter()
+            .position(|&child_idx| child_idx == node_idx)?;
+        children[..position]
+            .iter()
+            .rev()
+            .copied()
+            .find(|&child_idx| self.is_element(child_idx))
+    }
+
+    /// Mark the siblings reading `node_idx` through sibling rules dirty after its
+    /// sibling-visible output changed.
+    fn invalidate_sibling_readers(&mut self, node_idx: u64) {
+        if !self.is_element(node_idx) {
+            return;
         }
-        if let Some(id_value) = &compound.id
-            && !self.node_has_id(node, id_value)
-        {
-            return false;
+        let Some(parent_idx) = self.nodes.get(&node_idx).and_then(|node| node.parent) else {
+            return;
+        };
+        let Some(position) = self.nodes.get(&parent_idx).and_then(|parent| {
+            parent
+                .children
+                .iter()
+                .position(|&child_idx| child_idx == node_idx)
+        }) else {
+            return;
+        };
+        for reader_idx in self.sibling_readers_from(parent_idx, position + 1) {
+            self.set_node_dirty(reader_idx);
         }
-        for class_name in &compound.classes {
-            if !self.node_has_class(node, class_name) {
-                return false;
-            }
+    }
+
+    /// Mark the siblings from position `from` whose previous element sibling changed
+    /// after an insertion or removal. No-op when the NFA has no sibling rules.
+    fn invalidate_sibling_readers_from(&mut self, parent_idx: u64, from: usize, nfa: &NFA) {
+        if nfa.sibling_rules.is_empty() {
+            return;
         }
-        for (name, value) in &compound.attributes {
-            if !node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false)
-            {
-                return false;
-            }
+        for reader_idx in self.sibling_readers_from(parent_idx, from) {
+            self.set_node_dirty(reader_idx);
         }
-        for pseudo in &compound.pseudos {
-            if !node.computed_pseudo_classes.contains(pseudo) {
-                return false;
+    }
+
+    /// Recompute the sibling positions of `parent_idx`'s children and mark dirty those
+    /// whose positions read by the NFA's `:nth-*` predicates shifted.
+    fn refresh_sibling_positions(&mut self, parent_idx: u64, nfa: &NFA) {
+        if nfa.position_reads.is_empty() {
+            return;
+        }
+        let Some(children) = self
+            .nodes
+            .get(&parent_idx)
+            .map(|node| node.children.clone())
+        else {
+            return;
+        };
+        let tags: Vec<Option<SelectorId>> = children
+            .iter()
+            .map(|&child_idx| {
+                self.is_element(child_idx)
+                    .then(|| self.nodes[&child_idx].tag_id)
+            })
+            .collect();
+        for (child_idx, position) in children.into_iter().zip(sibling_positions(&tags)) {
+            let Some(child) = self.nodes.get_mut(&child_idx) else {
+                continue;
+            };
+            let previous = std::mem::replace(&mut child.position, position);
+            if nfa.position_reads.changed(&previous, &position) {
+                self.set_node_dirty(child_idx);
             }
         }
-        true
     }
 
-    fn node_has_class(&self, node: &DOMNode, class_name: &str) -> bool {
-        let selector = Selector::Class(class_name.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(class_id) => node.class_ids.contains(&class_id),
-            None => false,
+    /// Recompute `:empty` for `node_idx` from its children's `content` flags and mark it
+    /// dirty when the state flipped.
+    fn refresh_empty_state(&mut self, node_idx: u64) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return;
+        };
+        let empty = !node
+            .children
+            .iter()
+            .any(|child_idx| self.nodes.get(child_idx).is_some_and(|child| child.content));
+        let node = self.nodes.get_mut(&node_idx).unwrap();
+        let changed = if empty {
+            node.computed_pseudo_classes
+                .insert(PSEUDO_CLASS_EMPTY.to_string())
+        } else {
+            node.computed_pseudo_classes.remove(PSEUDO_CLASS_EMPTY)
+        };
+        if changed {
+            self.set_node_dirty(node_idx);
+        }
+    }
+
+    /// Recompute `node_idx`'s `:has()` bits from its own state and its element children's,
+    /// marking it dirty when the set of `:has()` selectors it satisfies changed. Returns
+    /// whether the bits its parent reads changed and whether that set changed.
+    fn update_has_state(&mut self, node_idx: u64, nfa: &NFA) -> (bool, bool) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return (false, false);
+        };
+        let children: Vec<&DOMNode> = node
+            .children
+            .iter()
+            .filter(|&&child_idx| self.is_element(child_idx))
+            .filter_map(|child_idx| self.nodes.get(child_idx))
+            .collect();
+        let mut arguments = Vec::with_capacity(nfa.has_selectors.len());
+        let mut subtree = Vec::with_capacity(nfa.has_selectors.len());
+        let mut has_ids = HashSet::new();
+        for (k, (has_id, has)) in nfa.has_selectors.iter().enumerate() {
+            let in_children = children.iter().any(|child| child.has_arguments[k]);
+            let in_subtree = children.iter().any(|child| child.has_subtree[k]);
+            if (has.child && in_children) || (!has.child && in_subtree) {
+                has_ids.insert(*has_id);
+            }
+            let matches_self = self
+                .selector_manager
+                .matches_has_arguments(*has_id, &node.view());
+            arguments.push(matches_self);
+            subtree.push(matches_self || in_subtree);
         }
+        let node = self.nodes.get_mut(&node_idx).unwrap();
+        let bits_changed = node.has_arguments != arguments || node.has_subtree != subtree;
+        node.has_arguments = arguments;
+        node.has_subtree = subtree;
+        let has_changed = node.has_ids != has_ids;
+        if has_changed {
+            node.has_ids = has_ids;
+            self.set_node_dirty(node_idx);
+        }
+        (bits_changed, has_changed)
     }
 
-    fn node_has_id(&self, node: &DOMNode, id_value: &str) -> bool {
-        let selector = Selector::Id(id_value.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(id) => node.id_selector_id == Some(id),
-            None => false,
+    /// Re-run `update_has_state` from `node_idx` up the ancestors for as long as the bits
+    /// their parents read keep changing. Returns whether any `:has()` result changed.
+    pub fn propagate_has_state(&mut self, node_idx: u64, nfa: &NFA) -> bool {
+        if nfa.has_selectors.is_empty() {
+            return false;
         }
+        let mut has_changed = false;
+        let mut current = Some(node_idx);
+        while let Some(idx) = current {
+            let (bits_changed, changed) = self.update_has_state(idx, nfa);
+            has_changed |= changed;
+            if !bits_changed {
+                break;
+            }
+            current = self.nodes.get(&idx).and_then(|node| node.parent);
+        }
+        has_changed
     }
 
-    fn node_has_tag(&self, node: &DOMNode, tag_name: &str) -> bool {
-        if tag_name == "*" {
-            return true;
+    /// Refresh the `:has()` bits of `parent_idx`'s children, whose positions may have
+    /// shifted, then propagate from `parent_idx` after its child list changed.
+    fn refresh_has_after_children_changed(&mut self, parent_idx: u64, nfa: &NFA) {
+        if nfa.has_selectors.is_empty() {
+            return;
         }
-        let selector = Selector::Type(tag_name.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(tag_id) => node.tag_id == tag_id,
-            None => false,
+        let children = self
+            .nodes
+            .get(&parent_idx)
+            .map(|node| node.children.clone())
+            .unwrap_or_default();
+        for child_idx in children {
+            self.update_has_state(child_idx, nfa);
         }
+        self.propagate_has_state(parent_idx, nfa);
     }
 
     pub fn get_root_node(&mut self) -> u64 {
@@ -470,6 +652,9 @@ impl DOM {
             parent_index,
             nfa,
         );
+        if let Some(node) = self.nodes.get_mut(&current_index) {
+            node.content = counts_as_content(json_node);
+        }
         // HACK
         if id == 5458 && classes.contains(&"hidden".to_string()) {
             panic!()
@@ -481,6 +666,9 @@ impl DOM {
                 self.json_to_html_node(child_json, Some(current_index), nfa);
             }
         }
+        self.refresh_sibling_positions(current_index, nfa);
+        self.refresh_empty_state(current_index);
+        self.refresh_has_after_children_changed(current_index, nfa);
         current_index
     }
 
@@ -505,10 +693,14 @@ impl DOM {
             parent.children.insert(insert_pos, new_node_idx);
         }
         self.set_node_dirty(current_idx);
+        self.invalidate_sibling_readers_from(current_idx, insert_pos + 1, nfa);
+        self.refresh_sibling_positions(current_idx, nfa);
+        self.refresh_empty_state(current_idx);
+        self.refresh_has_after_children_changed(current_idx, nfa);
     }
 
     /// Remove a node specified by a path.
-    pub fn remove_node_by_path(&mut self, path: &[usize]) {
+    pub fn remove_node_by_path(&mut self, path: &[usize], nfa: &NFA) {
         let root_nodes = self.get_root_node();
         // Descend to the target parent node
         let mut cur_idx = root_nodes;
@@ -533,6 +725,10 @@ impl DOM {
             self.remove_subtree(removed_child_id);
         }
         self.set_node_dirty(cur_idx);
+        self.invalidate_sibling_readers_from(cur_idx, rm_pos, nfa);
+        self.refresh_sibling_positions(cur_idx, nfa);
+        self.refresh_empty_state(cur_idx);
+        self.refresh_has_after_children_changed(cur_idx, nfa);
     }
 
     fn remove_subtree(&mut self, node_id: u64) {
@@ -554,20 +750,95 @@ impl DOM {
         }
         Some(current_idx)
     }
-    pub fn recompute_styles(&mut self, nfa: &NFA, input: &[bool]) {
+    /// The winning declarations for a node whose output states are `output`.
+    fn cascade(&self, output: &StateBits, nfa: &NFA) -> BTreeMap<String, String> {
+        unsafe {
+            CASCADE_CNT += 1;
+        }
+        let matched = nfa.cascade_order(output);
+        cascade_declarations(matched.iter().map(|(_, origin)| origin), &self.declarations)
+    }
+
+    /// Re-cascades the declarations of the selectors `node_idx` now matches.
+    fn refresh_computed_style(&mut self, node_idx: u64, nfa: &NFA) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return;
+        };
+        let computed_style = self.cascade(&node.output_state, nfa);
+        debug_log(|| {
+            format!(
+                "{} cascade -> {} propert(ies)",
+                self.describe_node(node_idx),
+                computed_style.len()
+            )
+        });
+        if let Some(node) = self.nodes.get_mut(&node_idx) {
+            node.inherit_dirty |= node.computed_style != computed_style;
+            node.computed_style = computed_style;
+        }
+    }
+
+    /// Re-derives the inherited values of `node_idx` if its own cascaded values changed
+    /// or its parent's changed in a property it reads (`parent_changes`), and returns
+    /// the properties whose inherited value changed for its children.
+    fn refresh_inherited_style(
+        &mut self,
+        node_idx: u64,
+        parent_changes: &BTreeSet<String>,
+    ) -> BTreeSet<String> {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return BTreeSet::new();
+        };
+        let reads_change = parent_changes
+            .iter()
+            .any(|property| inherits_from_parent(&node.computed_style, property));
+        if !node.inherit_dirty && !reads_change {
+            return BTreeSet::new();
+        }
+        unsafe {
+            INHERIT_MISS_CNT += 1;
+        }
+        let inherited = match node.parent.and_then(|parent| self.nodes.get(&parent)) {
+            Some(parent) => inherit_style(&parent.inherited_style, &node.computed_style),
+            None => inherit_style(&BTreeMap::new(), &node.computed_style),
+        };
+        let changes = changed_properties(&node.inherited_style, &inherited);
+        debug_log(|| {
+            format!(
+                "{} inherited values -> {} change(s)",
+                self.describe_node(node_idx),
+                changes.len()
+            )
+        });
+        let Some(node) = self.nodes.get_mut(&node_idx) else {
+            return BTreeSet::new();
+        };
+        node.inherited_style = inherited;
+        node.inherit_dirty = false;
+        changes
+    }
+
+    pub fn recompute_styles(&mut self, nfa: &NFA, input: &StateBits) {
         let root_node = self.get_root_node();
         debug_log(|| {
             format!(
                 "recompute start {}; input={}",
                 self.describe_node(root_node),
-                format_bits(input)
+                input
             )
         });
         self.recompute_focus_states(root_node);
-        self.recompute_styles_recursive(root_node, nfa, input);
+        self.recompute_styles_recursive(root_node, nfa, input, &get_input(), &BTreeSet::new());
         debug_log(|| format!("recompute done {}", self.describe_node(root_node)));
     }
-    fn recompute_styles_recursive(&mut self, node_idx: u64, nfa: &NFA, input: &[bool]) {
+    fn recompute_styles_recursive(
+        &mut self,
+        node_idx: u64,
+        nfa: &NFA,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        parent_changes: &BTreeSet<String>,
+    ) {
         let node_descriptor = self.describe_node(node_idx);
         self.refresh_computed_pseudos(node_idx);
         let (was_recursive_dirty, was_dirty, previous_output, child_indices_snapshot) =
@@ -588,8 +859,7 @@ impl DOM {
             debug_log(|| {
                 format!(
                     "{} ignored: recursive_dirty=false, input={}",
-                    node_descriptor,
-                    format_bits(input)
+                    node_descriptor, input
                 )
             });
             return;
@@ -598,9 +868,7 @@ impl DOM {
         debug_log(|| {
             format!(
                 "{} visit: dirty={} input={}",
-                node_descriptor,
-                was_dirty,
-                format_bits(input)
+                node_descriptor, was_dirty, input
             )
         });
 
@@ -610,7 +878,7 @@ impl DOM {
             }
             let new_output_state = {
                 if let Some(node) = self.nodes.get(&node_idx) {
-                    self.new_output_state(node, input, nfa)
+                    self.new_output_state(node, input, sibling_input, nfa)
                 } else {
                     debug_log(|| {
                         format!(
@@ -624,9 +892,7 @@ impl DOM {
             debug_log(|| {
                 format!(
                     "{} recompute -> output={} (prev={})",
-                    node_descriptor,
-                    format_bits(&new_output_state),
-                    format_bits(&previous_output)
+                    node_descriptor, &new_output_state, &previous_output
                 )
             });
             if previous_output != new_output_state {
@@ -648,6 +914,12 @@ impl DOM {
                     });
                     return;
                 }
+                if nfa.sibling_input_changed(&previous_output, &new_output_state) {
+                    self.invalidate_sibling_readers(node_idx);
+                }
+                if nfa.accept_output_changed(&previous_output, &new_output_state) {
+                    self.refresh_computed_style(node_idx, nfa);
+                }
                 let mut marked_children = Vec::new();
                 for &child_idx in &child_indices_snapshot {
                     if let Some(child) = self.nodes.get_mut(&child_idx) {
@@ -677,14 +949,12 @@ impl DOM {
             debug_log(|| {
                 format!(
                     "{} clean node; validating cached output={} with new input={}",
-                    node_descriptor,
-                    format_bits(&previous_output),
-                    format_bits(input)
+                    node_descriptor, &previous_output, input
                 )
             });
             let new_output_state = {
                 if let Some(node) = self.nodes.get(&node_idx) {
-                    self.new_output_state(node, input, nfa)
+                    self.new_output_state(node, input, sibling_input, nfa)
                 } else {
                     debug_log(|| {
                         format!(
@@ -698,8 +968,7 @@ impl DOM {
             debug_log(|| {
                 format!(
                     "{} validation recompute -> output={}",
-                    node_descriptor,
-                    format_bits(&new_output_state)
+                    node_descriptor, &new_output_state
                 )
             });
             assert_eq!(
@@ -722,6 +991,15 @@ impl DOM {
                 return;
             }
         };
+        let inherited_changes = self.refresh_inherited_style(node_idx, parent_changes);
+        if !inherited_changes.is_empty() {
+            // Children re-derive their inherited values without recomputing NFA bits.
+            for &child_idx in &child_indices_snapshot {
+                if let Some(child) = self.nodes.get_mut(&child_idx) {
+                    child.recursive_dirty = true;
+                }
+            }
+        }
         debug_log(|| {
             format!(
                 "{} propagating to {} children",
@@ -729,6 +1007,8 @@ impl DOM {
                 child_indices_snapshot.len()
             )
         });
+        let track_siblings = !nfa.sibling_rules.is_empty();
+        let mut previous_element = None;
         for &child_idx in &child_indices_snapshot {
             let child_needs_visit = self
                 .nodes
@@ -736,7 +1016,17 @@ impl DOM {
                 .map(|child| child.recursive_dirty)
                 .unwrap_or(false);
             if child_needs_visit {
-                self.recompute_styles_recursive(child_idx, nfa, &current_output_state);
+                let sibling_input = self.sibling_input(previous_element);
+                self.recompute_styles_recursive(
+                    child_idx,
+                    nfa,
+                    &current_output_state,
+                    &sibling_input,
+                    &inherited_changes,
+                );
+            }
+            if track_siblings && self.is_element(child_idx) {
+                previous_element = Some(child_idx);
             }
         }
 
@@ -751,37 +1041,120 @@ impl DOM {
     /// For an NFA, each edge corresponds to a `Rule`.
     /// Collect the rules in a `Vec` indexed by state to track which edges are already active.
     /// When new input arrives, you can skip edges that are already active.
-    fn new_output_state(&self, node: &DOMNode, input: &[bool], nfa: &NFA) -> Vec<bool> {
-        let mut new_state = vec![false; input.len()];
+    fn new_output_state(
+        &self,
+        node: &DOMNode,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        nfa: &NFA,
+    ) -> StateBits {
+        if !rule_cycles_enabled() {
+            return self.evaluate_rules(node, input, sibling_input, nfa);
+        }
+        let start = rdtsc();
+        let output = self.evaluate_rules(node, input, sibling_input, nfa);
+        unsafe {
+            RULE_EVAL_CYCLES += rdtsc() - start;
+        }
+        output
+    }
 
-        for &rule in nfa.rules.iter() {
-            match rule {
+    /// `rules`, narrowed to those `node` may fire when the NFA has a rule index.
+    fn candidate_rules<'a>(
+        &self,
+        node: &DOMNode,
+        rules: &'a [Rule],
+        buckets: Option<&'a RuleBuckets>,
+    ) -> impl Iterator<Item = &'a Rule> {
+        let selected = buckets.map(|buckets| {
+            buckets.select(
+                rules,
+                std::iter::once(node.tag_id)
+                    .chain(node.class_ids.iter().copied())
+                    .chain(node.id_selector_id),
+                node.attributes.keys().map(String::as_str),
+            )
+        });
+        let all = match selected {
+            Some(_) => None,
+            None => Some(rules.iter()),
+        };
+        all.into_iter()
+            .flatten()
+            .chain(selected.into_iter().flatten())
+    }
+
+    fn evaluate_rules(
+        &self,
+        node: &DOMNode,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        nfa: &NFA,
+    ) -> StateBits {
+        if let Some(output) = nfa.dfa.as_ref().and_then(|dfa| {
+            dfa.step(nfa, input, sibling_input, |a| {
+                self.node_matches_selector(node, a)
+            })
+        }) {
+            return output;
+        }
+        let mut new_state = StateBits::new(input.len());
+        new_state.union_with_masked(input, &nfa.self_loops);
+
+        let index = nfa.rule_index.as_ref();
+        let rules = self.candidate_rules(node, &nfa.rules, index.map(|index| &index.rules));
+        for rule in rules.filter(|rule| !NFA::is_self_loop(rule)) {
+            match *rule {
                 Rule(None, None, Nfacell(c)) => {
-                    new_state[c] = true;
+                    new_state.insert(c);
                 }
                 Rule(None, Some(Nfacell(b)), Nfacell(c)) => {
-                    if input[b] {
-                        new_state[c] = true;
+                    if input.get(b) {
+                        new_state.insert(c);
                     }
                 }
                 Rule(Some(a), None, Nfacell(c)) => {
                     if self.node_matches_selector(node, a) {
-                        new_state[c] = true;
+                        new_state.insert(c);
                     }
                 }
                 Rule(Some(a), Some(Nfacell(b)), Nfacell(c)) => {
-                    if self.node_matches_selector(node, a) && input[b] {
-                        new_state[c] = true;
+                    if self.node_matches_selector(node, a) && input.get(b) {
+                        new_state.insert(c);
                     }
                 }
             }
         }
+        // Sibling rules read the previous element sibling's output instead of the parent's.
+        let sibling_rules = self.candidate_rules(
+            node,
+            &nfa.sibling_rules,
+            index.map(|index| &index.sibling_rules),
+        );
+        for &Rule(selector, prev, Nfacell(c)) in sibling_rules {
+            let Some(Nfacell(b)) = prev else {
+                continue;
+            };
+            if sibling_input.get(b) && selector.is_none_or(|a| self.node_matches_selector(node, a))
+            {
+                new_state.insert(c);
+            }
+        }
         new_state
     }
+
+    /// Sibling-rule input for a node: the output of its previous element sibling,
+    /// or all-false when there is none.
+    fn sibling_input(&self, previous_element: Option<u64>) -> StateBits {
+        previous_element
+            .and_then(|idx| self.nodes.get(&idx))
+            .map(|sibling| sibling.output_state.clone())
+            .unwrap_or_else(get_input)
+    }
 }
 
 impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
-    type AttrState = Vec<bool>;
+    type AttrState = StateBits;
     fn reset_dom(&mut self) {
         self.nodes.clear();
         self.root_node = None;
@@ -792,8 +1165,8 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn add_node_by_path(&mut self, path: &[usize], node: &serde_json::Value, nfa: &NFA) {
         self.add_node_by_path(path, node, nfa);
     }
-    fn remove_node_by_path(&mut self, path: &[usize]) {
-        self.remove_node_by_path(path);
+    fn remove_node_by_path(&mut self, path: &[usize], nfa: &NFA) {
+        self.remove_node_by_path(path, nfa);
     }
     fn node_id_by_path(&mut self, path: &[usize]) -> Option<u64> {
         self.node_id_by_path(path)
@@ -801,16 +1174,19 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn set_node_dirty(&mut self, node_idx: u64) {
         self.set_node_dirty(node_idx);
     }
-    fn recompute_styles(&mut self, nfa: &NFA, input: &[bool]) {
+    fn propagate_has_state(&mut self, node_idx: u64, nfa: &NFA) -> bool {
+        self.propagate_has_state(node_idx, nfa)
+    }
+    fn recompute_styles(&mut self, nfa: &NFA, input: &StateBits) {
         self.recompute_styles(nfa, input);
     }
     fn attr_state_and_parent_input<F>(
         &self,
         node_idx: u64,
         make_root_input: &F,
-    ) -> (Self::AttrState, Vec<bool>)
+    ) -> (Self::AttrState, StateBits)
     where
-        F: Fn() -> Vec<bool>,
+        F: Fn() -> StateBits,
     {
         let node = &self.nodes[&node_idx];
         let parent_bits = node
@@ -823,20 +1199,26 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn recompute_attr_state(
         &self,
         node_idx: u64,
-        parent_bits: &[bool],
+        parent_bits: &StateBits,
         nfa: &NFA,
     ) -> Self::AttrState {
         let node = &self.nodes[&node_idx];
-        self.new_output_state(node, parent_bits, nfa)
+        let previous_element = if nfa.sibling_rules.is_empty() {
+            None
+        } else {
+            self.previous_element_sibling(node_idx)
+        };
+        let sibling_bits = self.sibling_input(previous_element);
+        self.new_output_state(node, parent_bits, &sibling_bits, nfa)
     }
 }
 
 fn apply_frame(dom: &mut DOM, frame: &LayoutFrame, nfa: &NFA) {
     let make_input = || get_input();
     let make_recalc_input = |nfa: &NFA| {
-        let mut input = vec![false; unsafe { STATE } + 1];
+        let mut input = get_input();
         if let Some(start) = nfa.start_state {
-            input[start.0] = true;
+            input.insert(start.0);
         }
         input
     };
@@ -847,50 +1229,116 @@ pub fn collect_rule_matches(
     dom: &DOM,
     nfas: &NFA,
     selects: &[String],
+    sources: &BTreeMap<String, Vec<String>>,
 ) -> HashMap<String, Vec<u64>> {
     let mut res: HashMap<String, Vec<u64>> = HashMap::new();
 
     for (node_id, node) in dom.nodes.iter() {
-        for (idx, &Nfacell(state_index)) in nfas.accept_states.iter().enumerate() {
-            if node.output_state[state_index] {
-                let rule = &selects[idx];
-                res.entry(rule.clone()).or_default().push(*node_id);
+        for (idx, accept) in nfas.accept_states.iter().enumerate() {
+            let Nfacell(state_index) = accept.state;
+            if node.output_state.get(state_index) {
+                for source in selector_sources(sources, &selects[idx]) {
+                    res.entry(source.to_string()).or_default().push(*node_id);
+                }
             }
         }
     }
 
     for v in res.values_mut() {
         v.sort_unstable();
+        v.dedup();
     }
     res
 }
 
+/// Each node's matching selectors in cascade order (see `NFA::cascade_order`), with the
+/// origin each match ranks by. Nodes without matches are left out.
+pub fn collect_cascade_matches(
+    dom: &DOM,
+    nfas: &NFA,
+    selects: &[String],
+) -> BTreeMap<u64, Vec<(String, SelectorOrigin)>> {
+    dom.nodes
+        .iter()
+        .filter_map(|(node_id, node)| {
+            let matched: Vec<_> = nfas
+                .cascade_order(&node.output_state)
+                .into_iter()
+                .map(|(idx, origin)| (selects[idx].clone(), origin))
+                .collect();
+            (!matched.is_empty()).then_some((*node_id, matched))
+        })
+        .collect()
+}
+
+/// Builds the code `codegen` generates for `nfa` in `language` into `dir`, steps it on
+/// every node of the document with the parent and sibling outputs the engine keeps, and
+/// returns how many nodes it checked and those whose generated output differs.
+fn check_generated_step(
+    dom: &mut DOM,
+    nfa: &NFA,
+    language: Language,
+    dir: &Path,
+) -> std::io::Result<(usize, Vec<u64>)> {
+    let plan = StepPlan::new(nfa, &dom.selector_manager);
+    // Only the tree under the root is kept up to date; nodes left parentless by earlier
+    // frames keep the outputs they last had.
+    let mut ids = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![dom.get_root_node()];
+    while let Some(idx) = stack.pop() {
+        if dom.nodes.contains_key(&idx) && seen.insert(idx) {
+            ids.push(idx);
+            stack.extend(dom.nodes[&idx].children.iter().rev());
+        }
+    }
+    let dom = &*dom;
+    let inputs: Vec<StepInput> = ids
+        .iter()
+        .map(|&idx| {
+            let node = &dom.nodes[&idx];
+            let previous_element = if nfa.sibling_rules.is_empty() {
+                None
+            } else {
+                dom.previous_element_sibling(idx)
+            };
+            StepInput {
+                predicates: plan.predicate_bits(|id| dom.node_matches_selector(node, id)),
+                parent_bits: node
+                    .parent
+                    .and_then(|parent| dom.nodes.get(&parent))
+                    .map_or_else(get_input, |parent| parent.output_state.clone()),
+                sibling_bits: dom.sibling_input(previous_element),
+            }
+        })
+        .collect();
+    let outputs = run_generated(language, &plan, &inputs, dir)?;
+    let mismatches = ids
+        .iter()
+        .zip(&outputs)
+        .filter(|(idx, output)| dom.nodes[idx].output_state != **output)
+        .map(|(&idx, _)| idx)
+        .collect();
+    Ok((ids.len(), mismatches))
+}
+
 fn main() {
     // 1. Build the DOM tree
     let mut dom = DOM::new();
-    let ParsedSelectors {
-        mut selectors,
-        mut pseudo_selectors,
-        unsupported_selectors,
-    } = parse_css_with_pseudo(
-        &std::fs::read_to_string(format!(
-            "css-gen-op/{0}/{0}.css",
-            std::env::var("WEBSITE_NAME").unwrap(),
-        ))
-        .unwrap(),
-    );
-    selectors.extend(drain_supported_pseudo_selectors(&mut pseudo_selectors));
-    selectors.sort();
-    selectors.dedup();
-    let (selectors, skipped_simple) = partition_simple_selectors(selectors);
-    report_skipped_selectors("bit", &skipped_simple);
-    report_pseudo_selectors("bit", &pseudo_selectors);
-    report_unsupported_selectors("bit", &unsupported_selectors);
-    // dbg!(&selectors);
-    let mut s = unsafe { STATE };
-    let nfa = generate_nfa(&selectors, &mut dom.selector_manager, &mut s);
+    let CompiledStylesheet {
+        mut nfa,
+        selector_manager,
+        selectors,
+        sources,
+        declarations,
+    } = CompiledStylesheet::for_site("bit", &std::env::var("WEBSITE_NAME").unwrap());
+    dom.selector_manager = selector_manager;
+    let frames = parse_trace();
+    prune_dead_states("bit", &mut nfa, &mut dom.selector_manager, &frames);
+    attach_automaton("bit", &mut nfa);
+    configure_rule_index("bit", &mut nfa);
     unsafe {
-        STATE = s;
+        STATE = nfa.max_state_id.0;
     }
     let _ = fs::write(
         format!(
@@ -899,6 +1347,7 @@ fn main() {
         ),
         nfa.to_dot(&dom.selector_manager),
     );
+    dom.declarations = declarations;
 
     // for Rule(a, b, c) in nfa.rules.iter() {
     //     println!(
@@ -909,36 +1358,107 @@ fn main() {
     //     );
     // }
 
-    for f in parse_trace() {
-        apply_frame(&mut dom, &f, &nfa);
+    for f in &frames {
+        apply_frame(&mut dom, f, &nfa);
     }
-    let mut final_matches = collect_rule_matches(&dom, &nfa, &selectors)
+    let mut final_matches = collect_rule_matches(&dom, &nfa, &selectors, &sources)
         .into_iter()
         .collect::<Vec<_>>();
     final_matches.sort();
+    // Output bits were a `Vec<bool>`.
+    report_state_bytes(
+        "bit",
+        dom.nodes.len(),
+        unsafe { STATE } + 1,
+        dom.nodes
+            .values()
+            .map(|node| node.output_state.heap_bytes())
+            .sum(),
+        1,
+    );
     println!("BEGIN");
     for (k, v) in final_matches {
         println!("{} -> {:?}", k.replace('>', " > "), v);
     }
     println!("END");
+    report_computed_styles(
+        dom.nodes
+            .iter()
+            .map(|(&node_id, node)| (node_id, node.inherited_style.clone())),
+    );
+    if std::env::var_os("BIT_CASCADE").is_some() {
+        println!("CASCADE ORDER");
+        for (node_id, matched) in collect_cascade_matches(&dom, &nfa, &selectors) {
+            let ranked: Vec<String> = matched
+                .iter()
+                .map(|(selector, origin)| {
+                    format!(
+                        "{} {}#{}",
+                        selector, origin.specificity, origin.source_index
+                    )
+                })
+                .collect();
+            println!("{} -> {}", node_id, ranked.join(", "));
+        }
+    }
+    if let Ok(value) = std::env::var("BIT_CODEGEN_CHECK") {
+        let language = match value.as_str() {
+            "c" => Language::C,
+            _ => Language::Rust,
+        };
+        let site = std::env::var("WEBSITE_NAME").unwrap();
+        let dir = Path::new("target/codegen")
+            .join(&site)
+            .join(language.to_string());
+        match check_generated_step(&mut dom, &nfa, language, &dir) {
+            Ok((nodes, mismatches)) => {
+                println!(
+                    "CODEGEN[bit] {}: {} node(s), {} mismatch(es)",
+                    language,
+                    nodes,
+                    mismatches.len()
+                );
+                for &idx in mismatches.iter().take(5) {
+                    println!("  {}", dom.describe_node(idx));
+                }
+                if !mismatches.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+            Err(err) => {
+                eprintln!("CODEGEN[bit] {}: {err}", dir.display());
+                std::process::exit(1);
+            }
+        }
+    }
+    report_automaton("bit", &nfa);
     dbg!(unsafe { MISS_CNT });
+    if rule_cycles_enabled() {
+        dbg!(unsafe { RULE_EVAL_CYCLES });
+    }
+    dbg!(unsafe { CASCADE_CNT });
+    dbg!(unsafe { INHERIT_MISS_CNT });
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::write;
 
-    use css_bitvector_compiler::generate_nfa;
+    use css_bitvector_compiler::{generate_nfa, parse_complex_selector, parse_site_trace};
 
     use super::*;
+
+    /// Held by tests that set `STATE`, which sizes every input.
+    static STATE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
     #[test]
     fn test_generate_nfa() {
         // Reset global state for testing
         let mut s = 0;
         let mut selector_manager = SelectorManager::new();
-        let selectors = ["div a", "p", "h1 > h2", "h1 h2", "div a p"].map(|x| x.into());
+        let selectors = ["div a", "p", "h1 > h2", "h1 h2", "div a p"]
+            .map(|x| parse_complex_selector(x).unwrap());
 
-        let nfa = generate_nfa(&selectors, &mut selector_manager, &mut s);
+        let nfa = generate_nfa(&selectors, &[], &mut selector_manager, &mut s);
         // dbg!(&nfa);
         let _ = write("./dot.dot", nfa.to_dot(&selector_manager));
         dbg!(nfa.rules);
@@ -950,6 +1470,7 @@ mod tests {
         let attr_selector = Selector::AttributeEquals {
             name: "data-test".into(),
             value: "foo".into(),
+            case_insensitive: false,
         };
         let attr_id = dom.selector_manager.get_or_create_id(attr_selector.clone());
         let tag_id = dom
@@ -963,11 +1484,19 @@ mod tests {
             attributes: HashMap::from([("data-test".into(), "foo".into())]),
             pseudo_classes: HashSet::new(),
             computed_pseudo_classes: HashSet::new(),
+            position: SiblingPosition::default(),
+            content: true,
+            has_arguments: Vec::new(),
+            has_subtree: Vec::new(),
+            has_ids: HashSet::new(),
             parent: None,
             children: Vec::new(),
             dirty: false,
             recursive_dirty: false,
-            output_state: Vec::new(),
+            output_state: StateBits::default(),
+            computed_style: BTreeMap::new(),
+            inherited_style: BTreeMap::new(),
+            inherit_dirty: false,
         };
 
         assert!(dom.node_matches_selector(&node, attr_id));
@@ -977,12 +1506,119 @@ mod tests {
             .get_or_create_id(Selector::AttributeEquals {
                 name: "data-test".into(),
                 value: "bar".into(),
+                case_insensitive: false,
             });
         assert!(!dom.node_matches_selector(&node, other_attr_id));
     }
 
+    #[test]
+    fn sibling_position_shift_dirties_only_affected_siblings() {
+        let mut dom = DOM::new();
+        let selectors = vec![parse_complex_selector("li:first-child").unwrap()];
+        let mut s = 0;
+        let nfa = generate_nfa(&selectors, &[], &mut dom.selector_manager, &mut s);
+        let ul = dom.selector_manager.get_or_create_type_id("ul");
+        let li = dom.selector_manager.get_or_create_type_id("li");
+        let first_child = nfa.rules[0].0.unwrap();
+
+        let insert = |dom: &mut DOM, id: u64, tag_id: SelectorId, parent: Option<u64>| {
+            dom.nodes.insert(
+                id,
+                DOMNode {
+                    tag_id,
+                    parent,
+                    ..Default::default()
+                },
+            );
+            if let Some(parent) = parent {
+                dom.nodes.get_mut(&parent).unwrap().children.push(id);
+            }
+        };
+        insert(&mut dom, 1, ul, None);
+        insert(&mut dom, 2, li, Some(1));
+        insert(&mut dom, 3, li, Some(1));
+        dom.refresh_sibling_positions(1, &nfa);
+        assert!(dom.node_matches_selector(&dom.nodes[&2], first_child));
+        assert!(!dom.node_matches_selector(&dom.nodes[&3], first_child));
+
+        for node in dom.nodes.values_mut() {
+            node.dirty = false;
+            node.recursive_dirty = false;
+        }
+        dom.nodes.get_mut(&1).unwrap().children.remove(0);
+        dom.nodes.remove(&2);
+        dom.refresh_sibling_positions(1, &nfa);
+
+        // `li:first-child` only reads positions from the start, so a removal before
+        // node 3 dirties it.
+        assert!(dom.nodes[&3].dirty);
+        assert!(dom.nodes[&1].recursive_dirty);
+        assert!(dom.node_matches_selector(&dom.nodes[&3], first_child));
+    }
+
+    #[test]
+    fn has_propagation_dirties_only_ancestors_whose_result_changed() {
+        let mut dom = DOM::new();
+        let selectors =
+            ["div:has(span.x)", "p:has(>i)"].map(|x| parse_complex_selector(x).unwrap());
+        let mut s = 0;
+        let nfa = generate_nfa(&selectors, &[], &mut dom.selector_manager, &mut s);
+        let div = dom.selector_manager.get_or_create_type_id("div");
+        let p = dom.selector_manager.get_or_create_type_id("p");
+        let span = dom.selector_manager.get_or_create_type_id("span");
+        let x = dom.selector_manager.get_or_create_class_id("x");
+        let has_count = nfa.has_selectors.len();
+
+        let insert = |dom: &mut DOM, id: u64, tag_id: SelectorId, parent: Option<u64>| {
+            dom.nodes.insert(
+                id,
+                DOMNode {
+                    tag_id,
+                    parent,
+                    has_arguments: vec![false; has_count],
+                    has_subtree: vec![false; has_count],
+                    ..Default::default()
+                },
+            );
+            if let Some(parent) = parent {
+                dom.nodes.get_mut(&parent).unwrap().children.push(id);
+            }
+        };
+        let clear_dirty = |dom: &mut DOM| {
+            for node in dom.nodes.values_mut() {
+                node.dirty = false;
+                node.recursive_dirty = false;
+            }
+        };
+        insert(&mut dom, 1, div, None);
+        insert(&mut dom, 2, p, Some(1));
+        insert(&mut dom, 3, span, Some(2));
+        insert(&mut dom, 4, p, Some(1));
+        dom.propagate_has_state(3, &nfa);
+        dom.propagate_has_state(4, &nfa);
+        assert!(dom.nodes.values().all(|node| node.has_ids.is_empty()));
+        clear_dirty(&mut dom);
+
+        // A matching class deep in the subtree flips `:has(span.x)` on its ancestors only;
+        // the sibling p and the `:has(>i)` results are untouched.
+        dom.nodes.get_mut(&3).unwrap().class_ids.insert(x);
+        assert!(dom.propagate_has_state(3, &nfa));
+        assert!(dom.nodes[&1].dirty && dom.nodes[&2].dirty);
+        assert!(!dom.nodes[&3].dirty && !dom.nodes[&4].dirty);
+        assert_eq!(dom.nodes[&1].has_ids, dom.nodes[&2].has_ids);
+        assert_eq!(dom.nodes[&1].has_ids.len(), 1);
+        clear_dirty(&mut dom);
+
+        // A second match below the same p leaves every `:has()` result unchanged.
+        insert(&mut dom, 5, span, Some(2));
+        dom.nodes.get_mut(&5).unwrap().class_ids.insert(x);
+        assert!(!dom.propagate_has_state(5, &nfa));
+        assert!(dom.nodes.values().all(|node| !node.dirty));
+    }
+
     #[test]
     fn debug_logs_skip_child_recompute_when_parent_change_is_irrelevant() {
+        let _state = STATE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
         unsafe {
             MISS_CNT = 0;
             STATE = 0;
@@ -990,9 +1626,9 @@ mod tests {
         }
 
         let mut dom = DOM::new();
-        let selectors = vec![".leaf".to_string()];
+        let selectors = vec![parse_complex_selector(".leaf").unwrap()];
         let mut s = 0;
-        let nfa = generate_nfa(&selectors, &mut dom.selector_manager, &mut s);
+        let nfa = generate_nfa(&selectors, &[], &mut dom.selector_manager, &mut s);
         unsafe {
             STATE = s;
         }
@@ -1050,4 +1686,30 @@ mod tests {
             std::env::remove_var("BIT_DEBUG");
         }
     }
+
+    #[test]
+    fn generated_c_step_matches_bit_on_traces() {
+        let _state = STATE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        for site in ["testcase", "a_to_b"] {
+            let mut dom = DOM::new();
+            let stylesheet = CompiledStylesheet::for_site("bit", site);
+            let nfa = stylesheet.nfa;
+            dom.selector_manager = stylesheet.selector_manager;
+            dom.declarations = stylesheet.declarations;
+            unsafe {
+                STATE = nfa.max_state_id.0;
+            }
+            for frame in parse_site_trace(site) {
+                apply_frame(&mut dom, &frame, &nfa);
+            }
+
+            let dir =
+                std::env::temp_dir().join(format!("css-codegen-{}-{site}", std::process::id()));
+            let (nodes, mismatches) =
+                check_generated_step(&mut dom, &nfa, Language::C, &dir).unwrap();
+            let _ = fs::remove_dir_all(&dir);
+            assert!(nodes > 0, "{site}: no nodes checked");
+            assert_eq!(mismatches, Vec::<u64>::new(), "{site}");
+        }
+    }
 }
@@ -0,0 +1,646 @@
+use crate::{NFA, Nfacell, Rule, SelectorId, SelectorManager, state_bits::StateBits};
+use std::{
+    fmt::{self, Write as _},
+    fs,
+    io::{self, Write as _},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Where a transition reads its predecessor state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Parent,
+    Sibling,
+}
+
+/// One transition lowered to bit positions: sets state `target` when the node matches
+/// predicate slot `predicate` (if any) and state `from` is set in the parent's or
+/// previous element sibling's output (if any).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub predicate: Option<usize>,
+    pub from: Option<(Source, usize)>,
+    pub target: usize,
+}
+
+/// An NFA's step function as straight-line bit operations, the form the emitters print.
+/// Predicates are numbered densely: bit `i` of a node's predicate words is set when the
+/// node matches selector `predicates[i]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepPlan {
+    /// Number of states, `max_state_id + 1`.
+    pub states: usize,
+    pub predicates: Vec<SelectorId>,
+    /// The text of each predicate's selector, for comments.
+    pub predicate_names: Vec<String>,
+    /// Accepting state of each compiled selector, in order.
+    pub accept_states: Vec<usize>,
+    /// States set on every node: targets of rules with neither selector nor predecessor.
+    pub always: StateBits,
+    /// States copied from the parent by selector-less self-loops, as one masked OR.
+    pub self_loops: StateBits,
+    pub transitions: Vec<Transition>,
+}
+
+impl StepPlan {
+    pub fn new(nfa: &NFA, sm: &SelectorManager) -> Self {
+        let mut predicates: Vec<SelectorId> = nfa
+            .rules
+            .iter()
+            .chain(&nfa.sibling_rules)
+            .filter_map(|Rule(selector, _, _)| *selector)
+            .collect();
+        predicates.sort();
+        predicates.dedup();
+        let slot = |id: SelectorId| predicates.binary_search(&id).unwrap();
+
+        let states = nfa.max_state_id.0 + 1;
+        let mut always = StateBits::new(states);
+        let mut transitions = Vec::new();
+        let parent_rules = nfa.rules.iter().map(|rule| (Source::Parent, rule));
+        let sibling_rules = nfa.sibling_rules.iter().map(|rule| (Source::Sibling, rule));
+        for (source, rule) in parent_rules.chain(sibling_rules) {
+            let &Rule(selector, prev, Nfacell(target)) = rule;
+            match (source, selector, prev) {
+                (Source::Parent, _, _) if NFA::is_self_loop(rule) => {}
+                // A sibling rule without predecessor never fires; see the engines.
+                (Source::Sibling, _, None) => {}
+                (Source::Parent, None, None) => always.insert(target),
+                _ => transitions.push(Transition {
+                    predicate: selector.map(slot),
+                    from: prev.map(|Nfacell(from)| (source, from)),
+                    target,
+                }),
+            }
+        }
+        StepPlan {
+            states,
+            predicate_names: predicates
+                .iter()
+                .map(|id| {
+                    sm.id_to_selector
+                        .get(id)
+                        .map_or_else(|| format!("sid:{}", id.0), |selector| selector.to_string())
+                })
+                .collect(),
+            predicates,
+            accept_states: nfa.accept_states.iter().map(|a| a.state.0).collect(),
+            always,
+            self_loops: nfa.self_loops.clone(),
+            transitions,
+        }
+    }
+
+    pub fn state_words(&self) -> usize {
+        self.states.div_ceil(WORD_BITS)
+    }
+
+    pub fn predicate_words(&self) -> usize {
+        self.predicates.len().div_ceil(WORD_BITS)
+    }
+
+    /// The predicate words of a node matching the selectors `matches` accepts.
+    pub fn predicate_bits(&self, mut matches: impl FnMut(SelectorId) -> bool) -> Vec<u64> {
+        let mut words = vec![0; self.predicate_words()];
+        for (slot, &id) in self.predicates.iter().enumerate() {
+            if matches(id) {
+                words[slot / WORD_BITS] |= 1 << (slot % WORD_BITS);
+            }
+        }
+        words
+    }
+}
+
+/// `states` as `words` packed words, state `i` at bit `i % 64` of word `i / 64`.
+pub fn pack_words(states: &StateBits, words: usize) -> Vec<u64> {
+    let mut packed = vec![0; words];
+    for state in states.iter_ones() {
+        packed[state / WORD_BITS] |= 1 << (state % WORD_BITS);
+    }
+    packed
+}
+
+/// The operand testing bit `index` of `array`, shifted down to bit 0.
+fn bit_operand(array: &str, index: usize) -> String {
+    match index % WORD_BITS {
+        0 => format!("{array}[{}]", index / WORD_BITS),
+        shift => format!("({array}[{}] >> {shift})", index / WORD_BITS),
+    }
+}
+
+/// The expression, 0 or 1, that `transition` contributes to its target bit, with the
+/// parent, sibling and predicate words named `i`, `s` and `p`.
+fn transition_condition(transition: &Transition) -> String {
+    let mut operands = Vec::new();
+    if let Some((source, from)) = transition.from {
+        let array = match source {
+            Source::Parent => "i",
+            Source::Sibling => "s",
+        };
+        operands.push(bit_operand(array, from));
+    }
+    if let Some(predicate) = transition.predicate {
+        operands.push(bit_operand("p", predicate));
+    }
+    operands.push("1".to_string());
+    operands.join(" & ")
+}
+
+/// The statement setting `transition`'s target bit, given its `condition`.
+fn transition_statement(transition: &Transition, condition: &str) -> String {
+    let word = transition.target / WORD_BITS;
+    match transition.target % WORD_BITS {
+        0 => format!("o[{word}] |= {condition};"),
+        shift => format!("o[{word}] |= ({condition}) << {shift};"),
+    }
+}
+
+/// A one-line comment naming what `transition` reads.
+fn transition_comment(plan: &StepPlan, transition: &Transition) -> String {
+    let from = match transition.from {
+        Some((Source::Parent, from)) => format!("{from}"),
+        Some((Source::Sibling, from)) => format!("sibling {from}"),
+        None => "start".to_string(),
+    };
+    let on = transition
+        .predicate
+        .map(|slot| {
+            format!(
+                " on {}",
+                plan.predicate_names[slot].replace(['\n', '\r'], " ")
+            )
+        })
+        .unwrap_or_default();
+    format!("{from} -> {}{on}", transition.target)
+}
+
+fn list<T: ToString>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A standalone Rust module computing `plan`'s step with straight-line bit operations:
+/// `step(node_preds, parent_bits, sibling_bits)` returns a node's output states, where
+/// the sibling bits are the previous element sibling's output (all clear for none).
+pub fn generate_rust(plan: &StepPlan) -> String {
+    let words = plan.state_words();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "// Generated by css-bitvector-compiler from {} selector(s); do not edit.",
+        plan.accept_states.len()
+    );
+    let _ = writeln!(
+        out,
+        "#![allow(clippy::all, dead_code, unused_mut, unused_variables)]\n"
+    );
+    let _ = writeln!(
+        out,
+        "/// Number of NFA states; state `i` is bit `i % 64` of word `i / 64`."
+    );
+    let _ = writeln!(out, "pub const STATES: usize = {};", plan.states);
+    let _ = writeln!(out, "pub const WORDS: usize = {words};");
+    let _ = writeln!(
+        out,
+        "/// The selector id of each predicate bit of `step`'s `node_preds`."
+    );
+    let _ = writeln!(
+        out,
+        "pub const PREDICATES: [usize; {}] = [{}];",
+        plan.predicates.len(),
+        list(plan.predicates.iter().map(|id| id.0))
+    );
+    let _ = writeln!(
+        out,
+        "pub const PREDICATE_WORDS: usize = {};",
+        plan.predicate_words()
+    );
+    let _ = writeln!(
+        out,
+        "/// Accepting state of each selector, in stylesheet order."
+    );
+    let _ = writeln!(
+        out,
+        "pub const ACCEPT_STATES: [usize; {}] = [{}];\n",
+        plan.accept_states.len(),
+        list(&plan.accept_states)
+    );
+    let _ = writeln!(out, "pub type Bits = [u64; WORDS];\n");
+    let _ = writeln!(out, "#[inline(never)]");
+    let _ = writeln!(
+        out,
+        "pub fn step(node_preds: &[u64; PREDICATE_WORDS], parent_bits: &Bits, sibling_bits: &Bits) -> Bits {{"
+    );
+    let _ = writeln!(
+        out,
+        "    let (p, i, s) = (node_preds, parent_bits, sibling_bits);"
+    );
+    let always = pack_words(&plan.always, words);
+    let _ = writeln!(
+        out,
+        "    let mut o: Bits = [{}];",
+        list(always.iter().map(|word| format!("{word:#x}")))
+    );
+    for (word, mask) in pack_words(&plan.self_loops, words).into_iter().enumerate() {
+        if mask != 0 {
+            let _ = writeln!(out, "    o[{word}] |= i[{word}] & {mask:#x};");
+        }
+    }
+    for transition in &plan.transitions {
+        let _ = writeln!(
+            out,
+            "    {} // {}",
+            transition_statement(transition, &transition_condition(transition)),
+            transition_comment(plan, transition)
+        );
+    }
+    let _ = writeln!(out, "    o\n}}");
+    out
+}
+
+/// One node to step a generated module on: the selectors it matches as predicate words
+/// (see `StepPlan::predicate_bits`), and its parent's and previous element sibling's
+/// output states.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepInput {
+    pub predicates: Vec<u64>,
+    pub parent_bits: StateBits,
+    pub sibling_bits: StateBits,
+}
+
+/// Reads one node per stdin line, its predicate words then its parent's and sibling's
+/// state words, and prints the words `step` returns.
+const RUST_DRIVER: &str = r#"mod generated;
+
+use std::io::BufRead;
+
+fn main() {
+    use generated::{PREDICATE_WORDS, WORDS};
+    for line in std::io::stdin().lock().lines() {
+        let numbers: Vec<u64> = line
+            .unwrap()
+            .split_whitespace()
+            .map(|number| number.parse().unwrap())
+            .collect();
+        let (preds, bits) = numbers.split_at(PREDICATE_WORDS);
+        let output = generated::step(
+            preds.try_into().unwrap(),
+            bits[..WORDS].try_into().unwrap(),
+            bits[WORDS..].try_into().unwrap(),
+        );
+        let words: Vec<String> = output.iter().map(u64::to_string).collect();
+        println!("{}", words.join(" "));
+    }
+}
+"#;
+
+/// Builds `module` (from `generate_rust` for `plan`) in `dir` with `rustc`, or `$RUSTC`,
+/// and steps it on each of `nodes`. Returns the output states of each.
+pub fn run_generated_rust(
+    module: &str,
+    plan: &StepPlan,
+    nodes: &[StepInput],
+    dir: &Path,
+) -> io::Result<Vec<StateBits>> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join("generated.rs"), module)?;
+    fs::write(dir.join("main.rs"), RUST_DRIVER)?;
+    let binary = dir.join("driver");
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    build(
+        Command::new(rustc)
+            .args(["--edition", "2024", "-O", "-o"])
+            .arg(&binary)
+            .arg(dir.join("main.rs")),
+    )?;
+    run_driver(&binary, plan, nodes)
+}
+
+/// A C header and source file for one stylesheet, their identifiers prefixed by
+/// `prefix` (macros by its upper-case form).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CModule {
+    pub prefix: String,
+    pub header: String,
+    pub source: String,
+}
+
+/// `text` as a C string literal.
+fn c_string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            '\n' => literal.push_str("\\n"),
+            // A `?` escape keeps `??x` from reading as a trigraph.
+            '?' => literal.push_str("\\?"),
+            c if c.is_ascii_graphic() || c == ' ' => literal.push(c),
+            c => {
+                let mut bytes = [0; 4];
+                for byte in c.encode_utf8(&mut bytes).bytes() {
+                    let _ = write!(literal, "\\{byte:03o}");
+                }
+            }
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// The elements of a C array initializer; C before C23 has no empty ones, so an empty
+/// table holds one unused `filler`.
+fn c_initializer(items: Vec<String>, filler: &str) -> String {
+    if items.is_empty() {
+        format!("{{{filler}}}")
+    } else {
+        format!("{{{}}}", items.join(", "))
+    }
+}
+
+/// `plan`'s step as C99: `<prefix>_step` computes a node's output states from the same
+/// predicate, parent and sibling words as `generate_rust`'s `step`, and the tables give
+/// each predicate bit's selector id and text in the `SelectorManager` the plan came from.
+pub fn generate_c(plan: &StepPlan, prefix: &str) -> CModule {
+    let upper = prefix.to_ascii_uppercase();
+    let words = plan.state_words();
+    let generated = format!(
+        "/* Generated by css-bitvector-compiler from {} selector(s); do not edit. */",
+        plan.accept_states.len()
+    );
+
+    let mut header = String::new();
+    let _ = writeln!(header, "{generated}");
+    let _ = writeln!(header, "#ifndef {upper}_H\n#define {upper}_H\n");
+    let _ = writeln!(header, "#include <stddef.h>\n#include <stdint.h>\n");
+    let _ = writeln!(header, "#ifdef __cplusplus\nextern \"C\" {{\n#endif\n");
+    let _ = writeln!(
+        header,
+        "/* Number of NFA states; state i is bit i % 64 of word i / 64. */"
+    );
+    let _ = writeln!(header, "#define {upper}_STATES {}", plan.states);
+    let _ = writeln!(header, "#define {upper}_WORDS {words}");
+    let _ = writeln!(
+        header,
+        "#define {upper}_PREDICATES {}",
+        plan.predicates.len()
+    );
+    let _ = writeln!(
+        header,
+        "#define {upper}_PREDICATE_WORDS {}",
+        plan.predicate_words()
+    );
+    let _ = writeln!(
+        header,
+        "#define {upper}_SELECTORS {}\n",
+        plan.accept_states.len()
+    );
+    let _ = writeln!(
+        header,
+        "/* The selector id and text of each predicate bit of {prefix}_step's node_preds. */"
+    );
+    let _ = writeln!(header, "extern const size_t {prefix}_predicate_ids[];");
+    let _ = writeln!(
+        header,
+        "extern const char *const {prefix}_predicate_selectors[];"
+    );
+    let _ = writeln!(
+        header,
+        "/* Accepting state of each selector, in stylesheet order. */"
+    );
+    let _ = writeln!(header, "extern const size_t {prefix}_accept_states[];\n");
+    let _ = writeln!(
+        header,
+        "/* Writes to out the output states of a node matching node_preds whose parent\n   \
+         and previous element sibling have parent_bits and sibling_bits (all clear for\n   \
+         none). */"
+    );
+    let _ = writeln!(
+        header,
+        "void {prefix}_step(const uint64_t *node_preds, const uint64_t *parent_bits,\n    \
+         const uint64_t *sibling_bits, uint64_t *out);\n"
+    );
+    let _ = writeln!(header, "#ifdef __cplusplus\n}}\n#endif\n\n#endif");
+
+    let mut source = String::new();
+    let _ = writeln!(source, "{generated}");
+    let _ = writeln!(source, "#include \"{prefix}.h\"\n");
+    let _ = writeln!(
+        source,
+        "const size_t {prefix}_predicate_ids[] = {};",
+        c_initializer(
+            plan.predicates.iter().map(|id| id.0.to_string()).collect(),
+            "0"
+        )
+    );
+    let _ = writeln!(
+        source,
+        "const char *const {prefix}_predicate_selectors[] = {};",
+        c_initializer(
+            plan.predicate_names
+                .iter()
+                .map(|name| c_string(name))
+                .collect(),
+            "NULL"
+        )
+    );
+    let _ = writeln!(
+        source,
+        "const size_t {prefix}_accept_states[] = {};\n",
+        c_initializer(
+            plan.accept_states.iter().map(usize::to_string).collect(),
+            "0"
+        )
+    );
+    let _ = writeln!(
+        source,
+        "void {prefix}_step(const uint64_t *node_preds, const uint64_t *parent_bits,\n    \
+         const uint64_t *sibling_bits, uint64_t *out) {{"
+    );
+    let _ = writeln!(
+        source,
+        "    const uint64_t *p = node_preds, *i = parent_bits, *s = sibling_bits;"
+    );
+    let always = pack_words(&plan.always, words);
+    let _ = writeln!(
+        source,
+        "    uint64_t o[{upper}_WORDS] = {{{}}};",
+        list(always.iter().map(|word| format!("UINT64_C({word:#x})")))
+    );
+    let _ = writeln!(source, "    (void)p, (void)i, (void)s;");
+    for (word, mask) in pack_words(&plan.self_loops, words).into_iter().enumerate() {
+        if mask != 0 {
+            let _ = writeln!(source, "    o[{word}] |= i[{word}] & UINT64_C({mask:#x});");
+        }
+    }
+    for transition in &plan.transitions {
+        let _ = writeln!(
+            source,
+            "    {} /* {} */",
+            transition_statement(transition, &transition_condition(transition)),
+            transition_comment(plan, transition).replace("*/", "* /")
+        );
+    }
+    let _ = writeln!(
+        source,
+        "    for (size_t w = 0; w < {upper}_WORDS; w++) {{\n        out[w] = o[w];\n    }}\n}}"
+    );
+    CModule {
+        prefix: prefix.to_string(),
+        header,
+        source,
+    }
+}
+
+/// The C counterpart of `RUST_DRIVER`, for a module with prefix `prefix`.
+fn c_driver(prefix: &str) -> String {
+    let upper = prefix.to_ascii_uppercase();
+    format!(
+        r#"#include <inttypes.h>
+#include <stdio.h>
+
+#include "{prefix}.h"
+
+static int read_words(uint64_t *words, size_t count) {{
+    for (size_t w = 0; w < count; w++) {{
+        if (scanf("%" SCNu64, &words[w]) != 1) {{
+            return 0;
+        }}
+    }}
+    return 1;
+}}
+
+int main(void) {{
+    uint64_t preds[{upper}_PREDICATE_WORDS + 1], parent[{upper}_WORDS];
+    uint64_t sibling[{upper}_WORDS], out[{upper}_WORDS];
+    while (read_words(preds, {upper}_PREDICATE_WORDS) && read_words(parent, {upper}_WORDS) &&
+           read_words(sibling, {upper}_WORDS)) {{
+        {prefix}_step(preds, parent, sibling, out);
+        for (size_t w = 0; w < {upper}_WORDS; w++) {{
+            printf(w ? " %" PRIu64 : "%" PRIu64, out[w]);
+        }}
+        printf("\n");
+    }}
+    return 0;
+}}
+"#
+    )
+}
+
+/// Builds `module` (from `generate_c` for `plan`) in `dir` with `cc`, or `$CC`, and
+/// steps it on each of `nodes` like `run_generated_rust`.
+pub fn run_generated_c(
+    module: &CModule,
+    plan: &StepPlan,
+    nodes: &[StepInput],
+    dir: &Path,
+) -> io::Result<Vec<StateBits>> {
+    let prefix = &module.prefix;
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(format!("{prefix}.h")), &module.header)?;
+    fs::write(dir.join(format!("{prefix}.c")), &module.source)?;
+    fs::write(dir.join("driver.c"), c_driver(prefix))?;
+    let binary = dir.join("driver");
+    let cc = std::env::var_os("CC").unwrap_or_else(|| "cc".into());
+    build(
+        Command::new(cc)
+            .args(["-std=c99", "-O2", "-Wall", "-Wextra", "-o"])
+            .arg(&binary)
+            .arg(dir.join("driver.c"))
+            .arg(dir.join(format!("{prefix}.c"))),
+    )?;
+    run_driver(&binary, plan, nodes)
+}
+
+/// Which emitter `run_generated` checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    C,
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Language::Rust => "rust",
+            Language::C => "c",
+        })
+    }
+}
+
+/// Generates `plan` in `language`, builds it in `dir` and steps it on each of `nodes`.
+pub fn run_generated(
+    language: Language,
+    plan: &StepPlan,
+    nodes: &[StepInput],
+    dir: &Path,
+) -> io::Result<Vec<StateBits>> {
+    match language {
+        Language::Rust => run_generated_rust(&generate_rust(plan), plan, nodes, dir),
+        Language::C => run_generated_c(&generate_c(plan, "css_selectors"), plan, nodes, dir),
+    }
+}
+
+/// Runs a compiler command, failing unless it succeeds.
+fn build(command: &mut Command) -> io::Result<()> {
+    let status = command.status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {status}",
+            command.get_program().to_string_lossy()
+        )));
+    }
+    Ok(())
+}
+
+/// Feeds `nodes` to a built driver, one line each, and parses the outputs it prints.
+fn run_driver(binary: &Path, plan: &StepPlan, nodes: &[StepInput]) -> io::Result<Vec<StateBits>> {
+    let words = plan.state_words();
+    let mut input = String::new();
+    for node in nodes {
+        let numbers = node
+            .predicates
+            .iter()
+            .copied()
+            .chain(pack_words(&node.parent_bits, words))
+            .chain(pack_words(&node.sibling_bits, words));
+        let numbers: Vec<String> = numbers.map(|number| number.to_string()).collect();
+        let _ = writeln!(input, "{}", numbers.join(" "));
+    }
+    let mut child = Command::new(binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Outputs are printed as the nodes arrive, so feed stdin from another thread.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    writer.join().unwrap()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            binary.display(),
+            output.status
+        )));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| {
+            let words: Vec<u64> = line
+                .split_whitespace()
+                .map(|word| word.parse().map_err(io::Error::other))
+                .collect::<io::Result<_>>()?;
+            Ok((0..plan.states)
+                .map(|state| words[state / WORD_BITS] >> (state % WORD_BITS) & 1 == 1)
+                .collect())
+        })
+        .collect()
+}
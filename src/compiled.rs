@@ -0,0 +1,620 @@
+use crate::{
+    AcceptState, CompoundSelector, Declaration, HasSelector, NFA, Nfacell, NthKind, NthSelector,
+    PositionReads, Rule, Selector, SelectorId, SelectorManager, SelectorOrigin, Specificity,
+    generate_nfa, parse_css_with_pseudo, partition_simple_selectors, report_nfa_states,
+    report_pseudo_selectors, report_skipped_selectors, report_unsupported_selectors,
+    state_bits::StateBits,
+};
+use serde_json::{Map, Value, json};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt, fs, io,
+    path::Path,
+};
+
+/// Version of the on-disk format `CompiledStylesheet::save` writes. Loading any other
+/// version fails, so bump it whenever the format or the meaning of a field changes.
+pub const FORMAT_VERSION: u64 = 1;
+
+/// Identifies a compiled stylesheet among other JSON files.
+const FORMAT_NAME: &str = "css-bitvector-compiler/stylesheet";
+
+/// A stylesheet compiled for the engines: the NFA, the interning table its selector ids
+/// refer to, and the strings matches are reported under. The DFA and the rule index are
+/// not stored; engines attach them after loading, as after compiling.
+#[derive(Debug)]
+pub struct CompiledStylesheet {
+    pub nfa: NFA,
+    pub selector_manager: SelectorManager,
+    /// The compiled selectors, one per entry of `nfa.accept_states`.
+    pub selectors: Vec<String>,
+    /// See `ParsedSelectors::sources`.
+    pub sources: BTreeMap<String, Vec<String>>,
+    /// See `ParsedSelectors::declarations`.
+    pub declarations: Vec<Vec<Declaration>>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// Written by a compiler with another `FORMAT_VERSION`.
+    Version(u64),
+    /// Not a compiled stylesheet, or one whose tables disagree; names what is wrong.
+    Malformed(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "cannot read compiled stylesheet: {err}"),
+            LoadError::Json(err) => write!(f, "compiled stylesheet is not JSON: {err}"),
+            LoadError::Version(version) => write!(
+                f,
+                "compiled stylesheet has format version {version}, expected {FORMAT_VERSION}"
+            ),
+            LoadError::Malformed(what) => write!(f, "malformed compiled stylesheet: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(err: serde_json::Error) -> Self {
+        LoadError::Json(err)
+    }
+}
+
+type Load<T> = Result<T, LoadError>;
+
+fn malformed<T>(what: impl Into<String>) -> Load<T> {
+    Err(LoadError::Malformed(what.into()))
+}
+
+impl CompiledStylesheet {
+    /// Parses and compiles a stylesheet the way the engines do, reporting what they
+    /// leave out under `label`.
+    pub fn compile(label: &str, css: &str) -> Self {
+        let parsed = parse_css_with_pseudo(css);
+        let (selectors, skipped_simple) = partition_simple_selectors(parsed.selectors.clone());
+        report_skipped_selectors(label, &skipped_simple);
+        report_pseudo_selectors(label, &parsed.pseudo_selectors);
+        report_unsupported_selectors(label, &parsed.unsupported_selectors);
+        let mut selector_manager = SelectorManager::new();
+        let (compiled, origins) = parsed.nfa_inputs(&selectors);
+        let nfa = generate_nfa(&compiled, &origins, &mut selector_manager, &mut 0);
+        report_nfa_states(label, &nfa, &compiled);
+        CompiledStylesheet {
+            nfa,
+            selector_manager,
+            selectors,
+            sources: parsed.sources,
+            declarations: parsed.declarations,
+        }
+    }
+
+    /// The site's stylesheet, `css-gen-op/<website>/<website>.css`, compiled. When
+    /// `COMPILED_STYLESHEET` names an existing file it is loaded instead; when it names
+    /// a missing one, the compiled stylesheet is saved there.
+    pub fn for_site(label: &str, website: &str) -> Self {
+        let artifact = std::env::var_os("COMPILED_STYLESHEET");
+        if let Some(path) = artifact.as_deref().map(Path::new)
+            && path.exists()
+        {
+            let stylesheet = CompiledStylesheet::load(path)
+                .unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+            println!("COMPILED[{label}] loaded {}", path.display());
+            return stylesheet;
+        }
+        let css = fs::read_to_string(format!("css-gen-op/{0}/{0}.css", website)).unwrap();
+        let stylesheet = CompiledStylesheet::compile(label, &css);
+        if let Some(path) = artifact.as_deref().map(Path::new) {
+            stylesheet
+                .save(path)
+                .unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+            println!("COMPILED[{label}] saved {}", path.display());
+        }
+        stylesheet
+    }
+
+    /// Writes the stylesheet as pretty-printed JSON, with every table in a fixed order
+    /// so the files of two compiler versions can be diffed.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = serde_json::to_string_pretty(&self.to_json())?;
+        text.push('\n');
+        fs::write(path, text)
+    }
+
+    pub fn load(path: &Path) -> Load<Self> {
+        CompiledStylesheet::from_json(&serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let nfa = &self.nfa;
+        let mut states: Vec<Option<usize>> = nfa.states.iter().map(|s| s.map(|s| s.0)).collect();
+        states.sort();
+        let interned: Vec<Value> = self
+            .selector_manager
+            .selectors()
+            .map(|(_, selector)| selector_to_json(selector))
+            .collect();
+        let declarations: Vec<Value> = self
+            .declarations
+            .iter()
+            .map(|block| {
+                block
+                    .iter()
+                    .map(|declaration| {
+                        json!([
+                            declaration.property,
+                            declaration.value,
+                            declaration.important
+                        ])
+                    })
+                    .collect()
+            })
+            .collect();
+        json!({
+            "format": FORMAT_NAME,
+            "version": FORMAT_VERSION,
+            "selectors": self.selectors,
+            "sources": self.sources,
+            "declarations": declarations,
+            "interned": interned,
+            "nfa": {
+                "states": states,
+                "start_state": nfa.start_state.map(|s| s.0),
+                "max_state_id": nfa.max_state_id.0,
+                "rules": nfa.rules.iter().map(rule_to_json).collect::<Vec<_>>(),
+                "sibling_rules": nfa.sibling_rules.iter().map(rule_to_json).collect::<Vec<_>>(),
+                "position_reads": [nfa.position_reads.from_start, nfa.position_reads.from_end],
+                "has_selectors": nfa.has_selectors.iter().map(|(id, _)| id.0).collect::<Vec<_>>(),
+                "accept_states": nfa.accept_states.iter().map(accept_to_json).collect::<Vec<_>>(),
+            },
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Load<Self> {
+        if value.get("format").and_then(Value::as_str) != Some(FORMAT_NAME) {
+            return malformed(format!("`format` is not \"{FORMAT_NAME}\""));
+        }
+        let version = field(value, "version")?
+            .as_u64()
+            .ok_or_else(|| LoadError::Malformed("`version` is not a number".into()))?;
+        if version != FORMAT_VERSION {
+            return Err(LoadError::Version(version));
+        }
+
+        let interned = array(field(value, "interned")?, "interned")?
+            .iter()
+            .map(selector_from_json)
+            .collect::<Load<Vec<_>>>()?;
+        let mut selector_manager = SelectorManager::from_selectors(interned)
+            .ok_or_else(|| LoadError::Malformed("a selector is interned twice".into()))?;
+        let selectors = array(field(value, "selectors")?, "selectors")?
+            .iter()
+            .map(|text| string(text, "selectors"))
+            .collect::<Load<Vec<_>>>()?;
+        let mut sources = BTreeMap::new();
+        for (text, from) in object(field(value, "sources")?, "sources")? {
+            let from = array(from, "sources")?
+                .iter()
+                .map(|source| string(source, "sources"))
+                .collect::<Load<_>>()?;
+            sources.insert(text.clone(), from);
+        }
+        let declarations = array(field(value, "declarations")?, "declarations")?
+            .iter()
+            .map(|block| {
+                array(block, "declarations")?
+                    .iter()
+                    .map(declaration_from_json)
+                    .collect()
+            })
+            .collect::<Load<_>>()?;
+
+        let nfa = field(value, "nfa")?;
+        let max_state_id = usize_field(nfa, "max_state_id")?;
+        let state = |value: &Value| -> Load<Nfacell> {
+            match value.as_u64().map(|s| s as usize) {
+                Some(s) if s <= max_state_id => Ok(Nfacell(s)),
+                _ => malformed(format!("state {value} is not in 0..={max_state_id}")),
+            }
+        };
+        let optional_state = |value: &Value| -> Load<Option<Nfacell>> {
+            if value.is_null() {
+                Ok(None)
+            } else {
+                state(value).map(Some)
+            }
+        };
+        let selector_count = selector_manager.selectors().count();
+        let selector_id = |value: &Value| -> Load<SelectorId> {
+            match value.as_u64().map(|id| id as usize) {
+                Some(id) if id < selector_count => Ok(SelectorId(id)),
+                _ => malformed(format!("selector id {value} is not interned")),
+            }
+        };
+        let rules = |name: &str| -> Load<Vec<Rule>> {
+            array(field(nfa, name)?, name)?
+                .iter()
+                .map(|rule| match array(rule, name)?.as_slice() {
+                    [selector, prev, next] => Ok(Rule(
+                        if selector.is_null() {
+                            None
+                        } else {
+                            Some(selector_id(selector)?)
+                        },
+                        optional_state(prev)?,
+                        state(next)?,
+                    )),
+                    _ => malformed(format!("`{name}` holds {rule}, not a rule")),
+                })
+                .collect()
+        };
+
+        let states = array(field(nfa, "states")?, "states")?
+            .iter()
+            .map(optional_state)
+            .collect::<Load<HashSet<_>>>()?;
+        let position_reads =
+            match array(field(nfa, "position_reads")?, "position_reads")?.as_slice() {
+                [Value::Bool(from_start), Value::Bool(from_end)] => PositionReads {
+                    from_start: *from_start,
+                    from_end: *from_end,
+                },
+                _ => return malformed("`position_reads` is not two booleans"),
+            };
+        let has_selectors = array(field(nfa, "has_selectors")?, "has_selectors")?
+            .iter()
+            .map(|id| {
+                let id = selector_id(id)?;
+                match selector_manager.id_to_selector.get(&id) {
+                    Some(Selector::Has(has)) => Ok((id, has.clone())),
+                    _ => malformed(format!("has selector {} is not a `:has()`", id.0)),
+                }
+            })
+            .collect::<Load<_>>()?;
+        let accept_states = array(field(nfa, "accept_states")?, "accept_states")?
+            .iter()
+            .map(|accept| {
+                Ok(AcceptState {
+                    state: state(field(accept, "state")?)?,
+                    origins: array(field(accept, "origins")?, "origins")?
+                        .iter()
+                        .map(origin_from_json)
+                        .collect::<Load<_>>()?,
+                })
+            })
+            .collect::<Load<Vec<_>>>()?;
+        if accept_states.len() != selectors.len() {
+            return malformed(format!(
+                "{} accept states for {} selectors",
+                accept_states.len(),
+                selectors.len()
+            ));
+        }
+
+        let mut nfa = NFA {
+            states,
+            rules: rules("rules")?,
+            sibling_rules: rules("sibling_rules")?,
+            position_reads,
+            has_selectors,
+            start_state: optional_state(field(nfa, "start_state")?)?,
+            max_state_id: Nfacell(max_state_id),
+            accept_states,
+            dfa: None,
+            rule_index: None,
+            self_loops: StateBits::default(),
+            sibling_reads: StateBits::default(),
+            accepting: StateBits::default(),
+            pseudo_element_states: StateBits::default(),
+        };
+        nfa.derive_tables(&mut selector_manager);
+        Ok(CompiledStylesheet {
+            nfa,
+            selector_manager,
+            selectors,
+            sources,
+            declarations,
+        })
+    }
+}
+
+fn rule_to_json(&Rule(selector, prev, next): &Rule) -> Value {
+    json!([selector.map(|id| id.0), prev.map(|s| s.0), next.0])
+}
+
+fn accept_to_json(accept: &AcceptState) -> Value {
+    let origins: Vec<Value> = accept
+        .origins
+        .iter()
+        .map(|origin| {
+            let Specificity(a, b, c) = origin.specificity;
+            json!([[a, b, c], origin.source_index, origin.rule_index])
+        })
+        .collect();
+    json!({ "state": accept.state.0, "origins": origins })
+}
+
+fn origin_from_json(value: &Value) -> Load<SelectorOrigin> {
+    let number = |value: &Value| value.as_u64().and_then(|n| u32::try_from(n).ok());
+    let origin = match array(value, "origins")?.as_slice() {
+        [specificity, source_index, rule_index] => {
+            match specificity.as_array().map(Vec::as_slice) {
+                Some([a, b, c]) => (|| {
+                    Some(SelectorOrigin {
+                        specificity: Specificity(number(a)?, number(b)?, number(c)?),
+                        source_index: source_index.as_u64()? as usize,
+                        rule_index: rule_index.as_u64()? as usize,
+                    })
+                })(),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    origin.ok_or_else(|| LoadError::Malformed(format!("{value} is not an origin")))
+}
+
+fn declaration_from_json(value: &Value) -> Load<Declaration> {
+    match array(value, "declarations")?.as_slice() {
+        [
+            Value::String(property),
+            Value::String(value),
+            Value::Bool(important),
+        ] => Ok(Declaration {
+            property: property.clone(),
+            value: value.clone(),
+            important: *important,
+        }),
+        _ => malformed(format!("{value} is not a declaration")),
+    }
+}
+
+/// One object per selector, keyed by its kind: `{"class": "a"}`, `{"nth": {...}}`.
+fn selector_to_json(selector: &Selector) -> Value {
+    let selectors =
+        |selectors: &[Selector]| -> Vec<Value> { selectors.iter().map(selector_to_json).collect() };
+    let attribute = |operator: &str, name: &str, value: &str, case_insensitive: bool| {
+        json!({ "attribute": {
+            "operator": operator,
+            "name": name,
+            "value": value,
+            "case_insensitive": case_insensitive,
+        } })
+    };
+    match selector {
+        Selector::Type(name) => json!({ "type": name }),
+        Selector::Class(name) => json!({ "class": name }),
+        Selector::Id(name) => json!({ "id": name }),
+        Selector::AttributeExists { name } => {
+            json!({ "attribute": { "operator": "exists", "name": name } })
+        }
+        Selector::AttributeEquals {
+            name,
+            value,
+            case_insensitive,
+        } => attribute("equals", name, value, *case_insensitive),
+        Selector::AttributeIncludes {
+            name,
+            value,
+            case_insensitive,
+        } => attribute("includes", name, value, *case_insensitive),
+        Selector::AttributeDashMatch {
+            name,
+            value,
+            case_insensitive,
+        } => attribute("dash_match", name, value, *case_insensitive),
+        Selector::AttributePrefix {
+            name,
+            value,
+            case_insensitive,
+        } => attribute("prefix", name, value, *case_insensitive),
+        Selector::AttributeSuffix {
+            name,
+            value,
+            case_insensitive,
+        } => attribute("suffix", name, value, *case_insensitive),
+        Selector::AttributeSubstring {
+            name,
+            value,
+            case_insensitive,
+        } => attribute("substring", name, value, *case_insensitive),
+        Selector::Not(arguments) => json!({ "not": selectors(arguments) }),
+        Selector::Nth(nth) => json!({ "nth": {
+            "kind": nth_kind_name(nth.kind),
+            "a": nth.a,
+            "b": nth.b,
+        } }),
+        Selector::Has(has) => json!({ "has": {
+            "child": has.child,
+            "arguments": selectors(&has.arguments),
+        } }),
+        Selector::Compound(compound) => json!({ "compound": {
+            "tag": compound.tag,
+            "id": compound.id,
+            "classes": compound.classes,
+            "attributes": selectors(&compound.attributes),
+            "pseudos": compound.pseudos,
+            "positions": selectors(&compound.positions),
+            "has": selectors(&compound.has),
+            "negations": selectors(&compound.negations),
+            "pseudo_element": compound.pseudo_element,
+        } }),
+    }
+}
+
+fn selector_from_json(value: &Value) -> Load<Selector> {
+    let Some((kind, body)) = value.as_object().and_then(single_entry) else {
+        return malformed(format!("{value} is not a selector"));
+    };
+    let selectors = |value: &Value| -> Load<Vec<Selector>> {
+        array(value, kind)?.iter().map(selector_from_json).collect()
+    };
+    Ok(match kind.as_str() {
+        "type" => Selector::Type(string(body, kind)?),
+        "class" => Selector::Class(string(body, kind)?),
+        "id" => Selector::Id(string(body, kind)?),
+        "attribute" => {
+            let name = string(field(body, "name")?, "attribute")?;
+            let operator = string(field(body, "operator")?, "attribute")?;
+            if operator == "exists" {
+                return Ok(Selector::AttributeExists { name });
+            }
+            let value = string(field(body, "value")?, "attribute")?;
+            let Some(case_insensitive) = field(body, "case_insensitive")?.as_bool() else {
+                return malformed("attribute `case_insensitive` is not a boolean");
+            };
+            match operator.as_str() {
+                "equals" => Selector::AttributeEquals {
+                    name,
+                    value,
+                    case_insensitive,
+                },
+                "includes" => Selector::AttributeIncludes {
+                    name,
+                    value,
+                    case_insensitive,
+                },
+                "dash_match" => Selector::AttributeDashMatch {
+                    name,
+                    value,
+                    case_insensitive,
+                },
+                "prefix" => Selector::AttributePrefix {
+                    name,
+                    value,
+                    case_insensitive,
+                },
+                "suffix" => Selector::AttributeSuffix {
+                    name,
+                    value,
+                    case_insensitive,
+                },
+                "substring" => Selector::AttributeSubstring {
+                    name,
+                    value,
+                    case_insensitive,
+                },
+                _ => return malformed(format!("unknown attribute operator `{operator}`")),
+            }
+        }
+        "not" => Selector::Not(selectors(body)?),
+        "nth" => {
+            let kind = string(field(body, "kind")?, "nth")?;
+            let Some(kind) = NTH_KINDS.into_iter().find(|&k| nth_kind_name(k) == kind) else {
+                return malformed(format!("unknown nth kind `{kind}`"));
+            };
+            let coefficient = |name: &str| -> Load<i32> {
+                field(body, name)?
+                    .as_i64()
+                    .and_then(|n| i32::try_from(n).ok())
+                    .ok_or_else(|| LoadError::Malformed(format!("nth `{name}` is not an i32")))
+            };
+            Selector::Nth(NthSelector {
+                kind,
+                a: coefficient("a")?,
+                b: coefficient("b")?,
+            })
+        }
+        "has" => Selector::Has(HasSelector {
+            child: field(body, "child")?
+                .as_bool()
+                .ok_or_else(|| LoadError::Malformed("has `child` is not a boolean".into()))?,
+            arguments: selectors(field(body, "arguments")?)?,
+        }),
+        "compound" => {
+            let optional = |name: &str| -> Load<Option<String>> {
+                match field(body, name)? {
+                    Value::Null => Ok(None),
+                    value => string(value, name).map(Some),
+                }
+            };
+            let names = |name: &str| -> Load<BTreeSet<String>> {
+                array(field(body, name)?, name)?
+                    .iter()
+                    .map(|value| string(value, name))
+                    .collect()
+            };
+            Selector::Compound(CompoundSelector {
+                tag: optional("tag")?,
+                id: optional("id")?,
+                classes: names("classes")?,
+                attributes: selectors(field(body, "attributes")?)?,
+                pseudos: names("pseudos")?,
+                positions: selectors(field(body, "positions")?)?,
+                has: selectors(field(body, "has")?)?,
+                negations: selectors(field(body, "negations")?)?,
+                pseudo_element: optional("pseudo_element")?,
+            })
+        }
+        _ => return malformed(format!("unknown selector kind `{kind}`")),
+    })
+}
+
+const NTH_KINDS: [NthKind; 6] = [
+    NthKind::Child,
+    NthKind::LastChild,
+    NthKind::OnlyChild,
+    NthKind::OfType,
+    NthKind::LastOfType,
+    NthKind::OnlyOfType,
+];
+
+fn nth_kind_name(kind: NthKind) -> &'static str {
+    match kind {
+        NthKind::Child => "child",
+        NthKind::LastChild => "last_child",
+        NthKind::OnlyChild => "only_child",
+        NthKind::OfType => "of_type",
+        NthKind::LastOfType => "last_of_type",
+        NthKind::OnlyOfType => "only_of_type",
+    }
+}
+
+fn single_entry(object: &Map<String, Value>) -> Option<(&String, &Value)> {
+    let mut entries = object.iter();
+    let entry = entries.next()?;
+    entries.next().is_none().then_some(entry)
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Load<&'a Value> {
+    value
+        .get(name)
+        .ok_or_else(|| LoadError::Malformed(format!("missing `{name}`")))
+}
+
+fn usize_field(value: &Value, name: &str) -> Load<usize> {
+    field(value, name)?
+        .as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| LoadError::Malformed(format!("`{name}` is not a number")))
+}
+
+fn array<'a>(value: &'a Value, name: &str) -> Load<&'a Vec<Value>> {
+    value
+        .as_array()
+        .ok_or_else(|| LoadError::Malformed(format!("`{name}` holds {value}, not an array")))
+}
+
+fn object<'a>(value: &'a Value, name: &str) -> Load<&'a Map<String, Value>> {
+    value
+        .as_object()
+        .ok_or_else(|| LoadError::Malformed(format!("`{name}` holds {value}, not an object")))
+}
+
+fn string(value: &Value, name: &str) -> Load<String> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| LoadError::Malformed(format!("`{name}` holds {value}, not a string")))
+}
@@ -0,0 +1,338 @@
+use crate::{NFA, Nfacell, Rule, SelectorId, state_bits::StateBits};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+};
+
+/// State limit used when `DFA_STATE_LIMIT` is not set.
+pub const DEFAULT_STATE_LIMIT: usize = 1024;
+
+/// Which automaton the engines evaluate, from `NFA_AUTOMATON` (`nfa` or `dfa`; see
+/// `from_env`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AutomatonMode {
+    #[default]
+    Nfa,
+    Dfa,
+}
+
+impl AutomatonMode {
+    pub fn from_env() -> Self {
+        match std::env::var("NFA_AUTOMATON").as_deref() {
+            Ok("dfa") => AutomatonMode::Dfa,
+            _ => AutomatonMode::Nfa,
+        }
+    }
+}
+
+pub fn state_limit_from_env() -> usize {
+    std::env::var("DFA_STATE_LIMIT")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_STATE_LIMIT)
+}
+
+pub type DfaStateId = usize;
+
+/// The moves out of one (parent state, previous element sibling state) configuration,
+/// added one letter at a time as nodes read them. A node's letter is the subset of
+/// `selectors` it matches, as a bitmask over their positions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Transitions {
+    /// The states every letter reaches.
+    base: StateBits,
+    /// Sorted by id, with the states matching each adds; selectors that cannot change
+    /// the successor are left out.
+    selectors: Vec<(SelectorId, StateBits)>,
+    /// The successor of every letter met so far.
+    next: HashMap<StateBits, DfaStateId>,
+}
+
+/// The part of the DFA determinized so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Explored {
+    /// The NFA state set of each DFA state. State 0 is the empty set: the root's input
+    /// and the sibling input of a first child.
+    states: Vec<StateBits>,
+    /// The DFA state of every NFA state set met so far.
+    ids: HashMap<StateBits, DfaStateId>,
+    transitions: HashMap<(DfaStateId, DfaStateId), Transitions>,
+    /// Steps that needed a state past the limit and fell back to the NFA rules.
+    fallbacks: usize,
+}
+
+/// The selector NFA determinized over node configurations: a node's DFA state follows
+/// from its parent's, its previous element sibling's, and which selectors it matches.
+/// Every state stands for the NFA state set the engines keep per node, so engines step
+/// through it and still read and store plain bitvectors.
+///
+/// Determinizing every letter up front is exponential in the selectors a configuration
+/// offers, so states and transitions are added lazily, for the configurations and
+/// letters the nodes actually produce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dfa {
+    limit: usize,
+    explored: RefCell<Explored>,
+}
+
+impl Dfa {
+    /// A DFA over `nfa`'s states holding only the empty state set, which grows to at
+    /// most `limit` states as `step` meets new configurations.
+    pub fn new(nfa: &NFA, limit: usize) -> Dfa {
+        let mut explored = Explored {
+            states: Vec::new(),
+            ids: HashMap::new(),
+            transitions: HashMap::new(),
+            fallbacks: 0,
+        };
+        let empty = StateBits::new(nfa.max_state_id.0 + 1);
+        let limit = limit.max(1);
+        intern(&mut explored.states, &mut explored.ids, &empty, limit);
+        Dfa {
+            limit,
+            explored: RefCell::new(explored),
+        }
+    }
+
+    pub fn state_count(&self) -> usize {
+        self.explored.borrow().states.len()
+    }
+
+    pub fn transition_count(&self) -> usize {
+        let explored = self.explored.borrow();
+        explored.transitions.values().map(|t| t.next.len()).sum()
+    }
+
+    pub fn fallback_count(&self) -> usize {
+        self.explored.borrow().fallbacks
+    }
+
+    /// The output of a node whose parent output is `input` and whose previous element
+    /// sibling output is `sibling_input`, determinizing the move if it is new. `None`
+    /// when that needs more than the state limit; the caller evaluates the NFA rules.
+    pub fn step(
+        &self,
+        nfa: &NFA,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        matches: impl FnMut(SelectorId) -> bool,
+    ) -> Option<StateBits> {
+        let mut explored = self.explored.borrow_mut();
+        let output = explored.step(nfa, self.limit, input, sibling_input, matches);
+        if output.is_none() {
+            explored.fallbacks += 1;
+        }
+        output
+    }
+
+    /// The number of states left once states no explored node can tell apart are
+    /// merged, by Hopcroft-style partition refinement. States start out split by which
+    /// selectors they accept; a block splits when its states move to different blocks
+    /// on some letter, with some other state as parent or sibling. Only blocks whose
+    /// successors split are checked again. Moves not explored yet count as moves of
+    /// their own, so this minimizes the explored part only. The count is reported
+    /// alongside the explored states; steps always go through the unminimized states,
+    /// whose moves are still determinized as nodes read them.
+    pub fn minimized_state_count(&self, nfa: &NFA) -> usize {
+        let explored = self.explored.borrow();
+        let count = explored.states.len();
+        let mut block: Vec<usize> = {
+            let mut accepting: HashMap<Vec<bool>, usize> = HashMap::new();
+            explored
+                .states
+                .iter()
+                .map(|set| {
+                    let key = nfa
+                        .accept_states
+                        .iter()
+                        .map(|accept| set.get(accept.state.0))
+                        .collect();
+                    let next = accepting.len();
+                    *accepting.entry(key).or_insert(next)
+                })
+                .collect()
+        };
+        let mut block_count = block.iter().max().map_or(0, |max| max + 1);
+
+        // The explored configurations each state is the parent, then the sibling of,
+        // and the states whose signatures read each state's block.
+        let mut as_parent: Vec<Vec<DfaStateId>> = vec![Vec::new(); count];
+        let mut as_sibling: Vec<Vec<DfaStateId>> = vec![Vec::new(); count];
+        let mut readers: Vec<BTreeSet<DfaStateId>> = vec![BTreeSet::new(); count];
+        for (&(parent, sibling), transitions) in &explored.transitions {
+            as_parent[parent].push(sibling);
+            as_sibling[sibling].push(parent);
+            for &next in transitions.next.values() {
+                readers[next].insert(parent);
+                readers[next].insert(sibling);
+            }
+        }
+        for others in as_parent.iter_mut().chain(as_sibling.iter_mut()) {
+            others.sort_unstable();
+        }
+        let signature = |state: DfaStateId, block: &[usize]| -> Signature {
+            let moves =
+                |others: &[DfaStateId], key: &dyn Fn(DfaStateId) -> (DfaStateId, DfaStateId)| {
+                    others
+                        .iter()
+                        .map(|&other| (other, canonical(&explored.transitions[&key(other)], block)))
+                        .collect()
+                };
+            (
+                moves(&as_parent[state], &|other| (state, other)),
+                moves(&as_sibling[state], &|other| (other, state)),
+            )
+        };
+
+        let mut pending: BTreeSet<usize> = (0..block_count).collect();
+        while let Some(checked) = pending.pop_first() {
+            let mut groups: HashMap<Signature, Vec<DfaStateId>> = HashMap::new();
+            for state in (0..count).filter(|&state| block[state] == checked) {
+                groups
+                    .entry(signature(state, &block))
+                    .or_default()
+                    .push(state);
+            }
+            if groups.len() < 2 {
+                continue;
+            }
+            let mut groups: Vec<Vec<DfaStateId>> = groups.into_values().collect();
+            groups.sort();
+            // The group holding the block's lowest state keeps its number.
+            for group in groups.into_iter().skip(1) {
+                for &state in &group {
+                    block[state] = block_count;
+                    pending.extend(readers[state].iter().map(|&reader| block[reader]));
+                }
+                pending.insert(block_count);
+                block_count += 1;
+            }
+        }
+        block_count
+    }
+}
+
+impl Explored {
+    fn step(
+        &mut self,
+        nfa: &NFA,
+        limit: usize,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        mut matches: impl FnMut(SelectorId) -> bool,
+    ) -> Option<StateBits> {
+        let Explored {
+            states,
+            ids,
+            transitions,
+            ..
+        } = self;
+        let parent = intern(states, ids, input, limit)?;
+        let sibling = intern(states, ids, sibling_input, limit)?;
+        let transitions = transitions.entry((parent, sibling)).or_insert_with(|| {
+            let (base, selectors) = step_parts(nfa, &states[parent], &states[sibling]);
+            Transitions {
+                base,
+                selectors,
+                next: HashMap::new(),
+            }
+        });
+        let mut letter = StateBits::new(transitions.selectors.len());
+        for (i, &(selector, _)) in transitions.selectors.iter().enumerate() {
+            if matches(selector) {
+                letter.insert(i);
+            }
+        }
+        if let Some(&next) = transitions.next.get(&letter) {
+            return Some(states[next].clone());
+        }
+        let mut set = transitions.base.clone();
+        for i in letter.iter_ones() {
+            set.union_with(&transitions.selectors[i].1);
+        }
+        let next = intern(states, ids, &set, limit)?;
+        transitions.next.insert(letter, next);
+        Some(set)
+    }
+}
+
+/// The DFA state of `set`, added if new; `None` once that exceeds `limit` states.
+fn intern(
+    states: &mut Vec<StateBits>,
+    ids: &mut HashMap<StateBits, DfaStateId>,
+    set: &StateBits,
+    limit: usize,
+) -> Option<DfaStateId> {
+    if let Some(&id) = ids.get(set) {
+        return Some(id);
+    }
+    if states.len() >= limit {
+        return None;
+    }
+    let id = states.len();
+    ids.insert(set.clone(), id);
+    states.push(set.clone());
+    Some(id)
+}
+
+/// The successor blocks of every explored parent, then sibling configuration of a
+/// state, by the other state of the configuration.
+type Signature = (
+    Vec<(DfaStateId, CanonicalTransitions)>,
+    Vec<(DfaStateId, CanonicalTransitions)>,
+);
+
+/// Successor blocks by the selectors each explored letter matches, so equal moves
+/// compare equal whatever selectors the configurations offered.
+type CanonicalTransitions = Vec<(Vec<SelectorId>, usize)>;
+
+fn canonical(transitions: &Transitions, block: &[usize]) -> CanonicalTransitions {
+    let mut moves: CanonicalTransitions = transitions
+        .next
+        .iter()
+        .map(|(letter, &next)| {
+            let matched = letter
+                .iter_ones()
+                .map(|i| transitions.selectors[i].0)
+                .collect();
+            (matched, block[next])
+        })
+        .collect();
+    moves.sort_unstable();
+    moves
+}
+
+/// What a node adds to its output given its parent's and previous sibling's state sets:
+/// the states it reaches whatever it matches, and per selector the states matching it
+/// adds. Selectors that add nothing new are dropped.
+fn step_parts(
+    nfa: &NFA,
+    parent: &StateBits,
+    sibling: &StateBits,
+) -> (StateBits, Vec<(SelectorId, StateBits)>) {
+    let mut base = StateBits::new(parent.len());
+    let mut contributions: BTreeMap<usize, StateBits> = BTreeMap::new();
+    let from_parent = nfa
+        .rules
+        .iter()
+        .filter(|Rule(_, prev, _)| prev.is_none_or(|Nfacell(b)| parent.get(b)));
+    let from_sibling = nfa
+        .sibling_rules
+        .iter()
+        .filter(|Rule(_, prev, _)| prev.is_some_and(|Nfacell(b)| sibling.get(b)));
+    for &Rule(selector, _, Nfacell(c)) in from_parent.chain(from_sibling) {
+        match selector {
+            None => base.insert(c),
+            Some(SelectorId(a)) => contributions
+                .entry(a)
+                .or_insert_with(|| StateBits::new(parent.len()))
+                .insert(c),
+        }
+    }
+    let contributions = contributions
+        .into_iter()
+        .filter(|(_, set)| !set.is_subset(&base))
+        .map(|(a, set)| (SelectorId(a), set))
+        .collect();
+    (base, contributions)
+}
@@ -1,20 +1,39 @@
+use dfa::{AutomatonMode, Dfa};
 use lightningcss::{
-    rules::CssRule,
-    selector::{Combinator as LCombinator, Component as LComponent, Selector as LightningSelector},
+    declaration::DeclarationBlock,
+    properties::Property,
+    rules::{CssRule, style::StyleRule},
+    selector::{
+        Combinator as LCombinator, Component as LComponent, PseudoClass, PseudoElement,
+        Selector as LightningSelector,
+    },
     stylesheet::{ParserOptions, PrinterOptions, StyleSheet},
     traits::ToCss,
 };
-use parcel_selectors::attr::AttrSelectorOperator;
+use media::{MediaEnvironment, supports_condition_holds};
+use parcel_selectors::{
+    attr::{AttrSelectorOperator, ParsedAttrSelectorOperation, ParsedCaseSensitivity},
+    parser::{NthSelectorData, NthType},
+};
+use state_bits::StateBits;
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Display,
+    sync::OnceLock,
 };
 
+pub mod codegen;
+pub mod compiled;
+pub mod dfa;
+pub mod media;
+pub mod prune;
 pub mod runtime_shared;
+pub mod state_bits;
 
 // Helpers used by naive implementation
 mod naive_util {
-    use crate::extract_pseudoclasses;
+    use crate::{counts_as_content, extract_pseudoclasses};
     use std::collections::{HashMap, HashSet};
 
     #[derive(Debug)]
@@ -25,6 +44,7 @@ mod naive_util {
         pub classes: HashSet<String>,
         pub html_id: Option<String>,
         pub pseudo_classes: HashSet<String>,
+        pub content: bool,
     }
 
     pub fn basic_node_from_json(json_node: &serde_json::Value) -> BasicNode {
@@ -53,6 +73,7 @@ mod naive_util {
             .map(String::from)
             .collect::<HashSet<_>>();
         let pseudo_classes = extract_pseudoclasses(json_node);
+        let content = counts_as_content(json_node);
 
         BasicNode {
             tag_name,
@@ -61,6 +82,7 @@ mod naive_util {
             classes,
             html_id,
             pseudo_classes,
+            content,
         }
     }
 }
@@ -71,28 +93,235 @@ struct SelectorPart {
     combinator: Combinator,
 }
 
+/// How a compound relates to the next compound of its selector.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum Combinator {
-    Descendant, // Space combinator
-    Child,      // >
-    None,       // The last selector has no combinator
+pub enum Combinator {
+    Descendant,   // Space combinator
+    Child,        // >
+    NextSibling,  // +
+    LaterSibling, // ~
+    None,         // The last selector has no combinator
 }
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// A selector as its compounds, each with the combinator leading to the next one
+/// (`Combinator::None` after the last). This is what `generate_nfa` compiles; the text
+/// form is only used to report matches.
+pub type ComplexSelector = Vec<(Selector, Combinator)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Selector {
     Type(String),
     Class(String),
     Id(String),
-    AttributeEquals { name: String, value: String },
+    // [attr]
+    AttributeExists {
+        name: String,
+    },
+    // Value operators below carry the resolved `i`/`s` flag, see `attribute_case_insensitive`.
+    // [attr=v]
+    AttributeEquals {
+        name: String,
+        value: String,
+        case_insensitive: bool,
+    },
+    // [attr~=v]
+    AttributeIncludes {
+        name: String,
+        value: String,
+        case_insensitive: bool,
+    },
+    // [attr|=v]
+    AttributeDashMatch {
+        name: String,
+        value: String,
+        case_insensitive: bool,
+    },
+    // [attr^=v]
+    AttributePrefix {
+        name: String,
+        value: String,
+        case_insensitive: bool,
+    },
+    // [attr$=v]
+    AttributeSuffix {
+        name: String,
+        value: String,
+        case_insensitive: bool,
+    },
+    // [attr*=v]
+    AttributeSubstring {
+        name: String,
+        value: String,
+        case_insensitive: bool,
+    },
+    Not(Vec<Selector>), // :not(a, b)
+    Nth(NthSelector),   // :nth-child(2n+1)
+    Has(HasSelector),   // :has(> a, > b)
     Compound(CompoundSelector),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+/// `:has()` whose arguments are compounds reached through the same combinator: a child
+/// for `:has(> a)`, any descendant for `:has(a)`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HasSelector {
+    pub child: bool,
+    pub arguments: Vec<Selector>,
+}
+
+/// Which element siblings an `:nth-*` predicate counts, and from which end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NthKind {
+    Child,
+    LastChild,
+    OnlyChild,
+    OfType,
+    LastOfType,
+    OnlyOfType,
+}
+
+impl NthKind {
+    fn is_of_type(self) -> bool {
+        matches!(
+            self,
+            NthKind::OfType | NthKind::LastOfType | NthKind::OnlyOfType
+        )
+    }
+
+    fn is_from_end(self) -> bool {
+        matches!(self, NthKind::LastChild | NthKind::LastOfType)
+    }
+
+    fn is_only(self) -> bool {
+        matches!(self, NthKind::OnlyChild | NthKind::OnlyOfType)
+    }
+}
+
+/// A structural pseudo-class matching positions `a*n + b` (n >= 0); `:first-child` is
+/// `Child` with `a = 0, b = 1`, `:only-child` is `OnlyChild` (a and b unused).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NthSelector {
+    pub kind: NthKind,
+    pub a: i32,
+    pub b: i32,
+}
+
+impl NthSelector {
+    pub fn matches(&self, position: &SiblingPosition) -> bool {
+        let (index, index_from_end) = if self.kind.is_of_type() {
+            (position.type_index, position.type_index_from_end)
+        } else {
+            (position.index, position.index_from_end)
+        };
+        if index == 0 {
+            return false;
+        }
+        if self.kind.is_only() {
+            return index == 1 && index_from_end == 1;
+        }
+        let index = if self.kind.is_from_end() {
+            index_from_end
+        } else {
+            index
+        };
+        let offset = index as i64 - self.b as i64;
+        match self.a as i64 {
+            0 => offset == 0,
+            a => offset % a == 0 && offset / a >= 0,
+        }
+    }
+
+    fn reads(&self) -> PositionReads {
+        PositionReads {
+            from_start: !self.kind.is_from_end(),
+            from_end: self.kind.is_from_end() || self.kind.is_only(),
+        }
+    }
+}
+
+/// 1-based position of an element among its parent's element children, overall and
+/// among those with the same tag. All zero for the root and for non-element nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SiblingPosition {
+    pub index: usize,
+    pub index_from_end: usize,
+    pub type_index: usize,
+    pub type_index_from_end: usize,
+}
+
+/// Positions of a parent's children, given each child's tag id, or `None` for
+/// non-element children.
+pub fn sibling_positions(children: &[Option<SelectorId>]) -> Vec<SiblingPosition> {
+    let mut positions = vec![SiblingPosition::default(); children.len()];
+    let mut seen = 0;
+    let mut seen_of_type: HashMap<SelectorId, usize> = HashMap::new();
+    for (position, tag) in positions.iter_mut().zip(children) {
+        if let Some(tag) = tag {
+            seen += 1;
+            position.index = seen;
+            let of_type = seen_of_type.entry(*tag).or_default();
+            *of_type += 1;
+            position.type_index = *of_type;
+        }
+    }
+    seen = 0;
+    seen_of_type.clear();
+    for (position, tag) in positions.iter_mut().zip(children).rev() {
+        if let Some(tag) = tag {
+            seen += 1;
+            position.index_from_end = seen;
+            let of_type = seen_of_type.entry(*tag).or_default();
+            *of_type += 1;
+            position.type_index_from_end = *of_type;
+        }
+    }
+    positions
+}
+
+/// Which sibling positions a stylesheet's `:nth-*` predicates read. An insertion or
+/// removal only invalidates siblings whose read positions shifted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PositionReads {
+    pub from_start: bool,
+    pub from_end: bool,
+}
+
+impl PositionReads {
+    fn union(self, other: PositionReads) -> PositionReads {
+        PositionReads {
+            from_start: self.from_start || other.from_start,
+            from_end: self.from_end || other.from_end,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.from_start && !self.from_end
+    }
+
+    /// Whether moving from `old` to `new` changes a position some predicate reads.
+    pub fn changed(&self, old: &SiblingPosition, new: &SiblingPosition) -> bool {
+        (self.from_start && (old.index != new.index || old.type_index != new.type_index))
+            || (self.from_end
+                && (old.index_from_end != new.index_from_end
+                    || old.type_index_from_end != new.type_index_from_end))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub struct CompoundSelector {
     pub tag: Option<String>,
     pub id: Option<String>,
     pub classes: BTreeSet<String>,
-    pub attributes: Vec<(String, String)>,
+    /// Attribute predicates; only the `Selector::Attribute*` variants appear here.
+    pub attributes: Vec<Selector>,
     pub pseudos: BTreeSet<String>,
+    /// Sibling-position predicates; only `Selector::Nth` appears here.
+    pub positions: Vec<Selector>,
+    /// Subtree predicates; only `Selector::Has` appears here.
+    pub has: Vec<Selector>,
+    /// Negated predicates; only `Selector::Not` appears here.
+    pub negations: Vec<Selector>,
+    /// Pseudo-element generated by the matched element (`before`, `after`, `marker`).
+    /// Matching ignores it: the compound matches the originating element.
+    pub pseudo_element: Option<String>,
 }
 
 impl CompoundSelector {
@@ -100,6 +329,9 @@ impl CompoundSelector {
         self.id.is_none()
             && self.attributes.is_empty()
             && self.pseudos.is_empty()
+            && self.positions.is_empty()
+            && self.has.is_empty()
+            && self.negations.is_empty()
             && self.classes.len() == 1
             && self.tag.is_none()
     }
@@ -110,6 +342,9 @@ impl CompoundSelector {
             && self.classes.is_empty()
             && self.attributes.is_empty()
             && self.pseudos.is_empty()
+            && self.positions.is_empty()
+            && self.has.is_empty()
+            && self.negations.is_empty()
     }
 
     fn is_simple_id_only(&self) -> bool {
@@ -118,6 +353,9 @@ impl CompoundSelector {
             && self.classes.is_empty()
             && self.attributes.is_empty()
             && self.pseudos.is_empty()
+            && self.positions.is_empty()
+            && self.has.is_empty()
+            && self.negations.is_empty()
     }
 
     fn is_simple_attr_only(&self) -> bool {
@@ -126,6 +364,277 @@ impl CompoundSelector {
             && self.id.is_none()
             && self.classes.is_empty()
             && self.pseudos.is_empty()
+            && self.positions.is_empty()
+            && self.has.is_empty()
+            && self.negations.is_empty()
+    }
+
+    fn is_simple_negation_only(&self) -> bool {
+        self.negations.len() == 1
+            && self.tag.is_none()
+            && self.id.is_none()
+            && self.classes.is_empty()
+            && self.attributes.is_empty()
+            && self.pseudos.is_empty()
+            && self.positions.is_empty()
+            && self.has.is_empty()
+    }
+
+    fn is_simple_position_only(&self) -> bool {
+        self.positions.len() == 1
+            && self.tag.is_none()
+            && self.id.is_none()
+            && self.classes.is_empty()
+            && self.attributes.is_empty()
+            && self.pseudos.is_empty()
+            && self.has.is_empty()
+            && self.negations.is_empty()
+    }
+
+    fn is_simple_has_only(&self) -> bool {
+        self.has.len() == 1
+            && self.tag.is_none()
+            && self.id.is_none()
+            && self.classes.is_empty()
+            && self.attributes.is_empty()
+            && self.pseudos.is_empty()
+            && self.positions.is_empty()
+            && self.negations.is_empty()
+    }
+
+    /// Adds one simple selector (or another compound) to this compound. Returns `false`
+    /// when the two require different tags, ids or pseudo-elements, so that no element
+    /// matches the result.
+    fn push(&mut self, selector: Selector) -> bool {
+        match selector {
+            Selector::Type(tag) => return self.push_tag(tag),
+            Selector::Class(class) => {
+                self.classes.insert(class);
+            }
+            Selector::Id(id) => return self.push_id(id),
+            Selector::Not(_) => self.negations.push(selector),
+            Selector::Nth(_) => self.positions.push(selector),
+            Selector::Has(_) => self.has.push(selector),
+            Selector::Compound(compound) => {
+                if let Some(tag) = compound.tag
+                    && !self.push_tag(tag)
+                {
+                    return false;
+                }
+                if let Some(id) = compound.id
+                    && !self.push_id(id)
+                {
+                    return false;
+                }
+                if let Some(pseudo_element) = compound.pseudo_element {
+                    if self
+                        .pseudo_element
+                        .as_ref()
+                        .is_some_and(|current| *current != pseudo_element)
+                    {
+                        return false;
+                    }
+                    self.pseudo_element = Some(pseudo_element);
+                }
+                self.classes.extend(compound.classes);
+                self.attributes.extend(compound.attributes);
+                self.pseudos.extend(compound.pseudos);
+                self.positions.extend(compound.positions);
+                self.has.extend(compound.has);
+                self.negations.extend(compound.negations);
+            }
+            attribute => self.attributes.push(attribute),
+        }
+        true
+    }
+
+    /// Narrows the tag; `*` leaves a named tag in place.
+    fn push_tag(&mut self, tag: String) -> bool {
+        match &self.tag {
+            Some(current) if tag == "*" || *current == tag => true,
+            Some(current) if current != "*" => false,
+            _ => {
+                self.tag = Some(tag);
+                true
+            }
+        }
+    }
+
+    fn push_id(&mut self, id: String) -> bool {
+        if self.id.as_ref().is_some_and(|current| *current != id) {
+            return false;
+        }
+        self.id = Some(id);
+        true
+    }
+
+    /// Collapses the compound into the narrowest `Selector` variant that represents it.
+    fn into_selector(mut self) -> Selector {
+        self.attributes.sort();
+        self.positions.sort();
+        self.positions.dedup();
+        self.has.sort();
+        self.has.dedup();
+        self.negations.sort();
+        if self.pseudo_element.is_some() {
+            // `.a::before` must stay distinct from `.a`, so it is never collapsed.
+            Selector::Compound(self)
+        } else if self.is_simple_class_only() {
+            Selector::Class(self.classes.into_iter().next().unwrap())
+        } else if self.is_simple_id_only() {
+            Selector::Id(self.id.unwrap())
+        } else if self.is_simple_attr_only() {
+            self.attributes.into_iter().next().unwrap()
+        } else if self.is_simple_negation_only() {
+            self.negations.into_iter().next().unwrap()
+        } else if self.is_simple_position_only() {
+            self.positions.into_iter().next().unwrap()
+        } else if self.is_simple_has_only() {
+            self.has.into_iter().next().unwrap()
+        } else if self.is_simple_tag_only() {
+            Selector::Type(self.tag.unwrap())
+        } else {
+            Selector::Compound(self)
+        }
+    }
+}
+
+impl Selector {
+    /// Sibling positions this selector's `:nth-*` predicates read, including those
+    /// nested in compounds and `:not(...)`.
+    pub fn position_reads(&self) -> PositionReads {
+        match self {
+            Selector::Nth(nth) => nth.reads(),
+            Selector::Not(arguments) | Selector::Has(HasSelector { arguments, .. }) => arguments
+                .iter()
+                .fold(PositionReads::default(), |reads, argument| {
+                    reads.union(argument.position_reads())
+                }),
+            Selector::Compound(compound) => compound
+                .positions
+                .iter()
+                .chain(&compound.has)
+                .chain(&compound.negations)
+                .fold(PositionReads::default(), |reads, selector| {
+                    reads.union(selector.position_reads())
+                }),
+            _ => PositionReads::default(),
+        }
+    }
+
+    /// Appends the `:has()` selectors in this selector, including those nested in
+    /// compounds and `:not(...)`, to `found`.
+    fn collect_has(&self, found: &mut Vec<HasSelector>) {
+        match self {
+            Selector::Has(has) => found.push(has.clone()),
+            Selector::Not(arguments) => {
+                for argument in arguments {
+                    argument.collect_has(found);
+                }
+            }
+            Selector::Compound(compound) => {
+                for selector in compound.has.iter().chain(&compound.negations) {
+                    selector.collect_has(found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns true for the `[attr]` / `[attr op value]` family of selectors.
+    pub fn is_attribute(&self) -> bool {
+        self.attribute_parts().is_some()
+    }
+
+    /// Splits an attribute selector into its name, operator token, expected value and
+    /// whether the value compares ASCII case-insensitively.
+    fn attribute_parts(&self) -> Option<(&str, &'static str, &str, bool)> {
+        let (name, operator, value, case_insensitive) = match self {
+            Selector::AttributeExists { name } => return Some((name, "", "", false)),
+            Selector::AttributeEquals {
+                name,
+                value,
+                case_insensitive,
+            } => (name, "=", value, case_insensitive),
+            Selector::AttributeIncludes {
+                name,
+                value,
+                case_insensitive,
+            } => (name, "~=", value, case_insensitive),
+            Selector::AttributeDashMatch {
+                name,
+                value,
+                case_insensitive,
+            } => (name, "|=", value, case_insensitive),
+            Selector::AttributePrefix {
+                name,
+                value,
+                case_insensitive,
+            } => (name, "^=", value, case_insensitive),
+            Selector::AttributeSuffix {
+                name,
+                value,
+                case_insensitive,
+            } => (name, "$=", value, case_insensitive),
+            Selector::AttributeSubstring {
+                name,
+                value,
+                case_insensitive,
+            } => (name, "*=", value, case_insensitive),
+            Selector::Type(_)
+            | Selector::Class(_)
+            | Selector::Id(_)
+            | Selector::Not(_)
+            | Selector::Nth(_)
+            | Selector::Has(_)
+            | Selector::Compound(_) => return None,
+        };
+        Some((name, operator, value, *case_insensitive))
+    }
+
+    /// Evaluate an attribute selector against a node's attributes (lowercase keys).
+    /// Non-attribute selectors never match here.
+    pub fn matches_attributes(&self, attributes: &HashMap<String, String>) -> bool {
+        let Some((name, _, value, case_insensitive)) = self.attribute_parts() else {
+            return false;
+        };
+        let Some(actual) = attributes.get(name) else {
+            return false;
+        };
+        let (actual, value) = if case_insensitive {
+            (
+                Cow::Owned(actual.to_ascii_lowercase()),
+                Cow::Owned(value.to_ascii_lowercase()),
+            )
+        } else {
+            (Cow::Borrowed(actual.as_str()), Cow::Borrowed(value))
+        };
+        let (actual, value) = (actual.as_ref(), value.as_ref());
+        match self {
+            Selector::AttributeExists { .. } => true,
+            Selector::AttributeEquals { .. } => actual == value,
+            Selector::AttributeIncludes { .. } => {
+                !value.is_empty()
+                    && !value.contains(char::is_whitespace)
+                    && actual.split_whitespace().any(|part| part == value)
+            }
+            Selector::AttributeDashMatch { .. } => {
+                actual == value
+                    || actual
+                        .strip_prefix(value)
+                        .is_some_and(|rest| rest.starts_with('-'))
+            }
+            Selector::AttributePrefix { .. } => !value.is_empty() && actual.starts_with(value),
+            Selector::AttributeSuffix { .. } => !value.is_empty() && actual.ends_with(value),
+            Selector::AttributeSubstring { .. } => !value.is_empty() && actual.contains(value),
+            Selector::Type(_)
+            | Selector::Class(_)
+            | Selector::Id(_)
+            | Selector::Not(_)
+            | Selector::Nth(_)
+            | Selector::Has(_)
+            | Selector::Compound(_) => false,
+        }
     }
 }
 
@@ -135,14 +644,72 @@ impl Display for Selector {
             Selector::Type(tag) => write!(f, "{}", tag),
             Selector::Class(class) => write!(f, ".{}", class),
             Selector::Id(id) => write!(f, "#{}", id),
-            Selector::AttributeEquals { name, value } => {
-                write!(f, "[{}=\"{}\"]", name, value)
+            Selector::AttributeExists { name } => write!(f, "[{}]", name),
+            Selector::AttributeEquals { .. }
+            | Selector::AttributeIncludes { .. }
+            | Selector::AttributeDashMatch { .. }
+            | Selector::AttributePrefix { .. }
+            | Selector::AttributeSuffix { .. }
+            | Selector::AttributeSubstring { .. } => {
+                let (name, operator, value, case_insensitive) = self.attribute_parts().unwrap();
+                write!(f, "[{}{}\"{}\"", name, operator, value)?;
+                // The flag is only written where it differs from the HTML default for `name`.
+                match (case_insensitive, attribute_case_insensitive(name, None)) {
+                    (true, false) => write!(f, " i]"),
+                    (false, true) => write!(f, " s]"),
+                    _ => write!(f, "]"),
+                }
+            }
+            Selector::Not(arguments) => {
+                let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+                write!(f, ":not({})", arguments.join(","))
             }
+            Selector::Nth(nth) => write!(f, "{}", nth),
+            Selector::Has(has) => write!(f, "{}", has),
             Selector::Compound(compound) => write!(f, "{}", compound),
         }
     }
 }
 
+impl Display for HasSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let combinator = if self.child { ">" } else { "" };
+        let arguments: Vec<String> = self
+            .arguments
+            .iter()
+            .map(|argument| format!("{}{}", combinator, argument))
+            .collect();
+        write!(f, ":has({})", arguments.join(","))
+    }
+}
+
+impl Display for NthSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (name, first) = match self.kind {
+            NthKind::OnlyChild => return write!(f, ":only-child"),
+            NthKind::OnlyOfType => return write!(f, ":only-of-type"),
+            NthKind::Child => ("child", ":first-child"),
+            NthKind::LastChild => ("last-child", ":last-child"),
+            NthKind::OfType => ("of-type", ":first-of-type"),
+            NthKind::LastOfType => ("last-of-type", ":last-of-type"),
+        };
+        if self.a == 0 && self.b == 1 {
+            return write!(f, "{}", first);
+        }
+        let step = match self.a {
+            1 => "n".to_string(),
+            -1 => "-n".to_string(),
+            a => format!("{}n", a),
+        };
+        let formula = match (self.a, self.b) {
+            (0, b) => b.to_string(),
+            (_, 0) => step,
+            (_, b) => format!("{}{:+}", step, b),
+        };
+        write!(f, ":nth-{}({})", name, formula)
+    }
+}
+
 impl Display for CompoundSelector {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut text = String::new();
@@ -157,13 +724,26 @@ impl Display for CompoundSelector {
             text.push('.');
             text.push_str(class);
         }
-        for (name, value) in &self.attributes {
-            text.push_str(&format!("[{}=\"{}\"]", name, value));
+        for attribute in &self.attributes {
+            text.push_str(&attribute.to_string());
         }
         for pseudo in &self.pseudos {
             text.push(':');
             text.push_str(pseudo);
         }
+        for position in &self.positions {
+            text.push_str(&position.to_string());
+        }
+        for has in &self.has {
+            text.push_str(&has.to_string());
+        }
+        for negation in &self.negations {
+            text.push_str(&negation.to_string());
+        }
+        if let Some(pseudo_element) = &self.pseudo_element {
+            text.push_str("::");
+            text.push_str(pseudo_element);
+        }
         write!(f, "{}", text)
     }
 }
@@ -178,6 +758,8 @@ impl Display for Combinator {
         match self {
             Combinator::Descendant => write!(f, " "),
             Combinator::Child => write!(f, ">"),
+            Combinator::NextSibling => write!(f, " + "),
+            Combinator::LaterSibling => write!(f, " ~ "),
             Combinator::None => write!(f, ""),
         }
     }
@@ -204,6 +786,14 @@ pub fn rdtsc() -> u64 {
     }
 }
 
+static RULE_CYCLES: OnceLock<bool> = OnceLock::new();
+
+/// Whether engines time rule evaluation with `rdtsc`, from `BIT_RULE_CYCLES`. Off by
+/// default: the timer reads are paid on every recomputed node.
+pub fn rule_cycles_enabled() -> bool {
+    *RULE_CYCLES.get_or_init(|| std::env::var_os("BIT_RULE_CYCLES").is_some())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OState {
     OOne,
@@ -333,13 +923,14 @@ impl LayoutFrame {
     }
 }
 
-/// Parse trace from command.json file
+/// Parse trace from the command.json file of the `WEBSITE_NAME` site
 pub fn parse_trace() -> Vec<LayoutFrame> {
-    let content = std::fs::read_to_string(format!(
-        "css-gen-op/{0}/command.json",
-        std::env::var("WEBSITE_NAME").unwrap()
-    ))
-    .unwrap();
+    parse_site_trace(&std::env::var("WEBSITE_NAME").unwrap())
+}
+
+/// The frames of `website`'s trace, `css-gen-op/<website>/command.json`.
+pub fn parse_site_trace(website: &str) -> Vec<LayoutFrame> {
+    let content = std::fs::read_to_string(format!("css-gen-op/{website}/command.json")).unwrap();
 
     let mut frames = vec![];
     for (frame_id, line) in content.lines().enumerate() {
@@ -376,37 +967,303 @@ pub fn extract_path_from_command(command_data: &serde_json::Value) -> Vec<usize>
         .unwrap()
 }
 
+/// A selector's specificity as (ids, classes/attributes/pseudo-classes, types/pseudo-elements).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Specificity(pub u32, pub u32, pub u32);
+
+impl Specificity {
+    /// Unpacks the `a << 20 | b << 10 | c` encoding lightningcss reports.
+    fn from_packed(packed: u32) -> Self {
+        Specificity(packed >> 20, (packed >> 10) & 0x3ff, packed & 0x3ff)
+    }
+}
+
+impl std::ops::Add for Specificity {
+    type Output = Specificity;
+
+    fn add(self, other: Specificity) -> Specificity {
+        Specificity(self.0 + other.0, self.1 + other.1, self.2 + other.2)
+    }
+}
+
+impl std::ops::Mul<u32> for Specificity {
+    type Output = Specificity;
+
+    fn mul(self, times: u32) -> Specificity {
+        Specificity(self.0 * times, self.1 * times, self.2 * times)
+    }
+}
+
+impl Display for Specificity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({},{},{})", self.0, self.1, self.2)
+    }
+}
+
+/// Where an author selector was written. Ordering origins orders them by the cascade:
+/// specificity first, then source order, so of two matches the greater one wins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SelectorOrigin {
+    pub specificity: Specificity,
+    /// Position of the selector among all selectors of the stylesheet, nested ones
+    /// included, in document order.
+    pub source_index: usize,
+    /// Position of its style rule among all style rules, in document order.
+    pub rule_index: usize,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct ParsedSelectors {
     pub selectors: Vec<String>,
     pub pseudo_selectors: BTreeMap<String, Vec<String>>,
     pub unsupported_selectors: Vec<String>,
+    /// Author selectors that an entry of `selectors` was expanded from by `:is()`/`:where()`.
+    /// Selectors missing here are their own source; see `selector_sources`.
+    pub sources: BTreeMap<String, Vec<String>>,
+    /// The active `@media`/`@supports`/`@layer` blocks a selector was written in, each as
+    /// its chain of preludes (`@media (min-width: 600px) @layer base`). Selectors that
+    /// also appear outside any block are unconditional and have no entry.
+    pub conditions: BTreeMap<String, Vec<String>>,
+    /// Every place an entry of `selectors` was written (or expanded from), sorted.
+    pub origins: BTreeMap<String, Vec<SelectorOrigin>>,
+    /// The typed form of each entry of `selectors`.
+    pub parts: BTreeMap<String, ComplexSelector>,
+    /// The declarations of each style rule, indexed by `SelectorOrigin::rule_index`.
+    pub declarations: Vec<Vec<Declaration>>,
 }
 
-pub fn parse_css(css_content: &str) -> Vec<String> {
-    parse_css_with_pseudo(css_content).selectors
+impl ParsedSelectors {
+    /// The typed forms and origins of `selectors`, which must be entries of `selectors`,
+    /// in order: what `generate_nfa` compiles.
+    pub fn nfa_inputs(
+        &self,
+        selectors: &[String],
+    ) -> (Vec<ComplexSelector>, Vec<Vec<SelectorOrigin>>) {
+        selectors
+            .iter()
+            .map(|selector| {
+                let origins = self.origins.get(selector).cloned().unwrap_or_default();
+                (self.parts[selector].clone(), origins)
+            })
+            .unzip()
+    }
+}
+
+/// The typed form of a selector given as text, e.g. by a test. `None` if it does not
+/// compile; the first alternative if `:is()` expands it.
+pub fn parse_complex_selector(text: &str) -> Option<ComplexSelector> {
+    parse_css_with_pseudo(&format!("{text} {{}}"))
+        .parts
+        .into_values()
+        .next()
+}
+
+/// A declaration of a style rule, with shorthands expanded to their longhands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Declaration {
+    pub property: String,
+    pub value: String,
+    pub important: bool,
+}
+
+/// Each property's winning value among the declarations of the rules `matched` points
+/// at, given in cascade order (see `NFA::cascade_order`). `!important` declarations beat
+/// normal ones; otherwise the later declaration wins.
+pub fn cascade_declarations<'a>(
+    matched: impl IntoIterator<Item = &'a SelectorOrigin>,
+    declarations: &[Vec<Declaration>],
+) -> BTreeMap<String, String> {
+    let mut winners: BTreeMap<&str, &Declaration> = BTreeMap::new();
+    for origin in matched {
+        for declaration in declarations.get(origin.rule_index).into_iter().flatten() {
+            match winners.get(declaration.property.as_str()) {
+                Some(winner) if winner.important && !declaration.important => {}
+                _ => {
+                    winners.insert(&declaration.property, declaration);
+                }
+            }
+        }
+    }
+    winners
+        .into_iter()
+        .map(|(property, declaration)| (property.to_string(), declaration.value.clone()))
+        .collect()
+}
+
+/// Standard properties whose value a child takes from its parent when it does not
+/// declare one itself. Custom properties (`--x`) inherit as well.
+const INHERITED_PROPERTIES: [&str; 42] = [
+    "border-collapse",
+    "border-spacing",
+    "caption-side",
+    "color",
+    "color-scheme",
+    "cursor",
+    "direction",
+    "empty-cells",
+    "font-family",
+    "font-feature-settings",
+    "font-kerning",
+    "font-size",
+    "font-size-adjust",
+    "font-stretch",
+    "font-style",
+    "font-variant",
+    "font-variant-caps",
+    "font-variant-numeric",
+    "font-weight",
+    "hyphens",
+    "letter-spacing",
+    "line-break",
+    "line-height",
+    "list-style-image",
+    "list-style-position",
+    "list-style-type",
+    "orphans",
+    "overflow-wrap",
+    "pointer-events",
+    "quotes",
+    "tab-size",
+    "text-align",
+    "text-align-last",
+    "text-indent",
+    "text-shadow",
+    "text-transform",
+    "visibility",
+    "white-space",
+    "widows",
+    "word-break",
+    "word-spacing",
+    "writing-mode",
+];
+
+pub fn is_inherited_property(property: &str) -> bool {
+    property.starts_with("--") || INHERITED_PROPERTIES.contains(&property)
+}
+
+/// Whether a node whose cascaded values are `own` takes `property` from its parent:
+/// it declares `inherit`, or the property is inherited and it declares no value or
+/// `unset`.
+pub fn inherits_from_parent(own: &BTreeMap<String, String>, property: &str) -> bool {
+    match own.get(property).map(String::as_str) {
+        None | Some("unset") => is_inherited_property(property),
+        Some("inherit") => true,
+        Some(_) => false,
+    }
 }
 
-pub fn drain_supported_pseudo_selectors(
-    pseudo_selectors: &mut BTreeMap<String, Vec<String>>,
-) -> Vec<String> {
-    let keys: Vec<String> = pseudo_selectors
-        .keys()
-        .filter(|name| is_supported_pseudo_class(name))
+/// A node's values after inheritance, from its cascaded values `own` and its parent's
+/// values after inheritance. Inherited properties it does not declare come from
+/// `parent`. Of the CSS-wide keywords, `inherit` takes the parent's value for any
+/// property and `unset` does so for inherited properties; `initial`, and `unset` on
+/// other properties, reset the property, so it has no value and children do not
+/// inherit one.
+pub fn inherit_style(
+    parent: &BTreeMap<String, String>,
+    own: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let mut style: BTreeMap<String, String> = parent
+        .iter()
+        .filter(|(property, _)| is_inherited_property(property))
+        .map(|(property, value)| (property.clone(), value.clone()))
+        .collect();
+    for (property, value) in own {
+        let resolved = match value.as_str() {
+            "inherit" | "unset" if inherits_from_parent(own, property) => {
+                parent.get(property).cloned()
+            }
+            "initial" | "unset" => None,
+            _ => Some(value.clone()),
+        };
+        match resolved {
+            Some(value) => style.insert(property.clone(), value),
+            None => style.remove(property),
+        };
+    }
+    style
+}
+
+/// The properties whose values differ between `old` and `new`.
+pub fn changed_properties(
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+) -> BTreeSet<String> {
+    old.keys()
+        .chain(new.keys())
+        .filter(|property| old.get(*property) != new.get(*property))
         .cloned()
+        .collect()
+}
+
+/// The declarations of `block` in source order, `!important` ones last.
+fn block_declarations(block: &DeclarationBlock) -> Vec<Declaration> {
+    let mut declarations = Vec::new();
+    let normal = block.declarations.iter().map(|property| (property, false));
+    let important = block
+        .important_declarations
+        .iter()
+        .map(|property| (property, true));
+    for (property, important) in normal.chain(important) {
+        push_longhands(property, important, &mut declarations);
+    }
+    declarations
+}
+
+fn push_longhands(property: &Property, important: bool, out: &mut Vec<Declaration>) {
+    let id = property.property_id();
+    // Shorthands whose value we cannot split (e.g. ones using `var()`) stay whole.
+    let longhands: Vec<Property> = id
+        .longhands()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|longhand| property.longhand(longhand))
         .collect();
-    let mut collected = Vec::new();
-    for key in keys {
-        if let Some(mut selectors) = pseudo_selectors.remove(&key) {
-            collected.append(&mut selectors);
+    if !longhands.is_empty() {
+        for longhand in &longhands {
+            push_longhands(longhand, important, out);
         }
+        return;
+    }
+    out.push(Declaration {
+        property: id
+            .to_css_string(PrinterOptions::default())
+            .unwrap_or_default(),
+        value: property
+            .value_to_css_string(PrinterOptions::default())
+            .unwrap_or_default(),
+        important,
+    });
+}
+
+/// The author selector texts whose matches `selector` reports under.
+pub fn selector_sources<'a>(
+    sources: &'a BTreeMap<String, Vec<String>>,
+    selector: &'a str,
+) -> Vec<&'a str> {
+    match sources.get(selector) {
+        Some(origins) => origins.iter().map(String::as_str).collect(),
+        None => vec![selector],
     }
-    collected
+}
+
+pub fn parse_css(css_content: &str) -> Vec<String> {
+    parse_css_with_pseudo(css_content).selectors
 }
 
 pub fn parse_css_with_pseudo(css_content: &str) -> ParsedSelectors {
-    let mut parser_options = ParserOptions::default();
-    parser_options.error_recovery = true;
+    parse_css_in_environment(css_content, &MediaEnvironment::from_env())
+}
+
+/// Like `parse_css_with_pseudo`, keeping the rules of the `@media` blocks that match
+/// `environment` and of the `@supports` blocks whose condition holds.
+pub fn parse_css_in_environment(
+    css_content: &str,
+    environment: &MediaEnvironment,
+) -> ParsedSelectors {
+    let parser_options = ParserOptions {
+        error_recovery: true,
+        ..ParserOptions::default()
+    };
 
     let stylesheet = match StyleSheet::parse(css_content, parser_options) {
         Ok(sheet) => sheet,
@@ -416,27 +1273,86 @@ pub fn parse_css_with_pseudo(css_content: &str) -> ParsedSelectors {
     let mut selectors = Vec::new();
     let mut pseudo_selectors: BTreeMap<String, Vec<String>> = BTreeMap::new();
     let mut unsupported_selectors = Vec::new();
+    let mut sources: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut verbatim: HashSet<String> = HashSet::new();
+    let mut conditions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut unconditional: HashSet<String> = HashSet::new();
 
-    for rule in stylesheet.rules.0 {
-        if let CssRule::Style(style_rule) = rule {
-            for selector in style_rule.selectors.0 {
-                match lightning_selector_to_rule_string(&selector) {
-                    SelectorConversionResult::Keep(s) => selectors.push(s),
-                    SelectorConversionResult::RecordPseudo { selector, pseudos } => {
-                        for pseudo in pseudos {
-                            pseudo_selectors
-                                .entry(pseudo)
-                                .or_default()
-                                .push(selector.clone());
-                        }
-                    }
-                    SelectorConversionResult::Skip => {
-                        unsupported_selectors.push(selector_to_string(&selector));
-                    }
+    let mut origins: BTreeMap<String, Vec<SelectorOrigin>> = BTreeMap::new();
+    let mut parts: BTreeMap<String, ComplexSelector> = BTreeMap::new();
+
+    let mut converted = ConvertedStylesheet::default();
+    convert_rules(
+        &stylesheet.rules.0,
+        environment,
+        &mut Vec::new(),
+        None,
+        &mut converted,
+    );
+    for ConvertedSelector {
+        conversion,
+        text,
+        condition,
+        origin,
+    } in converted.selectors.drain(..)
+    {
+        let compiled = matches!(
+            conversion,
+            SelectorConversionResult::Keep(..) | SelectorConversionResult::Expand { .. }
+        );
+        let produced = match conversion {
+            SelectorConversionResult::Keep(s, complex) => {
+                verbatim.insert(s.clone());
+                selectors.push(s.clone());
+                parts.entry(s.clone()).or_insert(complex);
+                vec![s]
+            }
+            SelectorConversionResult::Expand {
+                selectors: expanded,
+                source,
+            } => {
+                let mut produced = Vec::with_capacity(expanded.len());
+                for (s, complex) in expanded {
+                    sources.entry(s.clone()).or_default().push(source.clone());
+                    selectors.push(s.clone());
+                    parts.entry(s.clone()).or_insert(complex);
+                    produced.push(s);
+                }
+                produced
+            }
+            SelectorConversionResult::RecordPseudo { selector, pseudos } => {
+                for pseudo in pseudos {
+                    pseudo_selectors
+                        .entry(pseudo)
+                        .or_default()
+                        .push(selector.clone());
+                }
+                vec![selector]
+            }
+            SelectorConversionResult::Skip => {
+                unsupported_selectors.push(text.clone());
+                vec![text]
+            }
+        };
+        if compiled {
+            for s in &produced {
+                origins.entry(s.clone()).or_default().push(origin);
+            }
+        }
+        match &condition {
+            Some(condition) => {
+                for s in produced {
+                    conditions.entry(s).or_default().push(condition.clone());
                 }
             }
+            None => unconditional.extend(produced),
         }
     }
+    conditions.retain(|selector, _| !unconditional.contains(selector));
+    for blocks in conditions.values_mut() {
+        blocks.sort();
+        blocks.dedup();
+    }
 
     selectors.sort();
     selectors.dedup();
@@ -447,49 +1363,188 @@ pub fn parse_css_with_pseudo(css_content: &str) -> ParsedSelectors {
     unsupported_selectors.sort();
     unsupported_selectors.dedup();
 
-    let mut supported_with_pseudo = Vec::new();
-    pseudo_selectors.retain(|pseudo, sels| {
-        if is_supported_pseudo_class(pseudo) {
-            supported_with_pseudo.extend(sels.iter().cloned());
-            false
-        } else {
-            true
+    // A selector is recorded under every pseudo-class it uses; the supported ones are not
+    // what kept it from compiling.
+    pseudo_selectors.retain(|pseudo, _| !is_supported_pseudo_class(pseudo));
+
+    // A selector written out verbatim as well as produced by an expansion reports under both.
+    for (selector, origins) in sources.iter_mut() {
+        if verbatim.contains(selector) {
+            origins.push(selector.clone());
         }
-    });
-    selectors.extend(supported_with_pseudo);
-    selectors.sort();
-    selectors.dedup();
+        origins.sort();
+        origins.dedup();
+    }
+    for origins in origins.values_mut() {
+        origins.sort();
+    }
 
     ParsedSelectors {
         selectors,
         pseudo_selectors,
         unsupported_selectors,
+        sources,
+        conditions,
+        origins,
+        parts,
+        declarations: converted.declarations,
     }
 }
 
-/// Returns true if the selector is a single "simple" selector without combinators
-/// (e.g. `a`, `.button`, `#header`). Those selectors consist only of alphanumeric
-/// characters or the symbols `-`, `_`, `.`, and `#`.
-pub fn is_simple_selector(selector: &str) -> bool {
-    let trimmed = selector.trim();
-    if trimmed.is_empty() {
-        return false;
-    }
-    if trimmed.starts_with('@') || trimmed.contains(',') {
-        return false;
-    }
+/// A selector of a style rule, converted, and where it was written.
+struct ConvertedSelector {
+    conversion: SelectorConversionResult,
+    /// The selector's text, `&` resolved.
+    text: String,
+    /// The chain of block preludes it sits in, if any.
+    condition: Option<String>,
+    origin: SelectorOrigin,
+}
 
-    trimmed
-        .chars()
-        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '#'))
+/// The selectors converted so far and the declarations of the style rules they came from.
+#[derive(Default)]
+struct ConvertedStylesheet {
+    selectors: Vec<ConvertedSelector>,
+    declarations: Vec<Vec<Declaration>>,
 }
 
-/// Splits selectors into the ones we keep and the simple ones we skip.
-pub fn partition_simple_selectors(selectors: Vec<String>) -> (Vec<String>, Vec<String>) {
-    let mut considered = Vec::new();
-    let mut skipped = Vec::new();
+/// The selectors of an enclosing style rule and the specificity `&` contributes: the
+/// greatest among them.
+type NestingContext<'a> = (&'a [NestingParent], Specificity);
 
-    for selector in selectors {
+/// Converts the selectors of the style rules in `rules`, descending into nested style
+/// rules and into the conditional group rules that apply in `environment`. `chain` is the
+/// chain of block preludes `rules` sit in and `parents` the enclosing style rule, if any.
+fn convert_rules(
+    rules: &[CssRule],
+    environment: &MediaEnvironment,
+    chain: &mut Vec<String>,
+    parents: Option<NestingContext>,
+    out: &mut ConvertedStylesheet,
+) {
+    for rule in rules {
+        let (prelude, nested) = match rule {
+            CssRule::Style(style_rule) => {
+                convert_style_rule(style_rule, environment, chain, parents, out);
+                continue;
+            }
+            CssRule::Nesting(nesting) => {
+                convert_style_rule(&nesting.style, environment, chain, parents, out);
+                continue;
+            }
+            CssRule::Media(media) if environment.matches(&media.query) => {
+                let query = media
+                    .query
+                    .to_css_string(PrinterOptions::default())
+                    .unwrap_or_default();
+                (format!("@media {query}"), &media.rules.0)
+            }
+            CssRule::Supports(supports) if supports_condition_holds(&supports.condition) => {
+                let condition = supports
+                    .condition
+                    .to_css_string(PrinterOptions::default())
+                    .unwrap_or_default();
+                (format!("@supports {condition}"), &supports.rules.0)
+            }
+            CssRule::LayerBlock(layer) => {
+                let prelude = match &layer.name {
+                    Some(name) => format!(
+                        "@layer {}",
+                        name.to_css_string(PrinterOptions::default())
+                            .unwrap_or_default()
+                    ),
+                    None => "@layer".to_string(),
+                };
+                (prelude, &layer.rules.0)
+            }
+            _ => continue,
+        };
+        chain.push(prelude);
+        convert_rules(nested, environment, chain, parents, out);
+        chain.pop();
+    }
+}
+
+fn convert_style_rule(
+    style_rule: &StyleRule,
+    environment: &MediaEnvironment,
+    chain: &mut Vec<String>,
+    parents: Option<NestingContext>,
+    out: &mut ConvertedStylesheet,
+) {
+    let condition = (!chain.is_empty()).then(|| chain.join(" "));
+    let rule_index = out.declarations.len();
+    out.declarations
+        .push(block_declarations(&style_rule.declarations));
+    let mut nested_parents = Vec::new();
+    let mut nested_specificity = Specificity::default();
+    for selector in &style_rule.selectors.0 {
+        let resolved: Vec<_> = match parents {
+            None => vec![lightning_selector_to_rule_string(selector, None)],
+            Some((parents, _)) => parents
+                .iter()
+                .map(|parent| lightning_selector_to_rule_string(selector, Some(parent)))
+                .collect(),
+        };
+        // `&` counts as the most specific of the parent selectors, like `:is()`.
+        let mut specificity = Specificity::from_packed(selector.specificity());
+        if let Some((_, parent_specificity)) = parents {
+            let nestings = selector
+                .iter_raw_parse_order_from(0)
+                .filter(|component| matches!(component, LComponent::Nesting))
+                .count() as u32;
+            specificity = specificity + parent_specificity * nestings;
+        }
+        nested_specificity = nested_specificity.max(specificity);
+        for (conversion, parent) in resolved {
+            let origin = SelectorOrigin {
+                specificity,
+                source_index: out.selectors.len(),
+                rule_index,
+            };
+            out.selectors.push(ConvertedSelector {
+                conversion,
+                text: parent.text.clone(),
+                condition: condition.clone(),
+                origin,
+            });
+            nested_parents.push(parent);
+        }
+    }
+    if !style_rule.rules.0.is_empty() {
+        convert_rules(
+            &style_rule.rules.0,
+            environment,
+            chain,
+            Some((&nested_parents, nested_specificity)),
+            out,
+        );
+    }
+}
+
+/// Returns true if the selector is a single "simple" selector without combinators
+/// (e.g. `a`, `.button`, `#header`). Those selectors consist only of alphanumeric
+/// characters or the symbols `-`, `_`, `.`, and `#`.
+pub fn is_simple_selector(selector: &str) -> bool {
+    let trimmed = selector.trim();
+    if trimmed.is_empty() {
+        return false;
+    }
+    if trimmed.starts_with('@') || trimmed.contains(',') {
+        return false;
+    }
+
+    trimmed
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '#'))
+}
+
+/// Splits selectors into the ones we keep and the simple ones we skip.
+pub fn partition_simple_selectors(selectors: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let mut considered = Vec::new();
+    let mut skipped = Vec::new();
+
+    for selector in selectors {
         if is_simple_selector(&selector) {
             skipped.push(selector);
         } else {
@@ -551,10 +1606,154 @@ pub fn report_unsupported_selectors(label: &str, selectors: &[String]) {
     }
 }
 
+/// Prints each node's computed style when `BIT_STYLES` is set, so the engines can be
+/// diffed on it like on their matches. Nodes without any value are left out.
+pub fn report_computed_styles(styles: impl IntoIterator<Item = (u64, BTreeMap<String, String>)>) {
+    if std::env::var_os("BIT_STYLES").is_none() {
+        return;
+    }
+    let mut styles: Vec<_> = styles
+        .into_iter()
+        .filter(|(_, style)| !style.is_empty())
+        .collect();
+    styles.sort_by_key(|&(node_id, _)| node_id);
+    println!("COMPUTED STYLES");
+    for (node_id, style) in styles {
+        let values: Vec<String> = style
+            .iter()
+            .map(|(property, value)| format!("{property}: {value}"))
+            .collect();
+        println!("{} -> {}", node_id, values.join("; "));
+    }
+}
+
+/// Prints the NFA's state count next to the count without prefix sharing.
+pub fn report_nfa_states(label: &str, nfa: &NFA, selectors: &[ComplexSelector]) {
+    let shared = nfa.max_state_id.0;
+    let unshared = unshared_state_count(selectors);
+    let saved = unshared.saturating_sub(shared);
+    let percent = if unshared == 0 {
+        0.0
+    } else {
+        saved as f64 * 100.0 / unshared as f64
+    };
+    println!(
+        "NFA_STATES[{label}] {shared} state(s), {unshared} without prefix sharing ({percent:.1}% fewer)"
+    );
+}
+
+/// Reports the heap bytes an engine's per-node state vectors take as bitsets, next to
+/// what they took with one `vector_bytes_per_state`-byte element per state.
+pub fn report_state_bytes(
+    label: &str,
+    nodes: usize,
+    states: usize,
+    bitset_bytes: usize,
+    vector_bytes_per_state: usize,
+) {
+    let vector_bytes = nodes * states * vector_bytes_per_state;
+    println!(
+        "STATE_BYTES[{label}] {nodes} node(s), {states} state(s): {bitset_bytes} byte(s) as bitsets, {vector_bytes} as vectors"
+    );
+}
+
+/// Attaches the DFA `NFA_AUTOMATON` asks for and prints which automaton the engine runs
+/// on. The DFA is determinized as nodes step through it, up to `DFA_STATE_LIMIT` states;
+/// past that, steps fall back to the NFA rules.
+pub fn attach_automaton(label: &str, nfa: &mut NFA) {
+    let mode = AutomatonMode::from_env();
+    if mode == AutomatonMode::Nfa {
+        println!("AUTOMATON[{label}] nfa");
+        return;
+    }
+    let limit = dfa::state_limit_from_env();
+    println!("AUTOMATON[{label}] dfa with at most {limit} state(s)");
+    nfa.dfa = Some(Dfa::new(nfa, limit));
+}
+
+/// Prints how much of the DFA the run determinized and how many of those states
+/// minimization leaves.
+pub fn report_automaton(label: &str, nfa: &NFA) {
+    let Some(dfa) = &nfa.dfa else {
+        return;
+    };
+    println!(
+        "AUTOMATON[{label}] dfa explored {} state(s), {} transition(s); {} step(s) fell back to the nfa",
+        dfa.state_count(),
+        dfa.transition_count(),
+        dfa.fallback_count()
+    );
+    println!(
+        "AUTOMATON[{label}] explored states minimize to {} state(s)",
+        dfa.minimized_state_count(nfa)
+    );
+}
+
+/// Drops the NFA's rule index when `RULE_INDEX=off`, so engines scan every rule, and
+/// prints which way the engine evaluates rules.
+pub fn configure_rule_index(label: &str, nfa: &mut NFA) {
+    if std::env::var("RULE_INDEX").as_deref() == Ok("off") {
+        nfa.rule_index = None;
+    }
+    match &nfa.rule_index {
+        Some(index) => println!(
+            "RULE_INDEX[{label}] on: {} bucket(s), {} of {} rule(s) always evaluated",
+            index.rules.bucket_count() + index.sibling_rules.bucket_count(),
+            index.rules.always.len() + index.sibling_rules.always.len(),
+            nfa.rules.len() + nfa.sibling_rules.len()
+        ),
+        None => println!("RULE_INDEX[{label}] off"),
+    }
+}
+
+/// Prunes the rules and states `frames` can never fire when `NFA_PRUNE` is set (see
+/// `prune::prune`), and prints how much of the stylesheet is dead.
+pub fn prune_dead_states(
+    label: &str,
+    nfa: &mut NFA,
+    sm: &mut SelectorManager,
+    frames: &[LayoutFrame],
+) {
+    if std::env::var_os("NFA_PRUNE").is_none_or(|value| value == "off") {
+        println!("PRUNE[{label}] off");
+        return;
+    }
+    let vocabulary = prune::Vocabulary::from_trace(frames);
+    let report = prune::prune(nfa, sm, &vocabulary);
+    println!("PRUNE[{label}] {report}");
+}
+
 fn is_supported_pseudo_class(name: &str) -> bool {
     matches!(
         normalize_pseudo_name(name),
-        "hover" | "focus" | "focus-within"
+        "hover"
+            | "focus"
+            | "focus-within"
+            | "checked"
+            | "disabled"
+            | "enabled"
+            | "link"
+            | "visited"
+    )
+}
+
+/// The tree-structural pseudo-classes: `:root` and `:empty`, tracked in the computed
+/// pseudo-class set, and the `:nth-*` family compiled as sibling-position predicates.
+fn is_structural_pseudo_class(name: &str) -> bool {
+    matches!(
+        normalize_pseudo_name(name),
+        "root"
+            | "empty"
+            | "first-child"
+            | "last-child"
+            | "only-child"
+            | "nth-child"
+            | "nth-last-child"
+            | "first-of-type"
+            | "last-of-type"
+            | "only-of-type"
+            | "nth-of-type"
+            | "nth-last-of-type"
     )
 }
 
@@ -562,6 +1761,8 @@ fn normalize_pseudo_name(name: &str) -> &str {
     name.trim_start_matches(':')
 }
 
+// Short-lived; boxing the common `Keep` case is not worth it.
+#[allow(clippy::large_enum_variant)]
 enum ComponentConversion {
     Keep(Selector),
     Skip,
@@ -569,7 +1770,12 @@ enum ComponentConversion {
 }
 
 enum SelectorConversionResult {
-    Keep(String),
+    Keep(String, ComplexSelector),
+    /// `:is()`/`:where()` expanded into several selectors that together match like `source`.
+    Expand {
+        selectors: Vec<(String, ComplexSelector)>,
+        source: String,
+    },
     RecordPseudo {
         selector: String,
         pseudos: Vec<String>,
@@ -583,9 +1789,43 @@ fn selector_to_string(selector: &LightningSelector) -> String {
         .unwrap_or_else(|_| format!("{:?}", selector))
 }
 
-fn record_pseudo_selector(selector: &LightningSelector) -> SelectorConversionResult {
-    let selector_string = selector_to_string(selector);
+fn record_pseudo_selector(
+    selector: &LightningSelector,
+    selector_string: String,
+) -> SelectorConversionResult {
     let mut pseudos = extract_pseudo_tokens(&selector_string);
+    let negations_supported = selector.iter_raw_parse_order_from(0).all(|component| {
+        !matches!(component, LComponent::Negation(arguments) if convert_negation(arguments).is_none())
+    });
+    if negations_supported {
+        pseudos.retain(|pseudo| pseudo != ":not");
+    }
+    let has_supported = selector.iter_raw_parse_order_from(0).all(|component| {
+        !matches!(component, LComponent::Has(arguments) if convert_has(arguments).is_none())
+    });
+    if has_supported {
+        pseudos.retain(|pseudo| pseudo != ":has");
+    }
+    let positions_supported = selector.iter_raw_parse_order_from(0).all(|component| {
+        !matches!(component, LComponent::NthOf(_))
+            && !matches!(component, LComponent::Nth(data) if nth_selector(data).is_none())
+    });
+    if positions_supported {
+        pseudos.retain(|pseudo| !is_structural_pseudo_class(pseudo));
+    }
+    let mut components = selector.iter_raw_parse_order_from(0);
+    let pseudo_elements_supported = !components.by_ref().any(|component| {
+        matches!(
+            component,
+            LComponent::Combinator(LCombinator::PseudoElement)
+        )
+    }) || components.all(|component| {
+        matches!(component, LComponent::PseudoElement(element)
+                if originating_pseudo_element(element).is_some())
+    });
+    if pseudo_elements_supported {
+        pseudos.retain(|pseudo| !is_originating_pseudo_element(pseudo));
+    }
     if pseudos.is_empty() {
         pseudos.push("<pseudo>".to_string());
     }
@@ -595,83 +1835,516 @@ fn record_pseudo_selector(selector: &LightningSelector) -> SelectorConversionRes
     }
 }
 
-fn lightning_selector_to_rule_string(selector: &LightningSelector) -> SelectorConversionResult {
-    let mut selector_parts: Vec<SelectorPart> = Vec::new();
-    let mut current_selector: Option<Selector> = None;
+/// A selector of an enclosing style rule, as `&` in its nested rules sees it.
+struct NestingParent {
+    /// The selector's text with its own `&` resolved.
+    text: String,
+    /// Its converted alternatives, or the pseudo-classes it was recorded under when it
+    /// could not be converted (none if it is unsupported outright).
+    alternatives: Result<Vec<Vec<SelectorPart>>, Vec<String>>,
+}
+
+/// Converts `selector`, resolving `&` to `parent`. Also returns what `&` stands for in
+/// rules nested under this selector.
+fn lightning_selector_to_rule_string(
+    selector: &LightningSelector,
+    parent: Option<&NestingParent>,
+) -> (SelectorConversionResult, NestingParent) {
+    let text = match parent {
+        Some(parent) => resolve_nesting(&selector_to_string(selector), &parent.text),
+        None => selector_to_string(selector),
+    };
+    let nesting = match parent.map(|parent| &parent.alternatives) {
+        None => None,
+        Some(Ok(alternatives)) => Some(alternatives.as_slice()),
+        // Nested under a selector we cannot compile: reported the same way.
+        Some(Err(pseudos)) => {
+            let conversion = if pseudos.is_empty() {
+                SelectorConversionResult::Skip
+            } else {
+                SelectorConversionResult::RecordPseudo {
+                    selector: text.clone(),
+                    pseudos: pseudos.clone(),
+                }
+            };
+            let alternatives = Err(pseudos.clone());
+            return (conversion, NestingParent { text, alternatives });
+        }
+    };
+    let alternatives = match convert_selector(selector, nesting) {
+        Ok(alternatives) => alternatives,
+        Err(ConversionFailure::Pseudo) => {
+            let conversion = record_pseudo_selector(selector, text.clone());
+            let pseudos = match &conversion {
+                SelectorConversionResult::RecordPseudo { pseudos, .. } => pseudos.clone(),
+                _ => Vec::new(),
+            };
+            return (
+                conversion,
+                NestingParent {
+                    text,
+                    alternatives: Err(pseudos),
+                },
+            );
+        }
+        Err(ConversionFailure::Unsupported) => {
+            let alternatives = Err(Vec::new());
+            return (
+                SelectorConversionResult::Skip,
+                NestingParent { text, alternatives },
+            );
+        }
+    };
+    let mut expansions: Vec<(String, ComplexSelector)> = alternatives
+        .iter()
+        .map(|parts| {
+            let text = parts.iter().map(|part| part.to_string()).collect();
+            let complex = parts
+                .iter()
+                .map(|part| (part.selector.clone(), part.combinator.clone()))
+                .collect();
+            (text, complex)
+        })
+        .collect();
+    let expanded = expansions.len() > 1
+        || selector
+            .iter_raw_parse_order_from(0)
+            .any(|component| matches!(component, LComponent::Is(_) | LComponent::Where(_)));
+    let conversion = if expansions.is_empty() {
+        SelectorConversionResult::Skip
+    } else if expanded {
+        expansions.sort_by(|a, b| a.0.cmp(&b.0));
+        expansions.dedup_by(|a, b| a.0 == b.0);
+        SelectorConversionResult::Expand {
+            selectors: expansions,
+            source: text.clone(),
+        }
+    } else {
+        let (text, complex) = expansions.remove(0);
+        SelectorConversionResult::Keep(text, complex)
+    };
+    (
+        conversion,
+        NestingParent {
+            text,
+            alternatives: Ok(alternatives),
+        },
+    )
+}
+
+/// Replaces each `&` in `selector` (outside strings and attribute selectors) with
+/// `parent`, wrapped in `:is()` unless it is a single compound or opens the selector.
+fn resolve_nesting(selector: &str, parent: &str) -> String {
+    let compound = [' ', '>', '+', '~', ',']
+        .iter()
+        .all(|separator| split_top_level(parent.trim(), *separator).len() == 1);
+    let mut resolved = String::with_capacity(selector.len() + parent.len());
+    let mut quote: Option<char> = None;
+    let mut brackets = 0usize;
+    for (idx, ch) in selector.char_indices() {
+        match ch {
+            '"' | '\'' if quote == Some(ch) => quote = None,
+            '"' | '\'' if quote.is_none() => quote = Some(ch),
+            '[' if quote.is_none() => brackets += 1,
+            ']' if quote.is_none() => brackets = brackets.saturating_sub(1),
+            '&' if quote.is_none() && brackets == 0 => {
+                if compound || idx == 0 {
+                    resolved.push_str(parent);
+                } else {
+                    resolved.push_str(&format!(":is({parent})"));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        resolved.push(ch);
+    }
+    resolved
+}
+
+/// Upper bound on the selectors one source selector may expand into through
+/// `:is()`/`:where()`; larger products are reported as unsupported pseudo selectors.
+const MAX_SELECTOR_EXPANSIONS: usize = 64;
+
+enum ConversionFailure {
+    /// Uses a pseudo-class or pseudo-element we cannot compile yet.
+    Pseudo,
+    /// Uses namespaces or other syntax outside the supported subset.
+    Unsupported,
+}
+
+/// One alternative of a selector under conversion: the finished compounds and the one
+/// being read. Raw parse order does not keep a compound's components in source order,
+/// so they are collected into a `CompoundSelector` first.
+#[derive(Debug, Clone, Default)]
+struct PartialSelector {
+    parts: Vec<SelectorPart>,
+    current: Option<CompoundSelector>,
+}
+
+impl PartialSelector {
+    fn close_compound(&mut self, combinator: &Combinator) {
+        if !matches!(combinator, Combinator::None)
+            && let Some(compound) = self.current.take()
+        {
+            self.parts.push(SelectorPart {
+                selector: compound.into_selector(),
+                combinator: combinator.clone(),
+            });
+        }
+    }
+
+    fn finish(mut self) -> Vec<SelectorPart> {
+        if let Some(compound) = self.current {
+            self.parts.push(SelectorPart {
+                selector: compound.into_selector(),
+                combinator: Combinator::None,
+            });
+        }
+        self.parts
+    }
+}
+
+/// Converts a selector into one or more equivalent lists of selector parts; `:is()` and
+/// `:where()` contribute one alternative per argument, and `&` one per alternative of
+/// `nesting`, the enclosing rule's selector.
+fn convert_selector(
+    selector: &LightningSelector,
+    nesting: Option<&[Vec<SelectorPart>]>,
+) -> Result<Vec<Vec<SelectorPart>>, ConversionFailure> {
+    let mut alternatives = vec![PartialSelector::default()];
     let mut pending_combinator = Combinator::None;
+    // Set by the pseudo-element combinator. What follows it belongs to the pseudo-element
+    // (`::after:hover`), and only the pseudo-element itself is supported there.
+    let mut in_pseudo_element = false;
 
     for component in selector.iter_raw_parse_order_from(0) {
+        // Every alternative has read the same compounds so far; none are left when each
+        // required conflicting tags or ids, and the selector matches nothing.
+        let Some(first) = alternatives.first() else {
+            return Ok(Vec::new());
+        };
+        let has_current = first.current.is_some();
+        if in_pseudo_element && !matches!(component, LComponent::PseudoElement(_)) {
+            return Err(ConversionFailure::Pseudo);
+        }
         match component {
             LComponent::Combinator(combinator) => match combinator {
                 LCombinator::Descendant => {
-                    if current_selector.is_some() && matches!(pending_combinator, Combinator::None)
-                    {
+                    if has_current && matches!(pending_combinator, Combinator::None) {
                         pending_combinator = Combinator::Descendant;
                     }
                 }
                 LCombinator::Child => {
-                    if current_selector.is_some() {
+                    if has_current {
                         pending_combinator = Combinator::Child;
                     }
                 }
-                LCombinator::PseudoElement => {
-                    return record_pseudo_selector(selector);
+                LCombinator::NextSibling => {
+                    if has_current {
+                        pending_combinator = Combinator::NextSibling;
+                    }
+                }
+                LCombinator::LaterSibling => {
+                    if has_current {
+                        pending_combinator = Combinator::LaterSibling;
+                    }
                 }
-                _ => break,
+                // The pseudo-element joins the current compound, see below.
+                LCombinator::PseudoElement => in_pseudo_element = true,
+                // Shadow-tree combinators (`::slotted()`, `::part()`) and the column
+                // combinator have no counterpart in the engines.
+                _ => return Err(ConversionFailure::Unsupported),
             },
-            LComponent::Negation(_)
-            | LComponent::Root
-            | LComponent::Empty
-            | LComponent::Scope
-            | LComponent::Nth(_)
+            LComponent::Negation(arguments) => {
+                let negation = convert_negation(arguments).ok_or(ConversionFailure::Pseudo)?;
+                push_component(&mut alternatives, &mut pending_combinator, negation);
+            }
+            LComponent::Is(arguments) | LComponent::Where(arguments) => {
+                let mut expanded = Vec::new();
+                for argument in arguments.iter() {
+                    expanded.extend(convert_selector(argument, nesting)?);
+                }
+                alternatives = expand_alternatives(
+                    std::mem::take(&mut alternatives),
+                    &mut pending_combinator,
+                    &expanded,
+                )?;
+            }
+            // `&` matches like `:is()` over the enclosing rule's selector.
+            LComponent::Nesting => {
+                let parent = nesting.ok_or(ConversionFailure::Unsupported)?;
+                alternatives = expand_alternatives(
+                    std::mem::take(&mut alternatives),
+                    &mut pending_combinator,
+                    parent,
+                )?;
+            }
+            LComponent::Nth(data) => {
+                let nth = nth_selector(data).ok_or(ConversionFailure::Pseudo)?;
+                push_component(&mut alternatives, &mut pending_combinator, nth);
+            }
+            LComponent::Root => {
+                push_component(
+                    &mut alternatives,
+                    &mut pending_combinator,
+                    pseudo_compound(PSEUDO_CLASS_ROOT),
+                );
+            }
+            LComponent::Empty => {
+                push_component(
+                    &mut alternatives,
+                    &mut pending_combinator,
+                    pseudo_compound(PSEUDO_CLASS_EMPTY),
+                );
+            }
+            LComponent::Has(arguments) => {
+                let has = convert_has(arguments).ok_or(ConversionFailure::Pseudo)?;
+                push_component(&mut alternatives, &mut pending_combinator, has);
+            }
+            LComponent::PseudoElement(element) => {
+                let name = originating_pseudo_element(element).ok_or(ConversionFailure::Pseudo)?;
+                let compound = CompoundSelector {
+                    pseudo_element: Some(name.to_string()),
+                    ..Default::default()
+                };
+                push_component(
+                    &mut alternatives,
+                    &mut pending_combinator,
+                    Selector::Compound(compound),
+                );
+            }
+            LComponent::Scope
             | LComponent::NthOf(_)
-            | LComponent::NonTSPseudoClass(_)
             | LComponent::Slotted(_)
             | LComponent::Part(_)
             | LComponent::Host(_)
-            | LComponent::Where(_)
-            | LComponent::Is(_)
-            | LComponent::Any(_, _)
-            | LComponent::Has(_)
-            | LComponent::PseudoElement(_) => {
-                return record_pseudo_selector(selector);
+            | LComponent::Any(_, _) => {
+                return Err(ConversionFailure::Pseudo);
+            }
+            LComponent::NonTSPseudoClass(pseudo_class) => {
+                let pseudo =
+                    supported_pseudo_class(pseudo_class).ok_or(ConversionFailure::Pseudo)?;
+                push_component(&mut alternatives, &mut pending_combinator, pseudo);
             }
             _ => match convert_component(component) {
                 ComponentConversion::Keep(selector) => {
-                    if let Some(prev_selector) = current_selector.take() {
-                        selector_parts.push(SelectorPart {
-                            selector: prev_selector,
-                            combinator: pending_combinator.clone(),
-                        });
-                        pending_combinator = Combinator::None;
-                    }
-                    current_selector = Some(selector);
+                    push_component(&mut alternatives, &mut pending_combinator, selector)
                 }
                 ComponentConversion::Skip => {}
                 ComponentConversion::Abort => {
-                    return SelectorConversionResult::Skip;
+                    return Err(ConversionFailure::Unsupported);
                 }
             },
         }
     }
 
-    if let Some(selector) = current_selector {
-        selector_parts.push(SelectorPart {
-            selector,
-            combinator: Combinator::None,
-        });
+    Ok(alternatives
+        .into_iter()
+        .map(PartialSelector::finish)
+        .filter(|parts| !parts.is_empty())
+        .collect())
+}
+
+/// Adds a converted component to the current compound of every alternative, first
+/// closing the compound if a combinator has been seen since its last component.
+/// Alternatives the component conflicts with are dropped.
+fn push_component(
+    alternatives: &mut Vec<PartialSelector>,
+    pending_combinator: &mut Combinator,
+    selector: Selector,
+) {
+    let combinator = std::mem::replace(pending_combinator, Combinator::None);
+    alternatives.retain_mut(|alternative| {
+        alternative.close_compound(&combinator);
+        alternative
+            .current
+            .get_or_insert_default()
+            .push(selector.clone())
+    });
+}
+
+/// Crosses the alternatives read so far with the converted `:is()`/`:where()` arguments.
+/// A compound argument joins the current compound; a complex argument is only exact in the
+/// first compound of the selector, where its leading compounds become the selector's prefix.
+fn expand_alternatives(
+    alternatives: Vec<PartialSelector>,
+    pending_combinator: &mut Combinator,
+    arguments: &[Vec<SelectorPart>],
+) -> Result<Vec<PartialSelector>, ConversionFailure> {
+    if arguments.is_empty() || alternatives.len() * arguments.len() > MAX_SELECTOR_EXPANSIONS {
+        return Err(ConversionFailure::Pseudo);
+    }
+    let combinator = std::mem::replace(pending_combinator, Combinator::None);
+    let mut expanded = Vec::with_capacity(alternatives.len() * arguments.len());
+    for mut alternative in alternatives {
+        alternative.close_compound(&combinator);
+        for argument in arguments {
+            let Some((last, leading)) = argument.split_last() else {
+                continue;
+            };
+            if !leading.is_empty() && !alternative.parts.is_empty() {
+                return Err(ConversionFailure::Pseudo);
+            }
+            let mut next = alternative.clone();
+            next.parts.extend(leading.iter().cloned());
+            // An argument whose tag or id conflicts with the compound matches nothing.
+            if next
+                .current
+                .get_or_insert_default()
+                .push(last.selector.clone())
+            {
+                expanded.push(next);
+            }
+        }
     }
+    Ok(expanded)
+}
 
-    if selector_parts.is_empty() {
-        SelectorConversionResult::Skip
-    } else {
-        SelectorConversionResult::Keep(
-            selector_parts
-                .iter()
-                .map(|part| part.to_string())
-                .collect::<String>(),
-        )
+/// Maps the dynamic pseudo-classes the engines track (see `is_supported_pseudo_class`)
+/// to a compound carrying that pseudo-class.
+fn supported_pseudo_class(pseudo_class: &PseudoClass) -> Option<Selector> {
+    let name = match pseudo_class {
+        PseudoClass::Hover => "hover",
+        PseudoClass::Focus => "focus",
+        PseudoClass::FocusWithin => "focus-within",
+        PseudoClass::Checked => PSEUDO_CLASS_CHECKED,
+        PseudoClass::Disabled => PSEUDO_CLASS_DISABLED,
+        PseudoClass::Enabled => PSEUDO_CLASS_ENABLED,
+        PseudoClass::Link => PSEUDO_CLASS_LINK,
+        PseudoClass::Visited => PSEUDO_CLASS_VISITED,
+        _ => return None,
+    };
+    Some(pseudo_compound(name))
+}
+
+/// Pseudo-elements whose selectors match like their originating element; matches are
+/// reported per (element, pseudo-element) through the selector text.
+const ORIGINATING_PSEUDO_ELEMENTS: [&str; 3] = ["before", "after", "marker"];
+
+fn is_originating_pseudo_element(name: &str) -> bool {
+    ORIGINATING_PSEUDO_ELEMENTS.contains(&name.trim_start_matches(':'))
+}
+
+/// The `ORIGINATING_PSEUDO_ELEMENTS` name of `element`, if it is one of them.
+fn originating_pseudo_element(element: &PseudoElement) -> Option<&'static str> {
+    match element {
+        PseudoElement::Before => Some("before"),
+        PseudoElement::After => Some("after"),
+        PseudoElement::Marker => Some("marker"),
+        _ => None,
+    }
+}
+
+/// A compound holding only the pseudo-class `name`, matched against the engines'
+/// computed pseudo-class set.
+fn pseudo_compound(name: &str) -> Selector {
+    Selector::Compound(CompoundSelector {
+        pseudos: BTreeSet::from([name.to_string()]),
+        ..Default::default()
+    })
+}
+
+/// Maps `:first-child`, `:nth-child(an+b)` and relatives to a sibling-position predicate.
+/// The table pseudo-classes (`:nth-col()`) are not supported.
+fn nth_selector(data: &NthSelectorData) -> Option<Selector> {
+    let kind = match data.ty {
+        NthType::Child => NthKind::Child,
+        NthType::LastChild => NthKind::LastChild,
+        NthType::OnlyChild => NthKind::OnlyChild,
+        NthType::OfType => NthKind::OfType,
+        NthType::LastOfType => NthKind::LastOfType,
+        NthType::OnlyOfType => NthKind::OnlyOfType,
+        NthType::Col | NthType::LastCol => return None,
+    };
+    Some(Selector::Nth(NthSelector {
+        kind,
+        a: data.a,
+        b: data.b,
+    }))
+}
+
+/// Converts `:not(...)` when every argument is a single compound of supported simple
+/// selectors; anything else (complex arguments, nested pseudo-classes) is left to the
+/// pseudo report.
+fn convert_negation(arguments: &[LightningSelector]) -> Option<Selector> {
+    let mut negated = Vec::with_capacity(arguments.len());
+    for argument in arguments {
+        negated.push(convert_compound(
+            argument.iter_raw_parse_order_from(0),
+            true,
+        )?);
+    }
+    if negated.is_empty() {
+        return None;
+    }
+    negated.sort();
+    negated.dedup();
+    Some(Selector::Not(negated))
+}
+
+/// Converts `:has(...)` when every argument is a single compound reached through the same
+/// combinator, `>` or descendant. Hover and focus are rejected inside the arguments because
+/// the engines settle `:has()` before those states propagate down the tree.
+fn convert_has(arguments: &[LightningSelector]) -> Option<Selector> {
+    let mut child = None;
+    let mut converted = Vec::with_capacity(arguments.len());
+    for argument in arguments {
+        let mut components = argument.iter_raw_parse_order_from(0).peekable();
+        let is_child = matches!(components.peek(), Some(LComponent::Scope));
+        if is_child {
+            components.next();
+            if !matches!(
+                components.next(),
+                Some(LComponent::Combinator(LCombinator::Child))
+            ) {
+                return None;
+            }
+        }
+        if *child.get_or_insert(is_child) != is_child {
+            return None;
+        }
+        converted.push(convert_compound(components, false)?);
+    }
+    converted.sort();
+    converted.dedup();
+    Some(Selector::Has(HasSelector {
+        child: child?,
+        arguments: converted,
+    }))
+}
+
+/// Converts the components of a single compound; a combinator or an unsupported
+/// component fails the conversion. `dynamic` admits hover and focus.
+fn convert_compound<'a, 'i: 'a>(
+    components: impl Iterator<Item = &'a LComponent<'i>>,
+    dynamic: bool,
+) -> Option<Selector> {
+    let mut compound = CompoundSelector::default();
+    for component in components {
+        let conversion = match component {
+            LComponent::NonTSPseudoClass(pseudo_class) if dynamic => {
+                match supported_pseudo_class(pseudo_class) {
+                    Some(pseudo) => ComponentConversion::Keep(pseudo),
+                    None => ComponentConversion::Abort,
+                }
+            }
+            _ => convert_component(component),
+        };
+        match conversion {
+            ComponentConversion::Keep(selector) => {
+                if !compound.push(selector) {
+                    return None;
+                }
+            }
+            ComponentConversion::Skip if !matches!(component, LComponent::Combinator(_)) => {}
+            ComponentConversion::Skip | ComponentConversion::Abort => return None,
+        }
+    }
+    if compound == CompoundSelector::default() {
+        return None;
     }
+    Some(compound.into_selector())
 }
 
 fn convert_component(component: &LComponent) -> ComponentConversion {
@@ -688,25 +2361,52 @@ fn convert_component(component: &LComponent) -> ComponentConversion {
             local_name,
             operator,
             value,
+            case_sensitivity,
             ..
-        } => {
-            if matches!(operator, AttrSelectorOperator::Equal) {
-                ComponentConversion::Keep(Selector::AttributeEquals {
-                    name: local_name.as_ref().to_ascii_lowercase(),
-                    value: value.to_string(),
-                })
-            } else {
-                ComponentConversion::Skip
+        } => ComponentConversion::Keep(attribute_selector(
+            local_name.as_ref().to_ascii_lowercase(),
+            *operator,
+            value.to_string(),
+            case_flag(*case_sensitivity),
+        )),
+        LComponent::AttributeInNoNamespaceExists {
+            local_name_lower, ..
+        } => ComponentConversion::Keep(Selector::AttributeExists {
+            name: local_name_lower.as_ref().to_ascii_lowercase(),
+        }),
+        LComponent::AttributeOther(attr) => {
+            if attr.namespace.is_some() {
+                return ComponentConversion::Abort;
+            }
+            let name = attr.local_name_lower.as_ref().to_ascii_lowercase();
+            match &attr.operation {
+                ParsedAttrSelectorOperation::Exists => {
+                    ComponentConversion::Keep(Selector::AttributeExists { name })
+                }
+                ParsedAttrSelectorOperation::WithValue {
+                    operator,
+                    case_sensitivity,
+                    expected_value,
+                } => ComponentConversion::Keep(attribute_selector(
+                    name,
+                    *operator,
+                    expected_value.to_string(),
+                    case_flag(*case_sensitivity),
+                )),
             }
         }
-        LComponent::AttributeInNoNamespaceExists { .. } | LComponent::AttributeOther(_) => {
-            ComponentConversion::Skip
-        }
+        LComponent::Nth(data) => match nth_selector(data) {
+            Some(nth) => ComponentConversion::Keep(nth),
+            None => ComponentConversion::Abort,
+        },
+        LComponent::Root => ComponentConversion::Keep(pseudo_compound(PSEUDO_CLASS_ROOT)),
+        LComponent::Empty => ComponentConversion::Keep(pseudo_compound(PSEUDO_CLASS_EMPTY)),
+        LComponent::Has(arguments) => match convert_has(arguments) {
+            Some(has) => ComponentConversion::Keep(has),
+            None => ComponentConversion::Abort,
+        },
         LComponent::Negation(_)
-        | LComponent::Root
-        | LComponent::Empty
         | LComponent::Scope
-        | LComponent::Nth(_)
         | LComponent::NthOf(_)
         | LComponent::NonTSPseudoClass(_)
         | LComponent::Slotted(_)
@@ -715,7 +2415,6 @@ fn convert_component(component: &LComponent) -> ComponentConversion {
         | LComponent::Where(_)
         | LComponent::Is(_)
         | LComponent::Any(_, _)
-        | LComponent::Has(_)
         | LComponent::PseudoElement(_) => ComponentConversion::Abort,
         LComponent::ExplicitAnyNamespace
         | LComponent::ExplicitNoNamespace
@@ -773,15 +2472,154 @@ fn extract_pseudo_tokens(selector: &str) -> Vec<String> {
     tokens
 }
 
+/// Attributes whose values HTML compares ASCII case-insensitively when a selector carries
+/// no `i`/`s` flag. See <https://html.spec.whatwg.org/multipage/semantics-other.html#case-sensitivity-of-selectors>.
+const HTML_CASE_INSENSITIVE_ATTRIBUTES: &[&str] = &[
+    "accept",
+    "accept-charset",
+    "align",
+    "alink",
+    "axis",
+    "bgcolor",
+    "charset",
+    "checked",
+    "clear",
+    "codetype",
+    "color",
+    "compact",
+    "declare",
+    "defer",
+    "dir",
+    "direction",
+    "disabled",
+    "enctype",
+    "face",
+    "frame",
+    "hreflang",
+    "http-equiv",
+    "lang",
+    "language",
+    "link",
+    "media",
+    "method",
+    "multiple",
+    "nohref",
+    "noresize",
+    "noshade",
+    "nowrap",
+    "readonly",
+    "rel",
+    "rev",
+    "rules",
+    "scope",
+    "scrolling",
+    "selected",
+    "shape",
+    "target",
+    "text",
+    "type",
+    "valign",
+    "valuetype",
+    "vlink",
+];
+
+/// Whether `[name op value]` compares its value ASCII case-insensitively. An explicit
+/// `i` (`Some(true)`) or `s` (`Some(false)`) flag wins; otherwise the HTML list decides,
+/// since every element in a trace is an HTML element.
+fn attribute_case_insensitive(name: &str, flag: Option<bool>) -> bool {
+    flag.unwrap_or_else(|| {
+        HTML_CASE_INSENSITIVE_ATTRIBUTES
+            .binary_search(&name)
+            .is_ok()
+    })
+}
+
+/// The explicit flag behind lightningcss' case sensitivity; the HTML default is
+/// re-derived by `attribute_case_insensitive`.
+fn case_flag(case_sensitivity: ParsedCaseSensitivity) -> Option<bool> {
+    match case_sensitivity {
+        ParsedCaseSensitivity::AsciiCaseInsensitive => Some(true),
+        ParsedCaseSensitivity::ExplicitCaseSensitive => Some(false),
+        ParsedCaseSensitivity::CaseSensitive
+        | ParsedCaseSensitivity::AsciiCaseInsensitiveIfInHtmlElementInHtmlDocument => None,
+    }
+}
+
+fn attribute_selector(
+    name: String,
+    operator: AttrSelectorOperator,
+    value: String,
+    flag: Option<bool>,
+) -> Selector {
+    let case_insensitive = attribute_case_insensitive(&name, flag);
+    match operator {
+        AttrSelectorOperator::Equal => Selector::AttributeEquals {
+            name,
+            value,
+            case_insensitive,
+        },
+        AttrSelectorOperator::Includes => Selector::AttributeIncludes {
+            name,
+            value,
+            case_insensitive,
+        },
+        AttrSelectorOperator::DashMatch => Selector::AttributeDashMatch {
+            name,
+            value,
+            case_insensitive,
+        },
+        AttrSelectorOperator::Prefix => Selector::AttributePrefix {
+            name,
+            value,
+            case_insensitive,
+        },
+        AttrSelectorOperator::Suffix => Selector::AttributeSuffix {
+            name,
+            value,
+            case_insensitive,
+        },
+        AttrSelectorOperator::Substring => Selector::AttributeSubstring {
+            name,
+            value,
+            case_insensitive,
+        },
+    }
+}
+
 fn parse_attribute_selector(raw: &str) -> Option<Selector> {
     let raw = raw.trim();
     if !raw.starts_with('[') || !raw.ends_with(']') {
         return None;
     }
     let inner = &raw[1..raw.len() - 1];
-    let mut parts = inner.splitn(2, '=');
-    let name = parts.next()?.trim().to_lowercase();
-    let value_part = parts.next()?.trim();
+    let Some(eq_pos) = inner.find('=') else {
+        let name = inner.trim().to_lowercase();
+        if name.is_empty() {
+            return None;
+        }
+        return Some(Selector::AttributeExists { name });
+    };
+    let (name_part, operator) = match inner[..eq_pos].chars().last() {
+        Some('~') => (&inner[..eq_pos - 1], AttrSelectorOperator::Includes),
+        Some('|') => (&inner[..eq_pos - 1], AttrSelectorOperator::DashMatch),
+        Some('^') => (&inner[..eq_pos - 1], AttrSelectorOperator::Prefix),
+        Some('$') => (&inner[..eq_pos - 1], AttrSelectorOperator::Suffix),
+        Some('*') => (&inner[..eq_pos - 1], AttrSelectorOperator::Substring),
+        _ => (&inner[..eq_pos], AttrSelectorOperator::Equal),
+    };
+    let name = name_part.trim().to_lowercase();
+    let mut value_part = inner[eq_pos + 1..].trim();
+    let mut flag = None;
+    if let Some((quoted, suffix)) = value_part.rsplit_once(char::is_whitespace)
+        && quoted.trim_end().ends_with('"')
+    {
+        flag = match suffix {
+            "i" | "I" => Some(true),
+            "s" | "S" => Some(false),
+            _ => return None,
+        };
+        value_part = quoted.trim_end();
+    }
 
     if !value_part.starts_with('"') || !value_part.ends_with('"') || value_part.len() < 2 {
         return None;
@@ -789,13 +2627,13 @@ fn parse_attribute_selector(raw: &str) -> Option<Selector> {
     let mut value = value_part[1..value_part.len() - 1].to_string();
     value = value.replace("\\\"", "\"");
 
-    Some(Selector::AttributeEquals { name, value })
+    Some(attribute_selector(name, operator, value, flag))
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
 pub struct Nfacell(pub usize);
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub struct SelectorId(pub usize);
 /// Transition rule: (input selector, current state, next state)
 /// When the input selector is None it represents a wildcard/epsilon or special match; a current
@@ -809,36 +2647,257 @@ pub struct NFA {
     pub states: HashSet<Option<Nfacell>>,
     /// Rule list: (optional predicate, optional predecessor state, successor state)
     pub rules: Vec<Rule>,
+    /// Rules whose predecessor state is read from the previous element sibling's
+    /// output instead of the parent's (used by `+` and `~`).
+    pub sibling_rules: Vec<Rule>,
+    /// Sibling positions read by `:nth-*` predicates on any rule.
+    pub position_reads: PositionReads,
+    /// Every `:has()` selector the rules use, with its id. Engines keep one bit per entry
+    /// in a bottom-up pass and feed the results to the rules as node-local predicates.
+    pub has_selectors: Vec<(SelectorId, HasSelector)>,
     /// Start state.
     pub start_state: Option<Nfacell>,
     pub max_state_id: Nfacell,
-    // for print match
-    pub accept_states: Vec<Nfacell>,
+    /// One accepting state per compiled selector, in the order they were given.
+    pub accept_states: Vec<AcceptState>,
+    /// Determinized form the engines step through instead of the rules, when attached.
+    pub dfa: Option<Dfa>,
+    /// Narrows the rules a node evaluates to those it can fire; `None` scans them all.
+    pub rule_index: Option<RuleIndex>,
+    /// States with a selector-less self-loop in `rules`: each is set whenever it is set
+    /// in the parent's output, so engines apply these loops as one masked OR.
+    pub self_loops: StateBits,
+    /// States some sibling rule reads.
+    pub sibling_reads: StateBits,
+    /// The states of `accept_states`.
+    pub accepting: StateBits,
+    /// Accept states of selectors ending in a pseudo-element (`p::before`). They match on
+    /// the originating element but style another box, so they stay out of its cascade.
+    pub pseudo_element_states: StateBits,
 }
 
-impl NFA {
-    pub fn is_accept_state(&self, state: Nfacell) -> bool {
-        !self
-            .rules
-            .iter()
-            .any(|Rule(_, prev, _)| *prev == Some(state))
+/// `rules` and `sibling_rules` of an NFA, bucketed by a predicate their selector requires.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RuleIndex {
+    pub rules: RuleBuckets,
+    pub sibling_rules: RuleBuckets,
+}
+
+/// Rule positions keyed by a tag, class or id selector id, or by an attribute name, that
+/// a node must have for the rule's selector to match it. A node only evaluates the
+/// buckets for its own tag, classes, id and attributes, plus the rules in `always`:
+/// those without a selector or whose selector requires none of these.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RuleBuckets {
+    pub always: Vec<usize>,
+    pub by_selector: HashMap<SelectorId, Vec<usize>>,
+    pub by_attribute: HashMap<String, Vec<usize>>,
+}
+
+enum RuleKey {
+    Selector(SelectorId),
+    Attribute(String),
+}
+
+impl RuleBuckets {
+    pub fn new(rules: &[Rule], sm: &mut SelectorManager) -> Self {
+        let mut buckets = RuleBuckets::default();
+        for (position, &Rule(selector, _, _)) in rules.iter().enumerate() {
+            match selector.and_then(|id| rule_key(id, sm)) {
+                Some(RuleKey::Selector(id)) => {
+                    buckets.by_selector.entry(id).or_default().push(position)
+                }
+                Some(RuleKey::Attribute(name)) => {
+                    buckets.by_attribute.entry(name).or_default().push(position)
+                }
+                None => buckets.always.push(position),
+            }
+        }
+        buckets
     }
 
-    pub fn get_accept_states(&self) -> HashSet<Option<Nfacell>> {
-        self.states
-            .iter()
-            .filter(|&&state| self.is_accept_state(state.unwrap()))
-            .copied()
-            .collect()
+    /// The rules a node with these tag, class and id selector ids and attribute names
+    /// may fire, in their order in `rules`. Every bucket is sorted, so they are merged
+    /// as they are read.
+    pub fn select<'a, 'b>(
+        &'a self,
+        rules: &'a [Rule],
+        ids: impl IntoIterator<Item = SelectorId>,
+        attributes: impl IntoIterator<Item = &'b str>,
+    ) -> SelectedRules<'a> {
+        let mut buckets = vec![self.always.as_slice()];
+        buckets.extend(
+            ids.into_iter()
+                .filter_map(|id| self.by_selector.get(&id))
+                .map(Vec::as_slice),
+        );
+        buckets.extend(
+            attributes
+                .into_iter()
+                .filter_map(|name| self.by_attribute.get(name))
+                .map(Vec::as_slice),
+        );
+        SelectedRules { rules, buckets }
     }
-    pub fn to_dot(&self, sm: &SelectorManager) -> String {
-        let mut s = String::new();
-        s.push_str("digraph NFA {\n");
-        s.push_str("  rankdir=LR;\n");
-        s.push_str("  node [shape=circle, fontsize=10];\n");
 
-        // Start marker
-        s.push_str("  __start [shape=point, label=\"\"];\n");
+    pub fn bucket_count(&self) -> usize {
+        self.by_selector.len() + self.by_attribute.len()
+    }
+}
+
+/// The rules `RuleBuckets::select` picked, read off the front of the buckets in
+/// position order.
+pub struct SelectedRules<'a> {
+    rules: &'a [Rule],
+    buckets: Vec<&'a [usize]>,
+}
+
+impl<'a> Iterator for SelectedRules<'a> {
+    type Item = &'a Rule;
+
+    fn next(&mut self) -> Option<&'a Rule> {
+        let position = self
+            .buckets
+            .iter()
+            .filter_map(|bucket| bucket.first())
+            .min()
+            .copied()?;
+        // A node listing the same class twice reads its bucket twice.
+        for bucket in &mut self.buckets {
+            if bucket.first() == Some(&position) {
+                *bucket = &bucket[1..];
+            }
+        }
+        Some(&self.rules[position])
+    }
+}
+
+/// A predicate `id` requires of a node, preferring the rarest kind: id, class, tag, then
+/// attribute name. `:not()`, `:nth-*()` and `:has()` require none.
+fn rule_key(id: SelectorId, sm: &mut SelectorManager) -> Option<RuleKey> {
+    match sm.id_to_selector.get(&id)?.clone() {
+        Selector::Type(_) | Selector::Class(_) | Selector::Id(_) => Some(RuleKey::Selector(id)),
+        Selector::Compound(compound) => {
+            let required = compound
+                .id
+                .map(Selector::Id)
+                .or_else(|| compound.classes.first().cloned().map(Selector::Class))
+                .or_else(|| compound.tag.filter(|tag| tag != "*").map(Selector::Type));
+            match required {
+                Some(selector) => Some(RuleKey::Selector(sm.get_or_create_id(selector))),
+                None => compound
+                    .attributes
+                    .first()
+                    .and_then(Selector::attribute_parts)
+                    .map(|(name, ..)| RuleKey::Attribute(name.to_string())),
+            }
+        }
+        Selector::Not(_) | Selector::Nth(_) | Selector::Has(_) => None,
+        attribute => attribute
+            .attribute_parts()
+            .map(|(name, ..)| RuleKey::Attribute(name.to_string())),
+    }
+}
+
+/// The state a compiled selector accepts in, and everywhere that selector was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcceptState {
+    pub state: Nfacell,
+    /// Sorted, so the last origin is the one that wins the cascade.
+    pub origins: Vec<SelectorOrigin>,
+}
+
+impl NFA {
+    /// Fills in the rule index and the state masks from the rules and accept states.
+    pub(crate) fn derive_tables(&mut self, sm: &mut SelectorManager) {
+        self.rule_index = Some(RuleIndex {
+            rules: RuleBuckets::new(&self.rules, sm),
+            sibling_rules: RuleBuckets::new(&self.sibling_rules, sm),
+        });
+        let width = self.max_state_id.0 + 1;
+        self.self_loops = StateBits::new(width);
+        for rule in self.rules.iter().filter(|rule| NFA::is_self_loop(rule)) {
+            self.self_loops.insert(rule.2.0);
+        }
+        self.sibling_reads = StateBits::new(width);
+        for Rule(_, prev, _) in &self.sibling_rules {
+            if let Some(Nfacell(b)) = prev {
+                self.sibling_reads.insert(*b);
+            }
+        }
+        self.accepting = StateBits::new(width);
+        for accept in &self.accept_states {
+            self.accepting.insert(accept.state.0);
+        }
+        self.pseudo_element_states = StateBits::new(width);
+        for Rule(selector, _, next) in self.rules.iter().chain(&self.sibling_rules) {
+            if let Some(Selector::Compound(compound)) =
+                selector.and_then(|id| sm.id_to_selector.get(&id))
+                && compound.pseudo_element.is_some()
+            {
+                self.pseudo_element_states.insert(next.0);
+            }
+        }
+    }
+
+    pub fn is_accept_state(&self, state: Nfacell) -> bool {
+        !self
+            .rules
+            .iter()
+            .chain(&self.sibling_rules)
+            .any(|Rule(_, prev, _)| *prev == Some(state))
+    }
+
+    /// Whether a change from `old` to `new` in a node's output is visible to its
+    /// next element sibling, i.e. touches a state read by some sibling rule.
+    pub fn sibling_input_changed(&self, old: &StateBits, new: &StateBits) -> bool {
+        old.differs_within(new, &self.sibling_reads)
+    }
+
+    /// Whether `rule` is a selector-less self-loop, which `self_loops` applies.
+    pub fn is_self_loop(rule: &Rule) -> bool {
+        matches!(rule, Rule(None, Some(prev), next) if prev == next)
+    }
+
+    /// The selectors a node whose output states are `output` matches, as
+    /// (index into `accept_states`, origin) pairs in cascade order: later entries win.
+    /// A selector written in several places appears once per origin. Pseudo-element
+    /// selectors are left out; they do not style the node itself.
+    pub fn cascade_order(&self, output: &StateBits) -> Vec<(usize, SelectorOrigin)> {
+        let mut matched: Vec<(usize, SelectorOrigin)> = self
+            .accept_states
+            .iter()
+            .enumerate()
+            .filter(|(_, accept)| {
+                output.get(accept.state.0) && !self.pseudo_element_states.get(accept.state.0)
+            })
+            .flat_map(|(idx, accept)| accept.origins.iter().map(move |origin| (idx, *origin)))
+            .collect();
+        matched.sort_by_key(|&(idx, origin)| (origin, idx));
+        matched
+    }
+
+    /// Whether a change from `old` to `new` in a node's output touches an accept state,
+    /// i.e. changes which selectors the node matches.
+    pub fn accept_output_changed(&self, old: &StateBits, new: &StateBits) -> bool {
+        old.differs_within(new, &self.accepting)
+    }
+
+    pub fn get_accept_states(&self) -> HashSet<Option<Nfacell>> {
+        self.states
+            .iter()
+            .filter(|&&state| self.is_accept_state(state.unwrap()))
+            .copied()
+            .collect()
+    }
+    pub fn to_dot(&self, sm: &SelectorManager) -> String {
+        let mut s = String::new();
+        s.push_str("digraph NFA {\n");
+        s.push_str("  rankdir=LR;\n");
+        s.push_str("  node [shape=circle, fontsize=10];\n");
+
+        // Start marker
+        s.push_str("  __start [shape=point, label=\"\"];\n");
         s.push_str(&format!("  __start -> {:?};\n", self.start_state));
 
         // States
@@ -853,8 +2912,8 @@ impl NFA {
         // Accept states styling
         if !self.accept_states.is_empty() {
             s.push_str("  { node [shape=doublecircle]; ");
-            for st in &self.accept_states {
-                s.push_str(&format!("{} ", st.0));
+            for accept in &self.accept_states {
+                s.push_str(&format!("{} ", accept.state.0));
             }
             s.push_str("}\n");
         }
@@ -866,8 +2925,14 @@ impl NFA {
             zero_node, zero_node
         ));
 
-        // Edges
-        for Rule(selector_opt, from_opt, to) in &self.rules {
+        // Edges (sibling edges are dashed)
+        let sibling_edges = self.sibling_rules.iter().map(|rule| (rule, true));
+        for (Rule(selector_opt, from_opt, to), is_sibling) in self
+            .rules
+            .iter()
+            .map(|rule| (rule, false))
+            .chain(sibling_edges)
+        {
             let from = from_opt.unwrap_or(self.start_state.unwrap_or_default()).0;
             let label = match selector_opt {
                 None => "*".to_string(),
@@ -875,18 +2940,17 @@ impl NFA {
                     Some(Selector::Type(t)) => t.clone(),
                     Some(Selector::Class(c)) => format!(".{}", c),
                     Some(Selector::Id(i)) => format!("#{}", i),
-                    Some(Selector::AttributeEquals { name, value }) => {
-                        format!("[{}=\"{}\"]", name, value)
-                    }
                     Some(Selector::Compound(comp)) => comp.to_string(),
+                    Some(attribute) => attribute.to_string(),
                     None => format!("sid:{}", sel_id.0),
                 },
             };
             s.push_str(&format!(
-                "  {} -> {} [label=\"{}\"];\n",
+                "  {} -> {} [label=\"{}\"{}];\n",
                 from,
                 to.0,
-                escape_dot_label(&label)
+                escape_dot_label(&label),
+                if is_sibling { ", style=dashed" } else { "" }
             ));
         }
         s.push_str("}\n");
@@ -903,6 +2967,58 @@ pub struct SelectorManager {
     pub selector_to_id: HashMap<Selector, SelectorId>,
     pub id_to_selector: HashMap<SelectorId, Selector>,
     next_id: SelectorId,
+    /// Compiled form of every interned selector, indexed by `SelectorId`.
+    compiled: Vec<Option<Predicate>>,
+    /// Compiled arguments of each `:has()` selector, by its id.
+    has_arguments: HashMap<SelectorId, Vec<Predicate>>,
+}
+
+/// A selector with its names interned, so matching a node is integer checks against
+/// its tag, class and id ids and the `:has()` selectors it satisfies. Only attribute
+/// values and pseudo-class names are still compared as strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// `*`.
+    Any,
+    /// A pseudo-class no node carries.
+    Never,
+    Tag(SelectorId),
+    Class(SelectorId),
+    Id(SelectorId),
+    Attribute(Selector),
+    Compound(CompiledCompound),
+    /// Matches when none of the arguments do.
+    Not(Vec<Predicate>),
+    Nth(NthSelector),
+    Has(SelectorId),
+}
+
+/// `CompoundSelector` with every part interned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledCompound {
+    /// `None` for `*` or no tag.
+    pub tag: Option<SelectorId>,
+    pub id: Option<SelectorId>,
+    pub classes: Vec<SelectorId>,
+    /// Attribute selectors, each compiled to `Predicate::Attribute` under its id.
+    pub attributes: Vec<SelectorId>,
+    /// Names from `COMPUTED_PSEUDO_CLASSES`.
+    pub pseudos: Vec<&'static str>,
+    pub positions: Vec<NthSelector>,
+    pub has: Vec<SelectorId>,
+    /// The argument lists of `:not()`s.
+    pub negations: Vec<Vec<Predicate>>,
+}
+
+/// Borrowed view of what compiled selectors read from an engine's node.
+pub struct NodeView<'a> {
+    pub tag_id: SelectorId,
+    pub class_ids: &'a HashSet<SelectorId>,
+    pub id_selector_id: Option<SelectorId>,
+    pub attributes: &'a HashMap<String, String>,
+    pub computed_pseudo_classes: &'a HashSet<String>,
+    pub position: &'a SiblingPosition,
+    pub has_ids: &'a HashSet<SelectorId>,
 }
 
 impl SelectorManager {
@@ -917,11 +3033,61 @@ impl SelectorManager {
 
         let id = self.next_id;
         self.selector_to_id.insert(selector.clone(), id);
-        self.id_to_selector.insert(id, selector);
+        self.id_to_selector.insert(id, selector.clone());
         self.next_id = SelectorId(self.next_id.0 + 1);
+        self.compile_interned(id, &selector);
         id
     }
 
+    /// A manager that hands out `selectors[i]` as `SelectorId(i)`, as `selectors`
+    /// lists them; `None` when a selector appears twice.
+    pub fn from_selectors(selectors: Vec<Selector>) -> Option<Self> {
+        let mut manager = SelectorManager::new();
+        for (index, selector) in selectors.iter().enumerate() {
+            if manager
+                .selector_to_id
+                .insert(selector.clone(), SelectorId(index))
+                .is_some()
+            {
+                return None;
+            }
+            manager
+                .id_to_selector
+                .insert(SelectorId(index), selector.clone());
+        }
+        manager.next_id = SelectorId(selectors.len());
+        // Every part is interned already, unless the table lacks it.
+        for (index, selector) in selectors.iter().enumerate() {
+            manager.compile_interned(SelectorId(index), selector);
+        }
+        Some(manager)
+    }
+
+    /// Every interned selector, by id.
+    pub fn selectors(&self) -> impl Iterator<Item = (SelectorId, &Selector)> {
+        (0..self.next_id.0).filter_map(|index| {
+            let id = SelectorId(index);
+            self.id_to_selector.get(&id).map(|selector| (id, selector))
+        })
+    }
+
+    fn compile_interned(&mut self, id: SelectorId, selector: &Selector) {
+        // Interning the parts may create further ids, so the slot is filled last.
+        let predicate = self.compile(selector);
+        if let Selector::Has(has) = selector {
+            let arguments = has
+                .arguments
+                .iter()
+                .map(|argument| self.compile(argument))
+                .collect();
+            self.has_arguments.insert(id, arguments);
+        }
+        if self.compiled.len() <= id.0 {
+            self.compiled.resize(id.0 + 1, None);
+        }
+        self.compiled[id.0] = Some(predicate);
+    }
+
     /// Get the ID for a selector.
     pub fn get_id(&self, selector: &Selector) -> Option<SelectorId> {
         self.selector_to_id.get(selector).copied()
@@ -938,6 +3104,145 @@ impl SelectorManager {
     pub fn get_or_create_id_selector_id(&mut self, id_name: &str) -> SelectorId {
         self.get_or_create_id(Selector::Id(id_name.to_string()))
     }
+
+    fn compile(&mut self, selector: &Selector) -> Predicate {
+        match selector {
+            Selector::Type(tag) if tag == "*" => Predicate::Any,
+            Selector::Type(_) => Predicate::Tag(self.get_or_create_id(selector.clone())),
+            Selector::Class(_) => Predicate::Class(self.get_or_create_id(selector.clone())),
+            Selector::Id(_) => Predicate::Id(self.get_or_create_id(selector.clone())),
+            Selector::Compound(compound) => {
+                let mut pseudos = Vec::with_capacity(compound.pseudos.len());
+                for pseudo in &compound.pseudos {
+                    let name = normalize_pseudo_name(pseudo);
+                    match COMPUTED_PSEUDO_CLASSES.iter().find(|&&known| known == name) {
+                        Some(&known) => pseudos.push(known),
+                        None => return Predicate::Never,
+                    }
+                }
+                let attributes = compound
+                    .attributes
+                    .iter()
+                    .map(|attribute| self.get_or_create_id(attribute.clone()))
+                    .collect();
+                let negations = compound
+                    .negations
+                    .iter()
+                    .filter_map(|negation| match negation {
+                        Selector::Not(arguments) => Some(
+                            arguments
+                                .iter()
+                                .map(|argument| self.compile(argument))
+                                .collect(),
+                        ),
+                        _ => None,
+                    })
+                    .collect();
+                Predicate::Compound(CompiledCompound {
+                    tag: compound
+                        .tag
+                        .as_ref()
+                        .filter(|tag| *tag != "*")
+                        .map(|tag| self.get_or_create_type_id(tag)),
+                    id: compound
+                        .id
+                        .as_ref()
+                        .map(|id| self.get_or_create_id_selector_id(id)),
+                    classes: compound
+                        .classes
+                        .iter()
+                        .map(|class| self.get_or_create_class_id(class))
+                        .collect(),
+                    attributes,
+                    pseudos,
+                    positions: compound
+                        .positions
+                        .iter()
+                        .filter_map(|position| match position {
+                            Selector::Nth(nth) => Some(*nth),
+                            _ => None,
+                        })
+                        .collect(),
+                    has: compound
+                        .has
+                        .iter()
+                        .map(|has| self.get_or_create_id(has.clone()))
+                        .collect(),
+                    negations,
+                })
+            }
+            Selector::Not(arguments) => Predicate::Not(
+                arguments
+                    .iter()
+                    .map(|argument| self.compile(argument))
+                    .collect(),
+            ),
+            Selector::Nth(nth) => Predicate::Nth(*nth),
+            Selector::Has(_) => Predicate::Has(self.get_or_create_id(selector.clone())),
+            attribute => Predicate::Attribute(attribute.clone()),
+        }
+    }
+
+    /// Whether `node` matches the selector `id`; never for an id this manager did not
+    /// hand out.
+    pub fn matches(&self, id: SelectorId, node: &NodeView) -> bool {
+        self.compiled
+            .get(id.0)
+            .and_then(Option::as_ref)
+            .is_some_and(|predicate| self.matches_predicate(predicate, node))
+    }
+
+    /// Whether `node` matches any argument of the `:has()` selector `has_id`.
+    pub fn matches_has_arguments(&self, has_id: SelectorId, node: &NodeView) -> bool {
+        self.has_arguments
+            .get(&has_id)
+            .is_some_and(|arguments| self.matches_any(arguments, node))
+    }
+
+    fn matches_any(&self, predicates: &[Predicate], node: &NodeView) -> bool {
+        predicates
+            .iter()
+            .any(|predicate| self.matches_predicate(predicate, node))
+    }
+
+    fn matches_predicate(&self, predicate: &Predicate, node: &NodeView) -> bool {
+        match predicate {
+            Predicate::Any => true,
+            Predicate::Never => false,
+            Predicate::Tag(id) => node.tag_id == *id,
+            Predicate::Class(id) => node.class_ids.contains(id),
+            Predicate::Id(id) => node.id_selector_id == Some(*id),
+            Predicate::Attribute(attribute) => attribute.matches_attributes(node.attributes),
+            Predicate::Compound(compound) => {
+                compound.tag.is_none_or(|tag| node.tag_id == tag)
+                    && compound.id.is_none_or(|id| node.id_selector_id == Some(id))
+                    && compound
+                        .classes
+                        .iter()
+                        .all(|class| node.class_ids.contains(class))
+                    && compound
+                        .attributes
+                        .iter()
+                        .all(|&attribute| self.matches(attribute, node))
+                    && compound
+                        .pseudos
+                        .iter()
+                        .all(|&pseudo| node.computed_pseudo_classes.contains(pseudo))
+                    && compound
+                        .positions
+                        .iter()
+                        .all(|nth| nth.matches(node.position))
+                    && compound.has.iter().all(|has| node.has_ids.contains(has))
+                    && compound
+                        .negations
+                        .iter()
+                        .all(|arguments| !self.matches_any(arguments, node))
+            }
+            Predicate::Not(arguments) => !self.matches_any(arguments, node),
+            Predicate::Nth(nth) => nth.matches(node.position),
+            Predicate::Has(id) => node.has_ids.contains(id),
+        }
+    }
 }
 
 /// Encodes a slice of elements of type T using Run-Length Encoding.
@@ -979,69 +3284,148 @@ where
     encoded
 }
 
-pub fn generate_nfa(selectors: &[String], sm: &mut SelectorManager, state: &mut usize) -> NFA {
+/// How a compound's state is entered from the previous compound's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum StateEntry {
+    /// Through the parent's output (descendant and child combinators).
+    Parent,
+    /// Through the previous element sibling's output (`+`).
+    NextSibling,
+    /// Through any earlier element sibling's output (`~`).
+    LaterSibling,
+}
+
+/// The number of states `generate_nfa` would create for `selectors` without sharing
+/// prefixes: one per compound, plus one per `~`.
+pub fn unshared_state_count(selectors: &[ComplexSelector]) -> usize {
+    selectors
+        .iter()
+        .flatten()
+        .map(|(_, combinator)| match combinator {
+            Combinator::LaterSibling => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Compiles `selectors` into one NFA; `accept_states[i]` belongs to `selectors[i]` and
+/// carries `origins[i]` (none past the end of `origins`). Selectors with a common prefix
+/// share its states, so several accept states may name the same state.
+pub fn generate_nfa(
+    selectors: &[ComplexSelector],
+    origins: &[Vec<SelectorOrigin>],
+    sm: &mut SelectorManager,
+    state: &mut usize,
+) -> NFA {
     *state = 0;
     let start_state = Option::<Nfacell>::None;
     let mut states: HashSet<Option<Nfacell>> = [start_state].into_iter().collect();
     let mut rules: Vec<Rule> = Vec::new();
-    let mut accept_states: Vec<Nfacell> = Vec::with_capacity(selectors.len());
+    let mut sibling_rules: Vec<Rule> = Vec::new();
+    let mut accept_states: Vec<AcceptState> = Vec::with_capacity(selectors.len());
+    let mut position_reads = PositionReads::default();
+    let mut has_selectors: Vec<(SelectorId, HasSelector)> = Vec::new();
+
+    // States are shared between selectors with the same prefix: a compound reached from
+    // the same state, the same way, with the same predicate and self-loop is one state.
+    let mut shared: HashMap<(Option<Nfacell>, StateEntry, Option<SelectorId>, bool), Nfacell> =
+        HashMap::new();
+    // The state `~` steps through after each state; it is shared like the others.
+    let mut later_states: HashMap<Option<Nfacell>, Nfacell> = HashMap::new();
 
-    for rule in selectors {
-        let t = rule.replace('>', " > ");
-        let parts: Vec<&str> = t.split_whitespace().collect();
+    for (index, complex) in selectors.iter().enumerate() {
         let mut cur = start_state;
+        // Combinator between the previous compound and the current one
+        let mut combinator = &Combinator::Descendant;
 
-        let mut i = 0;
-        while i < parts.len() {
-            if parts[i] == ">" {
-                i += 1;
-                continue;
+        for (selector, next_combinator) in complex {
+            position_reads = position_reads.union(selector.position_reads());
+            let mut found = Vec::new();
+            selector.collect_has(&mut found);
+            for has in found {
+                let has_id = sm.get_or_create_id(Selector::Has(has.clone()));
+                if !has_selectors.iter().any(|(id, _)| *id == has_id) {
+                    has_selectors.push((has_id, has));
+                }
             }
-            let selector_str = parts[i];
-
-            // Look ahead to find the next selector and whether the combinator is direct (>)
-            let mut next_selector_index = i + 1;
-            let mut next_is_direct = false;
-            if next_selector_index < parts.len() && parts[next_selector_index] == ">" {
-                next_is_direct = true;
-                next_selector_index += 1;
+            let selector_id = match selector {
+                Selector::Type(s) if s == "*" => None,
+                other => Some(sm.get_or_create_id(other.clone())),
+            };
+            let entry = match combinator {
+                Combinator::NextSibling => StateEntry::NextSibling,
+                Combinator::LaterSibling => StateEntry::LaterSibling,
+                Combinator::Descendant | Combinator::Child | Combinator::None => StateEntry::Parent,
+            };
+            // Add self-loop only for descendant combinators (a b), not for child (a > b)
+            let self_loop = matches!(next_combinator, Combinator::Descendant);
+            let key = (cur, entry, selector_id, self_loop);
+            if let Some(&existing) = shared.get(&key) {
+                cur = Some(existing);
+                combinator = next_combinator;
+                continue;
             }
-            let has_next_selector = next_selector_index < parts.len();
 
             // Create new state and edge for current selector
             *state += 1;
-
             let new_state = Nfacell(*state);
             states.insert(Some(new_state));
-
-            let selector = parse_selector(selector_str);
-            match selector {
-                Selector::Type(ref s) if s == "*" => {
-                    rules.push(Rule(None, cur, new_state));
+            match entry {
+                StateEntry::NextSibling => {
+                    sibling_rules.push(Rule(selector_id, cur, new_state));
+                }
+                StateEntry::LaterSibling => {
+                    // `later` is set on every element following one that reached `cur`
+                    let later = match later_states.get(&cur) {
+                        Some(&later) => later,
+                        None => {
+                            *state += 1;
+                            let later = Nfacell(*state);
+                            states.insert(Some(later));
+                            sibling_rules.push(Rule(None, cur, later));
+                            sibling_rules.push(Rule(None, Some(later), later));
+                            later_states.insert(cur, later);
+                            later
+                        }
+                    };
+                    sibling_rules.push(Rule(selector_id, cur, new_state));
+                    sibling_rules.push(Rule(selector_id, Some(later), new_state));
                 }
-                other => {
-                    let selector_id = sm.get_or_create_id(other);
-                    rules.push(Rule(Some(selector_id), cur, new_state));
+                StateEntry::Parent => {
+                    rules.push(Rule(selector_id, cur, new_state));
                 }
             }
-
-            // Add self-loop only for descendant combinators (a b), not for child (a > b)
-            if has_next_selector && !next_is_direct {
+            if self_loop {
                 rules.push(Rule(None, Some(new_state), new_state));
             }
+            shared.insert(key, new_state);
 
             cur = Some(new_state);
-            i = next_selector_index;
+            combinator = next_combinator;
         }
-        accept_states.push(cur.unwrap());
+        accept_states.push(AcceptState {
+            state: cur.unwrap(),
+            origins: origins.get(index).cloned().unwrap_or_default(),
+        });
     }
-    NFA {
+    let mut nfa = NFA {
         states,
         rules,
+        sibling_rules,
+        position_reads,
+        has_selectors,
         start_state,
         max_state_id: Nfacell(*state),
         accept_states,
-    }
+        dfa: None,
+        rule_index: None,
+        self_loops: StateBits::default(),
+        sibling_reads: StateBits::default(),
+        accepting: StateBits::default(),
+        pseudo_element_states: StateBits::default(),
+    };
+    nfa.derive_tables(sm);
+    nfa
 }
 
 /// Parse a CSS selector string and produce the corresponding selector object.
@@ -1062,7 +3446,7 @@ pub fn parse_selector(selector_str: &str) -> Selector {
                 pos += ch.len_utf8();
                 let (class_name, next_pos) = consume_identifier(trimmed, pos);
                 if !class_name.is_empty() {
-                    compound.classes.insert(class_name.to_ascii_lowercase());
+                    compound.classes.insert(class_name);
                 }
                 pos = next_pos;
             }
@@ -1076,7 +3460,18 @@ pub fn parse_selector(selector_str: &str) -> Selector {
             }
             ':' => {
                 let (pseudo, next_pos) = consume_pseudo(trimmed, pos);
-                if !pseudo.is_empty() {
+                if let Some(negation) = parse_negation(&pseudo) {
+                    compound.negations.push(negation);
+                } else if let Some(nth) = parse_nth(&pseudo) {
+                    compound.positions.push(Selector::Nth(nth));
+                } else if let Some(has) = parse_has(&pseudo) {
+                    compound.has.push(has);
+                } else if let Some(element) = pseudo
+                    .strip_prefix("::")
+                    .filter(|element| is_originating_pseudo_element(element))
+                {
+                    compound.pseudo_element = Some(element.to_string());
+                } else if !pseudo.is_empty() {
                     compound.pseudos.insert(pseudo);
                 }
                 pos = next_pos;
@@ -1103,21 +3498,132 @@ pub fn parse_selector(selector_str: &str) -> Selector {
         }
     }
 
-    compound.attributes.sort();
+    compound.into_selector()
+}
 
-    if compound.is_simple_class_only() {
-        let class_name = compound.classes.iter().next().cloned().unwrap_or_default();
-        Selector::Class(class_name)
-    } else if compound.is_simple_id_only() {
-        Selector::Id(compound.id.unwrap())
-    } else if compound.is_simple_attr_only() {
-        let (name, value) = compound.attributes.into_iter().next().unwrap();
-        Selector::AttributeEquals { name, value }
-    } else if compound.is_simple_tag_only() {
-        Selector::Type(compound.tag.unwrap())
-    } else {
-        Selector::Compound(compound)
+/// Parses the name returned by `consume_pseudo` as `not(a, b)`. Arguments containing
+/// combinators are not node-local and stay plain pseudo-classes.
+fn parse_negation(pseudo: &str) -> Option<Selector> {
+    let inner = pseudo.strip_prefix("not(")?.strip_suffix(')')?;
+    let mut negated = Vec::new();
+    for argument in split_top_level(inner, ',') {
+        let argument = argument.trim();
+        if argument.is_empty()
+            || split_top_level(argument, ' ').len() > 1
+            || split_top_level(argument, '>').len() > 1
+        {
+            return None;
+        }
+        negated.push(parse_selector(argument));
+    }
+    negated.sort();
+    negated.dedup();
+    Some(Selector::Not(negated))
+}
+
+/// Parses the name returned by `consume_pseudo` as `has(a, b)` or `has(> a, > b)`.
+/// Arguments that mix combinators or hold more than one compound are not supported.
+fn parse_has(pseudo: &str) -> Option<Selector> {
+    let inner = pseudo.strip_prefix("has(")?.strip_suffix(')')?;
+    let mut child = None;
+    let mut arguments = Vec::new();
+    for argument in split_top_level(inner, ',') {
+        let argument = argument.trim();
+        let (is_child, argument) = match argument.strip_prefix('>') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, argument),
+        };
+        if argument.is_empty()
+            || *child.get_or_insert(is_child) != is_child
+            || split_top_level(argument, ' ').len() > 1
+            || split_top_level(argument, '>').len() > 1
+        {
+            return None;
+        }
+        arguments.push(parse_selector(argument));
     }
+    arguments.sort();
+    arguments.dedup();
+    Some(Selector::Has(HasSelector {
+        child: child?,
+        arguments,
+    }))
+}
+
+/// Parses the name returned by `consume_pseudo` as a structural pseudo-class such as
+/// `first-child`, `only-of-type` or `nth-last-child(2n+1)`.
+fn parse_nth(pseudo: &str) -> Option<NthSelector> {
+    let (kind, a, b) = match pseudo {
+        "first-child" => (NthKind::Child, 0, 1),
+        "last-child" => (NthKind::LastChild, 0, 1),
+        "only-child" => (NthKind::OnlyChild, 0, 1),
+        "first-of-type" => (NthKind::OfType, 0, 1),
+        "last-of-type" => (NthKind::LastOfType, 0, 1),
+        "only-of-type" => (NthKind::OnlyOfType, 0, 1),
+        _ => {
+            let (name, formula) = pseudo.strip_suffix(')')?.split_once('(')?;
+            let kind = match name {
+                "nth-child" => NthKind::Child,
+                "nth-last-child" => NthKind::LastChild,
+                "nth-of-type" => NthKind::OfType,
+                "nth-last-of-type" => NthKind::LastOfType,
+                _ => return None,
+            };
+            let (a, b) = parse_nth_formula(formula)?;
+            (kind, a, b)
+        }
+    };
+    Some(NthSelector { kind, a, b })
+}
+
+/// Parses an `an+b` formula, including the `odd` and `even` keywords.
+fn parse_nth_formula(formula: &str) -> Option<(i32, i32)> {
+    let formula: String = formula
+        .chars()
+        .filter(|ch| !ch.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    match formula.as_str() {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => {}
+    }
+    let Some((a, b)) = formula.split_once('n') else {
+        return Some((0, formula.parse().ok()?));
+    };
+    let a = match a {
+        "" | "+" => 1,
+        "-" => -1,
+        a => a.parse().ok()?,
+    };
+    let b = match b {
+        "" => 0,
+        b => b.strip_prefix('+').unwrap_or(b).parse().ok()?,
+    };
+    Some((a, b))
+}
+
+/// Splits `text` on `separator` where it is not nested in brackets, parentheses or quotes.
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut depth = 0usize;
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+    for (idx, ch) in text.char_indices() {
+        match ch {
+            '"' | '\'' if quote == Some(ch) => quote = None,
+            '"' | '\'' if quote.is_none() => quote = Some(ch),
+            '(' | '[' if quote.is_none() => depth += 1,
+            ')' | ']' if quote.is_none() => depth = depth.saturating_sub(1),
+            _ if ch == separator && quote.is_none() && depth == 0 => {
+                pieces.push(&text[start..idx]);
+                start = idx + ch.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces
 }
 
 fn consume_identifier(selector: &str, start: usize) -> (String, usize) {
@@ -1134,16 +3640,12 @@ fn consume_identifier(selector: &str, start: usize) -> (String, usize) {
     (ident, pos)
 }
 
-fn consume_attribute(selector: &str, start: usize) -> Option<((String, String), usize)> {
+fn consume_attribute(selector: &str, start: usize) -> Option<(Selector, usize)> {
     let remainder = &selector[start..];
     let closing = remainder.find(']')?;
     let end = start + closing + 1;
     let slice = &selector[start..end];
-    if let Some(Selector::AttributeEquals { name, value }) = parse_attribute_selector(slice) {
-        Some(((name, value), end))
-    } else {
-        None
-    }
+    parse_attribute_selector(slice).map(|attribute| (attribute, end))
 }
 
 fn consume_pseudo(selector: &str, start: usize) -> (String, usize) {
@@ -1179,7 +3681,11 @@ fn consume_pseudo(selector: &str, start: usize) -> (String, usize) {
         return (String::new(), idx);
     }
 
-    let mut pseudo = name.to_ascii_lowercase();
+    // Only the name is case-insensitive; arguments keep class names and attribute values.
+    let mut pseudo = match name.split_once('(') {
+        Some((head, arguments)) => format!("{}({}", head.to_ascii_lowercase(), arguments),
+        None => name.to_ascii_lowercase(),
+    };
     if colon_count >= 2 {
         pseudo = format!("::{}", pseudo);
     }
@@ -1201,6 +3707,129 @@ pub const PSEUDO_CLASS_HOVER_ROOT: &str = "hover-root";
 pub const PSEUDO_CLASS_FOCUS: &str = "focus";
 pub const PSEUDO_CLASS_FOCUS_ROOT: &str = "focus-root";
 pub const PSEUDO_CLASS_FOCUS_WITHIN: &str = "focus-within";
+pub const PSEUDO_CLASS_ROOT: &str = "root";
+pub const PSEUDO_CLASS_EMPTY: &str = "empty";
+pub const PSEUDO_CLASS_CHECKED: &str = "checked";
+pub const PSEUDO_CLASS_DISABLED: &str = "disabled";
+pub const PSEUDO_CLASS_ENABLED: &str = "enabled";
+pub const PSEUDO_CLASS_LINK: &str = "link";
+pub const PSEUDO_CLASS_VISITED: &str = "visited";
+
+/// Pseudo-classes engines keep in a node's computed pseudo-class set.
+pub const COMPUTED_PSEUDO_CLASSES: [&str; 10] = [
+    PSEUDO_CLASS_HOVER,
+    PSEUDO_CLASS_FOCUS,
+    PSEUDO_CLASS_FOCUS_WITHIN,
+    PSEUDO_CLASS_ROOT,
+    PSEUDO_CLASS_EMPTY,
+    PSEUDO_CLASS_CHECKED,
+    PSEUDO_CLASS_DISABLED,
+    PSEUDO_CLASS_ENABLED,
+    PSEUDO_CLASS_LINK,
+    PSEUDO_CLASS_VISITED,
+];
+
+/// The form and link state pseudo-classes. Unlike hover and focus they are node-local:
+/// each follows from the element's own tag, attributes and state flags.
+pub const ELEMENT_STATE_PSEUDO_CLASSES: [&str; 5] = [
+    PSEUDO_CLASS_CHECKED,
+    PSEUDO_CLASS_DISABLED,
+    PSEUDO_CLASS_ENABLED,
+    PSEUDO_CLASS_LINK,
+    PSEUDO_CLASS_VISITED,
+];
+
+/// Trace attributes that carry a state flag rather than an HTML attribute, with the
+/// pseudo-class flag each one sets while `true`.
+const STATE_FLAG_ATTRIBUTES: [(&str, &str); 7] = [
+    ("is_hovered_root", PSEUDO_CLASS_HOVER_ROOT),
+    ("is_focus_root", PSEUDO_CLASS_FOCUS_ROOT),
+    ("is_checked", PSEUDO_CLASS_CHECKED),
+    ("is_disabled", PSEUDO_CLASS_DISABLED),
+    ("is_enabled", PSEUDO_CLASS_ENABLED),
+    ("is_link", PSEUDO_CLASS_LINK),
+    ("is_visited", PSEUDO_CLASS_VISITED),
+];
+
+/// Elements that can be disabled, and so match `:enabled` otherwise.
+const FORM_CONTROL_ELEMENTS: [&str; 7] = [
+    "button", "fieldset", "input", "optgroup", "option", "select", "textarea",
+];
+
+/// The pseudo-class flag set by the state-flag attribute `key` (lowercase).
+pub fn state_flag_attribute(key: &str) -> Option<&'static str> {
+    STATE_FLAG_ATTRIBUTES
+        .iter()
+        .find(|(attribute, _)| *attribute == key)
+        .map(|(_, pseudo)| *pseudo)
+}
+
+/// Whether changing attribute `key` (lowercase) can change `derive_element_states`.
+pub fn affects_element_states(key: &str) -> bool {
+    matches!(key, "checked" | "selected" | "disabled" | "href" | "type")
+        || state_flag_attribute(key).is_some()
+}
+
+/// The element-state pseudo-classes of a `tag_name` element. Attributes give the HTML
+/// defaults (`checked` on checkbox and radio inputs, `selected` on options, `disabled` on
+/// form controls, `href` on links); explicit flags in `pseudo_flags` add to them.
+pub fn derive_element_states(
+    tag_name: &str,
+    attributes: &HashMap<String, String>,
+    pseudo_flags: &HashSet<String>,
+) -> Vec<&'static str> {
+    let tag = tag_name.to_ascii_lowercase();
+    let mut states = Vec::new();
+
+    let checked = match tag.as_str() {
+        "input" => {
+            attributes.get("type").is_some_and(|input_type| {
+                input_type.eq_ignore_ascii_case("checkbox")
+                    || input_type.eq_ignore_ascii_case("radio")
+            }) && attributes.contains_key("checked")
+        }
+        "option" => attributes.contains_key("selected"),
+        _ => false,
+    };
+    if checked || pseudo_flags.contains(PSEUDO_CLASS_CHECKED) {
+        states.push(PSEUDO_CLASS_CHECKED);
+    }
+
+    let form_control = FORM_CONTROL_ELEMENTS.contains(&tag.as_str());
+    if pseudo_flags.contains(PSEUDO_CLASS_DISABLED)
+        || (form_control && attributes.contains_key("disabled"))
+    {
+        states.push(PSEUDO_CLASS_DISABLED);
+    } else if form_control || pseudo_flags.contains(PSEUDO_CLASS_ENABLED) {
+        states.push(PSEUDO_CLASS_ENABLED);
+    }
+
+    let hyperlink = matches!(tag.as_str(), "a" | "area") && attributes.contains_key("href");
+    if pseudo_flags.contains(PSEUDO_CLASS_VISITED) {
+        states.push(PSEUDO_CLASS_VISITED);
+    } else if hyperlink || pseudo_flags.contains(PSEUDO_CLASS_LINK) {
+        states.push(PSEUDO_CLASS_LINK);
+    }
+    states
+}
+
+/// Brings the element-state pseudo-classes in `computed` in line with
+/// `derive_element_states`.
+pub fn refresh_element_states(
+    tag_name: &str,
+    attributes: &HashMap<String, String>,
+    pseudo_flags: &HashSet<String>,
+    computed: &mut HashSet<String>,
+) {
+    let states = derive_element_states(tag_name, attributes, pseudo_flags);
+    for state in ELEMENT_STATE_PSEUDO_CLASSES {
+        if states.contains(&state) {
+            computed.insert(state.to_string());
+        } else {
+            computed.remove(state);
+        }
+    }
+}
 
 pub fn derive_hover_state(pseudo_flags: &HashSet<String>, parent_hover: bool) -> bool {
     parent_hover
@@ -1208,6 +3837,22 @@ pub fn derive_hover_state(pseudo_flags: &HashSet<String>, parent_hover: bool) ->
         || pseudo_flags.contains(PSEUDO_CLASS_HOVER)
 }
 
+/// Trace nodes named `#text`, `#comment`, `#document`, ... are not elements and are
+/// skipped when sibling combinators look for the previous element sibling.
+pub fn is_element_name(name: &str) -> bool {
+    !name.starts_with('#')
+}
+
+/// Whether a trace node keeps its parent from matching `:empty`. Elements and text with
+/// non-empty `text` do; comments, shadow roots and empty text nodes do not.
+pub fn counts_as_content(node: &serde_json::Value) -> bool {
+    let name = node["name"].as_str().unwrap_or_default();
+    if node["type"].as_str() == Some("text") || name == "#text" {
+        return node["text"].as_str().is_some_and(|text| !text.is_empty());
+    }
+    is_element_name(name)
+}
+
 pub fn extract_pseudoclasses(node: &serde_json::Value) -> HashSet<String> {
     fn collect_from_value(value: &serde_json::Value, target: &mut HashSet<String>) {
         match value {
@@ -1220,10 +3865,8 @@ pub fn extract_pseudoclasses(node: &serde_json::Value) -> HashSet<String> {
                 for (key, val) in map {
                     let normalized = key.to_ascii_lowercase();
                     match val {
-                        serde_json::Value::Bool(active) => {
-                            if *active {
-                                target.insert(normalized.clone());
-                            }
+                        serde_json::Value::Bool(true) => {
+                            target.insert(normalized.clone());
                         }
                         serde_json::Value::String(s) => {
                             if !s.is_empty() {
@@ -1236,10 +3879,8 @@ pub fn extract_pseudoclasses(node: &serde_json::Value) -> HashSet<String> {
                     }
                 }
             }
-            serde_json::Value::String(s) => {
-                if !s.is_empty() {
-                    target.insert(s.to_ascii_lowercase());
-                }
+            serde_json::Value::String(s) if !s.is_empty() => {
+                target.insert(s.to_ascii_lowercase());
             }
             _ => {}
         }
@@ -1251,25 +3892,22 @@ pub fn extract_pseudoclasses(node: &serde_json::Value) -> HashSet<String> {
         "pseudo_classes",
         "pseudoclass",
         "pseudo_class",
+        "states",
+        "state",
     ] {
         if let Some(value) = node.get(key) {
             collect_from_value(value, &mut result);
         }
     }
     if let Some(attrs) = node.get("attributes").and_then(|attrs| attrs.as_object()) {
-        if attrs
-            .get("is_hovered_root")
-            .and_then(|value| value.as_bool())
-            .unwrap_or(false)
-        {
-            result.insert(PSEUDO_CLASS_HOVER_ROOT.to_string());
-        }
-        if attrs
-            .get("is_focus_root")
-            .and_then(|value| value.as_bool())
-            .unwrap_or(false)
-        {
-            result.insert(PSEUDO_CLASS_FOCUS_ROOT.to_string());
+        for (attribute, pseudo) in STATE_FLAG_ATTRIBUTES {
+            if attrs
+                .get(attribute)
+                .and_then(|value| value.as_bool())
+                .unwrap_or(false)
+            {
+                result.insert(pseudo.to_string());
+            }
         }
     }
     result
@@ -1278,6 +3916,7 @@ pub fn extract_pseudoclasses(node: &serde_json::Value) -> HashSet<String> {
 pub trait AddNode {
     /// Add a new node to the DOM.
     /// Returns the index of the new node.
+    #[allow(clippy::too_many_arguments)]
     fn add_node(
         &mut self,
         id: u64,
@@ -1294,6 +3933,7 @@ pub trait AddNode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::media::{ColorScheme, MediaKind};
 
     #[test]
     fn parse_css_handles_attribute_selector() {
@@ -1302,20 +3942,61 @@ mod tests {
     }
 
     #[test]
-    fn parse_css_skips_media_queries() {
-        let selectors = parse_css(
-            r#"@media screen and (max-width: 600px) {
-                .hidden { display: none; }
+    fn parse_css_evaluates_conditional_rules() {
+        let css = r#"@media screen and (max-width: 600px) {
+                .narrow { display: none; }
             }
-            .visible { display: block; }"#,
+            @media (min-width: 601px) and (prefers-color-scheme: dark) { .dark { x: y } }
+            @media print { .print { x: y } }
+            @media not print { @layer base { .screen { x: y } } }
+            @supports (display: grid) { .grid, .visible { x: y } }
+            @supports not (display: grid) { .fallback { x: y } }
+            @media (400px < width <= 800px) { .mid { x: y } }
+            .visible { display: block; }"#;
+
+        let desktop = MediaEnvironment::default();
+        let parsed = parse_css_in_environment(css, &desktop);
+        assert_eq!(parsed.selectors, vec![".grid", ".screen", ".visible"]);
+        assert_eq!(
+            parsed.conditions,
+            BTreeMap::from([
+                (
+                    ".grid".to_string(),
+                    vec!["@supports (display: grid)".to_string()]
+                ),
+                (
+                    ".screen".to_string(),
+                    vec!["@media not print @layer base".to_string()]
+                ),
+            ])
+        );
+
+        let phone = MediaEnvironment {
+            width: 500.0,
+            ..MediaEnvironment::default()
+        };
+        let parsed = parse_css_in_environment(css, &phone);
+        assert_eq!(
+            parsed.selectors,
+            vec![".grid", ".mid", ".narrow", ".screen", ".visible"]
+        );
+
+        let dark_print = MediaEnvironment {
+            color_scheme: ColorScheme::Dark,
+            kind: MediaKind::Print,
+            ..MediaEnvironment::default()
+        };
+        let parsed = parse_css_in_environment(css, &dark_print);
+        assert_eq!(
+            parsed.selectors,
+            vec![".dark", ".grid", ".print", ".visible"]
         );
-        assert_eq!(selectors, vec![".visible".to_string()]);
     }
 
     #[test]
     fn parse_selector_returns_attribute_variant() {
         match parse_selector(r#"[data-id="item-1"]"#) {
-            Selector::AttributeEquals { name, value } => {
+            Selector::AttributeEquals { name, value, .. } => {
                 assert_eq!(name, "data-id");
                 assert_eq!(value, "item-1");
             }
@@ -1323,6 +4004,1102 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_css_handles_attribute_operators() {
+        let expected = [
+            "[hidden]",
+            r#"[rel~="nofollow"]"#,
+            r#"[lang|="en"]"#,
+            r#"a[href^="https"]"#,
+            r#"[src$=".png"]"#,
+            r#"[title*="x"]"#,
+        ];
+        let selectors = parse_css(&format!("{} {{ color: red; }}", expected.join(", ")));
+        assert_eq!(selectors.len(), expected.len());
+        for text in expected {
+            let selector = parse_selector(text);
+            assert_eq!(selector.to_string(), text);
+            assert!(
+                selectors.iter().any(|s| parse_selector(s) == selector),
+                "missing {text} in {selectors:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_css_rejects_namespaced_attributes() {
+        // Dropping the attribute would widen the rule to every `a`.
+        let parsed = parse_css_with_pseudo(r#"a[*|href^="x"], p { color: red; }"#);
+        assert_eq!(parsed.selectors, vec!["p"]);
+        assert_eq!(parsed.unsupported_selectors.len(), 1);
+    }
+
+    #[test]
+    fn parse_css_rejects_shadow_tree_combinators() {
+        // Stopping at the combinator would match `div` on its own.
+        let parsed = parse_css_with_pseudo("div::slotted(span), p { color: red; }");
+        assert_eq!(parsed.selectors, vec!["p"]);
+        assert_eq!(parsed.unsupported_selectors.len(), 1);
+    }
+
+    #[test]
+    fn attribute_operators_match_like_css() {
+        let attributes = HashMap::from([
+            ("rel".to_string(), "noopener nofollow".to_string()),
+            ("lang".to_string(), "en-US".to_string()),
+            ("hidden".to_string(), String::new()),
+        ]);
+        let matches = |selector: &str| parse_selector(selector).matches_attributes(&attributes);
+        assert!(matches("[hidden]"));
+        assert!(!matches("[missing]"));
+        assert!(matches(r#"[rel~="nofollow"]"#));
+        assert!(!matches(r#"[rel~="follow"]"#));
+        assert!(matches(r#"[lang|="en"]"#));
+        assert!(!matches(r#"[lang|="e"]"#));
+        assert!(matches(r#"[lang^="en"]"#));
+        assert!(matches(r#"[lang$="US"]"#));
+        assert!(matches(r#"[rel*="open"]"#));
+        assert!(!matches(r#"[rel*=""]"#));
+    }
+
+    #[test]
+    fn attribute_case_flags_and_html_defaults() {
+        assert!(HTML_CASE_INSENSITIVE_ATTRIBUTES.is_sorted());
+        let selectors = parse_css(
+            r#"[data-x="Foo" i], [type="Checkbox"], [type="Checkbox" s], [data-x="Foo"] { x: y }"#,
+        );
+        let expected = [
+            r#"[data-x="Foo" i]"#,
+            r#"[data-x="Foo"]"#,
+            r#"[type="Checkbox" s]"#,
+            r#"[type="Checkbox"]"#,
+        ];
+        assert_eq!(selectors, expected);
+        for text in expected {
+            assert_eq!(parse_selector(text).to_string(), text);
+        }
+
+        let attributes = HashMap::from([
+            ("data-x".to_string(), "FOO".to_string()),
+            ("type".to_string(), "checkbox".to_string()),
+        ]);
+        let matches = |selector: &str| parse_selector(selector).matches_attributes(&attributes);
+        assert!(matches(r#"[data-x="Foo" i]"#));
+        assert!(matches(r#"[data-x^="fo" i]"#));
+        assert!(!matches(r#"[data-x="Foo"]"#));
+        assert!(matches(r#"[type="Checkbox"]"#));
+        assert!(matches(r#"[type~="CHECKBOX"]"#));
+        assert!(!matches(r#"[type="Checkbox" s]"#));
+    }
+
+    #[test]
+    fn generate_nfa_reads_sibling_output_for_sibling_combinators() {
+        let mut sm = SelectorManager::new();
+        let mut state = 0;
+        let nfa = generate_nfa(
+            &[parse_complex_selector("h1 + p").unwrap()],
+            &[],
+            &mut sm,
+            &mut state,
+        );
+        assert!(
+            nfa.sibling_rules
+                .iter()
+                .any(|Rule(selector, prev, next)| selector.is_some()
+                    && prev.is_some()
+                    && nfa.accept_states.iter().any(|accept| accept.state == *next))
+        );
+
+        let nfa = generate_nfa(
+            &[parse_complex_selector("h1 ~ p").unwrap()],
+            &[],
+            &mut sm,
+            &mut state,
+        );
+        assert!(
+            nfa.sibling_rules
+                .iter()
+                .any(|Rule(selector, prev, next)| selector.is_none() && prev == &Some(*next))
+        );
+    }
+
+    #[test]
+    fn parse_css_keeps_compound_negation() {
+        let parsed = parse_css_with_pseudo(
+            "a:not(.hidden), .list:not(.a, [hidden]) > li, li:not(p a) { color: red; }",
+        );
+        assert_eq!(
+            parsed.selectors,
+            vec![
+                ".list:not(.a,[hidden])>li".to_string(),
+                "a:not(.hidden)".to_string()
+            ]
+        );
+        assert_eq!(
+            parsed.pseudo_selectors.get(":not"),
+            Some(&vec!["li:not(p a)".to_string()])
+        );
+        match parse_selector("a:not(.hidden)") {
+            Selector::Compound(compound) => {
+                assert_eq!(compound.tag.as_deref(), Some("a"));
+                assert_eq!(
+                    compound.negations,
+                    vec![Selector::Not(vec![Selector::Class("hidden".into())])]
+                );
+            }
+            other => panic!("expected compound selector, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_css_does_not_compile_selectors_with_an_unsupported_pseudo_class() {
+        let parsed = parse_css_with_pseudo("a:hover, td:hover:nth-col(2) { color: red; }");
+        assert_eq!(parsed.selectors, vec!["a:hover"]);
+        assert_eq!(
+            parsed.pseudo_selectors.keys().collect::<Vec<_>>(),
+            vec![":nth-col"]
+        );
+        assert!(!parsed.origins.contains_key("td:hover:nth-col(2)"));
+    }
+
+    #[test]
+    fn parse_css_converts_structural_pseudo_classes() {
+        let parsed = parse_css_with_pseudo(
+            "li:first-child, tr:nth-child(odd) td, p:nth-last-of-type(-n + 3), \
+             li:not(:last-child), td:nth-col(2) { color: red; }",
+        );
+        assert_eq!(
+            parsed.selectors,
+            vec![
+                "li:first-child",
+                "li:not(:last-child)",
+                "p:nth-last-of-type(-n+3)",
+                "tr:nth-child(2n+1) td",
+            ]
+        );
+        assert!(parsed.pseudo_selectors.contains_key(":nth-col"));
+        // The text form reads back into the same compounds.
+        for parts in parsed.parts.values() {
+            for (selector, _) in parts {
+                assert_eq!(&parse_selector(&selector.to_string()), selector);
+            }
+        }
+
+        let nth = NthSelector {
+            kind: NthKind::Child,
+            a: 2,
+            b: 1,
+        };
+        let at = |index| SiblingPosition {
+            index,
+            index_from_end: 1,
+            type_index: 1,
+            type_index_from_end: 1,
+        };
+        assert!(nth.matches(&at(1)) && nth.matches(&at(3)));
+        assert!(!nth.matches(&at(2)) && !nth.matches(&at(0)));
+        assert_eq!(parse_nth_formula("-n+3"), Some((-1, 3)));
+        assert_eq!(parse_nth_formula("even"), Some((2, 0)));
+        assert_eq!(parse_nth_formula(" 3n - 2 "), Some((3, -2)));
+    }
+
+    #[test]
+    fn parse_css_converts_root_and_empty() {
+        let parsed = parse_css_with_pseudo(":root, :root > body, p:empty, a:not(:empty) { x: y }");
+        assert_eq!(
+            parsed.selectors,
+            vec![":root", ":root>body", "a:not(:empty)", "p:empty"]
+        );
+        assert!(parsed.pseudo_selectors.is_empty());
+        match parse_selector("p:empty") {
+            Selector::Compound(compound) => {
+                assert_eq!(compound.tag.as_deref(), Some("p"));
+                assert!(compound.pseudos.contains(PSEUDO_CLASS_EMPTY));
+            }
+            other => panic!("expected compound selector, got {:?}", other),
+        }
+        assert!(counts_as_content(
+            &serde_json::json!({"name": "#text", "text": "x"})
+        ));
+        assert!(!counts_as_content(
+            &serde_json::json!({"name": "#text", "type": "text", "text": ""})
+        ));
+        assert!(!counts_as_content(
+            &serde_json::json!({"name": "#comment", "type": "comment"})
+        ));
+        assert!(counts_as_content(&serde_json::json!({"name": "SPAN"})));
+    }
+
+    #[test]
+    fn element_states_follow_attributes_and_flags() {
+        let parsed = parse_css_with_pseudo(
+            "input:checked, :disabled + label, a:link, a:visited, button:enabled { x: y }",
+        );
+        assert_eq!(
+            parsed.selectors,
+            vec![
+                ":disabled + label",
+                "a:link",
+                "a:visited",
+                "button:enabled",
+                "input:checked"
+            ]
+        );
+        assert!(parsed.pseudo_selectors.is_empty());
+
+        let attributes = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let none = HashSet::new();
+        let checkbox = attributes(&[("type", "checkbox"), ("checked", "")]);
+        assert_eq!(
+            derive_element_states("INPUT", &checkbox, &none),
+            vec![PSEUDO_CLASS_CHECKED, PSEUDO_CLASS_ENABLED]
+        );
+        let text = attributes(&[("type", "text"), ("checked", ""), ("disabled", "")]);
+        assert_eq!(
+            derive_element_states("input", &text, &none),
+            vec![PSEUDO_CLASS_DISABLED]
+        );
+        let link = attributes(&[("href", "/")]);
+        assert_eq!(
+            derive_element_states("A", &link, &none),
+            vec![PSEUDO_CLASS_LINK]
+        );
+        assert!(derive_element_states("div", &link, &none).is_empty());
+
+        let node = serde_json::json!({
+            "name": "A",
+            "attributes": {"href": "/", "is_focus_root": false},
+            "states": {"visited": true, "checked": false}
+        });
+        let flags = extract_pseudoclasses(&node);
+        assert_eq!(flags, HashSet::from([PSEUDO_CLASS_VISITED.to_string()]));
+        assert_eq!(
+            derive_element_states("A", &link, &flags),
+            vec![PSEUDO_CLASS_VISITED]
+        );
+        assert_eq!(
+            state_flag_attribute("is_checked"),
+            Some(PSEUDO_CLASS_CHECKED)
+        );
+        assert!(affects_element_states("disabled") && !affects_element_states("class"));
+    }
+
+    #[test]
+    fn parse_css_compiles_pseudo_elements_on_originating_element() {
+        let parsed = parse_css_with_pseudo(
+            ".a::before, .a:hover::after, li > ::marker, .b:before, input::placeholder { x: y }",
+        );
+        assert_eq!(
+            parsed.selectors,
+            vec![".a::before", ".a:hover::after", ".b::before", "li>::marker"]
+        );
+        assert_eq!(
+            parsed.pseudo_selectors.get("::placeholder"),
+            Some(&vec!["input::placeholder".to_string()])
+        );
+        match parse_selector(".a::before") {
+            Selector::Compound(compound) => {
+                assert!(compound.classes.contains("a"));
+                assert_eq!(compound.pseudo_element.as_deref(), Some("before"));
+            }
+            other => panic!("expected compound selector, got {:?}", other),
+        }
+
+        // `.a` and `.a::before` match the same element but accept in different states.
+        let mut sm = SelectorManager::new();
+        let nfa = generate_nfa(
+            &[".a", ".a::before"].map(|x| parse_complex_selector(x).unwrap()),
+            &[],
+            &mut sm,
+            &mut 0,
+        );
+        assert_eq!(nfa.accept_states.len(), 2);
+    }
+
+    #[test]
+    fn parse_css_converts_has() {
+        let parsed = parse_css_with_pseudo(
+            "div:has(> .a), ul:has(li.b, p), a:has(+ b), p:has(:hover) { x: y }",
+        );
+        assert_eq!(parsed.selectors, vec!["div:has(>.a)", "ul:has(p,li.b)"]);
+        assert_eq!(
+            parsed.pseudo_selectors.get(":has"),
+            Some(&vec![
+                "a:has( + b)".to_string(),
+                "p:has(:hover)".to_string()
+            ])
+        );
+        match parse_selector("div:has(>.a)") {
+            Selector::Compound(compound) => {
+                assert_eq!(compound.tag.as_deref(), Some("div"));
+                assert_eq!(
+                    compound.has,
+                    vec![Selector::Has(HasSelector {
+                        child: true,
+                        arguments: vec![Selector::Class("a".to_string())],
+                    })]
+                );
+            }
+            other => panic!("expected compound selector, got {:?}", other),
+        }
+        let (compiled, origins) = parsed.nfa_inputs(&parsed.selectors);
+        let nfa = generate_nfa(&compiled, &origins, &mut SelectorManager::new(), &mut 0);
+        assert_eq!(nfa.has_selectors.len(), 2);
+    }
+
+    #[test]
+    fn parse_css_flattens_nesting() {
+        let parsed = parse_css_with_pseudo(
+            r#".a, #x {
+                color: red;
+                .b { x: y }
+                > .c { x: y }
+                &:hover, .d & { x: y }
+                @media (min-width: 1px) { .e { x: y } }
+                .f { .g & { x: y } }
+            }
+            :is(.m, .n) { & .o { x: y } }
+            .z:active { & .y { x: y } }
+            [data-x="&"] { & .w { x: y } }
+            & .top { x: y }"#,
+        );
+        assert_eq!(
+            parsed.selectors,
+            vec![
+                "#x",
+                "#x .b",
+                "#x .e",
+                "#x .f",
+                "#x:hover",
+                "#x>.c",
+                ".a",
+                ".a .b",
+                ".a .e",
+                ".a .f",
+                ".a:hover",
+                ".a>.c",
+                ".d #x",
+                ".d .a",
+                ".m",
+                ".m .o",
+                ".n",
+                ".n .o",
+                r#"[data-x="&"]"#,
+                r#"[data-x="&"] .w"#,
+            ]
+        );
+        assert_eq!(
+            parsed.sources.get(".m .o"),
+            Some(&vec![":is(.m, .n) .o".to_string()])
+        );
+        assert_eq!(
+            parsed.conditions.get(".a .e"),
+            Some(&vec!["@media (width >= 1px)".to_string()])
+        );
+        // `&` for a complex parent past the first compound is `:is()`, not a prefix.
+        assert_eq!(
+            parsed.pseudo_selectors.get(":is"),
+            Some(&vec![
+                ".g :is(#x .f)".to_string(),
+                ".g :is(.a .f)".to_string()
+            ])
+        );
+        assert_eq!(
+            parsed.pseudo_selectors.get(":active"),
+            Some(&vec![".z:active".to_string(), ".z:active .y".to_string()])
+        );
+        assert_eq!(parsed.unsupported_selectors, vec!["& .top"]);
+    }
+
+    #[test]
+    fn selectors_carry_specificity_and_source_order_into_the_nfa() {
+        let parsed = parse_css_with_pseudo(
+            "#n .a, p { x: y }
+            :is(.b, #m) span { x: y }
+            .c { & p { x: y } }
+            p { x: y }",
+        );
+        let origin = |a, b, c, source_index, rule_index| SelectorOrigin {
+            specificity: Specificity(a, b, c),
+            source_index,
+            rule_index,
+        };
+        assert_eq!(parsed.origins["#n .a"], vec![origin(1, 1, 0, 0, 0)]);
+        assert_eq!(
+            parsed.origins["p"],
+            vec![origin(0, 0, 1, 1, 0), origin(0, 0, 1, 5, 4)]
+        );
+        // An expansion keeps the specificity of the selector it was written as.
+        assert_eq!(parsed.origins[".b span"], vec![origin(1, 0, 1, 2, 1)]);
+        assert_eq!(parsed.origins["#m span"], vec![origin(1, 0, 1, 2, 1)]);
+        assert_eq!(parsed.origins[".c p"], vec![origin(0, 1, 1, 4, 3)]);
+
+        let (compiled, origins) = parsed.nfa_inputs(&parsed.selectors);
+        let nfa = generate_nfa(&compiled, &origins, &mut SelectorManager::new(), &mut 0);
+        for (selector, accept) in parsed.selectors.iter().zip(&nfa.accept_states) {
+            assert_eq!(&accept.origins, &parsed.origins[selector]);
+        }
+        let all = StateBits::new(nfa.max_state_id.0 + 1).complement();
+        let ranked: Vec<(&str, usize)> = nfa
+            .cascade_order(&all)
+            .into_iter()
+            .map(|(idx, origin)| (parsed.selectors[idx].as_str(), origin.source_index))
+            .collect();
+        assert_eq!(
+            ranked,
+            vec![
+                ("p", 1),
+                ("p", 5),
+                (".c", 3),
+                (".c p", 4),
+                ("#m span", 2),
+                (".b span", 2),
+                ("#n .a", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn cascade_declarations_ranks_importance_specificity_and_order() {
+        let parsed = parse_css_with_pseudo(
+            "div p { color: red !important; margin: 0 1px; --x: a }
+            #main p { color: blue; margin-left: 2px }
+            div p { margin-top: 3px; color: green !important }
+            body p { margin-left: 4px }",
+        );
+        assert_eq!(
+            parsed.declarations[0],
+            vec![
+                Declaration {
+                    property: "margin-top".to_string(),
+                    value: "0".to_string(),
+                    important: false,
+                },
+                Declaration {
+                    property: "margin-right".to_string(),
+                    value: "1px".to_string(),
+                    important: false,
+                },
+                Declaration {
+                    property: "margin-bottom".to_string(),
+                    value: "0".to_string(),
+                    important: false,
+                },
+                Declaration {
+                    property: "margin-left".to_string(),
+                    value: "1px".to_string(),
+                    important: false,
+                },
+                Declaration {
+                    property: "--x".to_string(),
+                    value: "a".to_string(),
+                    important: false,
+                },
+                Declaration {
+                    property: "color".to_string(),
+                    value: "red".to_string(),
+                    important: true,
+                },
+            ]
+        );
+
+        let mut matched: Vec<SelectorOrigin> = ["div p", "#main p", "body p"]
+            .iter()
+            .flat_map(|selector| parsed.origins[*selector].clone())
+            .collect();
+        matched.sort();
+        let computed = cascade_declarations(&matched, &parsed.declarations);
+        let value = |property: &str| computed.get(property).map(String::as_str);
+        // The later of two `!important` declarations wins over the more specific rule.
+        assert_eq!(value("color"), Some("green"));
+        assert_eq!(value("margin-left"), Some("2px"));
+        assert_eq!(value("margin-top"), Some("3px"));
+        assert_eq!(value("margin-right"), Some("1px"));
+        assert_eq!(value("--x"), Some("a"));
+    }
+
+    #[test]
+    fn cascade_order_leaves_out_pseudo_element_selectors() {
+        let parsed = parse_css_with_pseudo("p::before { color: red } p { margin-top: 0 }");
+        let mut sm = SelectorManager::new();
+        let (compiled, origins) = parsed.nfa_inputs(&parsed.selectors);
+        let nfa = generate_nfa(&compiled, &origins, &mut sm, &mut 0);
+        let mut output = StateBits::new(nfa.max_state_id.0 + 1);
+        for accept in &nfa.accept_states {
+            output.insert(accept.state.0);
+        }
+        // `p::before` matches on the `<p>`, but styles the generated box only.
+        let matched = nfa.cascade_order(&output);
+        assert_eq!(matched.len(), 1);
+        let computed = cascade_declarations(
+            matched.iter().map(|(_, origin)| origin),
+            &parsed.declarations,
+        );
+        assert_eq!(computed.get("margin-top").map(String::as_str), Some("0"));
+        assert_eq!(computed.get("color"), None);
+    }
+
+    #[test]
+    fn inherited_values_flow_from_parent_unless_overridden() {
+        let style = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(property, value)| (property.to_string(), value.to_string()))
+                .collect()
+        };
+        let parent = style(&[("color", "red"), ("--x", "1"), ("font-family", "serif")]);
+        let own = style(&[
+            ("color", "inherit"),
+            ("--x", "2"),
+            ("margin-top", "0"),
+            ("font-family", "unset"),
+        ]);
+        assert_eq!(
+            inherit_style(&parent, &own),
+            style(&[
+                ("color", "red"),
+                ("--x", "2"),
+                ("font-family", "serif"),
+                ("margin-top", "0"),
+            ])
+        );
+        assert!(inherits_from_parent(&own, "color"));
+        assert!(inherits_from_parent(&own, "line-height"));
+        assert!(!inherits_from_parent(&own, "--x"));
+        assert!(!inherits_from_parent(&own, "margin-top"));
+        assert!(!is_inherited_property("margin-top"));
+
+        let recolored = style(&[("color", "blue"), ("--x", "1"), ("font-family", "serif")]);
+        assert_eq!(
+            changed_properties(&parent, &recolored),
+            BTreeSet::from(["color".to_string()])
+        );
+    }
+
+    #[test]
+    fn generate_nfa_compiles_typed_selectors_without_resplitting() {
+        let parsed =
+            parse_css_with_pseudo(r#"[title="a > b"] span, p[data-x="c ~ d"] + .Q { x: y }"#);
+        let title = Selector::AttributeEquals {
+            name: "title".to_string(),
+            value: "a > b".to_string(),
+            case_insensitive: false,
+        };
+        assert_eq!(
+            parsed.parts[r#"[title="a > b"] span"#],
+            vec![
+                (title, Combinator::Descendant),
+                (Selector::Type("span".to_string()), Combinator::None),
+            ]
+        );
+        // Class names keep their case.
+        assert_eq!(
+            parsed.parts[r#"p[data-x="c ~ d"] + .Q"#][1],
+            (Selector::Class("Q".to_string()), Combinator::None)
+        );
+
+        let (compiled, origins) = parsed.nfa_inputs(&parsed.selectors);
+        let nfa = generate_nfa(&compiled, &origins, &mut SelectorManager::new(), &mut 0);
+        assert_eq!(nfa.accept_states.len(), 2);
+        // Two compounds each: a descendant rule pair and a sibling rule.
+        assert_eq!(nfa.rules.len(), 4);
+        assert_eq!(nfa.sibling_rules.len(), 1);
+    }
+
+    #[test]
+    fn dfa_steps_agree_with_the_nfa_and_fall_back_past_the_limit() {
+        let mut sm = SelectorManager::new();
+        let selectors = [".a .b", ".a > .c", ".a + .b"].map(|x| parse_complex_selector(x).unwrap());
+        let nfa = generate_nfa(&selectors, &[], &mut sm, &mut 0);
+        let [a, b, c] = [".a", ".b", ".c"].map(|x| sm.get_or_create_id(parse_selector(x)));
+        let accepts = |output: &StateBits| {
+            nfa.accept_states
+                .iter()
+                .map(|accept| output.get(accept.state.0))
+                .collect::<Vec<_>>()
+        };
+        let empty = StateBits::new(nfa.max_state_id.0 + 1);
+
+        let dfa = Dfa::new(&nfa, 64);
+        assert_eq!(dfa.state_count(), 1);
+        // <div class="a"><p class="b"/><p class="b c"/></div>
+        let root = dfa.step(&nfa, &empty, &empty, |id| id == a).unwrap();
+        let first = dfa.step(&nfa, &root, &empty, |id| id == b).unwrap();
+        let second = dfa
+            .step(&nfa, &root, &first, |id| id == b || id == c)
+            .unwrap();
+        assert_eq!(accepts(&root), [false, false, false]);
+        assert_eq!(accepts(&first), [true, false, false]);
+        // The first child is no `.a`, so the second is no `.a + .b`.
+        assert_eq!(accepts(&second), [true, true, false]);
+        // A `.b` following an `.a` sibling, which `root` stands in for.
+        let after_a = dfa.step(&nfa, &root, &root, |id| id == b).unwrap();
+        assert_eq!(accepts(&after_a), [true, false, true]);
+
+        // Stepping a letter again reuses its transition.
+        let (states, transitions) = (dfa.state_count(), dfa.transition_count());
+        assert_eq!(dfa.step(&nfa, &root, &empty, |id| id == b), Some(first));
+        assert_eq!(
+            (dfa.state_count(), dfa.transition_count()),
+            (states, transitions)
+        );
+        assert!(dfa.minimized_state_count(&nfa) <= states);
+        assert_eq!(dfa.fallback_count(), 0);
+
+        // Past the limit, new states are left to the NFA.
+        let small = Dfa::new(&nfa, 2);
+        let root = small.step(&nfa, &empty, &empty, |id| id == a).unwrap();
+        assert_eq!(small.step(&nfa, &root, &empty, |id| id == b), None);
+        assert_eq!((small.state_count(), small.fallback_count()), (2, 1));
+    }
+
+    #[test]
+    fn state_bits_apply_word_operations_across_words() {
+        use crate::state_bits::InputReads;
+
+        let bits = |ones: &[usize]| {
+            let mut bits = StateBits::new(130);
+            for &one in ones {
+                bits.insert(one);
+            }
+            bits
+        };
+        let a = bits(&[0, 63, 64, 129]);
+        let b = bits(&[63, 100, 129]);
+        assert_eq!(a.count_ones(), 4);
+        assert_eq!(a.complement().count_ones(), 126);
+        assert_eq!(a.diff(&b).iter_ones().collect::<Vec<_>>(), [0, 64, 100]);
+        assert!(a.differs_within(&b, &bits(&[100])));
+        assert!(!a.differs_within(&b, &bits(&[63, 129])));
+
+        let mut masked = StateBits::new(130);
+        masked.union_with_masked(&a, &b);
+        assert_eq!(masked, bits(&[63, 129]));
+        assert!(masked.is_subset(&a) && !a.is_subset(&b));
+        masked.union_with_missing(&b, &a);
+        assert_eq!(masked, bits(&[63, 100, 129]));
+        assert_eq!(masked, b);
+
+        let input = bits(&[1, 70]);
+        let mut reads = InputReads::new(130);
+        assert!(reads.read(&input, 70));
+        reads.read_masked(&input, &bits(&[1, 2]));
+        assert_eq!(reads.to_string().len(), 130);
+        assert_eq!(&reads.to_string()[..4], "_10_");
+        assert!(reads.consistent_with(&bits(&[1, 70, 90])));
+        assert!(!reads.consistent_with(&bits(&[1, 2, 70])));
+        assert!(!reads.consistent_with(&bits(&[70])));
+        assert!(InputReads::all(&input).is_read(129));
+    }
+
+    #[test]
+    fn compiled_stylesheet_round_trips_through_json() {
+        use crate::compiled::{CompiledStylesheet, FORMAT_VERSION, LoadError};
+
+        let stylesheet = CompiledStylesheet::compile(
+            "test",
+            r#"div > p.a#x, :is(.b, .c) span { color: red; }
+               li:nth-last-child(2n+1):not(.d) + [data-x^="v" i], ul ~ *:hover { margin: 0 !important; }
+               section:has(> img, .e) a::before { color: blue; }"#,
+        );
+        let json = stylesheet.to_json();
+        let loaded = CompiledStylesheet::from_json(&json).unwrap();
+        assert_eq!(loaded.nfa, stylesheet.nfa);
+        assert_eq!(loaded.selectors, stylesheet.selectors);
+        assert_eq!(loaded.sources, stylesheet.sources);
+        assert_eq!(loaded.declarations, stylesheet.declarations);
+        assert!(
+            loaded
+                .selector_manager
+                .selectors()
+                .eq(stylesheet.selector_manager.selectors())
+        );
+        assert_eq!(loaded.to_json(), json);
+
+        let mut newer = json.clone();
+        newer["version"] = (FORMAT_VERSION + 1).into();
+        assert!(matches!(
+            CompiledStylesheet::from_json(&newer),
+            Err(LoadError::Version(version)) if version == FORMAT_VERSION + 1
+        ));
+        let mut dangling = json;
+        dangling["nfa"]["rules"][0][0] = 100_000.into();
+        assert!(matches!(
+            CompiledStylesheet::from_json(&dangling),
+            Err(LoadError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn generated_rust_step_matches_rule_evaluation() {
+        use crate::codegen::{StepInput, StepPlan, generate_rust, run_generated_rust};
+
+        let mut sm = SelectorManager::new();
+        let selectors: Vec<ComplexSelector> = (0..24)
+            .flat_map(|i| {
+                [
+                    format!(".a{i} > .b{i} span"),
+                    format!("li.c{i} + li"),
+                    format!("p ~ .d{i}"),
+                ]
+            })
+            .map(|x| parse_complex_selector(&x).unwrap())
+            .collect();
+        let nfa = generate_nfa(&selectors, &[], &mut sm, &mut 0);
+        let plan = StepPlan::new(&nfa, &sm);
+        assert!(plan.state_words() > 1);
+
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut random_bits =
+            |len: usize| (0..len).map(|_| random() % 5 == 0).collect::<StateBits>();
+        let nodes: Vec<(StateBits, StepInput)> = (0..200)
+            .map(|_| {
+                let matched = random_bits(plan.predicates.len());
+                let predicates = plan
+                    .predicate_bits(|id| matched.get(plan.predicates.binary_search(&id).unwrap()));
+                let input = StepInput {
+                    predicates,
+                    parent_bits: random_bits(plan.states),
+                    sibling_bits: random_bits(plan.states),
+                };
+                (matched, input)
+            })
+            .collect();
+        let dir = std::env::temp_dir().join(format!("css-codegen-test-{}", std::process::id()));
+        let inputs: Vec<StepInput> = nodes.iter().map(|(_, input)| input.clone()).collect();
+        let outputs = run_generated_rust(&generate_rust(&plan), &plan, &inputs, &dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(outputs.len(), nodes.len());
+        for ((matched, input), output) in nodes.iter().zip(&outputs) {
+            let matches = |selector: Option<SelectorId>| {
+                selector.is_none_or(|id| matched.get(plan.predicates.binary_search(&id).unwrap()))
+            };
+            let mut expected = StateBits::new(plan.states);
+            for &Rule(selector, prev, Nfacell(c)) in &nfa.rules {
+                if matches(selector) && prev.is_none_or(|Nfacell(b)| input.parent_bits.get(b)) {
+                    expected.insert(c);
+                }
+            }
+            for &Rule(selector, prev, Nfacell(c)) in &nfa.sibling_rules {
+                if matches(selector) && prev.is_some_and(|Nfacell(b)| input.sibling_bits.get(b)) {
+                    expected.insert(c);
+                }
+            }
+            assert_eq!(*output, expected);
+        }
+    }
+
+    #[test]
+    fn pruning_keeps_only_what_the_vocabulary_can_fire() {
+        use crate::prune::{Vocabulary, prune};
+
+        let mut sm = SelectorManager::new();
+        let selectors = [
+            "div .a span",
+            ".b > p",
+            "li.c + li",
+            "#x",
+            "[data-y] em",
+            "div:has(.a) > span",
+        ]
+        .map(|x| parse_complex_selector(x).unwrap());
+        let mut nfa = generate_nfa(&selectors, &[], &mut sm, &mut 0);
+        let original = generate_nfa(&selectors, &[], &mut sm, &mut 0);
+        let vocabulary = Vocabulary {
+            tags: ["div", "span", "li", "em"].map(String::from).into(),
+            classes: ["a".to_string()].into(),
+            attributes: ["class".to_string()].into(),
+            ..Vocabulary::default()
+        };
+        let report = prune(&mut nfa, &mut sm, &vocabulary);
+        assert_eq!(report.selectors, (4, 6));
+        assert_eq!(report.states.1, original.max_state_id.0);
+
+        // Live states are numbered 1.. and the dead selectors share one state past them.
+        let sink = nfa.max_state_id;
+        assert_eq!(nfa.max_state_id.0, report.states.1 - report.states.0 + 1);
+        let dead: Vec<usize> = (0..6)
+            .filter(|&i| nfa.accept_states[i].state == sink)
+            .collect();
+        assert_eq!(dead, [1, 2, 3, 4]);
+        assert!(
+            nfa.rules
+                .iter()
+                .chain(&nfa.sibling_rules)
+                .all(|&Rule(_, prev, next)| next != sink && prev.is_none_or(|prev| prev.0 < sink.0))
+        );
+
+        // Down a chain of vocabulary nodes, both automata accept the same selectors.
+        let accepted = |nfa: &NFA, sm: &SelectorManager, chain: &[(&str, &[&str])]| {
+            let mut input = StateBits::new(nfa.max_state_id.0 + 1);
+            let mut matched = Vec::new();
+            for &(tag, classes) in chain {
+                let class_ids: HashSet<SelectorId> = classes
+                    .iter()
+                    .filter_map(|class| sm.get_id(&Selector::Class(class.to_string())))
+                    .collect();
+                let attributes = HashMap::new();
+                let (pseudos, has_ids) = (HashSet::new(), HashSet::new());
+                let node = NodeView {
+                    tag_id: sm
+                        .get_id(&Selector::Type(tag.to_string()))
+                        .unwrap_or_default(),
+                    class_ids: &class_ids,
+                    id_selector_id: None,
+                    attributes: &attributes,
+                    computed_pseudo_classes: &pseudos,
+                    position: &SiblingPosition::default(),
+                    has_ids: &has_ids,
+                };
+                let mut output = StateBits::new(input.len());
+                for &Rule(selector, prev, Nfacell(c)) in &nfa.rules {
+                    if selector.is_none_or(|id| sm.matches(id, &node))
+                        && prev.is_none_or(|Nfacell(b)| input.get(b))
+                    {
+                        output.insert(c);
+                    }
+                }
+                let node_matches: Vec<usize> = (0..nfa.accept_states.len())
+                    .filter(|&i| output.get(nfa.accept_states[i].state.0))
+                    .collect();
+                matched.push(node_matches);
+                input = output;
+            }
+            matched
+        };
+        let chain: &[(&str, &[&str])] = &[
+            ("div", &[]),
+            ("li", &["a"]),
+            ("em", &[]),
+            ("span", &["a"]),
+            ("span", &[]),
+        ];
+        let expected = accepted(&original, &sm, chain);
+        assert!(expected.iter().flatten().any(|&i| i == 0));
+        assert_eq!(accepted(&nfa, &sm, chain), expected);
+    }
+
+    #[test]
+    fn rule_index_buckets_rules_by_a_required_predicate() {
+        let mut sm = SelectorManager::new();
+        let selectors = ["div", "p.a#x", "[data-x] span", ":not(.a)", "* + [title=t]"]
+            .map(|x| parse_complex_selector(x).unwrap());
+        let nfa = generate_nfa(&selectors, &[], &mut sm, &mut 0);
+        let index = nfa.rule_index.as_ref().unwrap();
+        let [div, a, x] = [
+            Selector::Type("div".to_string()),
+            Selector::Class("a".to_string()),
+            Selector::Id("x".to_string()),
+        ]
+        .map(|selector| sm.get_or_create_id(selector));
+        // `p.a#x` is keyed by its id alone; `:not(.a)` and the self-loops by nothing.
+        assert!(index.rules.by_selector.contains_key(&x));
+        assert!(!index.rules.by_selector.contains_key(&a));
+        assert!(index.rules.by_attribute.contains_key("data-x"));
+        assert!(index.sibling_rules.by_attribute.contains_key("title"));
+
+        let select = |ids: &[SelectorId], attributes: &[&str]| {
+            index
+                .rules
+                .select(&nfa.rules, ids.iter().copied(), attributes.iter().copied())
+                .collect::<Vec<_>>()
+        };
+        let unkeyed = select(&[], &[]);
+        assert!(
+            unkeyed
+                .iter()
+                .all(|rule| rule.0.is_none() || rule.0 == sm.get_id(&parse_selector(":not(.a)")))
+        );
+        let for_div = select(&[div, a], &["data-x"]);
+        assert_eq!(for_div.len(), unkeyed.len() + 2);
+        assert_eq!(select(&[div, a, div], &["data-x"]), for_div);
+        // Selected rules keep their order in `nfa.rules`.
+        let positions: Vec<usize> = for_div
+            .iter()
+            .map(|rule| nfa.rules.iter().position(|other| other == *rule).unwrap())
+            .collect();
+        assert!(positions.is_sorted());
+    }
+
+    #[test]
+    fn compiled_selectors_match_through_interned_ids() {
+        let mut sm = SelectorManager::new();
+        let selector = parse_selector("div.a#x[data-y]:hover:not(.b)");
+        let id = sm.get_or_create_id(selector);
+        let [div, span, a, b, x] = [
+            sm.get_or_create_type_id("div"),
+            sm.get_or_create_type_id("span"),
+            sm.get_or_create_class_id("a"),
+            sm.get_or_create_class_id("b"),
+            sm.get_or_create_id_selector_id("x"),
+        ];
+        let classes = HashSet::from([a]);
+        let attributes = HashMap::from([("data-y".to_string(), String::new())]);
+        let pseudos = HashSet::from([PSEUDO_CLASS_HOVER.to_string()]);
+        let has_ids = HashSet::new();
+        let node = NodeView {
+            tag_id: div,
+            class_ids: &classes,
+            id_selector_id: Some(x),
+            attributes: &attributes,
+            computed_pseudo_classes: &pseudos,
+            position: &SiblingPosition::default(),
+            has_ids: &has_ids,
+        };
+        assert!(sm.matches(id, &node));
+        assert!(!sm.matches(
+            id,
+            &NodeView {
+                tag_id: span,
+                ..node
+            }
+        ));
+        assert!(!sm.matches(
+            id,
+            &NodeView {
+                id_selector_id: None,
+                ..node
+            }
+        ));
+        let with_b = HashSet::from([a, b]);
+        assert!(!sm.matches(
+            id,
+            &NodeView {
+                class_ids: &with_b,
+                ..node
+            }
+        ));
+        let no_pseudos = HashSet::new();
+        assert!(!sm.matches(
+            id,
+            &NodeView {
+                computed_pseudo_classes: &no_pseudos,
+                ..node
+            }
+        ));
+        let no_attributes = HashMap::new();
+        assert!(!sm.matches(
+            id,
+            &NodeView {
+                attributes: &no_attributes,
+                ..node
+            }
+        ));
+
+        // Unknown pseudo-classes never match; unknown ids neither.
+        let unknown = sm.get_or_create_id(parse_selector("div:unknown-state"));
+        assert!(!sm.matches(unknown, &node));
+        assert!(!sm.matches(SelectorId(usize::MAX), &node));
+    }
+
+    #[test]
+    fn generate_nfa_shares_states_between_common_prefixes() {
+        let selectors = [
+            ".nav .item",
+            ".nav .link",
+            ".nav > ul",
+            ".nav",
+            "h1 ~ p",
+            "h1 ~ a",
+        ]
+        .map(|x| parse_complex_selector(x).unwrap());
+        let nfa = generate_nfa(&selectors, &[], &mut SelectorManager::new(), &mut 0);
+        assert_eq!(unshared_state_count(&selectors), 13);
+        // The first two share `.nav` with its self-loop; `.nav > ul` and `.nav` share
+        // `.nav` without one; `h1 ~ a` reuses both the `h1` and the `~` state.
+        assert_eq!(nfa.max_state_id.0, 9);
+        let accept = |i: usize| nfa.accept_states[i].state;
+        assert_eq!(nfa.accept_states.len(), selectors.len());
+        assert_ne!(accept(0), accept(1));
+        assert_eq!(accept(3), Nfacell(4));
+        assert_eq!(
+            nfa.rules
+                .iter()
+                .filter(|rule| rule.1 == Some(Nfacell(1)) && rule.2 != Nfacell(1))
+                .count(),
+            2
+        );
+        assert_ne!(accept(4), accept(5));
+        assert_eq!(nfa.sibling_rules.len(), 6);
+    }
+
+    #[test]
+    fn parse_css_expands_is_and_where_with_source() {
+        let parsed = parse_css_with_pseudo(
+            ":is(.a, .b) p, ul :where(li, .x) > a, .a p, p :is(.a .b) { color: red; }",
+        );
+        assert_eq!(parsed.selectors, vec![".a p", ".b p", "ul .x>a", "ul li>a"]);
+        assert_eq!(
+            selector_sources(&parsed.sources, ".a p"),
+            vec![".a p", ":is(.a, .b) p"]
+        );
+        assert_eq!(
+            selector_sources(&parsed.sources, ".b p"),
+            vec![":is(.a, .b) p"]
+        );
+        assert_eq!(
+            selector_sources(&parsed.sources, "ul li>a"),
+            vec!["ul :where(li, .x) > a"]
+        );
+        // A complex argument past the first compound has no exact expansion.
+        assert!(parsed.pseudo_selectors.contains_key(":is"));
+    }
+
+    #[test]
+    fn parse_css_drops_is_arguments_with_conflicting_tags() {
+        let parsed = parse_css_with_pseudo(
+            "div:is(span), #a:is(#b), div:is(.x), div:is(*) p, *:is(li) { color: red; }",
+        );
+        assert_eq!(parsed.selectors, vec!["div p", "div.x", "li"]);
+    }
+
+    #[test]
+    fn css_wide_keywords_resolve_against_the_parent() {
+        let style = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(property, value)| (property.to_string(), value.to_string()))
+                .collect()
+        };
+        let parent = style(&[("color", "red"), ("margin-top", "4px"), ("--x", "1")]);
+
+        // `inherit` copies the parent's value even for a non-inherited property.
+        let own = style(&[("margin-top", "inherit")]);
+        assert!(inherits_from_parent(&own, "margin-top"));
+        assert_eq!(
+            inherit_style(&parent, &own),
+            style(&[("color", "red"), ("margin-top", "4px"), ("--x", "1")])
+        );
+
+        // `unset` inherits inherited properties and resets the others.
+        let own = style(&[("color", "unset"), ("margin-top", "unset")]);
+        assert!(inherits_from_parent(&own, "color"));
+        assert!(!inherits_from_parent(&own, "margin-top"));
+        assert_eq!(
+            inherit_style(&parent, &own),
+            style(&[("color", "red"), ("--x", "1")])
+        );
+
+        // `initial` resets the property, so children have nothing to inherit.
+        let own = style(&[("color", "initial"), ("--x", "initial")]);
+        assert!(!inherits_from_parent(&own, "color"));
+        let reset = inherit_style(&parent, &own);
+        assert_eq!(reset, BTreeMap::new());
+        assert_eq!(inherit_style(&reset, &BTreeMap::new()), BTreeMap::new());
+    }
+
     #[test]
     fn parse_selector_handles_class_and_pseudo() {
         match parse_selector(".foo:hover") {
@@ -1333,4 +5110,17 @@ mod tests {
             other => panic!("expected compound selector, got {:?}", other),
         }
     }
+
+    #[test]
+    fn parse_selector_keeps_class_name_case() {
+        // Class names are case-sensitive in standards mode, so the text form reported for
+        // `.Foo` must read back as the same class the stylesheet parser produced.
+        let parsed = parse_css_with_pseudo(".Foo, P:NOT(.Bar) { color: red; }");
+        assert!(parsed.selectors.contains(&".Foo".to_string()));
+        assert_eq!(parse_selector(".Foo"), Selector::Class("Foo".to_string()));
+        assert_ne!(parse_selector(".Foo"), parse_selector(".foo"));
+        // Pseudo-class names fold case, their arguments do not.
+        assert_eq!(parse_selector("p:NOT(.Bar)"), parse_selector("p:not(.Bar)"));
+        assert_ne!(parse_selector("p:not(.Bar)"), parse_selector("p:not(.bar)"));
+    }
 }
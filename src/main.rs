@@ -1,5 +1,10 @@
-use css_bitvector_compiler::{parse_css_with_pseudo, partition_simple_selectors};
-use std::{error::Error, fs, path::PathBuf};
+use css_bitvector_compiler::{parse_css_with_pseudo, partition_simple_selectors, selector_sources};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    path::PathBuf,
+};
 
 struct CssInput {
     label: String,
@@ -19,6 +24,8 @@ fn run() -> Result<(), Box<dyn Error>> {
     let parsed = parse_css_with_pseudo(&css);
     let (supported_selectors, skipped_simple) =
         partition_simple_selectors(parsed.selectors.clone());
+    let supported_selectors = author_selectors(&supported_selectors, &parsed.sources);
+    let skipped_simple = author_selectors(&skipped_simple, &parsed.sources);
 
     let mut pseudo_breakdown: Vec<(String, usize)> = parsed
         .pseudo_selectors
@@ -48,6 +55,10 @@ fn run() -> Result<(), Box<dyn Error>> {
     println!("Skipped simple selectors: {}", skipped_simple.len());
     println!("Unsupported pseudo-derived selectors: {pseudo_total}");
     println!("Unsupported complex selectors: {}", unsupported_total);
+    println!(
+        "Selectors from @media/@supports/@layer blocks: {}",
+        parsed.conditions.len()
+    );
 
     if !pseudo_breakdown.is_empty() {
         println!("\nTop unsupported pseudo-classes:");
@@ -79,6 +90,16 @@ fn run() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    if !parsed.conditions.is_empty() {
+        println!("\nExamples of conditional selectors:");
+        for (selector, blocks) in parsed.conditions.iter().take(10) {
+            println!("  {selector} in {}", blocks.join(", "));
+        }
+        if parsed.conditions.len() > 10 {
+            println!("  ...");
+        }
+    }
+
     if !supported_selectors.is_empty() {
         println!("\nSupported selector sample:");
         for selector in supported_selectors.iter().take(25) {
@@ -92,6 +113,17 @@ fn run() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Counts `:is()`/`:where()` selectors once, under the text the author wrote.
+fn author_selectors(selectors: &[String], sources: &BTreeMap<String, Vec<String>>) -> Vec<String> {
+    selectors
+        .iter()
+        .flat_map(|selector| selector_sources(sources, selector))
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
 fn resolve_input() -> Result<CssInput, Box<dyn Error>> {
     if let Some(path) = std::env::args().nth(1) {
         return Ok(CssInput {
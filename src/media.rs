@@ -0,0 +1,252 @@
+use lightningcss::{
+    media_query::{
+        MediaCondition, MediaFeatureComparison, MediaFeatureId, MediaFeatureName,
+        MediaFeatureValue, MediaList, MediaQuery, MediaType, Operator, Qualifier, QueryFeature,
+    },
+    rules::supports::SupportsCondition,
+    values::length::{Length, LengthValue},
+};
+
+/// Font size `em`/`rem` lengths resolve against in media queries.
+const DEFAULT_FONT_SIZE_PX: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Screen,
+    Print,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+/// The device `@media` blocks are evaluated against. Defaults to a 1920x1080 light
+/// screen; `MEDIA_WIDTH`, `MEDIA_HEIGHT`, `MEDIA_COLOR_SCHEME` (`light`/`dark`) and
+/// `MEDIA_TYPE` (`screen`/`print`) override it (see `from_env`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaEnvironment {
+    pub width: f32,
+    pub height: f32,
+    pub color_scheme: ColorScheme,
+    pub kind: MediaKind,
+}
+
+impl Default for MediaEnvironment {
+    fn default() -> Self {
+        MediaEnvironment {
+            width: 1920.0,
+            height: 1080.0,
+            color_scheme: ColorScheme::Light,
+            kind: MediaKind::Screen,
+        }
+    }
+}
+
+impl MediaEnvironment {
+    pub fn from_env() -> Self {
+        let mut environment = MediaEnvironment::default();
+        if let Some(width) = env_number("MEDIA_WIDTH") {
+            environment.width = width;
+        }
+        if let Some(height) = env_number("MEDIA_HEIGHT") {
+            environment.height = height;
+        }
+        match std::env::var("MEDIA_COLOR_SCHEME").as_deref() {
+            Ok("dark") => environment.color_scheme = ColorScheme::Dark,
+            Ok("light") => environment.color_scheme = ColorScheme::Light,
+            _ => {}
+        }
+        match std::env::var("MEDIA_TYPE").as_deref() {
+            Ok("print") => environment.kind = MediaKind::Print,
+            Ok("screen") => environment.kind = MediaKind::Screen,
+            _ => {}
+        }
+        environment
+    }
+
+    /// Whether any query of `media` matches. An empty list matches everything.
+    pub fn matches(&self, media: &MediaList) -> bool {
+        media.media_queries.is_empty()
+            || media
+                .media_queries
+                .iter()
+                .any(|query| self.matches_query(query))
+    }
+
+    fn matches_query(&self, query: &MediaQuery) -> bool {
+        let type_matches = match &query.media_type {
+            MediaType::All => true,
+            MediaType::Screen => self.kind == MediaKind::Screen,
+            MediaType::Print => self.kind == MediaKind::Print,
+            MediaType::Custom(_) => false,
+        };
+        let matches = type_matches
+            && query
+                .condition
+                .as_ref()
+                .is_none_or(|condition| self.matches_condition(condition));
+        if query.qualifier == Some(Qualifier::Not) {
+            !matches
+        } else {
+            matches
+        }
+    }
+
+    fn matches_condition(&self, condition: &MediaCondition) -> bool {
+        match condition {
+            MediaCondition::Feature(feature) => self.matches_feature(feature),
+            MediaCondition::Not(inner) => !self.matches_condition(inner),
+            MediaCondition::Operation {
+                operator: Operator::And,
+                conditions,
+            } => conditions.iter().all(|inner| self.matches_condition(inner)),
+            MediaCondition::Operation {
+                operator: Operator::Or,
+                conditions,
+            } => conditions.iter().any(|inner| self.matches_condition(inner)),
+        }
+    }
+
+    /// Features the environment does not model never match.
+    fn matches_feature(&self, feature: &QueryFeature<MediaFeatureId>) -> bool {
+        match feature {
+            QueryFeature::Plain { name, value } => self
+                .compare(name, value)
+                .is_some_and(|ordering| ordering.is_eq()),
+            QueryFeature::Boolean { name } => matches!(
+                standard_feature(name),
+                Some(
+                    MediaFeatureId::Width
+                        | MediaFeatureId::Height
+                        | MediaFeatureId::AspectRatio
+                        | MediaFeatureId::Orientation
+                        | MediaFeatureId::Color
+                        | MediaFeatureId::Hover
+                        | MediaFeatureId::AnyHover
+                        | MediaFeatureId::Pointer
+                        | MediaFeatureId::AnyPointer
+                        | MediaFeatureId::PrefersColorScheme
+                )
+            ),
+            QueryFeature::Range {
+                name,
+                operator,
+                value,
+            } => self
+                .compare(name, value)
+                .is_some_and(|ordering| comparison_holds(*operator, ordering)),
+            QueryFeature::Interval {
+                name,
+                start,
+                start_operator,
+                end,
+                end_operator,
+            } => {
+                // `start op1 feature op2 end`: the start comparison reads right to left.
+                self.compare(name, start)
+                    .is_some_and(|ordering| comparison_holds(*start_operator, ordering.reverse()))
+                    && self
+                        .compare(name, end)
+                        .is_some_and(|ordering| comparison_holds(*end_operator, ordering))
+            }
+        }
+    }
+
+    /// Orders the environment's value of `name` against `value`; `None` when they are
+    /// not comparable.
+    fn compare(
+        &self,
+        name: &MediaFeatureName<MediaFeatureId>,
+        value: &MediaFeatureValue,
+    ) -> Option<std::cmp::Ordering> {
+        match (standard_feature(name)?, value) {
+            (MediaFeatureId::Width, MediaFeatureValue::Length(length)) => {
+                self.width.partial_cmp(&length_px(length)?)
+            }
+            (MediaFeatureId::Height, MediaFeatureValue::Length(length)) => {
+                self.height.partial_cmp(&length_px(length)?)
+            }
+            (MediaFeatureId::AspectRatio, MediaFeatureValue::Ratio(ratio)) => {
+                (self.width * ratio.1).partial_cmp(&(self.height * ratio.0))
+            }
+            (MediaFeatureId::Orientation, MediaFeatureValue::Ident(ident)) => {
+                let orientation = if self.height >= self.width {
+                    "portrait"
+                } else {
+                    "landscape"
+                };
+                ident_ordering(&ident.0, orientation)
+            }
+            (MediaFeatureId::PrefersColorScheme, MediaFeatureValue::Ident(ident)) => {
+                let scheme = match self.color_scheme {
+                    ColorScheme::Light => "light",
+                    ColorScheme::Dark => "dark",
+                };
+                ident_ordering(&ident.0, scheme)
+            }
+            (MediaFeatureId::Hover | MediaFeatureId::AnyHover, MediaFeatureValue::Ident(ident)) => {
+                ident_ordering(&ident.0, "hover")
+            }
+            (
+                MediaFeatureId::Pointer | MediaFeatureId::AnyPointer,
+                MediaFeatureValue::Ident(ident),
+            ) => ident_ordering(&ident.0, "fine"),
+            (MediaFeatureId::PrefersReducedMotion, MediaFeatureValue::Ident(ident)) => {
+                ident_ordering(&ident.0, "no-preference")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether an `@supports` condition holds. Declarations and selectors are assumed
+/// supported; only the boolean structure is evaluated.
+pub fn supports_condition_holds(condition: &SupportsCondition) -> bool {
+    match condition {
+        SupportsCondition::Not(inner) => !supports_condition_holds(inner),
+        SupportsCondition::And(conditions) => conditions.iter().all(supports_condition_holds),
+        SupportsCondition::Or(conditions) => conditions.iter().any(supports_condition_holds),
+        SupportsCondition::Declaration { .. } | SupportsCondition::Selector(_) => true,
+        _ => false,
+    }
+}
+
+fn env_number(name: &str) -> Option<f32> {
+    std::env::var(name).ok()?.parse().ok()
+}
+
+fn standard_feature(name: &MediaFeatureName<MediaFeatureId>) -> Option<MediaFeatureId> {
+    match name {
+        MediaFeatureName::Standard(id) => Some(*id),
+        _ => None,
+    }
+}
+
+fn length_px(length: &Length) -> Option<f32> {
+    match length {
+        Length::Value(LengthValue::Em(value) | LengthValue::Rem(value)) => {
+            Some(value * DEFAULT_FONT_SIZE_PX)
+        }
+        _ => length.to_px(),
+    }
+}
+
+/// Identifiers only compare equal or not.
+fn ident_ordering(ident: &str, actual: &str) -> Option<std::cmp::Ordering> {
+    ident
+        .eq_ignore_ascii_case(actual)
+        .then_some(std::cmp::Ordering::Equal)
+}
+
+/// `feature <operator> value`, given how the feature orders against the value.
+fn comparison_holds(operator: MediaFeatureComparison, ordering: std::cmp::Ordering) -> bool {
+    match operator {
+        MediaFeatureComparison::Equal => ordering.is_eq(),
+        MediaFeatureComparison::GreaterThan => ordering.is_gt(),
+        MediaFeatureComparison::GreaterThanEqual => ordering.is_ge(),
+        MediaFeatureComparison::LessThan => ordering.is_lt(),
+        MediaFeatureComparison::LessThanEqual => ordering.is_le(),
+    }
+}
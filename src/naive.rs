@@ -4,12 +4,13 @@ use std::{
 };
 
 use css_bitvector_compiler::{
-    CompoundSelector, PSEUDO_CLASS_FOCUS, PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_FOCUS_WITHIN,
-    PSEUDO_CLASS_HOVER, PSEUDO_CLASS_HOVER_ROOT, ParsedSelectors, Selector, basic_node_from_json,
-    derive_hover_state, drain_supported_pseudo_selectors, is_simple_selector,
-    parse_css_with_pseudo, parse_selector, parse_trace, report_pseudo_selectors,
-    report_skipped_selectors, report_unsupported_selectors,
+    CompoundSelector, HasSelector, PSEUDO_CLASS_EMPTY, PSEUDO_CLASS_FOCUS, PSEUDO_CLASS_FOCUS_ROOT,
+    PSEUDO_CLASS_FOCUS_WITHIN, PSEUDO_CLASS_HOVER, PSEUDO_CLASS_ROOT, ParsedSelectors, Selector,
+    SiblingPosition, basic_node_from_json, derive_hover_state, is_element_name, is_simple_selector,
+    parse_css_with_pseudo, parse_selector, parse_trace, refresh_element_states,
+    report_pseudo_selectors, report_skipped_selectors, report_unsupported_selectors,
     runtime_shared::{BasicDomOps, apply_frame_basic},
+    selector_sources, state_flag_attribute,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -28,6 +29,15 @@ impl Display for CssRule {
     }
 }
 
+impl CssRule {
+    /// The compiled selector, which differs from the source for `:is()`/`:where()` expansions.
+    fn selector_text(&self) -> String {
+        match self {
+            CssRule::Complex { parts, .. } => parts.iter().map(|part| part.to_string()).collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct SelectorPart {
     selector: Selector,
@@ -40,6 +50,8 @@ impl Display for SelectorPart {
         match self.combinator {
             Combinator::Descendant => write!(f, " "),
             Combinator::Child => write!(f, " > "),
+            Combinator::NextSibling => write!(f, " + "),
+            Combinator::LaterSibling => write!(f, " ~ "),
             Combinator::None => Ok(()),
         }
     }
@@ -47,9 +59,11 @@ impl Display for SelectorPart {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum Combinator {
-    Descendant, // Space combinator
-    Child,      // >
-    None,       // The last selector has no combinator
+    Descendant,   // Space combinator
+    Child,        // >
+    NextSibling,  // +
+    LaterSibling, // ~
+    None,         // The last selector has no combinator
 }
 
 impl Display for Combinator {
@@ -57,6 +71,8 @@ impl Display for Combinator {
         match self {
             Combinator::Descendant => write!(f, " "),
             Combinator::Child => write!(f, ">"),
+            Combinator::NextSibling => write!(f, "+"),
+            Combinator::LaterSibling => write!(f, "~"),
             Combinator::None => write!(f, ""),
         }
     }
@@ -66,18 +82,20 @@ fn parse_css_rules(
     css_content: &str,
 ) -> (Vec<CssRule>, BTreeMap<String, Vec<String>>, Vec<String>) {
     let ParsedSelectors {
-        mut selectors,
-        mut pseudo_selectors,
+        selectors,
+        pseudo_selectors,
         unsupported_selectors,
+        sources,
+        ..
     } = parse_css_with_pseudo(css_content);
 
-    selectors.extend(drain_supported_pseudo_selectors(&mut pseudo_selectors));
-    selectors.sort();
-    selectors.dedup();
-
     let mut rules: Vec<CssRule> = selectors
-        .into_iter()
-        .filter_map(|selector| convert_selector_string_to_rule(&selector))
+        .iter()
+        .flat_map(|selector| {
+            selector_sources(&sources, selector)
+                .into_iter()
+                .filter_map(|source| convert_selector_string_to_rule(selector, source))
+        })
         .collect();
 
     rules.sort_by(|a, b| format!("{:?}", a).cmp(&format!("{:?}", b)));
@@ -85,7 +103,8 @@ fn parse_css_rules(
     (rules, pseudo_selectors, unsupported_selectors)
 }
 
-fn convert_selector_string_to_rule(selector: &str) -> Option<CssRule> {
+/// Builds the rule matching `selector`; its matches are reported under `source`.
+fn convert_selector_string_to_rule(selector: &str, source: &str) -> Option<CssRule> {
     let tokens = tokenize_rule(selector);
     let mut parts: Vec<SelectorPart> = Vec::new();
     let mut current_selector: Option<Selector> = None;
@@ -106,13 +125,17 @@ fn convert_selector_string_to_rule(selector: &str) -> Option<CssRule> {
                     pending_combinator = Combinator::None;
                 }
             }
-            RuleToken::Combinator(Combinator::Child) => {
+            RuleToken::Combinator(
+                combinator @ (Combinator::Child
+                | Combinator::NextSibling
+                | Combinator::LaterSibling),
+            ) => {
                 if current_selector.is_some() {
-                    pending_combinator = Combinator::Child;
+                    pending_combinator = combinator;
                 }
             }
             RuleToken::Combinator(Combinator::Descendant) => {
-                if current_selector.is_some() && !matches!(pending_combinator, Combinator::Child) {
+                if current_selector.is_some() && matches!(pending_combinator, Combinator::None) {
                     pending_combinator = Combinator::Descendant;
                 }
             }
@@ -132,7 +155,7 @@ fn convert_selector_string_to_rule(selector: &str) -> Option<CssRule> {
     } else {
         Some(CssRule::Complex {
             parts,
-            source: selector.trim().to_string(),
+            source: source.trim().to_string(),
         })
     }
 }
@@ -147,6 +170,7 @@ fn tokenize_rule(selector: &str) -> Vec<RuleToken> {
     let mut tokens = Vec::new();
     let mut current = String::new();
     let mut in_brackets = false;
+    let mut paren_depth = 0usize;
     let mut quote_char: Option<char> = None;
     let mut pending_descendant = false;
 
@@ -175,14 +199,27 @@ fn tokenize_rule(selector: &str) -> Vec<RuleToken> {
                 in_brackets = false;
                 current.push(ch);
             }
-            '>' if quote_char.is_none() && !in_brackets => {
+            '(' if quote_char.is_none() && !in_brackets => {
+                paren_depth += 1;
+                current.push(ch);
+            }
+            ')' if quote_char.is_none() && !in_brackets => {
+                paren_depth = paren_depth.saturating_sub(1);
+                current.push(ch);
+            }
+            '>' | '+' | '~' if quote_char.is_none() && !in_brackets && paren_depth == 0 => {
                 push_selector(&mut current, &mut tokens);
                 pending_descendant = false;
                 if matches!(tokens.last(), Some(RuleToken::Selector(_))) {
-                    tokens.push(RuleToken::Combinator(Combinator::Child));
+                    let combinator = match ch {
+                        '>' => Combinator::Child,
+                        '+' => Combinator::NextSibling,
+                        _ => Combinator::LaterSibling,
+                    };
+                    tokens.push(RuleToken::Combinator(combinator));
                 }
             }
-            c if c.is_whitespace() && quote_char.is_none() && !in_brackets => {
+            c if c.is_whitespace() && quote_char.is_none() && !in_brackets && paren_depth == 0 => {
                 if !current.is_empty() {
                     push_selector(&mut current, &mut tokens);
                 }
@@ -221,6 +258,7 @@ struct SimpleDomNode {
     pub classes: HashSet<String>,
     pub pseudo_classes: HashSet<String>,
     pub computed_pseudo_classes: HashSet<String>,
+    pub content: bool,
     pub parent: Option<u64>,
     pub children: Vec<u64>,
 }
@@ -235,6 +273,7 @@ impl SimpleDomNode {
             attributes: basic.attributes,
             classes: basic.classes,
             pseudo_classes: basic.pseudo_classes,
+            content: basic.content,
             ..Default::default()
         }
     }
@@ -262,12 +301,8 @@ impl SimpleDomNode {
                     self.attributes.remove("id");
                 }
             }
-            "is_hovered_root" | "is_focus_root" => {
-                let pseudo = if key_lower == "is_hovered_root" {
-                    PSEUDO_CLASS_HOVER_ROOT
-                } else {
-                    PSEUDO_CLASS_FOCUS_ROOT
-                };
+            flag if state_flag_attribute(flag).is_some() => {
+                let pseudo = state_flag_attribute(flag).unwrap();
                 let should_set = new_value
                     .as_deref()
                     .map(|value| value.eq_ignore_ascii_case("true"))
@@ -307,8 +342,8 @@ impl SimpleDom {
     }
 
     fn build_subtree(&mut self, node_json: &serde_json::Value, parent: Option<u64>) -> u64 {
-        let node_id = node_json["id"].as_u64().unwrap();
         let mut node = SimpleDomNode::from_json(node_json);
+        let node_id = node.id;
         node.parent = parent;
         self.nodes.insert(node_id, node);
         if let Some(children) = node_json["children"].as_array() {
@@ -440,16 +475,34 @@ impl SimpleDom {
 
             focus_root_active = node.pseudo_classes.contains(PSEUDO_CLASS_FOCUS_ROOT)
                 || node.pseudo_classes.contains(PSEUDO_CLASS_FOCUS);
+            refresh_element_states(
+                &node.tag_name,
+                &node.attributes,
+                &node.pseudo_classes,
+                &mut node.computed_pseudo_classes,
+            );
         }
         let mut focus_within_active = focus_root_active;
+        let mut empty = true;
         if let Some(children) = self.nodes.get(&node_id).map(|n| n.children.clone()) {
             for child_id in children {
                 if self.refresh_pseudo_recursive(child_id, hover_active) {
                     focus_within_active = true;
                 }
+                empty &= !self.nodes.get(&child_id).is_some_and(|child| child.content);
             }
         }
         if let Some(node) = self.nodes.get_mut(&node_id) {
+            if node.parent.is_none() {
+                node.computed_pseudo_classes
+                    .insert(PSEUDO_CLASS_ROOT.to_string());
+            }
+            if empty {
+                node.computed_pseudo_classes
+                    .insert(PSEUDO_CLASS_EMPTY.to_string());
+            } else {
+                node.computed_pseudo_classes.remove(PSEUDO_CLASS_EMPTY);
+            }
             if focus_root_active {
                 node.computed_pseudo_classes
                     .insert(PSEUDO_CLASS_FOCUS.to_string());
@@ -481,12 +534,13 @@ impl SimpleDom {
             }
             Selector::Class(class) => node.classes.contains(class),
             Selector::Id(id) => node.html_id.as_deref() == Some(id.as_str()),
-            Selector::AttributeEquals { name, value } => node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false),
             Selector::Compound(compound) => self.matches_compound_selector(node, compound),
+            Selector::Not(negated) => !negated
+                .iter()
+                .any(|selector| self.matches_simple_selector(node_id, selector)),
+            Selector::Nth(nth) => nth.matches(&self.sibling_position(node_id)),
+            Selector::Has(has) => self.matches_has(node_id, has),
+            attribute => attribute.matches_attributes(&node.attributes),
         }
     }
 
@@ -507,22 +561,31 @@ impl SimpleDom {
                 return false;
             }
         }
-        for (name, value) in &compound.attributes {
-            if node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false)
-            {
-                continue;
+        for attribute in &compound.attributes {
+            if !attribute.matches_attributes(&node.attributes) {
+                return false;
             }
-            return false;
         }
         for pseudo in &compound.pseudos {
             if !node.computed_pseudo_classes.contains(pseudo) {
                 return false;
             }
         }
+        for position in &compound.positions {
+            if !self.matches_simple_selector(node.id, position) {
+                return false;
+            }
+        }
+        for has in &compound.has {
+            if !self.matches_simple_selector(node.id, has) {
+                return false;
+            }
+        }
+        for negation in &compound.negations {
+            if !self.matches_simple_selector(node.id, negation) {
+                return false;
+            }
+        }
         true
     }
 
@@ -557,9 +620,90 @@ impl SimpleDom {
             Combinator::Descendant => parent_id
                 .map(|pid| self.matches_complex_selector_recursive(pid, &parts[..parts.len() - 1]))
                 .unwrap_or(false),
+            Combinator::NextSibling => self
+                .previous_element_siblings(node_id)
+                .next()
+                .map(|sid| self.matches_complex_selector(sid, &parts[..parts.len() - 1]))
+                .unwrap_or(false),
+            Combinator::LaterSibling => self
+                .previous_element_siblings(node_id)
+                .any(|sid| self.matches_complex_selector(sid, &parts[..parts.len() - 1])),
         }
     }
 
+    /// Element siblings before `node_id`, nearest first.
+    fn previous_element_siblings(&self, node_id: u64) -> impl Iterator<Item = u64> + '_ {
+        let siblings = self
+            .nodes
+            .get(&node_id)
+            .and_then(|node| node.parent)
+            .and_then(|pid| self.nodes.get(&pid))
+            .map(|parent| parent.children.as_slice())
+            .unwrap_or_default();
+        let position = siblings.iter().position(|&sid| sid == node_id).unwrap_or(0);
+        siblings[..position].iter().rev().copied().filter(|sid| {
+            self.nodes
+                .get(sid)
+                .is_some_and(|sibling| is_element_name(&sibling.tag_name))
+        })
+    }
+
+    /// Searches the element children (or, without `>`, all element descendants) of
+    /// `node_id` for one matching an argument of `has`.
+    fn matches_has(&self, node_id: u64, has: &HasSelector) -> bool {
+        let children = self
+            .nodes
+            .get(&node_id)
+            .map(|node| node.children.as_slice())
+            .unwrap_or_default();
+        children.iter().any(|&child_id| {
+            self.nodes
+                .get(&child_id)
+                .is_some_and(|child| is_element_name(&child.tag_name))
+                && (has
+                    .arguments
+                    .iter()
+                    .any(|argument| self.matches_simple_selector(child_id, argument))
+                    || (!has.child && self.matches_has(child_id, has)))
+        })
+    }
+
+    /// Counts the element siblings around `node_id` for `:nth-*` predicates.
+    fn sibling_position(&self, node_id: u64) -> SiblingPosition {
+        let Some(node) = self.nodes.get(&node_id) else {
+            return SiblingPosition::default();
+        };
+        if !is_element_name(&node.tag_name) {
+            return SiblingPosition::default();
+        }
+        let siblings = node
+            .parent
+            .and_then(|pid| self.nodes.get(&pid))
+            .map(|parent| parent.children.as_slice())
+            .unwrap_or_default();
+        let Some(position) = siblings.iter().position(|&sid| sid == node_id) else {
+            return SiblingPosition::default();
+        };
+        let mut result = SiblingPosition::default();
+        for (idx, sibling) in siblings
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, sid)| Some((idx, self.nodes.get(sid)?)))
+            .filter(|(_, sibling)| is_element_name(&sibling.tag_name))
+        {
+            let same_type = sibling.tag_name.eq_ignore_ascii_case(&node.tag_name);
+            if idx <= position {
+                result.index += 1;
+                result.type_index += usize::from(same_type);
+            }
+            if idx >= position {
+                result.index_from_end += 1;
+                result.type_index_from_end += usize::from(same_type);
+            }
+        }
+        result
+    }
+
     fn matches_complex_selector_recursive(&self, node_id: u64, parts: &[SelectorPart]) -> bool {
         if self.matches_complex_selector(node_id, parts) {
             return true;
@@ -588,14 +732,22 @@ impl SimpleDom {
     fn print_css_matches(&mut self, rules: &mut [CssRule]) {
         self.recompute_pseudo_states();
         rules.sort_by_key(|x| format!("{x:?}"));
+        // Expansions of one `:is()`/`:where()` selector report together under its source.
+        let mut by_source: BTreeMap<String, Vec<u64>> = BTreeMap::new();
         for rule in rules.iter() {
-            let mut matches = self.collect_rule_matches(rule);
+            let matches = self.collect_rule_matches(rule);
             if matches.is_empty() {
                 continue;
             }
+            by_source
+                .entry(rule.to_string())
+                .or_default()
+                .extend(matches);
+        }
+        for (source, mut matches) in by_source {
             matches.sort_unstable();
             matches.dedup();
-            let printable = rule.to_string().replace('>', " > ");
+            let printable = source.replace('>', " > ");
             println!("{} -> {:?}", printable, matches);
         }
     }
@@ -624,9 +776,9 @@ fn partition_rules(rules: Vec<CssRule>) -> (Vec<CssRule>, Vec<String>) {
     let mut skipped = Vec::new();
 
     for rule in rules {
-        let printable = rule.to_string();
-        if is_simple_selector(&printable) {
-            skipped.push(printable);
+        let selector = rule.selector_text();
+        if is_simple_selector(&selector) {
+            skipped.push(selector);
         } else {
             considered.push(rule);
         }
@@ -676,9 +828,14 @@ mod tests {
             CssRule::Complex { parts, .. } => {
                 assert_eq!(parts.len(), 1);
                 match &parts[0].selector {
-                    Selector::AttributeEquals { name, value } => {
+                    Selector::AttributeEquals {
+                        name,
+                        value,
+                        case_insensitive,
+                    } => {
                         assert_eq!(name, "data-role");
                         assert_eq!(value, "hero");
+                        assert!(!case_insensitive);
                     }
                     other => panic!("unexpected selector: {:?}", other),
                 }
@@ -689,8 +846,10 @@ mod tests {
     #[test]
     fn matches_attribute_selector_on_node() {
         let mut dom = SimpleDom::default();
-        let mut node = SimpleDomNode::default();
-        node.id = 1;
+        let mut node = SimpleDomNode {
+            id: 1,
+            ..Default::default()
+        };
         node.attributes.insert("data-id".into(), "item-1".into());
         dom.nodes.insert(1, node);
         dom.root_id = Some(1);
@@ -698,21 +857,231 @@ mod tests {
         let selector = Selector::AttributeEquals {
             name: "data-id".into(),
             value: "item-1".into(),
+            case_insensitive: false,
         };
         assert!(dom.matches_simple_selector(1, &selector));
 
         let mismatch = Selector::AttributeEquals {
             name: "data-id".into(),
             value: "item-2".into(),
+            case_insensitive: false,
         };
         assert!(!dom.matches_simple_selector(1, &mismatch));
     }
 
+    #[test]
+    fn matches_attribute_operators_on_node() {
+        let mut dom = SimpleDom::default();
+        let mut node = SimpleDomNode {
+            id: 1,
+            ..Default::default()
+        };
+        node.attributes
+            .insert("rel".into(), "noopener nofollow".into());
+        dom.nodes.insert(1, node);
+        dom.root_id = Some(1);
+
+        let (rules, _, _) =
+            parse_css_rules(r#"[rel], [rel~="nofollow"], [rel^="noop"] { color: red; }"#);
+        assert_eq!(rules.len(), 3);
+        for rule in &rules {
+            let CssRule::Complex { parts, .. } = rule;
+            assert!(dom.matches_simple_selector(1, &parts[0].selector));
+        }
+
+        let missing = Selector::AttributeExists {
+            name: "href".into(),
+        };
+        assert!(!dom.matches_simple_selector(1, &missing));
+    }
+
+    #[test]
+    fn matches_negated_compound() {
+        let mut dom = SimpleDom::default();
+        let mut node = SimpleDomNode {
+            id: 1,
+            tag_name: "a".into(),
+            ..Default::default()
+        };
+        node.classes.insert("visible".into());
+        dom.nodes.insert(1, node);
+        dom.root_id = Some(1);
+
+        assert!(dom.matches_simple_selector(1, &parse_selector("a:not(.hidden)")));
+        assert!(!dom.matches_simple_selector(1, &parse_selector(":not(.hidden, .visible)")));
+        assert!(!dom.matches_simple_selector(1, &parse_selector("a:not(a.visible)")));
+    }
+
+    #[test]
+    fn matches_structural_pseudo_classes() {
+        let mut dom = SimpleDom::default();
+        dom.init(&serde_json::json!({
+            "id": 1, "name": "UL", "type": "element", "attributes": {}, "children": [
+                {"id": 2, "name": "LI", "type": "element", "attributes": {}, "children": []},
+                {"id": 3, "name": "#text", "type": "text", "attributes": {}, "children": []},
+                {"id": 4, "name": "P", "type": "element", "attributes": {}, "children": []},
+                {"id": 5, "name": "LI", "type": "element", "attributes": {}, "children": []}
+            ]
+        }));
+        let matches = |id, selector| dom.matches_simple_selector(id, &parse_selector(selector));
+
+        assert!(matches(2, "li:first-child"));
+        assert!(!matches(4, ":first-child"));
+        assert!(matches(5, ":last-child:nth-child(3)"));
+        assert!(matches(4, ":nth-child(2n)"));
+        assert!(matches(5, "li:nth-of-type(2):last-of-type"));
+        assert!(matches(4, "p:only-of-type"));
+        assert!(!matches(3, ":nth-child(n)"));
+        assert!(!matches(1, ":first-child"));
+    }
+
+    #[test]
+    fn matches_root_and_empty() {
+        let mut dom = SimpleDom::default();
+        dom.init(&serde_json::json!({
+            "id": 1, "name": "#document", "type": "document", "attributes": {}, "children": [
+                {"id": 2, "name": "P", "type": "element", "attributes": {}, "children": [
+                    {"id": 3, "name": "#comment", "type": "comment", "attributes": {}, "children": []},
+                    {"id": 4, "name": "#text", "type": "text", "text": "", "attributes": {}, "children": []}
+                ]},
+                {"id": 5, "name": "P", "type": "element", "attributes": {}, "children": [
+                    {"id": 6, "name": "#text", "type": "text", "text": "hi", "attributes": {}, "children": []}
+                ]}
+            ]
+        }));
+        dom.recompute_pseudo_states();
+        let matches = |id, selector| dom.matches_simple_selector(id, &parse_selector(selector));
+
+        assert!(matches(1, ":root"));
+        assert!(!matches(2, ":root"));
+        assert!(matches(2, "p:empty"));
+        assert!(!matches(5, "p:empty"));
+        assert!(!matches(1, ":empty"));
+
+        dom.remove_by_path(&[1, 0]);
+        dom.recompute_pseudo_states();
+        assert!(dom.matches_simple_selector(5, &parse_selector(":empty")));
+    }
+
+    #[test]
+    fn matches_pseudo_element_on_originating_element() {
+        let mut dom = SimpleDom::default();
+        dom.init(&serde_json::json!({
+            "id": 1, "name": "UL", "type": "element", "attributes": {}, "children": [
+                {"id": 2, "name": "LI", "type": "element", "attributes": {"class": "a"}, "children": []}
+            ]
+        }));
+        dom.recompute_pseudo_states();
+        let (rules, _, _) = parse_css_rules(".a::before, ul > ::marker { x: y }");
+        assert_eq!(rules.len(), 2);
+        for rule in &rules {
+            assert_eq!(dom.collect_rule_matches(rule), vec![2], "{rule}");
+        }
+    }
+
+    #[test]
+    fn matches_form_and_link_states_after_updates() {
+        let mut dom = SimpleDom::default();
+        dom.init(&serde_json::json!({
+            "id": 1, "name": "FORM", "type": "element", "attributes": {}, "children": [
+                {"id": 2, "name": "INPUT", "type": "element",
+                 "attributes": {"type": "checkbox"}, "children": []},
+                {"id": 3, "name": "A", "type": "element",
+                 "attributes": {"href": "/"}, "states": {"visited": true}, "children": []}
+            ]
+        }));
+        dom.recompute_pseudo_states();
+        let (rules, _, _) =
+            parse_css_rules("input:checked, :enabled, :disabled, a:link, a:visited { x: y }");
+        let matches = |dom: &SimpleDom| {
+            rules
+                .iter()
+                .map(|rule| (rule.to_string(), dom.collect_rule_matches(rule)))
+                .filter(|(_, ids)| !ids.is_empty())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            matches(&dom),
+            vec![
+                (":enabled".to_string(), vec![2]),
+                ("a:visited".to_string(), vec![3])
+            ]
+        );
+
+        dom.set_attribute(&[0], "checked", Some(String::new()));
+        dom.set_attribute(&[0], "disabled", Some(String::new()));
+        dom.set_attribute(&[1], "is_visited", Some("false".to_string()));
+        dom.recompute_pseudo_states();
+        assert_eq!(
+            matches(&dom),
+            vec![
+                (":disabled".to_string(), vec![2]),
+                ("a:link".to_string(), vec![3]),
+                ("input:checked".to_string(), vec![2])
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_has_relative_selectors() {
+        let mut dom = SimpleDom::default();
+        dom.init(&serde_json::json!({
+            "id": 1, "name": "DIV", "type": "element", "attributes": {}, "children": [
+                {"id": 2, "name": "UL", "type": "element", "attributes": {}, "children": [
+                    {"id": 3, "name": "LI", "type": "element", "attributes": {"class": "b"}, "children": []}
+                ]}
+            ]
+        }));
+        dom.recompute_pseudo_states();
+        let matches = |id, selector| dom.matches_simple_selector(id, &parse_selector(selector));
+
+        assert!(matches(1, "div:has(li.b)"));
+        assert!(!matches(1, "div:has(>li.b)"));
+        assert!(matches(2, "ul:has(>li.b)"));
+        assert!(!matches(3, "li:has(li)"));
+        assert!(matches(1, "div:not(:has(p))"));
+    }
+
+    #[test]
+    fn matches_sibling_combinators() {
+        let mut dom = SimpleDom::default();
+        let tags = ["div", "h1", "#text", "p", "span", "p"];
+        for (id, tag) in tags.iter().enumerate() {
+            let id = id as u64;
+            dom.nodes.insert(
+                id,
+                SimpleDomNode {
+                    id,
+                    tag_name: tag.to_string(),
+                    parent: (id > 0).then_some(0),
+                    ..Default::default()
+                },
+            );
+        }
+        dom.nodes.get_mut(&0).unwrap().children = (1..tags.len() as u64).collect();
+        dom.root_id = Some(0);
+
+        let parts = |css: &str| {
+            let (rules, _, _) = parse_css_rules(css);
+            let [CssRule::Complex { parts, .. }] = rules.as_slice() else {
+                panic!("expected one rule, got {:?}", rules);
+            };
+            parts.clone()
+        };
+        let adjacent = &parts("h1 + p { color: red; }");
+        let later = &parts("h1 ~ p { color: red; }");
+        assert!(dom.matches_complex_selector(3, adjacent));
+        assert!(!dom.matches_complex_selector(5, adjacent));
+        assert!(dom.matches_complex_selector(3, later));
+        assert!(dom.matches_complex_selector(5, later));
+        assert!(!dom.matches_complex_selector(4, later));
+    }
+
     #[test]
     fn parse_css_handles_pseudo_classes() {
         let (rules, pseudo, _) =
             parse_css_rules(".wrapper .item:hover strong { font-weight: bold; }");
-        assert!(pseudo.get(":hover").is_none());
+        assert!(!pseudo.contains_key(":hover"));
         assert_eq!(rules.len(), 1);
         match &rules[0] {
             CssRule::Complex { parts, .. } => {
@@ -731,7 +1100,7 @@ mod tests {
     #[test]
     fn parse_css_keeps_descendant_hover_selector() {
         let (rules, pseudo, _) = parse_css_rules(".wrapper :hover { color: blue; }");
-        assert!(pseudo.get(":hover").is_none());
+        assert!(!pseudo.contains_key(":hover"));
         assert_eq!(rules.len(), 1, "hover selector should be preserved");
         match &rules[0] {
             CssRule::Complex { parts, .. } => {
@@ -0,0 +1,324 @@
+use crate::{
+    AcceptState, Command, LayoutFrame, NFA, Nfacell, NodeView, Predicate, Rule, Selector,
+    SelectorId, SelectorManager, json_value_to_attr_string,
+};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+};
+
+/// The tag, class, id and attribute names a document ever uses. A selector that needs a
+/// name outside it cannot match any of the document's nodes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Vocabulary {
+    /// Lower-cased, as the engines intern them.
+    pub tags: BTreeSet<String>,
+    pub classes: BTreeSet<String>,
+    pub ids: BTreeSet<String>,
+    /// Lower-cased, as the engines store them.
+    pub attributes: BTreeSet<String>,
+}
+
+impl Vocabulary {
+    /// Every name the trace's `init` and `add` nodes carry or its attribute changes set.
+    pub fn from_trace(frames: &[LayoutFrame]) -> Self {
+        let mut vocabulary = Vocabulary::default();
+        for frame in frames {
+            match frame.as_command() {
+                Command::Init { node } | Command::Add { node, .. } => {
+                    vocabulary.add_json_node(node)
+                }
+                Command::ReplaceValue { key, value, .. }
+                | Command::InsertValue { key, value, .. } => {
+                    vocabulary.add_attribute(key, value.map(json_value_to_attr_string).as_deref())
+                }
+                Command::DeleteValue { .. } | Command::Recalculate | Command::Remove { .. } => {}
+            }
+        }
+        vocabulary
+    }
+
+    /// Every name the nodes of a built DOM carry, read back through `sm`.
+    pub fn from_nodes<'a>(
+        sm: &SelectorManager,
+        nodes: impl IntoIterator<Item = NodeView<'a>>,
+    ) -> Self {
+        let mut vocabulary = Vocabulary::default();
+        for node in nodes {
+            let names = std::iter::once(node.tag_id)
+                .chain(node.class_ids.iter().copied())
+                .chain(node.id_selector_id);
+            for id in names {
+                match sm.id_to_selector.get(&id) {
+                    Some(Selector::Type(tag)) => vocabulary.tags.insert(tag.clone()),
+                    Some(Selector::Class(class)) => vocabulary.classes.insert(class.clone()),
+                    Some(Selector::Id(name)) => vocabulary.ids.insert(name.clone()),
+                    _ => false,
+                };
+            }
+            vocabulary
+                .attributes
+                .extend(node.attributes.keys().cloned());
+        }
+        vocabulary
+    }
+
+    /// Adds a trace node and its children, read the way the engines build nodes.
+    fn add_json_node(&mut self, node: &serde_json::Value) {
+        if let Some(tag) = node["name"].as_str() {
+            self.tags.insert(tag.to_lowercase());
+        }
+        if let Some(attributes) = node["attributes"].as_object() {
+            for (name, value) in attributes {
+                if !value.is_array() && !value.is_object() && !value.is_null() {
+                    self.add_attribute(name, Some(&json_value_to_attr_string(value)));
+                }
+            }
+        }
+        for child in node["children"].as_array().into_iter().flatten() {
+            self.add_json_node(child);
+        }
+    }
+
+    /// Adds attribute `name`, and for `class` and `id` the names `value` gives.
+    fn add_attribute(&mut self, name: &str, value: Option<&str>) {
+        let name = name.to_lowercase();
+        match (name.as_str(), value) {
+            ("class", Some(value)) => self
+                .classes
+                .extend(value.split_whitespace().map(String::from)),
+            ("id", Some(value)) => {
+                self.ids.insert(value.to_string());
+            }
+            _ => {}
+        }
+        if value.is_some() {
+            self.attributes.insert(name);
+        }
+    }
+}
+
+/// The interned tag, class and id selectors a vocabulary's nodes can carry, and its
+/// attribute names.
+struct LiveNames<'a> {
+    ids: HashSet<SelectorId>,
+    attributes: &'a BTreeSet<String>,
+}
+
+impl<'a> LiveNames<'a> {
+    fn new(vocabulary: &'a Vocabulary, sm: &SelectorManager) -> Self {
+        let tags = vocabulary
+            .tags
+            .iter()
+            .map(|tag| Selector::Type(tag.clone()));
+        let classes = vocabulary
+            .classes
+            .iter()
+            .map(|class| Selector::Class(class.clone()));
+        let ids = vocabulary.ids.iter().map(|id| Selector::Id(id.clone()));
+        LiveNames {
+            ids: tags
+                .chain(classes)
+                .chain(ids)
+                .filter_map(|selector| sm.get_id(&selector))
+                .collect(),
+            attributes: &vocabulary.attributes,
+        }
+    }
+
+    /// Whether some node of the vocabulary may match `predicate`. Conservative: only
+    /// names the predicate requires are checked, so `:not()` and positions always may.
+    fn may_match(&self, sm: &SelectorManager, predicate: &Predicate) -> bool {
+        let interned = |id: &SelectorId| {
+            sm.compiled
+                .get(id.0)
+                .and_then(Option::as_ref)
+                .is_some_and(|predicate| self.may_match(sm, predicate))
+        };
+        match predicate {
+            Predicate::Any | Predicate::Not(_) | Predicate::Nth(_) => true,
+            Predicate::Never => false,
+            Predicate::Tag(id) | Predicate::Class(id) | Predicate::Id(id) => self.ids.contains(id),
+            Predicate::Attribute(attribute) => attribute
+                .attribute_parts()
+                .is_some_and(|(name, ..)| self.attributes.contains(name)),
+            Predicate::Compound(compound) => {
+                compound
+                    .tag
+                    .iter()
+                    .chain(&compound.id)
+                    .chain(&compound.classes)
+                    .all(|id| self.ids.contains(id))
+                    && compound.attributes.iter().all(interned)
+                    && compound.has.iter().all(interned)
+            }
+            Predicate::Has(id) => sm.has_arguments.get(id).is_some_and(|arguments| {
+                arguments
+                    .iter()
+                    .any(|argument| self.may_match(sm, argument))
+            }),
+        }
+    }
+}
+
+/// How much of a stylesheet `prune` found dead, as (dead, total) counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub selectors: (usize, usize),
+    pub rules: (usize, usize),
+    pub states: (usize, usize),
+}
+
+fn share((dead, total): (usize, usize)) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * dead as f64 / total as f64
+    }
+}
+
+/// `D of T selector(s) (P%), ...`, each count dead of total.
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            ("selector(s)", self.selectors),
+            ("rule(s)", self.rules),
+            ("state(s)", self.states),
+        ];
+        for (i, (what, counts)) in parts.into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(
+                f,
+                "{} of {} {what} ({:.1}%)",
+                counts.0,
+                counts.1,
+                share(counts)
+            )?;
+        }
+        f.write_str(" dead")
+    }
+}
+
+/// Drops the rules no node of `vocabulary` can fire and the states they leave either
+/// unreachable or unable to reach an accepting state, then renumbers the remaining
+/// states from 1 in their old order. Selectors left without a live accepting state all
+/// accept in one extra state no rule sets. Outputs on documents within `vocabulary` are
+/// unchanged, apart from the numbering.
+pub fn prune(nfa: &mut NFA, sm: &mut SelectorManager, vocabulary: &Vocabulary) -> PruneReport {
+    let names = LiveNames::new(vocabulary, sm);
+    let fires = |Rule(selector, _, _): &Rule| {
+        selector.is_none_or(|id| {
+            sm.compiled
+                .get(id.0)
+                .and_then(Option::as_ref)
+                .is_some_and(|predicate| names.may_match(sm, predicate))
+        })
+    };
+    let parent_rules: Vec<Rule> = nfa.rules.iter().copied().filter(&fires).collect();
+    // A sibling rule without predecessor never fires; see the engines.
+    let sibling_rules: Vec<Rule> = nfa
+        .sibling_rules
+        .iter()
+        .copied()
+        .filter(|rule| rule.1.is_some() && fires(rule))
+        .collect();
+    let live_rules = || parent_rules.iter().chain(&sibling_rules);
+
+    let mut reachable: HashSet<Nfacell> = nfa.start_state.into_iter().collect();
+    loop {
+        let before = reachable.len();
+        for &Rule(_, prev, next) in live_rules() {
+            if prev.is_none_or(|prev| reachable.contains(&prev)) {
+                reachable.insert(next);
+            }
+        }
+        if reachable.len() == before {
+            break;
+        }
+    }
+    let mut useful: HashSet<Nfacell> = nfa
+        .accept_states
+        .iter()
+        .map(|accept| accept.state)
+        .filter(|state| reachable.contains(state))
+        .collect();
+    loop {
+        let before = useful.len();
+        for &Rule(_, prev, next) in live_rules() {
+            if let Some(prev) = prev
+                && useful.contains(&next)
+                && reachable.contains(&prev)
+            {
+                useful.insert(prev);
+            }
+        }
+        if useful.len() == before {
+            break;
+        }
+    }
+    useful.extend(nfa.start_state);
+
+    let total_states = nfa.max_state_id.0;
+    let mut renumber = vec![None; total_states + 1];
+    let mut next = 0;
+    for (state, slot) in renumber.iter_mut().enumerate().skip(1) {
+        if useful.contains(&Nfacell(state)) {
+            next += 1;
+            *slot = Some(Nfacell(next));
+        }
+    }
+    let live_states = next;
+    let dead_selectors = nfa
+        .accept_states
+        .iter()
+        .filter(|accept| renumber[accept.state.0].is_none())
+        .count();
+    let sink = (dead_selectors > 0).then(|| {
+        next += 1;
+        Nfacell(next)
+    });
+
+    let map = |rules: &[Rule]| -> Vec<Rule> {
+        rules
+            .iter()
+            .filter_map(|&Rule(selector, prev, Nfacell(c))| {
+                let prev = match prev {
+                    Some(Nfacell(b)) => Some(renumber[b]?),
+                    None => None,
+                };
+                Some(Rule(selector, prev, renumber[c]?))
+            })
+            .collect()
+    };
+    let rules = map(&parent_rules);
+    let sibling_rules = map(&sibling_rules);
+    let report = PruneReport {
+        selectors: (dead_selectors, nfa.accept_states.len()),
+        rules: (
+            nfa.rules.len() + nfa.sibling_rules.len() - rules.len() - sibling_rules.len(),
+            nfa.rules.len() + nfa.sibling_rules.len(),
+        ),
+        states: (total_states - live_states, total_states),
+    };
+
+    nfa.rules = rules;
+    nfa.sibling_rules = sibling_rules;
+    nfa.accept_states = nfa
+        .accept_states
+        .iter()
+        .map(|accept| AcceptState {
+            state: renumber[accept.state.0].or(sink).unwrap(),
+            origins: accept.origins.clone(),
+        })
+        .collect();
+    nfa.start_state = nfa.start_state.and_then(|Nfacell(s)| renumber[s]);
+    nfa.states = std::iter::once(nfa.start_state)
+        .chain((1..=next).map(|state| Some(Nfacell(state))))
+        .collect();
+    nfa.max_state_id = Nfacell(next);
+    nfa.dfa = None;
+    nfa.derive_tables(sm);
+    report
+}
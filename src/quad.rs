@@ -1,19 +1,28 @@
 use css_bitvector_compiler::{
-    AddNode, CompoundSelector, LayoutFrame, NFA, Nfacell, PSEUDO_CLASS_FOCUS,
-    PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_FOCUS_WITHIN, PSEUDO_CLASS_HOVER, Rule, Selector,
-    SelectorId, SelectorManager, derive_hover_state, encode, extract_pseudoclasses, generate_nfa,
-    parse_css_with_pseudo, parse_trace, partition_simple_selectors, report_pseudo_selectors,
-    report_skipped_selectors, report_unsupported_selectors,
+    AcceptState, AddNode, Declaration, LayoutFrame, NFA, Nfacell, NodeView, PSEUDO_CLASS_EMPTY,
+    PSEUDO_CLASS_FOCUS, PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_FOCUS_WITHIN, PSEUDO_CLASS_HOVER,
+    PSEUDO_CLASS_ROOT, Rule, RuleBuckets, Selector, SelectorId, SelectorManager, SiblingPosition,
+    attach_automaton, cascade_declarations, changed_properties,
+    compiled::CompiledStylesheet,
+    configure_rule_index, counts_as_content, derive_hover_state, extract_pseudoclasses,
+    inherit_style, inherits_from_parent, is_element_name, parse_trace, prune_dead_states, rdtsc,
+    refresh_element_states, report_automaton, report_computed_styles, report_state_bytes,
+    rule_cycles_enabled,
     runtime_shared::{HasNodes, HasSelectorManager, NodeAttributes, apply_frame_common},
+    selector_sources, sibling_positions,
+    state_bits::{InputReads, StateBits},
 };
 use std::{
-    collections::{HashMap, HashSet},
-    fs,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, fs,
     sync::OnceLock,
 };
 static mut MISS_CNT: usize = 0;
+static mut RULE_EVAL_CYCLES: u64 = 0;
 static mut INPUT_CHANGE_COUNT: usize = 0;
 static mut INPUT_SKIP_COUNT: usize = 0;
+static mut CASCADE_CNT: usize = 0;
+static mut INHERIT_MISS_CNT: usize = 0;
 static mut STATE: usize = 0; // global state
 static DEBUG_MODE: OnceLock<bool> = OnceLock::new();
 
@@ -36,25 +45,135 @@ where
     }
 }
 
-fn format_bits(bits: &[bool]) -> String {
-    bits.iter()
-        .map(|bit| if *bit { '1' } else { '0' })
-        .collect()
+/// A node's outputs while its rules are applied in order: a state is set in `ones`,
+/// copied from a parent state in `copied`, or clear. A copy of the state itself is in
+/// `self_copies`; a copy of any other state took that state's value from the input when
+/// its rule ran, kept in `copied_ones`.
+struct RuleOutputs {
+    ones: StateBits,
+    copied: StateBits,
+    self_copies: StateBits,
+    copied_ones: StateBits,
 }
 
-/// whether a part of input is: 1, 0, or unused
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum IState {
-    IOne,
-    IZero,
-    IUnused,
+impl RuleOutputs {
+    fn new(len: usize) -> Self {
+        RuleOutputs {
+            ones: StateBits::new(len),
+            copied: StateBits::new(len),
+            self_copies: StateBits::new(len),
+            copied_ones: StateBits::new(len),
+        }
+    }
+
+    fn set_one(&mut self, state: usize) {
+        self.ones.insert(state);
+        self.copied.remove(state);
+    }
+
+    fn set_zero(&mut self, state: usize) {
+        self.ones.remove(state);
+        self.copied.remove(state);
+    }
+
+    /// `state` copies `parent`, which is `parent_active` in the input.
+    fn set_from_parent(&mut self, state: usize, parent: usize, parent_active: bool) {
+        self.ones.remove(state);
+        self.copied.insert(state);
+        self.self_copies.set(state, parent == state);
+        self.copied_ones
+            .set(state, parent != state && parent_active);
+    }
+
+    fn is_zero(&self, state: usize) -> bool {
+        !self.ones.get(state) && !self.copied.get(state)
+    }
+
+    fn is_copied(&self, state: usize) -> bool {
+        self.copied.get(state)
+    }
+
+    /// Replaces a copy in `state` with the copied value; the rule that made the copy
+    /// already read it.
+    fn resolve(&mut self, state: usize, input: &StateBits) {
+        if !self.copied.get(state) {
+            return;
+        }
+        let active = if self.self_copies.get(state) {
+            input.get(state)
+        } else {
+            self.copied_ones.get(state)
+        };
+        if active {
+            self.set_one(state);
+        } else {
+            self.set_zero(state);
+        }
+    }
+
+    fn into_output(self) -> OutputStates {
+        let mut output = OutputStates {
+            from_parent: StateBits::new(self.ones.len()),
+            ones: self.ones,
+        };
+        output
+            .from_parent
+            .union_with_masked(&self.copied, &self.self_copies);
+        output
+            .ones
+            .union_with_masked(&self.copied, &self.copied_ones);
+        output
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum OState {
-    OOne,
-    OZero,
-    OFromParent(usize),
+/// A node's output relative to its parent's: the states in `ones` are set, those in
+/// `from_parent` are set when the parent's output has them (a selector-less self-loop),
+/// and the rest are clear. Copies of any other parent state are resolved when the rules
+/// run: that state was read, so changing it recomputes the node anyway.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputStates {
+    pub ones: StateBits,
+    pub from_parent: StateBits,
+}
+
+impl OutputStates {
+    fn new(len: usize) -> Self {
+        OutputStates {
+            ones: StateBits::new(len),
+            from_parent: StateBits::new(len),
+        }
+    }
+
+    /// The output under a parent output of `input`.
+    fn materialize(&self, input: &StateBits) -> StateBits {
+        let mut output = self.ones.clone();
+        output.union_with_masked(&self.from_parent, input);
+        output
+    }
+
+    fn differs_within(&self, other: &OutputStates, mask: &StateBits) -> bool {
+        self.ones.differs_within(&other.ones, mask)
+            || self.from_parent.differs_within(&other.from_parent, mask)
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.ones.heap_bytes() + self.from_parent.heap_bytes()
+    }
+}
+
+/// `1` and `0` for set and clear states, `P` for those copied from the parent.
+impl fmt::Display for OutputStates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for index in 0..self.ones.len() {
+            let state = match (self.ones.get(index), self.from_parent.get(index)) {
+                (true, _) => "1",
+                (_, true) => "P",
+                _ => "0",
+            };
+            f.write_str(state)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -83,38 +202,41 @@ pub struct DOMNode {
     pub attributes: HashMap<String, String>,      // Node attribute key-value pairs (lowercase keys)
     pub pseudo_classes: HashSet<String>,          // Original pseudo-class labels
     pub computed_pseudo_classes: HashSet<String>, // Computed pseudo-class states
-    pub parent: Option<u64>,                      // Index of the parent node in the arena
-    pub children: Vec<u64>,                       // Indices of child nodes in the arena
+    pub position: SiblingPosition,                // Position among element siblings, for `:nth-*`
+    pub content: bool,                            // Counts against the parent's `:empty`
+    pub has_arguments: Vec<bool>,                 // Per `nfa.has_selectors`: matches the arguments
+    pub has_subtree: Vec<bool>, // Per `nfa.has_selectors`: it or a descendant matches
+    pub has_ids: HashSet<SelectorId>, // `:has()` selectors this node satisfies
+    pub parent: Option<u64>,    // Index of the parent node in the arena
+    pub children: Vec<u64>,     // Indices of child nodes in the arena
     pub dirty: DirtyState,
     pub recursive_dirty: bool,
-    pub input_state: Vec<IState>,
-    pub output_state: Vec<OState>,
-}
-
-fn format_input_state(states: &[IState]) -> String {
-    states
-        .iter()
-        .map(|state| match state {
-            IState::IOne => '1',
-            IState::IZero => '0',
-            IState::IUnused => '_',
-        })
-        .collect()
-}
-
-fn format_output_state(states: &[OState]) -> String {
-    states
-        .iter()
-        .map(|state| match state {
-            OState::OOne => "1".to_string(),
-            OState::OZero => "0".to_string(),
-            OState::OFromParent(idx) => format!("P{}", idx),
-        })
-        .collect::<Vec<_>>()
-        .join(",")
+    pub input_state: InputReads,
+    pub output_state: OutputStates,
+    /// Winning value per property, refreshed whenever the node's accept bits change.
+    pub computed_style: BTreeMap<String, String>,
+    /// Values after inheritance: `computed_style` with inherited properties filled in
+    /// from the parent and CSS-wide keywords resolved (see `inherit_style`).
+    pub inherited_style: BTreeMap<String, String>,
+    /// `computed_style` changed since `inherited_style` was last derived.
+    pub inherit_dirty: bool,
+    /// The materialized output of the last visit; `computed_style` was cascaded for it.
+    pub materialized_output: StateBits,
 }
 
 impl DOMNode {
+    fn view(&self) -> NodeView<'_> {
+        NodeView {
+            tag_id: self.tag_id,
+            class_ids: &self.class_ids,
+            id_selector_id: self.id_selector_id,
+            attributes: &self.attributes,
+            computed_pseudo_classes: &self.computed_pseudo_classes,
+            position: &self.position,
+            has_ids: &self.has_ids,
+        }
+    }
+
     fn mark_node_changed(&mut self) {
         self.dirty = DirtyState::NodeChanged;
         self.recursive_dirty = true;
@@ -137,6 +259,7 @@ impl DOMNode {
 pub struct DOM {
     pub nodes: HashMap<u64, DOMNode>, // Arena storage for all nodes
     pub selector_manager: SelectorManager,
+    pub declarations: Vec<Vec<Declaration>>, // Per style rule, see `ParsedSelectors`
     root_node: Option<u64>,
 }
 
@@ -153,6 +276,12 @@ impl NodeAttributes for DOMNode {
     fn pseudo_classes(&mut self) -> &mut HashSet<String> {
         &mut self.pseudo_classes
     }
+    fn computed_pseudo_classes(&mut self) -> &mut HashSet<String> {
+        &mut self.computed_pseudo_classes
+    }
+    fn tag_id(&self) -> SelectorId {
+        self.tag_id
+    }
 }
 
 impl HasSelectorManager for DOM {
@@ -200,6 +329,17 @@ impl AddNode for DOM {
         if derive_hover_state(&pseudo_classes, parent_hover_active) {
             computed_pseudo_classes.insert(PSEUDO_CLASS_HOVER.to_string());
         }
+        // A new node has no children yet; `refresh_empty_state` follows them as they arrive.
+        computed_pseudo_classes.insert(PSEUDO_CLASS_EMPTY.to_string());
+        if parent_index.is_none() {
+            computed_pseudo_classes.insert(PSEUDO_CLASS_ROOT.to_string());
+        }
+        refresh_element_states(
+            tag_name,
+            &attributes,
+            &pseudo_classes,
+            &mut computed_pseudo_classes,
+        );
 
         let mut new_node = DOMNode {
             tag_id,
@@ -208,14 +348,25 @@ impl AddNode for DOM {
             attributes,
             pseudo_classes,
             computed_pseudo_classes,
+            position: SiblingPosition::default(),
+            content: is_element_name(tag_name),
+            has_arguments: vec![false; nfa.has_selectors.len()],
+            has_subtree: vec![false; nfa.has_selectors.len()],
+            has_ids: HashSet::new(),
             parent: parent_index,
             children: Vec::new(),
             dirty: DirtyState::NodeChanged,
             recursive_dirty: true,
-            output_state: vec![OState::OZero; unsafe { STATE } + 1],
-            input_state: vec![IState::IUnused; unsafe { STATE } + 1],
+            output_state: OutputStates::new(unsafe { STATE } + 1),
+            input_state: InputReads::new(unsafe { STATE } + 1),
+            computed_style: BTreeMap::new(),
+            inherited_style: BTreeMap::new(),
+            inherit_dirty: true,
+            materialized_output: StateBits::default(),
         };
-        let (input, output) = self.new_output_state(&new_node, &get_input(), nfa);
+        let (input, output) = self.new_output_state(&new_node, &get_input(), &get_input(), nfa);
+        new_node.materialized_output = output.materialize(&get_input());
+        new_node.computed_style = self.cascade(&new_node.materialized_output, nfa);
         new_node.input_state = input;
         new_node.output_state = output;
         self.nodes.insert(id, new_node);
@@ -283,19 +434,208 @@ impl DOM {
 
     /// Check whether a node matches the given selector ID.
     pub fn node_matches_selector(&self, node: &DOMNode, selector_id: SelectorId) -> bool {
-        match self.selector_manager.id_to_selector.get(&selector_id) {
-            Some(Selector::Type(_)) => node.tag_id == selector_id,
-            Some(Selector::Class(_)) => node.class_ids.contains(&selector_id),
-            Some(Selector::Id(_)) => node.id_selector_id == Some(selector_id),
-            Some(Selector::AttributeEquals { name, value }) => node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false),
-            Some(Selector::Compound(compound)) => self.node_matches_compound(node, compound),
-            None => false,
+        self.selector_manager.matches(selector_id, &node.view())
+    }
+
+    fn is_element(&self, node_idx: u64) -> bool {
+        self.nodes.get(&node_idx).is_some_and(|node| {
+            matches!(
+                self.selector_manager.id_to_selector.get(&node.tag_id),
+                Some(Selector::Type(name)) if is_element_name(name)
+            )
+        })
+    }
+
+    /// Children of `parent_idx` from position `from` up to and including the next element:
+    /// the nodes whose sibling input is the element preceding `from`.
+    fn sibling_readers_from(&self, parent_idx: u64, from: usize) -> Vec<u64> {
+        let mut readers = Vec::new();
+        if let Some(parent) = self.nodes.get(&parent_idx) {
+            for &child_idx in parent.children.iter().skip(from) {
+                readers.push(child_idx);
+                if self.is_element(child_idx) {
+                    break;
+                }
+            }
+        }
+        readers
+    }
+
+    fn previous_element_sibling(&self, node_idx: u64) -> Option<u64> {
+        let parent_idx = self.nodes.get(&node_idx)?.parent?;
+        let children = &self.nodes.get(&parent_idx)?.children;
+        let position = children
+            .iter()
+            .position(|&child_idx| child_idx == node_idx)?;
+        children[..position]
+            .iter()
+            .rev()
+            .copied()
+            .find(|&child_idx| self.is_element(child_idx))
+    }
+
+    /// Mark the siblings reading `node_idx` through sibling rules dirty after its
+    /// sibling-visible output changed.
+    fn invalidate_sibling_readers(&mut self, node_idx: u64) {
+        if !self.is_element(node_idx) {
+            return;
+        }
+        let Some(parent_idx) = self.nodes.get(&node_idx).and_then(|node| node.parent) else {
+            return;
+        };
+        let Some(position) = self.nodes.get(&parent_idx).and_then(|parent| {
+            parent
+                .children
+                .iter()
+                .position(|&child_idx| child_idx == node_idx)
+        }) else {
+            return;
+        };
+        for reader_idx in self.sibling_readers_from(parent_idx, position + 1) {
+            self.set_node_dirty(reader_idx);
+        }
+    }
+
+    /// Mark the siblings from position `from` whose previous element sibling changed
+    /// after an insertion or removal. No-op when the NFA has no sibling rules.
+    fn invalidate_sibling_readers_from(&mut self, parent_idx: u64, from: usize, nfa: &NFA) {
+        if nfa.sibling_rules.is_empty() {
+            return;
+        }
+        for reader_idx in self.sibling_readers_from(parent_idx, from) {
+            self.set_node_dirty(reader_idx);
         }
     }
+
+    /// Recompute the sibling positions of `parent_idx`'s children and mark dirty those
+    /// whose positions read by the NFA's `:nth-*` predicates shifted.
+    fn refresh_sibling_positions(&mut self, parent_idx: u64, nfa: &NFA) {
+        if nfa.position_reads.is_empty() {
+            return;
+        }
+        let Some(children) = self
+            .nodes
+            .get(&parent_idx)
+            .map(|node| node.children.clone())
+        else {
+            return;
+        };
+        let tags: Vec<Option<SelectorId>> = children
+            .iter()
+            .map(|&child_idx| {
+                self.is_element(child_idx)
+                    .then(|| self.nodes[&child_idx].tag_id)
+            })
+            .collect();
+        for (child_idx, position) in children.into_iter().zip(sibling_positions(&tags)) {
+            let Some(child) = self.nodes.get_mut(&child_idx) else {
+                continue;
+            };
+            let previous = std::mem::replace(&mut child.position, position);
+            if nfa.position_reads.changed(&previous, &position) {
+                self.set_node_dirty(child_idx);
+            }
+        }
+    }
+
+    /// Recompute `:empty` for `node_idx` from its children's `content` flags and mark it
+    /// dirty when the state flipped.
+    fn refresh_empty_state(&mut self, node_idx: u64) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return;
+        };
+        let empty = !node
+            .children
+            .iter()
+            .any(|child_idx| self.nodes.get(child_idx).is_some_and(|child| child.content));
+        let node = self.nodes.get_mut(&node_idx).unwrap();
+        let changed = if empty {
+            node.computed_pseudo_classes
+                .insert(PSEUDO_CLASS_EMPTY.to_string())
+        } else {
+            node.computed_pseudo_classes.remove(PSEUDO_CLASS_EMPTY)
+        };
+        if changed {
+            self.set_node_dirty(node_idx);
+        }
+    }
+
+    /// Recompute `node_idx`'s `:has()` bits from its own state and its element children's,
+    /// marking it dirty when the set of `:has()` selectors it satisfies changed. Returns
+    /// whether the bits its parent reads changed and whether that set changed.
+    fn update_has_state(&mut self, node_idx: u64, nfa: &NFA) -> (bool, bool) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return (false, false);
+        };
+        let children: Vec<&DOMNode> = node
+            .children
+            .iter()
+            .filter(|&&child_idx| self.is_element(child_idx))
+            .filter_map(|child_idx| self.nodes.get(child_idx))
+            .collect();
+        let mut arguments = Vec::with_capacity(nfa.has_selectors.len());
+        let mut subtree = Vec::with_capacity(nfa.has_selectors.len());
+        let mut has_ids = HashSet::new();
+        for (k, (has_id, has)) in nfa.has_selectors.iter().enumerate() {
+            let in_children = children.iter().any(|child| child.has_arguments[k]);
+            let in_subtree = children.iter().any(|child| child.has_subtree[k]);
+            if (has.child && in_children) || (!has.child && in_subtree) {
+                has_ids.insert(*has_id);
+            }
+            let matches_self = self
+                .selector_manager
+                .matches_has_arguments(*has_id, &node.view());
+            arguments.push(matches_self);
+            subtree.push(matches_self || in_subtree);
+        }
+        let node = self.nodes.get_mut(&node_idx).unwrap();
+        let bits_changed = node.has_arguments != arguments || node.has_subtree != subtree;
+        node.has_arguments = arguments;
+        node.has_subtree = subtree;
+        let has_changed = node.has_ids != has_ids;
+        if has_changed {
+            node.has_ids = has_ids;
+            self.set_node_dirty(node_idx);
+        }
+        (bits_changed, has_changed)
+    }
+
+    /// Re-run `update_has_state` from `node_idx` up the ancestors for as long as the bits
+    /// their parents read keep changing. Returns whether any `:has()` result changed.
+    pub fn propagate_has_state(&mut self, node_idx: u64, nfa: &NFA) -> bool {
+        if nfa.has_selectors.is_empty() {
+            return false;
+        }
+        let mut has_changed = false;
+        let mut current = Some(node_idx);
+        while let Some(idx) = current {
+            let (bits_changed, changed) = self.update_has_state(idx, nfa);
+            has_changed |= changed;
+            if !bits_changed {
+                break;
+            }
+            current = self.nodes.get(&idx).and_then(|node| node.parent);
+        }
+        has_changed
+    }
+
+    /// Refresh the `:has()` bits of `parent_idx`'s children, whose positions may have
+    /// shifted, then propagate from `parent_idx` after its child list changed.
+    fn refresh_has_after_children_changed(&mut self, parent_idx: u64, nfa: &NFA) {
+        if nfa.has_selectors.is_empty() {
+            return;
+        }
+        let children = self
+            .nodes
+            .get(&parent_idx)
+            .map(|node| node.children.clone())
+            .unwrap_or_default();
+        for child_idx in children {
+            self.update_has_state(child_idx, nfa);
+        }
+        self.propagate_has_state(parent_idx, nfa);
+    }
+
     pub fn get_root_node(&mut self) -> u64 {
         if let Some(r) = self.root_node {
             if self.nodes.contains_key(&r) {
@@ -340,67 +680,6 @@ impl DOM {
         }
     }
 
-    fn node_matches_compound(&self, node: &DOMNode, compound: &CompoundSelector) -> bool {
-        if let Some(tag) = &compound.tag
-            && !self.node_has_tag(node, tag)
-        {
-            return false;
-        }
-        if let Some(id_name) = &compound.id
-            && !self.node_has_id(node, id_name)
-        {
-            return false;
-        }
-        for class_name in &compound.classes {
-            if !self.node_has_class(node, class_name) {
-                return false;
-            }
-        }
-        for (name, value) in &compound.attributes {
-            if !node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false)
-            {
-                return false;
-            }
-        }
-        for pseudo in &compound.pseudos {
-            if !node.computed_pseudo_classes.contains(pseudo) {
-                return false;
-            }
-        }
-        true
-    }
-
-    fn node_has_class(&self, node: &DOMNode, class_name: &str) -> bool {
-        let selector = Selector::Class(class_name.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(class_id) => node.class_ids.contains(&class_id),
-            None => false,
-        }
-    }
-
-    fn node_has_id(&self, node: &DOMNode, id_value: &str) -> bool {
-        let selector = Selector::Id(id_value.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(id) => node.id_selector_id == Some(id),
-            None => false,
-        }
-    }
-
-    fn node_has_tag(&self, node: &DOMNode, tag_name: &str) -> bool {
-        if tag_name == "*" {
-            return true;
-        }
-        let selector = Selector::Type(tag_name.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(tag_id) => node.tag_id == tag_id,
-            None => false,
-        }
-    }
-
     fn refresh_computed_pseudos(&mut self, node_idx: u64) {
         let (parent_idx, parent_hover) = match self.nodes.get(&node_idx) {
             Some(node) => {
@@ -538,6 +817,9 @@ impl DOM {
             parent_index,
             nfa,
         );
+        if let Some(node) = self.nodes.get_mut(&current_index) {
+            node.content = counts_as_content(json_node);
+        }
         // HACK
         if id == 5458 && classes.contains(&"hidden".to_string()) {
             panic!()
@@ -549,6 +831,9 @@ impl DOM {
                 self.json_to_html_node(child_json, Some(current_index), nfa);
             }
         }
+        self.refresh_sibling_positions(current_index, nfa);
+        self.refresh_empty_state(current_index);
+        self.refresh_has_after_children_changed(current_index, nfa);
         current_index
     }
 
@@ -573,10 +858,14 @@ impl DOM {
             parent.children.insert(insert_pos, new_node_idx);
         }
         self.set_node_dirty(current_idx);
+        self.invalidate_sibling_readers_from(current_idx, insert_pos + 1, nfa);
+        self.refresh_sibling_positions(current_idx, nfa);
+        self.refresh_empty_state(current_idx);
+        self.refresh_has_after_children_changed(current_idx, nfa);
     }
 
     /// Remove a node specified by a path.
-    pub fn remove_node_by_path(&mut self, path: &[usize]) {
+    pub fn remove_node_by_path(&mut self, path: &[usize], nfa: &NFA) {
         let root_nodes = self.get_root_node();
         // Descend to the target parent node
         let mut cur_idx = root_nodes;
@@ -597,9 +886,21 @@ impl DOM {
             .map(|node| node.parent == Some(cur_idx))
             .unwrap_or(true);
         if should_remove {
-            self.nodes.remove(&removed_child_id);
+            self.remove_subtree(removed_child_id);
         }
         self.set_node_dirty(cur_idx);
+        self.invalidate_sibling_readers_from(cur_idx, rm_pos, nfa);
+        self.refresh_sibling_positions(cur_idx, nfa);
+        self.refresh_empty_state(cur_idx);
+        self.refresh_has_after_children_changed(cur_idx, nfa);
+    }
+
+    fn remove_subtree(&mut self, node_id: u64) {
+        if let Some(node) = self.nodes.remove(&node_id) {
+            for child in node.children {
+                self.remove_subtree(child);
+            }
+        }
     }
 
     pub fn node_id_by_path(&mut self, path: &[usize]) -> Option<u64> {
@@ -614,32 +915,111 @@ impl DOM {
         Some(current_idx)
     }
 
-    pub fn recompute_styles(&mut self, nfa: &NFA, input: &[bool]) {
+    /// The winning declarations for a node whose output states are `output`.
+    fn cascade(&self, output: &StateBits, nfa: &NFA) -> BTreeMap<String, String> {
+        unsafe {
+            CASCADE_CNT += 1;
+        }
+        let matched = nfa.cascade_order(output);
+        cascade_declarations(matched.iter().map(|(_, origin)| origin), &self.declarations)
+    }
+
+    /// Records the materialized output `output` of `node_idx`, re-cascading the
+    /// declarations of the selectors it matches if an accept state changed. Returns
+    /// whether the materialized output changed, which the children read as input.
+    fn refresh_computed_style(&mut self, node_idx: u64, output: &StateBits, nfa: &NFA) -> bool {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return false;
+        };
+        if node.materialized_output == *output {
+            return false;
+        }
+        let computed_style = nfa
+            .accept_output_changed(&node.materialized_output, output)
+            .then(|| self.cascade(output, nfa));
+        if let Some(computed_style) = &computed_style {
+            debug_log(|| {
+                format!(
+                    "{} cascade -> {} propert(ies)",
+                    self.describe_node(node_idx),
+                    computed_style.len()
+                )
+            });
+        }
+        if let Some(node) = self.nodes.get_mut(&node_idx) {
+            if let Some(computed_style) = computed_style {
+                node.inherit_dirty |= node.computed_style != computed_style;
+                node.computed_style = computed_style;
+            }
+            node.materialized_output = output.clone();
+        }
+        true
+    }
+
+    /// Re-derives the inherited values of `node_idx` if its own cascaded values changed
+    /// or its parent's changed in a property it reads (`parent_changes`), and returns
+    /// the properties whose inherited value changed for its children.
+    fn refresh_inherited_style(
+        &mut self,
+        node_idx: u64,
+        parent_changes: &BTreeSet<String>,
+    ) -> BTreeSet<String> {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return BTreeSet::new();
+        };
+        let reads_change = parent_changes
+            .iter()
+            .any(|property| inherits_from_parent(&node.computed_style, property));
+        if !node.inherit_dirty && !reads_change {
+            return BTreeSet::new();
+        }
+        unsafe {
+            INHERIT_MISS_CNT += 1;
+        }
+        let inherited = match node.parent.and_then(|parent| self.nodes.get(&parent)) {
+            Some(parent) => inherit_style(&parent.inherited_style, &node.computed_style),
+            None => inherit_style(&BTreeMap::new(), &node.computed_style),
+        };
+        let changes = changed_properties(&node.inherited_style, &inherited);
+        debug_log(|| {
+            format!(
+                "{} inherited values -> {} change(s)",
+                self.describe_node(node_idx),
+                changes.len()
+            )
+        });
+        let Some(node) = self.nodes.get_mut(&node_idx) else {
+            return BTreeSet::new();
+        };
+        node.inherited_style = inherited;
+        node.inherit_dirty = false;
+        changes
+    }
+
+    pub fn recompute_styles(&mut self, nfa: &NFA, input: &StateBits) {
         let root_node = self.get_root_node();
         debug_log(|| {
             format!(
                 "recompute start {}; input={}",
                 self.describe_node(root_node),
-                format_bits(input)
+                input
             )
         });
         self.recompute_focus_states(root_node);
-        self.recompute_styles_recursive(root_node, nfa, input);
+        self.recompute_styles_recursive(root_node, nfa, input, &get_input(), &BTreeSet::new());
         debug_log(|| format!("recompute done {}", self.describe_node(root_node)));
     }
-    fn materialize(&self, input: &[bool], output: &[OState]) -> Vec<bool> {
-        (output)
-            .iter()
-            .map(|p| match p {
-                OState::OFromParent(index) => input[*index],
-                OState::OOne => true,
-                OState::OZero => false,
-            })
-            .collect()
+    /// Sibling-rule input for a node: the materialized output of its previous element
+    /// sibling under `parent_bits`, or all-false when there is none.
+    fn sibling_input(&self, previous_element: Option<u64>, parent_bits: &StateBits) -> StateBits {
+        previous_element
+            .and_then(|idx| self.nodes.get(&idx))
+            .map(|sibling| sibling.output_state.materialize(parent_bits))
+            .unwrap_or_else(get_input)
     }
-    fn materialize_chain<F>(&self, node_idx: u64, make_root_input: &F) -> Vec<bool>
+    fn materialize_chain<F>(&self, node_idx: u64, make_root_input: &F) -> StateBits
     where
-        F: Fn() -> Vec<bool>,
+        F: Fn() -> StateBits,
     {
         let node = self
             .nodes
@@ -650,9 +1030,16 @@ impl DOM {
         } else {
             make_root_input()
         };
-        self.materialize(&parent_bits, &node.output_state)
+        node.output_state.materialize(&parent_bits)
     }
-    fn recompute_styles_recursive(&mut self, node_idx: u64, nfa: &NFA, input: &[bool]) {
+    fn recompute_styles_recursive(
+        &mut self,
+        node_idx: u64,
+        nfa: &NFA,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        parent_changes: &BTreeSet<String>,
+    ) {
         let node_descriptor = self.describe_node(node_idx);
         self.refresh_computed_pseudos(node_idx);
         let (
@@ -679,8 +1066,7 @@ impl DOM {
             debug_log(|| {
                 format!(
                     "{} ignored: recursive_dirty=false, input={}",
-                    node_descriptor,
-                    format_bits(input)
+                    node_descriptor, input
                 )
             });
             return;
@@ -691,9 +1077,9 @@ impl DOM {
                 "{} visit: dirty={} input={} cached_input={} cached_output={}",
                 node_descriptor,
                 dirty_state.label(),
-                format_bits(input),
-                format_input_state(&previous_input_state),
-                format_output_state(&previous_output_state)
+                input,
+                &previous_input_state,
+                &previous_output_state
             )
         });
 
@@ -702,7 +1088,7 @@ impl DOM {
             DirtyState::Clean => {
                 debug_log(|| format!("{} clean validation start", node_descriptor));
                 let (new_input, new_output) = match self.nodes.get(&node_idx) {
-                    Some(node) => self.new_output_state(node, input, nfa),
+                    Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                     None => {
                         debug_log(|| {
                             format!(
@@ -716,9 +1102,7 @@ impl DOM {
                 debug_log(|| {
                     format!(
                         "{} validation -> input={} output={}",
-                        node_descriptor,
-                        format_input_state(&new_input),
-                        format_output_state(&new_output)
+                        node_descriptor, &new_input, &new_output
                     )
                 });
                 let input_mismatch = previous_input_state != new_input;
@@ -744,16 +1128,7 @@ impl DOM {
                 }
             }
             DirtyState::InputChanged => {
-                let need_re = !input
-                    .iter()
-                    .copied()
-                    .zip(previous_input_state.iter().copied())
-                    .all(|(input_bit, state)| {
-                        matches!(
-                            (input_bit, state),
-                            (false, IState::IZero) | (true, IState::IOne) | (_, IState::IUnused)
-                        )
-                    });
+                let need_re = !previous_input_state.consistent_with(input);
                 unsafe {
                     INPUT_CHANGE_COUNT += 1;
                 }
@@ -761,9 +1136,7 @@ impl DOM {
                 debug_log(|| {
                     format!(
                         "{} input_changed need_recompute={} input_state={}",
-                        node_descriptor,
-                        need_re,
-                        format_input_state(&previous_input_state)
+                        node_descriptor, need_re, &previous_input_state
                     )
                 });
 
@@ -772,7 +1145,7 @@ impl DOM {
                         MISS_CNT += 1;
                     }
                     let (new_input_state, new_output_state) = match self.nodes.get(&node_idx) {
-                        Some(node) => self.new_output_state(node, input, nfa),
+                        Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                         None => {
                             debug_log(|| {
                                 format!(
@@ -788,10 +1161,10 @@ impl DOM {
                         format!(
                             "{} recompute -> input={} (prev={}) output={} (prev={})",
                             node_descriptor,
-                            format_input_state(&new_input_state),
-                            format_input_state(&previous_input_state),
-                            format_output_state(&new_output_state),
-                            format_output_state(&previous_output_state)
+                            &new_input_state,
+                            &previous_input_state,
+                            &new_output_state,
+                            &previous_output_state
                         )
                     });
                     if let Some(node) = self.nodes.get_mut(&node_idx) {
@@ -814,7 +1187,7 @@ impl DOM {
                         INPUT_SKIP_COUNT += 1;
                     }
                     let (new_input, new_output) = match self.nodes.get(&node_idx) {
-                        Some(node) => self.new_output_state(node, input, nfa),
+                        Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                         None => {
                             debug_log(|| {
                                 format!(
@@ -828,26 +1201,24 @@ impl DOM {
                     debug_log(|| {
                         format!(
                             "{} input reused; output stays {} input stays {}",
-                            node_descriptor,
-                            format_output_state(&previous_output_state),
-                            format_input_state(&previous_input_state)
+                            node_descriptor, &previous_output_state, &previous_input_state
                         )
                     });
                     assert_eq!(
                         previous_input_state,
                         new_input,
-                        "input is {:?}
-old_tri is {:?}
-old_output is {:?}
-new_output is {:?}
-new_tri is {:?}
+                        "input is {}
+old_tri is {}
+old_output is {}
+new_output is {}
+new_tri is {}
 
                    ",
-                        encode(input),
-                        encode(&previous_input_state),
-                        encode(&previous_output_state),
-                        encode(&new_output),
-                        encode(&new_input)
+                        input,
+                        &previous_input_state,
+                        &previous_output_state,
+                        &new_output,
+                        &new_input
                     );
                 }
             }
@@ -856,7 +1227,7 @@ new_tri is {:?}
                     MISS_CNT += 1;
                 }
                 let (new_input_state, new_output_state) = match self.nodes.get(&node_idx) {
-                    Some(node) => self.new_output_state(node, input, nfa),
+                    Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                     None => {
                         debug_log(|| {
                             format!(
@@ -872,10 +1243,10 @@ new_tri is {:?}
                     format!(
                         "{} recompute (node_changed) -> input={} (prev={}) output={} (prev={})",
                         node_descriptor,
-                        format_input_state(&new_input_state),
-                        format_input_state(&previous_input_state),
-                        format_output_state(&new_output_state),
-                        format_output_state(&previous_output_state)
+                        &new_input_state,
+                        &previous_input_state,
+                        &new_output_state,
+                        &previous_output_state
                     )
                 });
                 if let Some(node) = self.nodes.get_mut(&node_idx) {
@@ -896,7 +1267,37 @@ new_tri is {:?}
             }
         }
 
+        // The next element sibling reads this node's output through sibling rules.
+        if should_mark_children
+            && let Some(node) = self.nodes.get(&node_idx)
+            && previous_output_state.differs_within(&node.output_state, &nfa.sibling_reads)
+        {
+            self.invalidate_sibling_readers(node_idx);
+        }
+
+        let output_state_snapshot = match self.nodes.get(&node_idx) {
+            Some(node) => node.output_state.clone(),
+            None => {
+                debug_log(|| {
+                    format!(
+                        "{} removed before child propagation; aborting traversal",
+                        node_descriptor
+                    )
+                });
+                return;
+            }
+        };
+        let current_output_state = output_state_snapshot.materialize(input);
+        // The quad output is relative to the input, so an unchanged quad output can
+        // still materialize differently; the children must then re-read it.
+        if self.refresh_computed_style(node_idx, &current_output_state, nfa) {
+            should_mark_children = true;
+        }
+
         // Propagate dirty state to children if this node's output changed.
+        // With sibling rules a child's materialized output can shift without its quad
+        // output changing, so the following siblings cannot rely on input tracking.
+        let track_siblings = !nfa.sibling_rules.is_empty();
         if should_mark_children {
             debug_log(|| {
                 format!(
@@ -908,7 +1309,11 @@ new_tri is {:?}
             let mut marked_children = Vec::new();
             for &child_idx in &child_indices_snapshot {
                 if let Some(child) = self.nodes.get_mut(&child_idx) {
-                    child.mark_input_changed();
+                    if track_siblings {
+                        child.mark_node_changed();
+                    } else {
+                        child.mark_input_changed();
+                    }
                     let dirty_label = child.dirty.label();
                     marked_children.push((child_idx, dirty_label));
                 }
@@ -926,27 +1331,24 @@ new_tri is {:?}
             debug_log(|| format!("{} children remain clean", node_descriptor));
         }
 
-        let output_state_snapshot = match self.nodes.get(&node_idx) {
-            Some(node) => node.output_state.clone(),
-            None => {
-                debug_log(|| {
-                    format!(
-                        "{} removed before child propagation; aborting traversal",
-                        node_descriptor
-                    )
-                });
-                return;
+        let inherited_changes = self.refresh_inherited_style(node_idx, parent_changes);
+        if !inherited_changes.is_empty() {
+            // Children re-derive their inherited values without recomputing NFA bits.
+            for &child_idx in &child_indices_snapshot {
+                if let Some(child) = self.nodes.get_mut(&child_idx) {
+                    child.recursive_dirty = true;
+                }
             }
-        };
-        let current_output_state = self.materialize(input, &output_state_snapshot);
+        }
         debug_log(|| {
             format!(
                 "{} propagating to {} children with materialized output={}",
                 node_descriptor,
                 child_indices_snapshot.len(),
-                format_bits(&current_output_state)
+                &current_output_state
             )
         });
+        let mut previous_element = None;
         for &child_idx in &child_indices_snapshot {
             let child_needs_visit = if should_mark_children {
                 self.nodes.contains_key(&child_idx)
@@ -957,7 +1359,17 @@ new_tri is {:?}
                     .unwrap_or(false)
             };
             if child_needs_visit {
-                self.recompute_styles_recursive(child_idx, nfa, &current_output_state);
+                let sibling_input = self.sibling_input(previous_element, &current_output_state);
+                self.recompute_styles_recursive(
+                    child_idx,
+                    nfa,
+                    &current_output_state,
+                    &sibling_input,
+                    &inherited_changes,
+                );
+            }
+            if track_siblings && self.is_element(child_idx) {
+                previous_element = Some(child_idx);
             }
         }
 
@@ -970,43 +1382,77 @@ new_tri is {:?}
     fn new_output_state(
         &self,
         node: &DOMNode,
-        input: &[bool],
+        input: &StateBits,
+        sibling_input: &StateBits,
         nfa: &NFA,
-    ) -> (Vec<IState>, Vec<OState>) {
-        let mut new_state = vec![OState::OZero; input.len()];
-
-        struct Read {
-            input: Vec<bool>,
-            pub tri: Vec<IState>,
-        }
-        impl Read {
-            fn new(v: &[bool]) -> Self {
-                let l = v.len();
-                Self {
-                    input: v.into(),
-                    tri: vec![IState::IUnused; l],
-                }
-            }
-            fn get(&mut self, idx: usize) -> bool {
-                self.tri[idx] = if self.input[idx] {
-                    IState::IOne
-                } else {
-                    IState::IZero
-                };
-                self.input[idx]
-            }
+    ) -> (InputReads, OutputStates) {
+        if !rule_cycles_enabled() {
+            return self.evaluate_rules(node, input, sibling_input, nfa);
+        }
+        let start = rdtsc();
+        let output = self.evaluate_rules(node, input, sibling_input, nfa);
+        unsafe {
+            RULE_EVAL_CYCLES += rdtsc() - start;
         }
-        let mut input = Read::new(input);
+        output
+    }
+
+    /// `rules`, narrowed to those `node` may fire when the NFA has a rule index.
+    fn candidate_rules<'a>(
+        &self,
+        node: &DOMNode,
+        rules: &'a [Rule],
+        buckets: Option<&'a RuleBuckets>,
+    ) -> impl Iterator<Item = &'a Rule> {
+        let selected = buckets.map(|buckets| {
+            buckets.select(
+                rules,
+                std::iter::once(node.tag_id)
+                    .chain(node.class_ids.iter().copied())
+                    .chain(node.id_selector_id),
+                node.attributes.keys().map(String::as_str),
+            )
+        });
+        let all = match selected {
+            Some(_) => None,
+            None => Some(rules.iter()),
+        };
+        all.into_iter()
+            .flatten()
+            .chain(selected.into_iter().flatten())
+    }
+
+    fn evaluate_rules(
+        &self,
+        node: &DOMNode,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        nfa: &NFA,
+    ) -> (InputReads, OutputStates) {
+        // A DFA step reads the whole input and yields concrete outputs.
+        if let Some(output) = nfa.dfa.as_ref().and_then(|dfa| {
+            dfa.step(nfa, input, sibling_input, |a| {
+                self.node_matches_selector(node, a)
+            })
+        }) {
+            let mut concrete = OutputStates::new(output.len());
+            concrete.ones.union_with(&output);
+            return (InputReads::all(input), concrete);
+        }
+        let mut outputs = RuleOutputs::new(input.len());
+        let mut reads = InputReads::new(input.len());
         let mut propagate_rules: Vec<Rule> = Vec::new();
 
-        for &rule in nfa.rules.iter() {
+        let index = nfa.rule_index.as_ref();
+        let rules = self.candidate_rules(node, &nfa.rules, index.map(|index| &index.rules));
+        for &rule in rules {
             match rule {
                 Rule(None, None, Nfacell(c)) => {
-                    new_state[c] = OState::OOne;
+                    outputs.set_one(c);
                 }
                 Rule(Some(selector_id), None, Nfacell(c)) => {
                     if self.node_matches_selector(node, selector_id) {
-                        new_state[c] = OState::OOne;
+                        outputs.set_one(c);
                     }
                 }
                 Rule(_, Some(_), _) => {
@@ -1022,43 +1468,57 @@ new_tri is {:?}
 
             match selector_opt {
                 None => {
-                    if matches!(new_state[target_idx], OState::OZero) {
-                        let _ = input.get(parent_idx);
-                        new_state[target_idx] = OState::OFromParent(parent_idx);
+                    if outputs.is_zero(target_idx) {
+                        let parent_active = reads.read(input, parent_idx);
+                        outputs.set_from_parent(target_idx, parent_idx, parent_active);
                     }
                 }
                 Some(selector_id) => {
                     if self.node_matches_selector(node, selector_id) {
-                        let parent_active = input.get(parent_idx);
+                        let parent_active = reads.read(input, parent_idx);
                         if parent_active {
-                            new_state[target_idx] = OState::OFromParent(parent_idx);
+                            outputs.set_from_parent(target_idx, parent_idx, true);
                         } else {
-                            new_state[target_idx] = OState::OZero;
+                            outputs.set_zero(target_idx);
                         }
-                    } else if matches!(new_state[target_idx], OState::OFromParent(_)) {
-                        new_state[target_idx] = OState::OZero;
+                    } else if outputs.is_copied(target_idx) {
+                        outputs.set_zero(target_idx);
                     }
                 }
             }
         }
 
-        for &Nfacell(state_idx) in &nfa.accept_states {
-            if let OState::OFromParent(parent_idx) = new_state[state_idx] {
-                let parent_active = input.get(parent_idx);
-                new_state[state_idx] = if parent_active {
-                    OState::OOne
-                } else {
-                    OState::OZero
-                };
+        // Sibling rules read the previous element sibling's materialized output.
+        let sibling_rules = self.candidate_rules(
+            node,
+            &nfa.sibling_rules,
+            index.map(|index| &index.sibling_rules),
+        );
+        for &Rule(selector, prev, Nfacell(target_idx)) in sibling_rules {
+            let Some(Nfacell(sibling_idx)) = prev else {
+                continue;
+            };
+            if sibling_input.get(sibling_idx)
+                && selector.is_none_or(|a| self.node_matches_selector(node, a))
+            {
+                outputs.set_one(target_idx);
             }
         }
 
-        (input.tri, new_state)
+        for &AcceptState {
+            state: Nfacell(state_idx),
+            ..
+        } in &nfa.accept_states
+        {
+            outputs.resolve(state_idx, input);
+        }
+
+        (reads, outputs.into_output())
     }
 }
 
 impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
-    type AttrState = (Vec<IState>, Vec<OState>);
+    type AttrState = (InputReads, OutputStates);
     fn reset_dom(&mut self) {
         self.nodes.clear();
         self.root_node = None;
@@ -1069,8 +1529,8 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn add_node_by_path(&mut self, path: &[usize], node: &serde_json::Value, nfa: &NFA) {
         self.add_node_by_path(path, node, nfa);
     }
-    fn remove_node_by_path(&mut self, path: &[usize]) {
-        self.remove_node_by_path(path);
+    fn remove_node_by_path(&mut self, path: &[usize], nfa: &NFA) {
+        self.remove_node_by_path(path, nfa);
     }
     fn node_id_by_path(&mut self, path: &[usize]) -> Option<u64> {
         self.node_id_by_path(path)
@@ -1078,16 +1538,19 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn set_node_dirty(&mut self, node_idx: u64) {
         self.set_node_dirty(node_idx);
     }
-    fn recompute_styles(&mut self, nfa: &NFA, input: &[bool]) {
+    fn propagate_has_state(&mut self, node_idx: u64, nfa: &NFA) -> bool {
+        self.propagate_has_state(node_idx, nfa)
+    }
+    fn recompute_styles(&mut self, nfa: &NFA, input: &StateBits) {
         self.recompute_styles(nfa, input);
     }
     fn attr_state_and_parent_input<F>(
         &self,
         node_idx: u64,
         make_root_input: &F,
-    ) -> (Self::AttrState, Vec<bool>)
+    ) -> (Self::AttrState, StateBits)
     where
-        F: Fn() -> Vec<bool>,
+        F: Fn() -> StateBits,
     {
         let node = self
             .nodes
@@ -1105,19 +1568,25 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn recompute_attr_state(
         &self,
         node_idx: u64,
-        parent_bits: &[bool],
+        parent_bits: &StateBits,
         nfa: &NFA,
     ) -> Self::AttrState {
         let node = self
             .nodes
             .get(&node_idx)
             .unwrap_or_else(|| panic!("node {node_idx} not found"));
-        self.new_output_state(node, parent_bits, nfa)
+        let previous_element = if nfa.sibling_rules.is_empty() {
+            None
+        } else {
+            self.previous_element_sibling(node_idx)
+        };
+        let sibling_bits = self.sibling_input(previous_element, parent_bits);
+        self.new_output_state(node, parent_bits, &sibling_bits, nfa)
     }
 }
 
-fn get_input() -> Vec<bool> {
-    vec![false; unsafe { STATE } + 1]
+fn get_input() -> StateBits {
+    StateBits::new(unsafe { STATE } + 1)
 }
 
 fn apply_frame(dom: &mut DOM, frame: &LayoutFrame, nfa: &NFA) {
@@ -1130,16 +1599,17 @@ pub fn collect_rule_matches(
     dom: &mut DOM,
     nfas: &NFA,
     selects: &[String],
+    sources: &BTreeMap<String, Vec<String>>,
 ) -> HashMap<String, Vec<u64>> {
     let mut res: HashMap<String, Vec<u64>> = HashMap::new();
 
-    let mut state_cache: HashMap<u64, Vec<bool>> = HashMap::new();
+    let mut state_cache: HashMap<u64, StateBits> = HashMap::new();
 
     fn materialize_node(
         dom: &DOM,
         node_idx: u64,
-        cache: &mut HashMap<u64, Vec<bool>>,
-    ) -> Vec<bool> {
+        cache: &mut HashMap<u64, StateBits>,
+    ) -> StateBits {
         if let Some(existing) = cache.get(&node_idx) {
             return existing.clone();
         }
@@ -1149,13 +1619,13 @@ pub fn collect_rule_matches(
             if dom.nodes.contains_key(&parent_idx) {
                 materialize_node(dom, parent_idx, cache)
             } else {
-                vec![false; unsafe { STATE } + 1]
+                get_input()
             }
         } else {
-            vec![false; unsafe { STATE } + 1]
+            get_input()
         };
 
-        let current_state = dom.materialize(&parent_state, &node.output_state);
+        let current_state = node.output_state.materialize(&parent_state);
         cache.insert(node_idx, current_state.clone());
         current_state
     }
@@ -1165,38 +1635,40 @@ pub fn collect_rule_matches(
 
     for (&node_id, _) in dom.nodes.iter() {
         let current_state = materialize_node(dom, node_id, &mut state_cache);
-        for (idx, &Nfacell(state_index)) in nfas.accept_states.iter().enumerate() {
-            if current_state[state_index] {
-                let rule = &selects[idx];
-                res.entry(rule.clone()).or_default().push(node_id);
+        for (idx, accept) in nfas.accept_states.iter().enumerate() {
+            let Nfacell(state_index) = accept.state;
+            if current_state.get(state_index) {
+                for source in selector_sources(sources, &selects[idx]) {
+                    res.entry(source.to_string()).or_default().push(node_id);
+                }
             }
         }
     }
 
     for v in res.values_mut() {
-        v.dedup();
         v.sort_unstable();
+        v.dedup();
     }
     res
 }
 fn main() {
     let mut dom = DOM::new();
-    let parsed = parse_css_with_pseudo(
-        &std::fs::read_to_string(format!(
-            "css-gen-op/{0}/{0}.css",
-            std::env::var("WEBSITE_NAME").unwrap(),
-        ))
-        .unwrap(),
-    );
-    let (selectors, skipped_simple) = partition_simple_selectors(parsed.selectors);
-    report_skipped_selectors("quad", &skipped_simple);
-    report_pseudo_selectors("quad", &parsed.pseudo_selectors);
-    report_unsupported_selectors("quad", &parsed.unsupported_selectors);
-    let mut s = unsafe { STATE };
-    let nfa = generate_nfa(&selectors, &mut dom.selector_manager, &mut s);
+    let CompiledStylesheet {
+        mut nfa,
+        selector_manager,
+        selectors,
+        sources,
+        declarations,
+    } = CompiledStylesheet::for_site("quad", &std::env::var("WEBSITE_NAME").unwrap());
+    dom.selector_manager = selector_manager;
+    let frames = parse_trace();
+    prune_dead_states("quad", &mut nfa, &mut dom.selector_manager, &frames);
+    attach_automaton("quad", &mut nfa);
+    configure_rule_index("quad", &mut nfa);
     unsafe {
-        STATE = s;
+        STATE = nfa.max_state_id.0;
     }
+    dom.declarations = declarations;
     let _ = fs::write(
         format!(
             "css-gen-op/{0}/dot_quad.dot",
@@ -1205,21 +1677,43 @@ fn main() {
         nfa.to_dot(&dom.selector_manager),
     );
     // dbg!(&nfa);
-    for f in parse_trace() {
-        apply_frame(&mut dom, &f, &nfa);
+    for f in &frames {
+        apply_frame(&mut dom, f, &nfa);
     }
 
-    let mut final_matches = collect_rule_matches(&mut dom, &nfa, &selectors)
+    let mut final_matches = collect_rule_matches(&mut dom, &nfa, &selectors, &sources)
         .into_iter()
         .collect::<Vec<_>>();
     final_matches.sort();
+    // Reads and outputs were an `IState` and a 16-byte `OState` per state.
+    report_state_bytes(
+        "quad",
+        dom.nodes.len(),
+        unsafe { STATE } + 1,
+        dom.nodes
+            .values()
+            .map(|node| node.input_state.heap_bytes() + node.output_state.heap_bytes())
+            .sum(),
+        17,
+    );
     println!("BEGIN");
     for (k, mut v) in final_matches {
         v.dedup();
         println!("{} -> {:?}", k.replace('>', " > "), v);
     }
     println!("END");
+    report_computed_styles(
+        dom.nodes
+            .iter()
+            .map(|(&node_id, node)| (node_id, node.inherited_style.clone())),
+    );
+    report_automaton("quad", &nfa);
     dbg!(unsafe { MISS_CNT });
+    if rule_cycles_enabled() {
+        dbg!(unsafe { RULE_EVAL_CYCLES });
+    }
     dbg!(unsafe { INPUT_CHANGE_COUNT });
     dbg!(unsafe { INPUT_SKIP_COUNT });
+    dbg!(unsafe { CASCADE_CNT });
+    dbg!(unsafe { INHERIT_MISS_CNT });
 }
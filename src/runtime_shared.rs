@@ -1,8 +1,9 @@
 use std::collections::{HashMap, HashSet};
 
 use crate::{
-    Command, LayoutFrame, NFA, PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_HOVER_ROOT, Selector,
-    SelectorId, SelectorManager, json_value_to_attr_string, parse_command,
+    Command, LayoutFrame, NFA, Selector, SelectorId, SelectorManager, affects_element_states,
+    json_value_to_attr_string, parse_command, refresh_element_states, state_bits::StateBits,
+    state_flag_attribute,
 };
 
 /// Access to selector manager from a DOM implementation.
@@ -21,9 +22,13 @@ pub trait NodeAttributes {
     fn class_ids(&mut self) -> &mut HashSet<SelectorId>;
     fn id_selector_id(&mut self) -> &mut Option<SelectorId>;
     fn pseudo_classes(&mut self) -> &mut HashSet<String>;
+    fn computed_pseudo_classes(&mut self) -> &mut HashSet<String>;
+    fn tag_id(&self) -> SelectorId;
 }
 
 /// Common update_attribute used by bit/tri/quad DOMs.
+/// The node's element-state pseudo-classes (`:checked`, `:link`, ...) are refreshed in
+/// place when the key feeds into them.
 pub fn update_attribute_common<D, N>(
     dom: &mut D,
     node_idx: u64,
@@ -73,12 +78,8 @@ pub fn update_attribute_common<D, N>(
                 *node.id_selector_id() = new_selector_id;
             }
         }
-        "is_hovered_root" | "is_focus_root" => {
-            let pseudo_name = if key_lower == "is_hovered_root" {
-                PSEUDO_CLASS_HOVER_ROOT
-            } else {
-                PSEUDO_CLASS_FOCUS_ROOT
-            };
+        flag if state_flag_attribute(flag).is_some() => {
+            let pseudo_name = state_flag_attribute(flag).unwrap();
             let should_set = new_value
                 .as_deref()
                 .map(|value| value.eq_ignore_ascii_case("true"))
@@ -106,6 +107,26 @@ pub fn update_attribute_common<D, N>(
             }
         }
     }
+
+    if affects_element_states(&key_lower) {
+        let Some(tag_id) = dom.nodes_mut().get(&node_idx).map(|node| node.tag_id()) else {
+            return;
+        };
+        let tag_name = match dom.selector_manager().id_to_selector.get(&tag_id) {
+            Some(Selector::Type(tag_name)) => tag_name.clone(),
+            _ => return,
+        };
+        if let Some(node) = dom.nodes_mut().get_mut(&node_idx) {
+            let attributes = node.attributes().clone();
+            let pseudo_flags = node.pseudo_classes().clone();
+            refresh_element_states(
+                &tag_name,
+                &attributes,
+                &pseudo_flags,
+                node.computed_pseudo_classes(),
+            );
+        }
+    }
 }
 
 /// Minimal DOM surface necessary for a shared apply_frame implementation.
@@ -117,23 +138,27 @@ where
     fn reset_dom(&mut self);
     fn json_to_html_node(&mut self, node: &serde_json::Value, parent: Option<u64>, nfa: &NFA);
     fn add_node_by_path(&mut self, path: &[usize], node: &serde_json::Value, nfa: &NFA);
-    fn remove_node_by_path(&mut self, path: &[usize]);
+    fn remove_node_by_path(&mut self, path: &[usize], nfa: &NFA);
     fn node_id_by_path(&mut self, path: &[usize]) -> Option<u64>;
     fn set_node_dirty(&mut self, node_idx: u64);
-    fn recompute_styles(&mut self, nfa: &NFA, input: &[bool]);
+    fn recompute_styles(&mut self, nfa: &NFA, input: &StateBits);
     fn attr_state_and_parent_input<F>(
         &self,
         node_idx: u64,
         make_root_input: &F,
-    ) -> (Self::AttrState, Vec<bool>)
+    ) -> (Self::AttrState, StateBits)
     where
-        F: Fn() -> Vec<bool>;
+        F: Fn() -> StateBits;
     fn recompute_attr_state(
         &self,
         node_idx: u64,
-        parent_bits: &[bool],
+        parent_bits: &StateBits,
         nfa: &NFA,
     ) -> Self::AttrState;
+    /// Re-evaluates the `:has()` bookkeeping from `node_idx` upward after its own state
+    /// changed, marking dirty the ancestors whose `:has()` result flipped. Returns whether
+    /// any did.
+    fn propagate_has_state(&mut self, node_idx: u64, nfa: &NFA) -> bool;
     fn force_attribute_recompute(&self, key_lower: &str) -> bool {
         matches!(key_lower, "is_hovered_root" | "is_focus_root")
     }
@@ -146,20 +171,22 @@ where
         make_root_input: &F,
     ) -> bool
     where
-        F: Fn() -> Vec<bool>,
+        F: Fn() -> StateBits,
         Self: Sized,
     {
         let key_lower = key.to_ascii_lowercase();
         if self.force_attribute_recompute(&key_lower) {
             update_attribute_common(self, node_idx, key, new_value);
+            self.propagate_has_state(node_idx, nfa);
             return true;
         }
 
         let (previous_state, parent_bits) =
             self.attr_state_and_parent_input(node_idx, make_root_input);
         update_attribute_common(self, node_idx, key, new_value);
+        let ancestors_changed = self.propagate_has_state(node_idx, nfa);
         let new_state = self.recompute_attr_state(node_idx, &parent_bits, nfa);
-        previous_state != new_state
+        previous_state != new_state || ancestors_changed
     }
 }
 
@@ -173,8 +200,8 @@ pub fn apply_frame_common<D, N, FInput, FRecalcInput>(
 ) where
     D: FrameDom<N>,
     N: NodeAttributes,
-    FInput: Fn() -> Vec<bool>,
-    FRecalcInput: Fn(&NFA) -> Vec<bool>,
+    FInput: Fn() -> StateBits,
+    FRecalcInput: Fn(&NFA) -> StateBits,
 {
     match frame.as_command() {
         crate::Command::Init { node } => {
@@ -257,7 +284,7 @@ pub fn apply_frame_common<D, N, FInput, FRecalcInput>(
             dom.recompute_styles(nfa, &make_recalc_input(nfa));
         }
         crate::Command::Remove { path } => {
-            dom.remove_node_by_path(&path);
+            dom.remove_node_by_path(&path, nfa);
             dom.recompute_styles(nfa, &make_input());
         }
     }
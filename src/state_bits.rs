@@ -0,0 +1,276 @@
+use std::fmt;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// A fixed-length set of NFA states packed into `u64` words, the engines' per-node
+/// state vector. Bits past `len` stay clear, so equal sets compare and hash equal.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct StateBits {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl StateBits {
+    /// `len` states, none set.
+    pub fn new(len: usize) -> Self {
+        StateBits {
+            words: vec![0; len.div_ceil(WORD_BITS)],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bits past `len` in the last word are clear, so only the word index is checked:
+    /// rule evaluation reads a state per rule, as often as it read a `Vec<bool>`.
+    #[inline]
+    pub fn get(&self, index: usize) -> bool {
+        self.words
+            .get(index / WORD_BITS)
+            .is_some_and(|word| word >> (index % WORD_BITS) & 1 == 1)
+    }
+
+    #[inline]
+    pub fn insert(&mut self, index: usize) {
+        debug_assert!(index < self.len, "state {index} out of range {}", self.len);
+        self.words[index / WORD_BITS] |= 1 << (index % WORD_BITS);
+    }
+
+    #[inline]
+    pub fn remove(&mut self, index: usize) {
+        if index < self.len {
+            self.words[index / WORD_BITS] &= !(1 << (index % WORD_BITS));
+        }
+    }
+
+    #[inline]
+    pub fn set(&mut self, index: usize, value: bool) {
+        if value {
+            self.insert(index);
+        } else {
+            self.remove(index);
+        }
+    }
+
+    /// Number of states set.
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Whether any state is set.
+    pub fn any(&self) -> bool {
+        self.words.iter().any(|&word| word != 0)
+    }
+
+    /// `self |= other`.
+    pub fn union_with(&mut self, other: &StateBits) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// `self &= other`.
+    pub fn intersect_with(&mut self, other: &StateBits) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    /// `self |= a & b`: the states `b` lets through from `a`.
+    pub fn union_with_masked(&mut self, a: &StateBits, b: &StateBits) {
+        for ((word, a), b) in self.words.iter_mut().zip(&a.words).zip(&b.words) {
+            *word |= a & b;
+        }
+    }
+
+    /// `self |= mask & !a`: the states of `mask` that `a` does not have.
+    pub fn union_with_missing(&mut self, mask: &StateBits, a: &StateBits) {
+        for ((word, mask), a) in self.words.iter_mut().zip(&mask.words).zip(&a.words) {
+            *word |= mask & !a;
+        }
+    }
+
+    /// The states not set.
+    pub fn complement(&self) -> StateBits {
+        let mut words: Vec<u64> = self.words.iter().map(|word| !word).collect();
+        if let Some(last) = words.last_mut()
+            && !self.len.is_multiple_of(WORD_BITS)
+        {
+            *last &= (1 << (self.len % WORD_BITS)) - 1;
+        }
+        StateBits {
+            words,
+            len: self.len,
+        }
+    }
+
+    /// The states set in exactly one of `self` and `other`.
+    pub fn diff(&self, other: &StateBits) -> StateBits {
+        StateBits {
+            words: self
+                .words
+                .iter()
+                .zip(&other.words)
+                .map(|(word, other)| word ^ other)
+                .collect(),
+            len: self.len,
+        }
+    }
+
+    /// Whether `self` and `other` differ in a state of `mask`, without building the diff.
+    pub fn differs_within(&self, other: &StateBits, mask: &StateBits) -> bool {
+        self.words
+            .iter()
+            .zip(&other.words)
+            .zip(&mask.words)
+            .any(|((word, other), mask)| (word ^ other) & mask != 0)
+    }
+
+    /// Whether every state of `self` is also in `other`.
+    pub fn is_subset(&self, other: &StateBits) -> bool {
+        self.words
+            .iter()
+            .zip(&other.words)
+            .all(|(word, other)| word & !other == 0)
+    }
+
+    /// Whether `self` and `other` share a state.
+    pub fn intersects(&self, other: &StateBits) -> bool {
+        self.words
+            .iter()
+            .zip(&other.words)
+            .any(|(word, other)| word & other != 0)
+    }
+
+    /// The states set, in increasing order.
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            let mut rest = word;
+            std::iter::from_fn(move || {
+                (rest != 0).then(|| {
+                    let bit = rest.trailing_zeros() as usize;
+                    rest &= rest - 1;
+                    i * WORD_BITS + bit
+                })
+            })
+        })
+    }
+
+    /// Heap bytes the words take.
+    pub fn heap_bytes(&self) -> usize {
+        self.words.capacity() * size_of::<u64>()
+    }
+}
+
+impl FromIterator<bool> for StateBits {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut words = Vec::with_capacity(iter.size_hint().0.div_ceil(WORD_BITS));
+        let mut word = 0;
+        let mut len: usize = 0;
+        for bit in iter {
+            word |= u64::from(bit) << (len % WORD_BITS);
+            len += 1;
+            if len.is_multiple_of(WORD_BITS) {
+                words.push(word);
+                word = 0;
+            }
+        }
+        if !len.is_multiple_of(WORD_BITS) {
+            words.push(word);
+        }
+        StateBits { words, len }
+    }
+}
+
+/// One `0`/`1` per state, state 0 first.
+impl fmt::Display for StateBits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for index in 0..self.len {
+            f.write_str(if self.get(index) { "1" } else { "0" })?;
+        }
+        Ok(())
+    }
+}
+
+/// The input states a node's last evaluation read, and the value each had: `ones` were
+/// read set, `zeros` read clear, and states in neither were not read. The evaluation
+/// stays valid for any input that agrees on the states read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct InputReads {
+    pub ones: StateBits,
+    pub zeros: StateBits,
+}
+
+impl InputReads {
+    /// Nothing read yet from an input of `len` states.
+    pub fn new(len: usize) -> Self {
+        InputReads {
+            ones: StateBits::new(len),
+            zeros: StateBits::new(len),
+        }
+    }
+
+    /// Every state of `input` read.
+    pub fn all(input: &StateBits) -> Self {
+        InputReads {
+            ones: input.clone(),
+            zeros: input.complement(),
+        }
+    }
+
+    /// Reads state `index` of `input`.
+    #[inline]
+    pub fn read(&mut self, input: &StateBits, index: usize) -> bool {
+        // Both words are written unconditionally: the value read is data dependent, and
+        // a branch on it mispredicts about as often as not.
+        let bit = input.get(index);
+        let (word, shift) = (index / WORD_BITS, index % WORD_BITS);
+        self.ones.words[word] |= u64::from(bit) << shift;
+        self.zeros.words[word] |= u64::from(!bit) << shift;
+        bit
+    }
+
+    /// Reads every state of `mask` from `input` at once.
+    pub fn read_masked(&mut self, input: &StateBits, mask: &StateBits) {
+        self.ones.union_with_masked(input, mask);
+        self.zeros.union_with_missing(mask, input);
+    }
+
+    pub fn is_read(&self, index: usize) -> bool {
+        self.ones.get(index) || self.zeros.get(index)
+    }
+
+    /// Whether `input` agrees with every read.
+    pub fn consistent_with(&self, input: &StateBits) -> bool {
+        self.ones.is_subset(input) && !self.zeros.intersects(input)
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.ones.heap_bytes() + self.zeros.heap_bytes()
+    }
+}
+
+/// `1`/`0` for states read set/clear, `_` for those not read.
+impl fmt::Display for InputReads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for index in 0..self.ones.len() {
+            let read = match (self.ones.get(index), self.zeros.get(index)) {
+                (true, _) => "1",
+                (_, true) => "0",
+                _ => "_",
+            };
+            f.write_str(read)?;
+        }
+        Ok(())
+    }
+}
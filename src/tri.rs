@@ -1,19 +1,28 @@
 use css_bitvector_compiler::{
-    AddNode, CompoundSelector, LayoutFrame, NFA, Nfacell, PSEUDO_CLASS_FOCUS,
-    PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_FOCUS_WITHIN, PSEUDO_CLASS_HOVER, Rule, Selector,
-    SelectorId, SelectorManager, derive_hover_state, encode, extract_pseudoclasses, generate_nfa,
-    parse_css_with_pseudo, parse_trace, partition_simple_selectors, report_pseudo_selectors,
-    report_skipped_selectors, report_unsupported_selectors,
+    AddNode, Declaration, LayoutFrame, NFA, Nfacell, NodeView, PSEUDO_CLASS_EMPTY,
+    PSEUDO_CLASS_FOCUS, PSEUDO_CLASS_FOCUS_ROOT, PSEUDO_CLASS_FOCUS_WITHIN, PSEUDO_CLASS_HOVER,
+    PSEUDO_CLASS_ROOT, Rule, RuleBuckets, Selector, SelectorId, SelectorManager, SiblingPosition,
+    attach_automaton, cascade_declarations, changed_properties,
+    compiled::CompiledStylesheet,
+    configure_rule_index, counts_as_content, derive_hover_state, extract_pseudoclasses,
+    inherit_style, inherits_from_parent, is_element_name, parse_trace, prune_dead_states, rdtsc,
+    refresh_element_states, report_automaton, report_computed_styles, report_state_bytes,
+    rule_cycles_enabled,
     runtime_shared::{HasNodes, HasSelectorManager, NodeAttributes, apply_frame_common},
+    selector_sources, sibling_positions,
+    state_bits::{InputReads, StateBits},
 };
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fs,
     sync::OnceLock,
 };
 static mut MISS_CNT: usize = 0;
+static mut RULE_EVAL_CYCLES: u64 = 0;
 static mut INPUT_CHANGE_COUNT: usize = 0;
 static mut INPUT_SKIP_COUNT: usize = 0;
+static mut CASCADE_CNT: usize = 0;
+static mut INHERIT_MISS_CNT: usize = 0;
 static mut STATE: usize = 0; // global state
 static DEBUG_MODE: OnceLock<bool> = OnceLock::new();
 
@@ -40,20 +49,6 @@ where
     }
 }
 
-fn format_bits(bits: &[bool]) -> String {
-    bits.iter()
-        .map(|bit| if *bit { '1' } else { '0' })
-        .collect()
-}
-
-/// whether a part of input is: 1, 0, or unused
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum IState {
-    IOne,
-    IZero,
-    IUnused,
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum DirtyState {
     #[default]
@@ -80,25 +75,39 @@ pub struct DOMNode {
     pub attributes: HashMap<String, String>,      // Node attribute key-value pairs (lowercase keys)
     pub pseudo_classes: HashSet<String>,          // Original pseudo-class set
     pub computed_pseudo_classes: HashSet<String>, // Computed pseudo-class states
-    pub parent: Option<u64>,                      // Index of the parent node in the arena
-    pub children: Vec<u64>,                       // Indices of child nodes in the arena
+    pub position: SiblingPosition,                // Position among element siblings, for `:nth-*`
+    pub content: bool,                            // Counts against the parent's `:empty`
+    pub has_arguments: Vec<bool>,                 // Per `nfa.has_selectors`: matches the arguments
+    pub has_subtree: Vec<bool>, // Per `nfa.has_selectors`: it or a descendant matches
+    pub has_ids: HashSet<SelectorId>, // `:has()` selectors this node satisfies
+    pub parent: Option<u64>,    // Index of the parent node in the arena
+    pub children: Vec<u64>,     // Indices of child nodes in the arena
     pub dirty: DirtyState,
     pub recursive_dirty: bool,
-    pub output_state: Vec<bool>,
-    pub tri_state: Vec<IState>,
-}
-
-fn format_tri_state(tri: &[IState]) -> String {
-    tri.iter()
-        .map(|state| match state {
-            IState::IOne => '1',
-            IState::IZero => '0',
-            IState::IUnused => '_',
-        })
-        .collect()
+    pub output_state: StateBits,
+    pub tri_state: InputReads,
+    /// Winning value per property, refreshed whenever the node's accept bits change.
+    pub computed_style: BTreeMap<String, String>,
+    /// Values after inheritance: `computed_style` with inherited properties filled in
+    /// from the parent and CSS-wide keywords resolved (see `inherit_style`).
+    pub inherited_style: BTreeMap<String, String>,
+    /// `computed_style` changed since `inherited_style` was last derived.
+    pub inherit_dirty: bool,
 }
 
 impl DOMNode {
+    fn view(&self) -> NodeView<'_> {
+        NodeView {
+            tag_id: self.tag_id,
+            class_ids: &self.class_ids,
+            id_selector_id: self.id_selector_id,
+            attributes: &self.attributes,
+            computed_pseudo_classes: &self.computed_pseudo_classes,
+            position: &self.position,
+            has_ids: &self.has_ids,
+        }
+    }
+
     fn mark_node_changed(&mut self) {
         self.dirty = DirtyState::NodeChanged;
         self.recursive_dirty = true;
@@ -121,6 +130,7 @@ impl DOMNode {
 pub struct DOM {
     pub nodes: HashMap<u64, DOMNode>, // Arena storage for all nodes
     pub selector_manager: SelectorManager,
+    pub declarations: Vec<Vec<Declaration>>, // Per style rule, see `ParsedSelectors`
     root_node: Option<u64>,
 }
 
@@ -137,6 +147,12 @@ impl NodeAttributes for DOMNode {
     fn pseudo_classes(&mut self) -> &mut HashSet<String> {
         &mut self.pseudo_classes
     }
+    fn computed_pseudo_classes(&mut self) -> &mut HashSet<String> {
+        &mut self.computed_pseudo_classes
+    }
+    fn tag_id(&self) -> SelectorId {
+        self.tag_id
+    }
 }
 
 impl HasSelectorManager for DOM {
@@ -184,6 +200,17 @@ impl AddNode for DOM {
         if derive_hover_state(&pseudo_classes, parent_hover_active) {
             computed_pseudo_classes.insert(PSEUDO_CLASS_HOVER.to_string());
         }
+        // A new node has no children yet; `refresh_empty_state` follows them as they arrive.
+        computed_pseudo_classes.insert(PSEUDO_CLASS_EMPTY.to_string());
+        if parent_index.is_none() {
+            computed_pseudo_classes.insert(PSEUDO_CLASS_ROOT.to_string());
+        }
+        refresh_element_states(
+            tag_name,
+            &attributes,
+            &pseudo_classes,
+            &mut computed_pseudo_classes,
+        );
 
         let mut new_node = DOMNode {
             tag_id,
@@ -192,14 +219,23 @@ impl AddNode for DOM {
             attributes,
             pseudo_classes,
             computed_pseudo_classes,
+            position: SiblingPosition::default(),
+            content: is_element_name(tag_name),
+            has_arguments: vec![false; nfa.has_selectors.len()],
+            has_subtree: vec![false; nfa.has_selectors.len()],
+            has_ids: HashSet::new(),
             parent: parent_index,
             children: Vec::new(),
             dirty: DirtyState::NodeChanged,
             recursive_dirty: true,
-            output_state: vec![false; unsafe { STATE } + 1],
-            tri_state: vec![IState::IUnused; unsafe { STATE } + 1],
+            output_state: get_input(),
+            tri_state: InputReads::new(unsafe { STATE } + 1),
+            computed_style: BTreeMap::new(),
+            inherited_style: BTreeMap::new(),
+            inherit_dirty: true,
         };
-        let (output, tri) = self.new_output_state(&new_node, &get_input(), nfa);
+        let (output, tri) = self.new_output_state(&new_node, &get_input(), &get_input(), nfa);
+        new_node.computed_style = self.cascade(&output, nfa);
         new_node.output_state = output;
         new_node.tri_state = tri;
         self.nodes.insert(id, new_node);
@@ -266,80 +302,208 @@ impl DOM {
     }
     /// Check whether a node matches the given selector ID.
     pub fn node_matches_selector(&self, node: &DOMNode, selector_id: SelectorId) -> bool {
-        match self.selector_manager.id_to_selector.get(&selector_id) {
-            Some(Selector::Type(_)) => node.tag_id == selector_id,
-            Some(Selector::Class(_)) => node.class_ids.contains(&selector_id),
-            Some(Selector::Id(_)) => node.id_selector_id == Some(selector_id),
-            Some(Selector::AttributeEquals { name, value }) => node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false),
-            Some(Selector::Compound(compound)) => self.node_matches_compound(node, compound),
-            None => false,
+        self.selector_manager.matches(selector_id, &node.view())
+    }
+
+    fn is_element(&self, node_idx: u64) -> bool {
+        self.nodes.get(&node_idx).is_some_and(|node| {
+            matches!(
+                self.selector_manager.id_to_selector.get(&node.tag_id),
+                Some(Selector::Type(name)) if is_element_name(name)
+            )
+        })
+    }
+
+    /// Children of `parent_idx` from position `from` up to and including the next element:
+    /// the nodes whose sibling input is the element preceding `from`.
+    fn sibling_readers_from(&self, parent_idx: u64, from: usize) -> Vec<u64> {
+        let mut readers = Vec::new();
+        if let Some(parent) = self.nodes.get(&parent_idx) {
+            for &child_idx in parent.children.iter().skip(from) {
+                readers.push(child_idx);
+                if self.is_element(child_idx) {
+                    break;
+                }
+            }
         }
+        readers
     }
 
-    fn node_matches_compound(&self, node: &DOMNode, compound: &CompoundSelector) -> bool {
-        if let Some(tag) = &compound.tag
-            && !self.node_has_tag(node, tag)
-        {
-            return false;
+    fn previous_element_sibling(&self, node_idx: u64) -> Option<u64> {
+        let parent_idx = self.nodes.get(&node_idx)?.parent?;
+        let children = &self.nodes.get(&parent_idx)?.children;
+        let position = children
+            .iter()
+            .position(|&child_idx| child_idx == node_idx)?;
+        children[..position]
+            .iter()
+            .rev()
+            .copied()
+            .find(|&child_idx| self.is_element(child_idx))
+    }
+
+    /// Mark the siblings reading `node_idx` through sibling rules dirty after its
+    /// sibling-visible output changed.
+    fn invalidate_sibling_readers(&mut self, node_idx: u64) {
+        if !self.is_element(node_idx) {
+            return;
         }
-        if let Some(id_value) = &compound.id
-            && !self.node_has_id(node, id_value)
-        {
-            return false;
+        let Some(parent_idx) = self.nodes.get(&node_idx).and_then(|node| node.parent) else {
+            return;
+        };
+        let Some(position) = self.nodes.get(&parent_idx).and_then(|parent| {
+            parent
+                .children
+                .iter()
+                .position(|&child_idx| child_idx == node_idx)
+        }) else {
+            return;
+        };
+        for reader_idx in self.sibling_readers_from(parent_idx, position + 1) {
+            self.set_node_dirty(reader_idx);
         }
-        for class_name in &compound.classes {
-            if !self.node_has_class(node, class_name) {
-                return false;
-            }
+    }
+
+    /// Mark the siblings from position `from` whose previous element sibling changed
+    /// after an insertion or removal. No-op when the NFA has no sibling rules.
+    fn invalidate_sibling_readers_from(&mut self, parent_idx: u64, from: usize, nfa: &NFA) {
+        if nfa.sibling_rules.is_empty() {
+            return;
         }
-        for (name, value) in &compound.attributes {
-            if !node
-                .attributes
-                .get(name)
-                .map(|v| v == value)
-                .unwrap_or(false)
-            {
-                return false;
-            }
+        for reader_idx in self.sibling_readers_from(parent_idx, from) {
+            self.set_node_dirty(reader_idx);
         }
-        for pseudo in &compound.pseudos {
-            if !node.computed_pseudo_classes.contains(pseudo) {
-                return false;
+    }
+
+    /// Recompute the sibling positions of `parent_idx`'s children and mark dirty those
+    /// whose positions read by the NFA's `:nth-*` predicates shifted.
+    fn refresh_sibling_positions(&mut self, parent_idx: u64, nfa: &NFA) {
+        if nfa.position_reads.is_empty() {
+            return;
+        }
+        let Some(children) = self
+            .nodes
+            .get(&parent_idx)
+            .map(|node| node.children.clone())
+        else {
+            return;
+        };
+        let tags: Vec<Option<SelectorId>> = children
+            .iter()
+            .map(|&child_idx| {
+                self.is_element(child_idx)
+                    .then(|| self.nodes[&child_idx].tag_id)
+            })
+            .collect();
+        for (child_idx, position) in children.into_iter().zip(sibling_positions(&tags)) {
+            let Some(child) = self.nodes.get_mut(&child_idx) else {
+                continue;
+            };
+            let previous = std::mem::replace(&mut child.position, position);
+            if nfa.position_reads.changed(&previous, &position) {
+                self.set_node_dirty(child_idx);
             }
         }
-        true
     }
 
-    fn node_has_class(&self, node: &DOMNode, class_name: &str) -> bool {
-        let selector = Selector::Class(class_name.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(class_id) => node.class_ids.contains(&class_id),
-            None => false,
+    /// Recompute `:empty` for `node_idx` from its children's `content` flags and mark it
+    /// dirty when the state flipped.
+    fn refresh_empty_state(&mut self, node_idx: u64) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return;
+        };
+        let empty = !node
+            .children
+            .iter()
+            .any(|child_idx| self.nodes.get(child_idx).is_some_and(|child| child.content));
+        let node = self.nodes.get_mut(&node_idx).unwrap();
+        let changed = if empty {
+            node.computed_pseudo_classes
+                .insert(PSEUDO_CLASS_EMPTY.to_string())
+        } else {
+            node.computed_pseudo_classes.remove(PSEUDO_CLASS_EMPTY)
+        };
+        if changed {
+            self.set_node_dirty(node_idx);
         }
     }
 
-    fn node_has_id(&self, node: &DOMNode, id_value: &str) -> bool {
-        let selector = Selector::Id(id_value.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(id) => node.id_selector_id == Some(id),
-            None => false,
+    /// Recompute `node_idx`'s `:has()` bits from its own state and its element children's,
+    /// marking it dirty when the set of `:has()` selectors it satisfies changed. Returns
+    /// whether the bits its parent reads changed and whether that set changed.
+    fn update_has_state(&mut self, node_idx: u64, nfa: &NFA) -> (bool, bool) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return (false, false);
+        };
+        let children: Vec<&DOMNode> = node
+            .children
+            .iter()
+            .filter(|&&child_idx| self.is_element(child_idx))
+            .filter_map(|child_idx| self.nodes.get(child_idx))
+            .collect();
+        let mut arguments = Vec::with_capacity(nfa.has_selectors.len());
+        let mut subtree = Vec::with_capacity(nfa.has_selectors.len());
+        let mut has_ids = HashSet::new();
+        for (k, (has_id, has)) in nfa.has_selectors.iter().enumerate() {
+            let in_children = children.iter().any(|child| child.has_arguments[k]);
+            let in_subtree = children.iter().any(|child| child.has_subtree[k]);
+            if (has.child && in_children) || (!has.child && in_subtree) {
+                has_ids.insert(*has_id);
+            }
+            let matches_self = self
+                .selector_manager
+                .matches_has_arguments(*has_id, &node.view());
+            arguments.push(matches_self);
+            subtree.push(matches_self || in_subtree);
+        }
+        let node = self.nodes.get_mut(&node_idx).unwrap();
+        let bits_changed = node.has_arguments != arguments || node.has_subtree != subtree;
+        node.has_arguments = arguments;
+        node.has_subtree = subtree;
+        let has_changed = node.has_ids != has_ids;
+        if has_changed {
+            node.has_ids = has_ids;
+            self.set_node_dirty(node_idx);
+        }
+        (bits_changed, has_changed)
+    }
+
+    /// Re-run `update_has_state` from `node_idx` up the ancestors for as long as the bits
+    /// their parents read keep changing. Returns whether any `:has()` result changed.
+    pub fn propagate_has_state(&mut self, node_idx: u64, nfa: &NFA) -> bool {
+        if nfa.has_selectors.is_empty() {
+            return false;
+        }
+        let mut has_changed = false;
+        let mut current = Some(node_idx);
+        while let Some(idx) = current {
+            let (bits_changed, changed) = self.update_has_state(idx, nfa);
+            has_changed |= changed;
+            if !bits_changed {
+                break;
+            }
+            current = self.nodes.get(&idx).and_then(|node| node.parent);
         }
+        has_changed
     }
 
-    fn node_has_tag(&self, node: &DOMNode, tag_name: &str) -> bool {
-        if tag_name == "*" {
-            return true;
+    /// Refresh the `:has()` bits of `parent_idx`'s children, whose positions may have
+    /// shifted, then propagate from `parent_idx` after its child list changed.
+    fn refresh_has_after_children_changed(&mut self, parent_idx: u64, nfa: &NFA) {
+        if nfa.has_selectors.is_empty() {
+            return;
         }
-        let selector = Selector::Type(tag_name.to_string());
-        match self.selector_manager.get_id(&selector) {
-            Some(tag_id) => node.tag_id == tag_id,
-            None => false,
+        let children = self
+            .nodes
+            .get(&parent_idx)
+            .map(|node| node.children.clone())
+            .unwrap_or_default();
+        for child_idx in children {
+            self.update_has_state(child_idx, nfa);
         }
+        self.propagate_has_state(parent_idx, nfa);
     }
+
     pub fn get_root_node(&mut self) -> u64 {
         if let Some(r) = self.root_node {
             if self.nodes.contains_key(&r) {
@@ -512,6 +676,9 @@ impl DOM {
             parent_index,
             nfa,
         );
+        if let Some(node) = self.nodes.get_mut(&current_index) {
+            node.content = counts_as_content(json_node);
+        }
         // HACK
         if id == 5458 && classes.contains(&"hidden".to_string()) {
             panic!()
@@ -523,6 +690,9 @@ impl DOM {
                 self.json_to_html_node(child_json, Some(current_index), nfa);
             }
         }
+        self.refresh_sibling_positions(current_index, nfa);
+        self.refresh_empty_state(current_index);
+        self.refresh_has_after_children_changed(current_index, nfa);
         current_index
     }
 
@@ -547,10 +717,14 @@ impl DOM {
             parent.children.insert(insert_pos, new_node_idx);
         }
         self.set_node_dirty(current_idx);
+        self.invalidate_sibling_readers_from(current_idx, insert_pos + 1, nfa);
+        self.refresh_sibling_positions(current_idx, nfa);
+        self.refresh_empty_state(current_idx);
+        self.refresh_has_after_children_changed(current_idx, nfa);
     }
 
     /// Remove a node specified by a path.
-    pub fn remove_node_by_path(&mut self, path: &[usize]) {
+    pub fn remove_node_by_path(&mut self, path: &[usize], nfa: &NFA) {
         let root_nodes = self.get_root_node();
         // Descend to the target parent node
         let mut cur_idx = root_nodes;
@@ -574,6 +748,10 @@ impl DOM {
             self.remove_subtree(removed_child_id);
         }
         self.set_node_dirty(cur_idx);
+        self.invalidate_sibling_readers_from(cur_idx, rm_pos, nfa);
+        self.refresh_sibling_positions(cur_idx, nfa);
+        self.refresh_empty_state(cur_idx);
+        self.refresh_has_after_children_changed(cur_idx, nfa);
     }
 
     fn remove_subtree(&mut self, node_id: u64) {
@@ -595,20 +773,95 @@ impl DOM {
         Some(current_idx)
     }
 
-    pub fn recompute_styles(&mut self, nfa: &NFA, input: &[bool]) {
+    /// The winning declarations for a node whose output states are `output`.
+    fn cascade(&self, output: &StateBits, nfa: &NFA) -> BTreeMap<String, String> {
+        unsafe {
+            CASCADE_CNT += 1;
+        }
+        let matched = nfa.cascade_order(output);
+        cascade_declarations(matched.iter().map(|(_, origin)| origin), &self.declarations)
+    }
+
+    /// Re-cascades the declarations of the selectors `node_idx` now matches.
+    fn refresh_computed_style(&mut self, node_idx: u64, nfa: &NFA) {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return;
+        };
+        let computed_style = self.cascade(&node.output_state, nfa);
+        debug_log(|| {
+            format!(
+                "{} cascade -> {} propert(ies)",
+                self.describe_node(node_idx),
+                computed_style.len()
+            )
+        });
+        if let Some(node) = self.nodes.get_mut(&node_idx) {
+            node.inherit_dirty |= node.computed_style != computed_style;
+            node.computed_style = computed_style;
+        }
+    }
+
+    /// Re-derives the inherited values of `node_idx` if its own cascaded values changed
+    /// or its parent's changed in a property it reads (`parent_changes`), and returns
+    /// the properties whose inherited value changed for its children.
+    fn refresh_inherited_style(
+        &mut self,
+        node_idx: u64,
+        parent_changes: &BTreeSet<String>,
+    ) -> BTreeSet<String> {
+        let Some(node) = self.nodes.get(&node_idx) else {
+            return BTreeSet::new();
+        };
+        let reads_change = parent_changes
+            .iter()
+            .any(|property| inherits_from_parent(&node.computed_style, property));
+        if !node.inherit_dirty && !reads_change {
+            return BTreeSet::new();
+        }
+        unsafe {
+            INHERIT_MISS_CNT += 1;
+        }
+        let inherited = match node.parent.and_then(|parent| self.nodes.get(&parent)) {
+            Some(parent) => inherit_style(&parent.inherited_style, &node.computed_style),
+            None => inherit_style(&BTreeMap::new(), &node.computed_style),
+        };
+        let changes = changed_properties(&node.inherited_style, &inherited);
+        debug_log(|| {
+            format!(
+                "{} inherited values -> {} change(s)",
+                self.describe_node(node_idx),
+                changes.len()
+            )
+        });
+        let Some(node) = self.nodes.get_mut(&node_idx) else {
+            return BTreeSet::new();
+        };
+        node.inherited_style = inherited;
+        node.inherit_dirty = false;
+        changes
+    }
+
+    pub fn recompute_styles(&mut self, nfa: &NFA, input: &StateBits) {
         let root_node = self.get_root_node();
         debug_log(|| {
             format!(
                 "recompute start {}; input={}",
                 self.describe_node(root_node),
-                format_bits(input)
+                input
             )
         });
         self.recompute_focus_states(root_node);
-        self.recompute_styles_recursive(root_node, nfa, input);
+        self.recompute_styles_recursive(root_node, nfa, input, &get_input(), &BTreeSet::new());
         debug_log(|| format!("recompute done {}", self.describe_node(root_node)));
     }
-    fn recompute_styles_recursive(&mut self, node_idx: u64, nfa: &NFA, input: &[bool]) {
+    fn recompute_styles_recursive(
+        &mut self,
+        node_idx: u64,
+        nfa: &NFA,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        parent_changes: &BTreeSet<String>,
+    ) {
         let node_descriptor = self.describe_node(node_idx);
         self.refresh_computed_pseudos(node_idx);
         let (
@@ -635,8 +888,7 @@ impl DOM {
             debug_log(|| {
                 format!(
                     "{} ignored: recursive_dirty=false, input={}",
-                    node_descriptor,
-                    format_bits(input)
+                    node_descriptor, input
                 )
             });
             return;
@@ -647,9 +899,9 @@ impl DOM {
                 "{} visit: dirty={} input={} cached_output={} tri={}",
                 node_descriptor,
                 dirty_state.label(),
-                format_bits(input),
-                format_bits(&previous_output),
-                format_tri_state(&previous_tri)
+                input,
+                &previous_output,
+                &previous_tri
             )
         });
 
@@ -658,7 +910,7 @@ impl DOM {
             DirtyState::Clean => {
                 debug_log(|| format!("{} clean validation start", node_descriptor));
                 let (new_output, new_tri) = match self.nodes.get(&node_idx) {
-                    Some(node) => self.new_output_state(node, input, nfa),
+                    Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                     None => {
                         debug_log(|| {
                             format!(
@@ -672,37 +924,23 @@ impl DOM {
                 debug_log(|| {
                     format!(
                         "{} validation -> output={} tri={}",
-                        node_descriptor,
-                        format_bits(&new_output),
-                        format_tri_state(&new_tri)
+                        node_descriptor, &new_output, &new_tri
                     )
                 });
                 assert_eq!(
-                    previous_tri,
-                    new_tri,
-                    "input is {:?}
-old_tri is {:?}
-old_output is {:?}
-new_output is {:?}
-new_tri is {:?}
+                    previous_tri, new_tri,
+                    "input is {}
+old_tri is {}
+old_output is {}
+new_output is {}
+new_tri is {}
 
                    ",
-                    encode(input),
-                    encode(&previous_tri),
-                    encode(&previous_output),
-                    encode(&new_output),
-                    encode(&new_tri)
+                    input, &previous_tri, &previous_output, &new_output, &new_tri
                 );
             }
             DirtyState::InputChanged => {
-                let need_re = !input.iter().copied().zip(previous_tri.iter().copied()).all(
-                    |(input_bit, state)| {
-                        matches!(
-                            (input_bit, state),
-                            (false, IState::IZero) | (true, IState::IOne) | (_, IState::IUnused)
-                        )
-                    },
-                );
+                let need_re = !previous_tri.consistent_with(input);
                 unsafe {
                     INPUT_CHANGE_COUNT += 1;
                 }
@@ -710,9 +948,7 @@ new_tri is {:?}
                 debug_log(|| {
                     format!(
                         "{} input_changed need_recompute={} tri={}",
-                        node_descriptor,
-                        need_re,
-                        format_tri_state(&previous_tri)
+                        node_descriptor, need_re, &previous_tri
                     )
                 });
 
@@ -721,7 +957,7 @@ new_tri is {:?}
                         MISS_CNT += 1;
                     }
                     let (new_output_state, new_tri_state) = match self.nodes.get(&node_idx) {
-                        Some(node) => self.new_output_state(node, input, nfa),
+                        Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                         None => {
                             debug_log(|| {
                                 format!(
@@ -749,10 +985,10 @@ new_tri is {:?}
                         format!(
                             "{} recompute -> output={} (prev={}) tri={} (prev={})",
                             node_descriptor,
-                            format_bits(&new_output_state),
-                            format_bits(&previous_output),
-                            format_tri_state(&new_tri_state),
-                            format_tri_state(&previous_tri)
+                            &new_output_state,
+                            &previous_output,
+                            &new_tri_state,
+                            &previous_tri
                         )
                     });
                     if output_changed {
@@ -763,7 +999,7 @@ new_tri is {:?}
                         INPUT_SKIP_COUNT += 1;
                     }
                     let (new_output, new_tri) = match self.nodes.get(&node_idx) {
-                        Some(node) => self.new_output_state(node, input, nfa),
+                        Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                         None => {
                             debug_log(|| {
                                 format!(
@@ -777,26 +1013,19 @@ new_tri is {:?}
                     debug_log(|| {
                         format!(
                             "{} input reused; output stays {} tri stays {}",
-                            node_descriptor,
-                            format_bits(&previous_output),
-                            format_tri_state(&previous_tri)
+                            node_descriptor, &previous_output, &previous_tri
                         )
                     });
                     assert_eq!(
-                        previous_tri,
-                        new_tri,
-                        "input is {:?}
-old_tri is {:?}
-old_output is {:?}
-new_output is {:?}
-new_tri is {:?}
+                        previous_tri, new_tri,
+                        "input is {}
+old_tri is {}
+old_output is {}
+new_output is {}
+new_tri is {}
 
                    ",
-                        encode(input),
-                        encode(&previous_tri),
-                        encode(&previous_output),
-                        encode(&new_output),
-                        encode(&new_tri)
+                        input, &previous_tri, &previous_output, &new_output, &new_tri
                     );
                 }
             }
@@ -805,7 +1034,7 @@ new_tri is {:?}
                     MISS_CNT += 1;
                 }
                 let (new_output_state, new_tri_state) = match self.nodes.get(&node_idx) {
-                    Some(node) => self.new_output_state(node, input, nfa),
+                    Some(node) => self.new_output_state(node, input, sibling_input, nfa),
                     None => {
                         debug_log(|| {
                             format!(
@@ -821,10 +1050,10 @@ new_tri is {:?}
                     format!(
                         "{} recompute (node_changed) -> output={} (prev={}) tri={} (prev={})",
                         node_descriptor,
-                        format_bits(&new_output_state),
-                        format_bits(&previous_output),
-                        format_tri_state(&new_tri_state),
-                        format_tri_state(&previous_tri)
+                        &new_output_state,
+                        &previous_output,
+                        &new_tri_state,
+                        &previous_tri
                     )
                 });
                 if let Some(node) = self.nodes.get_mut(&node_idx) {
@@ -845,6 +1074,20 @@ new_tri is {:?}
             }
         }
 
+        // The next element sibling reads this node's output through sibling rules.
+        if should_mark_children
+            && let Some(node) = self.nodes.get(&node_idx)
+            && nfa.sibling_input_changed(&previous_output, &node.output_state)
+        {
+            self.invalidate_sibling_readers(node_idx);
+        }
+        if should_mark_children
+            && let Some(node) = self.nodes.get(&node_idx)
+            && nfa.accept_output_changed(&previous_output, &node.output_state)
+        {
+            self.refresh_computed_style(node_idx, nfa);
+        }
+
         // Recursively process children
         if should_mark_children {
             debug_log(|| {
@@ -887,6 +1130,15 @@ new_tri is {:?}
                 return;
             }
         };
+        let inherited_changes = self.refresh_inherited_style(node_idx, parent_changes);
+        if !inherited_changes.is_empty() {
+            // Children re-derive their inherited values without recomputing NFA bits.
+            for &child_idx in &child_indices_snapshot {
+                if let Some(child) = self.nodes.get_mut(&child_idx) {
+                    child.recursive_dirty = true;
+                }
+            }
+        }
         debug_log(|| {
             format!(
                 "{} propagating to {} children",
@@ -894,6 +1146,8 @@ new_tri is {:?}
                 child_indices_snapshot.len()
             )
         });
+        let track_siblings = !nfa.sibling_rules.is_empty();
+        let mut previous_element = None;
         for &child_idx in &child_indices_snapshot {
             let child_needs_visit = if should_mark_children {
                 self.nodes.contains_key(&child_idx)
@@ -904,7 +1158,17 @@ new_tri is {:?}
                     .unwrap_or(false)
             };
             if child_needs_visit {
-                self.recompute_styles_recursive(child_idx, nfa, &current_output_state);
+                let sibling_input = self.sibling_input(previous_element);
+                self.recompute_styles_recursive(
+                    child_idx,
+                    nfa,
+                    &current_output_state,
+                    &sibling_input,
+                    &inherited_changes,
+                );
+            }
+            if track_siblings && self.is_element(child_idx) {
+                previous_element = Some(child_idx);
             }
         }
 
@@ -917,61 +1181,121 @@ new_tri is {:?}
     fn new_output_state(
         &self,
         node: &DOMNode,
-        input: &[bool],
+        input: &StateBits,
+        sibling_input: &StateBits,
         nfa: &NFA,
-    ) -> (Vec<bool>, Vec<IState>) {
-        let mut new_state = vec![false; input.len()];
-
-        struct Read {
-            input: Vec<bool>,
-            pub tri: Vec<IState>,
+    ) -> (StateBits, InputReads) {
+        if !rule_cycles_enabled() {
+            return self.evaluate_rules(node, input, sibling_input, nfa);
         }
-        impl Read {
-            fn new(v: &[bool]) -> Self {
-                let l = v.len();
-                Self {
-                    input: v.into(),
-                    tri: vec![IState::IUnused; l],
-                }
-            }
-            fn get(&mut self, idx: usize) -> bool {
-                self.tri[idx] = if self.input[idx] {
-                    IState::IOne
-                } else {
-                    IState::IZero
-                };
-                self.input[idx]
-            }
+        let start = rdtsc();
+        let output = self.evaluate_rules(node, input, sibling_input, nfa);
+        unsafe {
+            RULE_EVAL_CYCLES += rdtsc() - start;
+        }
+        output
+    }
+
+    /// `rules`, narrowed to those `node` may fire when the NFA has a rule index.
+    fn candidate_rules<'a>(
+        &self,
+        node: &DOMNode,
+        rules: &'a [Rule],
+        buckets: Option<&'a RuleBuckets>,
+    ) -> impl Iterator<Item = &'a Rule> {
+        let selected = buckets.map(|buckets| {
+            buckets.select(
+                rules,
+                std::iter::once(node.tag_id)
+                    .chain(node.class_ids.iter().copied())
+                    .chain(node.id_selector_id),
+                node.attributes.keys().map(String::as_str),
+            )
+        });
+        let all = match selected {
+            Some(_) => None,
+            None => Some(rules.iter()),
+        };
+        all.into_iter()
+            .flatten()
+            .chain(selected.into_iter().flatten())
+    }
+
+    fn evaluate_rules(
+        &self,
+        node: &DOMNode,
+        input: &StateBits,
+        sibling_input: &StateBits,
+        nfa: &NFA,
+    ) -> (StateBits, InputReads) {
+        // A DFA step reads the whole input.
+        if let Some(output) = nfa.dfa.as_ref().and_then(|dfa| {
+            dfa.step(nfa, input, sibling_input, |a| {
+                self.node_matches_selector(node, a)
+            })
+        }) {
+            return (output, InputReads::all(input));
         }
-        let mut input = Read::new(input);
-        for &rule in nfa.rules.iter() {
-            match rule {
+        let mut new_state = StateBits::new(input.len());
+        let mut reads = InputReads::new(input.len());
+        new_state.union_with_masked(input, &nfa.self_loops);
+        reads.read_masked(input, &nfa.self_loops);
+
+        let index = nfa.rule_index.as_ref();
+        let rules = self.candidate_rules(node, &nfa.rules, index.map(|index| &index.rules));
+        for rule in rules.filter(|rule| !NFA::is_self_loop(rule)) {
+            match *rule {
                 Rule(None, None, Nfacell(c)) => {
-                    new_state[c] = true;
+                    new_state.insert(c);
                 }
                 Rule(None, Some(Nfacell(b)), Nfacell(c)) => {
-                    if input.get(b) {
-                        new_state[c] = true;
+                    if reads.read(input, b) {
+                        new_state.insert(c);
                     }
                 }
                 Rule(Some(a), None, Nfacell(c)) => {
                     if self.node_matches_selector(node, a) {
-                        new_state[c] = true;
+                        new_state.insert(c);
                     }
                 }
                 Rule(Some(a), Some(Nfacell(b)), Nfacell(c)) => {
-                    if self.node_matches_selector(node, a) && input.get(b) {
-                        new_state[c] = true;
+                    if self.node_matches_selector(node, a) && reads.read(input, b) {
+                        new_state.insert(c);
                     }
                 }
             }
         }
-        (new_state, input.tri)
+        // Sibling reads are not tracked in `tri`: a sibling whose visible output changes
+        // marks this node NodeChanged instead.
+        let sibling_rules = self.candidate_rules(
+            node,
+            &nfa.sibling_rules,
+            index.map(|index| &index.sibling_rules),
+        );
+        for &Rule(selector, prev, Nfacell(c)) in sibling_rules {
+            let Some(Nfacell(b)) = prev else {
+                continue;
+            };
+            if sibling_input.get(b) && selector.is_none_or(|a| self.node_matches_selector(node, a))
+            {
+                new_state.insert(c);
+            }
+        }
+        (new_state, reads)
+    }
+
+    /// Sibling-rule input for a node: the output of its previous element sibling,
+    /// or all-false when there is none.
+    fn sibling_input(&self, previous_element: Option<u64>) -> StateBits {
+        previous_element
+            .and_then(|idx| self.nodes.get(&idx))
+            .map(|sibling| sibling.output_state.clone())
+            .unwrap_or_else(get_input)
     }
 }
 
 impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
-    type AttrState = (Vec<bool>, Vec<IState>);
+    type AttrState = (StateBits, InputReads);
     fn reset_dom(&mut self) {
         self.nodes.clear();
         self.root_node = None;
@@ -982,8 +1306,8 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn add_node_by_path(&mut self, path: &[usize], node: &serde_json::Value, nfa: &NFA) {
         self.add_node_by_path(path, node, nfa);
     }
-    fn remove_node_by_path(&mut self, path: &[usize]) {
-        self.remove_node_by_path(path);
+    fn remove_node_by_path(&mut self, path: &[usize], nfa: &NFA) {
+        self.remove_node_by_path(path, nfa);
     }
     fn node_id_by_path(&mut self, path: &[usize]) -> Option<u64> {
         self.node_id_by_path(path)
@@ -991,16 +1315,19 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn set_node_dirty(&mut self, node_idx: u64) {
         self.set_node_dirty(node_idx);
     }
-    fn recompute_styles(&mut self, nfa: &NFA, input: &[bool]) {
+    fn propagate_has_state(&mut self, node_idx: u64, nfa: &NFA) -> bool {
+        self.propagate_has_state(node_idx, nfa)
+    }
+    fn recompute_styles(&mut self, nfa: &NFA, input: &StateBits) {
         self.recompute_styles(nfa, input);
     }
     fn attr_state_and_parent_input<F>(
         &self,
         node_idx: u64,
         make_root_input: &F,
-    ) -> (Self::AttrState, Vec<bool>)
+    ) -> (Self::AttrState, StateBits)
     where
-        F: Fn() -> Vec<bool>,
+        F: Fn() -> StateBits,
     {
         let node = &self.nodes[&node_idx];
         let parent_bits = node
@@ -1016,16 +1343,22 @@ impl css_bitvector_compiler::runtime_shared::FrameDom<DOMNode> for DOM {
     fn recompute_attr_state(
         &self,
         node_idx: u64,
-        parent_bits: &[bool],
+        parent_bits: &StateBits,
         nfa: &NFA,
     ) -> Self::AttrState {
         let node = &self.nodes[&node_idx];
-        self.new_output_state(node, parent_bits, nfa)
+        let previous_element = if nfa.sibling_rules.is_empty() {
+            None
+        } else {
+            self.previous_element_sibling(node_idx)
+        };
+        let sibling_bits = self.sibling_input(previous_element);
+        self.new_output_state(node, parent_bits, &sibling_bits, nfa)
     }
 }
 
-fn get_input() -> Vec<bool> {
-    vec![false; unsafe { STATE } + 1]
+fn get_input() -> StateBits {
+    StateBits::new(unsafe { STATE } + 1)
 }
 
 fn apply_frame(dom: &mut DOM, frame: &LayoutFrame, nfa: &NFA) {
@@ -1038,20 +1371,24 @@ pub fn collect_rule_matches(
     dom: &DOM,
     nfas: &NFA,
     selects: &[String],
+    sources: &BTreeMap<String, Vec<String>>,
 ) -> HashMap<String, Vec<u64>> {
     let mut res: HashMap<String, Vec<u64>> = HashMap::new();
 
     for (node_id, node) in dom.nodes.iter() {
-        for (idx, &Nfacell(state_index)) in nfas.accept_states.iter().enumerate() {
-            if node.output_state[state_index] {
-                let rule = &selects[idx];
-                res.entry(rule.clone()).or_default().push(*node_id);
+        for (idx, accept) in nfas.accept_states.iter().enumerate() {
+            let Nfacell(state_index) = accept.state;
+            if node.output_state.get(state_index) {
+                for source in selector_sources(sources, &selects[idx]) {
+                    res.entry(source.to_string()).or_default().push(*node_id);
+                }
             }
         }
     }
 
     for v in res.values_mut() {
         v.sort_unstable();
+        v.dedup();
     }
     res
 }
@@ -1090,18 +1427,22 @@ fn main() {
     let mut dom = DOM::new();
     let website_name = std::env::var("WEBSITE_NAME").unwrap();
     let log_match_deltas = env_flag("TRI_LOG_MATCH_DELTAS");
-    let parsed = parse_css_with_pseudo(
-        &std::fs::read_to_string(format!("css-gen-op/{0}/{0}.css", website_name)).unwrap(),
-    );
-    let (selectors, skipped_simple) = partition_simple_selectors(parsed.selectors);
-    report_skipped_selectors("tri", &skipped_simple);
-    report_pseudo_selectors("tri", &parsed.pseudo_selectors);
-    report_unsupported_selectors("tri", &parsed.unsupported_selectors);
-    let mut s = unsafe { STATE };
-    let nfa = generate_nfa(&selectors, &mut dom.selector_manager, &mut s);
+    let CompiledStylesheet {
+        mut nfa,
+        selector_manager,
+        selectors,
+        sources,
+        declarations,
+    } = CompiledStylesheet::for_site("tri", &website_name);
+    dom.selector_manager = selector_manager;
+    let frames = parse_trace();
+    prune_dead_states("tri", &mut nfa, &mut dom.selector_manager, &frames);
+    attach_automaton("tri", &mut nfa);
+    configure_rule_index("tri", &mut nfa);
     unsafe {
-        STATE = s;
+        STATE = nfa.max_state_id.0;
     }
+    dom.declarations = declarations;
     let _ = fs::write(
         format!("css-gen-op/{0}/dot_tri.dot", website_name),
         nfa.to_dot(&dom.selector_manager),
@@ -1114,16 +1455,16 @@ fn main() {
     };
     let mut cached_rule_matches: Option<HashMap<String, Vec<u64>>> = None;
 
-    for f in parse_trace() {
+    for f in &frames {
         let before_miss = if log_match_deltas {
             unsafe { MISS_CNT }
         } else {
             0
         };
-        apply_frame(&mut dom, &f, &nfa);
+        apply_frame(&mut dom, f, &nfa);
         if log_match_deltas {
             let after_miss = unsafe { MISS_CNT };
-            let rule_matches = collect_rule_matches(&dom, &nfa, &selectors);
+            let rule_matches = collect_rule_matches(&dom, &nfa, &selectors, &sources);
             let node_matches = matches_grouped_by_node(&rule_matches);
             let changed_nodes = prev_node_matches
                 .as_ref()
@@ -1145,17 +1486,39 @@ fn main() {
     let mut final_matches = if let Some(matches) = cached_rule_matches {
         matches.into_iter().collect::<Vec<_>>()
     } else {
-        collect_rule_matches(&dom, &nfa, &selectors)
+        collect_rule_matches(&dom, &nfa, &selectors, &sources)
             .into_iter()
             .collect::<Vec<_>>()
     };
     final_matches.sort();
+    // Output bits and reads were a `bool` and an `IState` per state.
+    report_state_bytes(
+        "tri",
+        dom.nodes.len(),
+        unsafe { STATE } + 1,
+        dom.nodes
+            .values()
+            .map(|node| node.output_state.heap_bytes() + node.tri_state.heap_bytes())
+            .sum(),
+        2,
+    );
     println!("BEGIN");
     for (k, v) in final_matches {
         println!("{} -> {:?}", k.replace('>', " > "), v);
     }
     println!("END");
+    report_computed_styles(
+        dom.nodes
+            .iter()
+            .map(|(&node_id, node)| (node_id, node.inherited_style.clone())),
+    );
+    report_automaton("tri", &nfa);
     dbg!(unsafe { MISS_CNT });
+    if rule_cycles_enabled() {
+        dbg!(unsafe { RULE_EVAL_CYCLES });
+    }
     dbg!(unsafe { INPUT_CHANGE_COUNT });
     dbg!(unsafe { INPUT_SKIP_COUNT });
+    dbg!(unsafe { CASCADE_CNT });
+    dbg!(unsafe { INHERIT_MISS_CNT });
 }
@@ -0,0 +1,78 @@
+//! Runs every engine on real site traces with the DFA attached and diffs its matches
+//! against the naive matcher's.
+
+use std::{env, fs, path::PathBuf, process::Command};
+
+/// Small enough to run in debug builds, with stylesheets big enough to need a lazy DFA.
+const SITES: [&str; 2] = ["bing", "google"];
+
+/// A scratch working directory holding only `site`'s stylesheet and trace, so the
+/// files the engines write stay out of `css-gen-op`.
+fn scratch_site(site: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("css-dfa-sites-{}-{site}", std::process::id()));
+    let dir = root.join("css-gen-op").join(site);
+    fs::create_dir_all(&dir).unwrap();
+    let source = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("css-gen-op")
+        .join(site);
+    for file in [format!("{site}.css"), "command.json".to_string()] {
+        fs::copy(source.join(&file), dir.join(&file)).unwrap();
+    }
+    root
+}
+
+/// The sorted lines an engine prints between `BEGIN` and `END`.
+fn matches(engine: &str, site: &str, root: &PathBuf, automaton: &str) -> Vec<String> {
+    let output = Command::new(engine)
+        .current_dir(root)
+        .env("WEBSITE_NAME", site)
+        .env("NFA_AUTOMATON", automaton)
+        .env_remove("BIT_DEBUG")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{engine} on {site}: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if automaton != "nfa" {
+        // Every step went through the DFA rather than falling back to the rules.
+        assert!(
+            stdout.contains("; 0 step(s) fell back to the nfa"),
+            "{engine} ({automaton}) on {site} did not run on the DFA"
+        );
+    }
+    let mut lines: Vec<String> = stdout
+        .lines()
+        .skip_while(|line| *line != "BEGIN")
+        .skip(1)
+        .take_while(|line| *line != "END")
+        .map(str::to_string)
+        .collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn dfa_engines_match_naive_on_sites() {
+    let engines = [
+        env!("CARGO_BIN_EXE_bit"),
+        env!("CARGO_BIN_EXE_tri"),
+        env!("CARGO_BIN_EXE_quad"),
+        env!("CARGO_BIN_EXE_rec_tri"),
+    ];
+    for site in SITES {
+        let root = scratch_site(site);
+        let expected = matches(env!("CARGO_BIN_EXE_naive"), site, &root, "nfa");
+        assert!(!expected.is_empty(), "{site}: naive printed no matches");
+        for engine in engines {
+            let actual = matches(engine, site, &root, "dfa");
+            assert!(
+                actual == expected,
+                "{engine} (dfa) on {site} differs from naive"
+            );
+        }
+        let _ = fs::remove_dir_all(&root);
+    }
+}